@@ -1,12 +1,14 @@
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 
-use nix::unistd::User;
 use target_lexicon::OperatingSystem;
 use tokio::process::Command;
 use tracing::{span, Span};
 
-use crate::action::{ActionError, ActionErrorKind};
+use crate::action::{ActionError, ActionErrorKind, Verification};
 use crate::execute_command;
+use crate::os::passwd::{find_group, find_user};
+use crate::settings::is_alternate_root;
 
 use crate::action::{Action, ActionDescription, StatefulAction};
 
@@ -19,6 +21,8 @@ pub struct AddUserToGroup {
     uid: u32,
     groupname: String,
     gid: u32,
+    #[serde(default = "crate::settings::default_root")]
+    root: PathBuf,
 }
 
 impl AddUserToGroup {
@@ -28,16 +32,25 @@ impl AddUserToGroup {
         uid: u32,
         groupname: String,
         gid: u32,
+        root: impl AsRef<Path>,
     ) -> Result<StatefulAction<Self>, ActionError> {
+        let root = root.as_ref().to_path_buf();
         let this = Self {
             name: name.clone(),
             uid,
             groupname,
             gid,
+            root: root.clone(),
         };
 
         match OperatingSystem::host() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => (),
+            _ if is_alternate_root(&root) => {
+                // Only the shadow utilities can operate on a root other than the host's
+                if which::which("gpasswd").is_err() {
+                    return Err(Self::error(ActionErrorKind::MissingAddUserToGroupCommand));
+                }
+            },
             _ => {
                 if !(which::which("addgroup").is_ok() || which::which("gpasswd").is_ok()) {
                     return Err(Self::error(ActionErrorKind::MissingAddUserToGroupCommand));
@@ -51,22 +64,19 @@ impl AddUserToGroup {
         }
 
         // Ensure user does not exists
-        if let Some(user) = User::from_name(name.as_str())
-            .map_err(|e| ActionErrorKind::GettingUserId(name.clone(), e))
-            .map_err(Self::error)?
-        {
-            if user.uid.as_raw() != uid {
+        if let Some(user) = find_user(&root, &name).map_err(Self::error)? {
+            if user.uid != uid {
                 return Err(Self::error(ActionErrorKind::UserUidMismatch(
                     name.clone(),
-                    user.uid.as_raw(),
+                    user.uid,
                     uid,
                 )));
             }
 
-            if user.gid.as_raw() != gid {
+            if user.gid != gid {
                 return Err(Self::error(ActionErrorKind::UserGidMismatch(
                     name.clone(),
-                    user.gid.as_raw(),
+                    user.gid,
                     gid,
                 )));
             }
@@ -120,6 +130,20 @@ impl AddUserToGroup {
                         },
                     };
                 },
+                _ if is_alternate_root(&root) => {
+                    let user_in_group = find_group(&root, &this.groupname)
+                        .map_err(Self::error)?
+                        .is_some_and(|group| group.members.contains(&this.name));
+
+                    if user_in_group {
+                        tracing::debug!(
+                            "Adding user `{}` to group `{}` already complete",
+                            this.name,
+                            this.groupname
+                        );
+                        return Ok(StatefulAction::completed(this));
+                    }
+                },
                 _ => {
                     let output = execute_command(
                         Command::new("groups")
@@ -188,6 +212,7 @@ impl Action for AddUserToGroup {
             uid: _,
             groupname,
             gid: _,
+            root,
         } = self;
 
         use target_lexicon::OperatingSystem;
@@ -226,6 +251,19 @@ impl Action for AddUserToGroup {
                 .await
                 .map_err(Self::error)?;
             },
+            _ if is_alternate_root(root) => {
+                execute_command(
+                    Command::new("gpasswd")
+                        .process_group(0)
+                        .arg("--root")
+                        .arg(&root)
+                        .args(["-a"])
+                        .args([name, groupname])
+                        .stdin(std::process::Stdio::null()),
+                )
+                .await
+                .map_err(Self::error)?;
+            },
             _ => {
                 if which::which("gpasswd").is_ok() {
                     execute_command(
@@ -276,6 +314,7 @@ impl Action for AddUserToGroup {
             uid: _,
             groupname,
             gid: _,
+            root,
         } = self;
 
         use target_lexicon::OperatingSystem;
@@ -296,6 +335,19 @@ impl Action for AddUserToGroup {
                 .await
                 .map_err(Self::error)?;
             },
+            _ if is_alternate_root(root) => {
+                execute_command(
+                    Command::new("gpasswd")
+                        .process_group(0)
+                        .arg("--root")
+                        .arg(&root)
+                        .args(["-d"])
+                        .args([&name.to_string(), &groupname.to_string()])
+                        .stdin(std::process::Stdio::null()),
+                )
+                .await
+                .map_err(Self::error)?;
+            },
             _ => {
                 if which::which("gpasswd").is_ok() {
                     execute_command(
@@ -326,4 +378,25 @@ impl Action for AddUserToGroup {
 
         Ok(())
     }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        let user = find_user(&self.root, &self.name).map_err(Self::error)?;
+        let group = find_group(&self.root, &self.groupname).map_err(Self::error)?;
+
+        let drift = match (user, group) {
+            (None, _) => format!("User `{}` no longer exists", self.name),
+            (_, None) => format!("Group `{}` no longer exists", self.groupname),
+            (Some(user), Some(group))
+                if user.gid == group.gid || group.members.contains(&user.name) =>
+            {
+                return Ok(Verification::Verified)
+            },
+            (Some(_), Some(_)) => format!(
+                "User `{}` is no longer a member of group `{}`",
+                self.name, self.groupname
+            ),
+        };
+        Ok(Verification::Drifted { drift: vec![drift] })
+    }
 }
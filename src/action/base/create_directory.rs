@@ -7,8 +7,8 @@ use tokio::fs::{create_dir, remove_dir_all, remove_file};
 use tokio::process::Command;
 use tracing::{span, Span};
 
+use crate::action::{verification::verify_path, ActionError, StatefulAction, Verification};
 use crate::action::{Action, ActionDescription, ActionErrorKind, ActionState};
-use crate::action::{ActionError, StatefulAction};
 use crate::execute_command;
 
 /** Create a directory at the given location, optionally with an owning user, group, and mode.
@@ -110,6 +110,7 @@ impl CreateDirectory {
                 force_prune_on_revert,
             },
             state: action_state,
+            checkpoint: None,
         })
     }
 }
@@ -287,6 +288,27 @@ impl Action for CreateDirectory {
 
         Ok(())
     }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        let mut drift = Vec::new();
+        let metadata = verify_path(
+            &self.path,
+            self.user.as_deref(),
+            self.group.as_deref(),
+            self.mode,
+            &mut drift,
+        )
+        .await
+        .map_err(Self::error)?;
+        if metadata.is_some_and(|metadata| !metadata.is_dir()) {
+            drift.push(format!(
+                "`{}` is no longer a directory",
+                self.path.display()
+            ))
+        }
+        Ok(Verification::from_drift(drift))
+    }
 }
 
 // There are cleaner ways of doing this (eg `systemctl status $PATH`) however we need a widely supported way.
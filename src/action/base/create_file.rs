@@ -11,7 +11,8 @@ use tokio::{
 };
 
 use crate::action::{
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    verification::verify_path, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag,
+    StatefulAction, Verification,
 };
 
 /** Create a file at the given location with the provided `buf`,
@@ -140,6 +141,27 @@ impl CreateFile {
 
         Ok(StatefulAction::uncompleted(this))
     }
+
+    /// Plan creating a file at `path` which is moved out of the way before this executes
+    ///
+    /// Unlike [`CreateFile::plan`], whatever is at `path` when planning is not checked.
+    pub(crate) fn plan_replacing(
+        path: impl AsRef<Path>,
+        user: impl Into<Option<String>>,
+        group: impl Into<Option<String>>,
+        mode: impl Into<Option<u32>>,
+        buf: String,
+        force: bool,
+    ) -> StatefulAction<Self> {
+        StatefulAction::uncompleted(Self {
+            path: path.as_ref().to_path_buf(),
+            user: user.into(),
+            group: group.into(),
+            mode: mode.into(),
+            buf,
+            force,
+        })
+    }
 }
 
 #[async_trait::async_trait]
@@ -278,6 +300,36 @@ impl Action for CreateFile {
 
         Ok(())
     }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        let mut drift = Vec::new();
+        let metadata = verify_path(
+            &self.path,
+            self.user.as_deref(),
+            self.group.as_deref(),
+            self.mode,
+            &mut drift,
+        )
+        .await
+        .map_err(Self::error)?;
+        match metadata {
+            Some(metadata) if !metadata.is_file() => {
+                drift.push(format!("`{}` is no longer a file", self.path.display()))
+            },
+            Some(_) => {
+                let discovered_buf = tokio::fs::read_to_string(&self.path)
+                    .await
+                    .map_err(|e| ActionErrorKind::Read(self.path.clone(), e))
+                    .map_err(Self::error)?;
+                if discovered_buf != self.buf {
+                    drift.push(format!("`{}` has different content", self.path.display()))
+                }
+            },
+            None => (),
+        }
+        Ok(Verification::from_drift(drift))
+    }
 }
 
 #[cfg(test)]
@@ -464,6 +516,42 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn verify_detects_drift() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("verify_detects_drift");
+        let mut action = CreateFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            Some(0o644),
+            "Some content".into(),
+            false,
+        )
+        .await?;
+
+        action.try_execute().await?;
+        assert_eq!(
+            action.try_verify().await.verification,
+            Verification::Verified
+        );
+
+        write(test_file.as_path(), "Some different content").await?;
+        tokio::fs::set_permissions(&test_file, PermissionsExt::from_mode(0o600)).await?;
+        match action.try_verify().await.verification {
+            Verification::Drifted { drift } => assert_eq!(drift.len(), 2),
+            verification => return Err(eyre!("Expected drift, got {verification:?}")),
+        }
+
+        action.try_revert().await?;
+        assert_eq!(
+            action.try_verify().await.verification,
+            Verification::Unchecked
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn errors_on_dir() -> eyre::Result<()> {
         let temp_dir = tempfile::tempdir()?;
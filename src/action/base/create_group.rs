@@ -1,10 +1,13 @@
-use nix::unistd::Group;
+use std::path::{Path, PathBuf};
+
 use target_lexicon::OperatingSystem;
 use tokio::process::Command;
 use tracing::{span, Span};
 
-use crate::action::{ActionError, ActionErrorKind, ActionTag};
+use crate::action::{ActionError, ActionErrorKind, ActionTag, Verification};
 use crate::execute_command;
+use crate::os::passwd::find_group;
+use crate::settings::is_alternate_root;
 
 use crate::action::{Action, ActionDescription, StatefulAction};
 
@@ -15,18 +18,35 @@ Create an operating system level user group
 pub struct CreateGroup {
     name: String,
     gid: u32,
+    #[serde(default = "crate::settings::default_root")]
+    root: PathBuf,
 }
 
 impl CreateGroup {
     #[tracing::instrument(level = "debug", skip_all)]
-    pub fn plan(name: String, gid: u32) -> Result<StatefulAction<Self>, ActionError> {
+    pub fn plan(
+        name: String,
+        gid: u32,
+        root: impl AsRef<Path>,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let root = root.as_ref().to_path_buf();
         let this = Self {
             name: name.clone(),
             gid,
+            root: root.clone(),
         };
 
         match OperatingSystem::host() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => (),
+            _ if is_alternate_root(&root) => {
+                // Only the shadow utilities can operate on a root other than the host's
+                if which::which("groupadd").is_err() {
+                    return Err(Self::error(ActionErrorKind::MissingGroupCreationCommand));
+                }
+                if which::which("groupdel").is_err() {
+                    return Err(Self::error(ActionErrorKind::MissingGroupDeletionCommand));
+                }
+            },
             _ => {
                 if !(which::which("groupadd").is_ok() || which::which("addgroup").is_ok()) {
                     return Err(Self::error(ActionErrorKind::MissingGroupCreationCommand));
@@ -38,14 +58,11 @@ impl CreateGroup {
         }
 
         // Ensure group does not exists
-        if let Some(group) = Group::from_name(name.as_str())
-            .map_err(|e| ActionErrorKind::GettingGroupId(name.clone(), e))
-            .map_err(Self::error)?
-        {
-            if group.gid.as_raw() != gid {
+        if let Some(group) = find_group(&root, &name).map_err(Self::error)? {
+            if group.gid != gid {
                 return Err(Self::error(ActionErrorKind::GroupGidMismatch(
                     name.clone(),
-                    group.gid.as_raw(),
+                    group.gid,
                     gid,
                 )));
             }
@@ -67,7 +84,11 @@ impl Action for CreateGroup {
         format!("Create group `{}` (GID {})", self.name, self.gid)
     }
     fn execute_description(&self) -> Vec<ActionDescription> {
-        let Self { name: _, gid: _ } = &self;
+        let Self {
+            name: _,
+            gid: _,
+            root: _,
+        } = &self;
         vec![ActionDescription::new(
             self.tracing_synopsis(),
             vec![format!(
@@ -87,7 +108,7 @@ impl Action for CreateGroup {
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
-        let Self { name, gid } = self;
+        let Self { name, gid, root } = self;
 
         use OperatingSystem;
         match OperatingSystem::host() {
@@ -114,6 +135,18 @@ impl Action for CreateGroup {
                 .await
                 .map_err(Self::error)?;
             },
+            _ if is_alternate_root(root) => {
+                execute_command(
+                    Command::new("groupadd")
+                        .process_group(0)
+                        .arg("--prefix")
+                        .arg(&root)
+                        .args(["-g", &gid.to_string(), "--system", name])
+                        .stdin(std::process::Stdio::null()),
+                )
+                .await
+                .map_err(Self::error)?;
+            },
             _ => {
                 if which::which("groupadd").is_ok() {
                     execute_command(
@@ -143,7 +176,7 @@ impl Action for CreateGroup {
     }
 
     fn revert_description(&self) -> Vec<ActionDescription> {
-        let Self { name, gid } = &self;
+        let Self { name, gid, .. } = &self;
         vec![ActionDescription::new(
             format!("Delete group `{name}` (GID {gid})"),
             vec![format!(
@@ -154,7 +187,7 @@ impl Action for CreateGroup {
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
-        let Self { name, gid: _ } = self;
+        let Self { name, gid: _, root } = self;
 
         use OperatingSystem;
         match OperatingSystem::host() {
@@ -172,6 +205,18 @@ impl Action for CreateGroup {
                 .await
                 .map_err(Self::error)?;
             },
+            _ if is_alternate_root(root) => {
+                execute_command(
+                    Command::new("groupdel")
+                        .process_group(0)
+                        .arg("--prefix")
+                        .arg(&root)
+                        .arg(name)
+                        .stdin(std::process::Stdio::null()),
+                )
+                .await
+                .map_err(Self::error)?;
+            },
             _ => {
                 if which::which("groupdel").is_ok() {
                     execute_command(
@@ -199,4 +244,18 @@ impl Action for CreateGroup {
 
         Ok(())
     }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        let mut drift = Vec::new();
+        match find_group(&self.root, &self.name).map_err(Self::error)? {
+            Some(group) if group.gid != self.gid => drift.push(format!(
+                "Group `{}` has GID {}, expected {}",
+                self.name, group.gid, self.gid
+            )),
+            Some(_) => (),
+            None => drift.push(format!("Group `{}` no longer exists", self.name)),
+        }
+        Ok(Verification::from_drift(drift))
+    }
 }
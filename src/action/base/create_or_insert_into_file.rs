@@ -1,7 +1,8 @@
 use nix::unistd::{chown, Group, User};
 
 use crate::action::{
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    verification::verify_path, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag,
+    StatefulAction, Verification,
 };
 use rand::Rng;
 use std::{
@@ -389,6 +390,33 @@ impl Action for CreateOrInsertIntoFile {
         }
         Ok(())
     }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        let mut drift = Vec::new();
+        let metadata = verify_path(
+            &self.path,
+            self.user.as_deref(),
+            self.group.as_deref(),
+            self.mode,
+            &mut drift,
+        )
+        .await
+        .map_err(Self::error)?;
+        if metadata.is_some() {
+            let file_contents = tokio::fs::read_to_string(&self.path)
+                .await
+                .map_err(|e| ActionErrorKind::Read(self.path.clone(), e))
+                .map_err(Self::error)?;
+            if !file_contents.contains(self.buf.as_str()) {
+                drift.push(format!(
+                    "`{}` no longer contains the Nix related fragment",
+                    self.path.display()
+                ))
+            }
+        }
+        Ok(Verification::from_drift(drift))
+    }
 }
 
 #[cfg(test)]
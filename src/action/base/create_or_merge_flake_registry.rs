@@ -0,0 +1,668 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use rand::Rng;
+use serde_json::{json, Value};
+use tokio::{
+    fs::{remove_file, OpenOptions},
+    io::AsyncWriteExt,
+};
+use tracing::{span, Span};
+use url::Url;
+
+use crate::action::{
+    verification::verify_path, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag,
+    StatefulAction, Verification,
+};
+
+const FLAKE_REGISTRY_MODE: u32 = 0o644;
+const FLAKE_REGISTRY_VERSION: u64 = 2;
+/// The archives Nix fetches an `http(s)` flake reference as a `tarball` rather than a `file`
+const TARBALL_SUFFIXES: &[&str] = &[
+    ".zip", ".tar", ".tgz", ".tar.gz", ".tar.xz", ".tar.bz2", ".tar.zst",
+];
+
+/// A flake reference to pin a registry entry to, written in the URL-like syntax, such as
+/// `github:NixOS/nixpkgs/057f9aecfb71c4437d2b27d3323df7f93c010b7e`
+///
+/// It is checked to be well formed when parsed, and written to the registry as attributes.
+#[derive(Debug, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct FlakeRef {
+    original: String,
+    attrs: BTreeMap<String, String>,
+}
+
+impl FlakeRef {
+    /// The attributes of the reference, as `to` is written in `registry.json`
+    pub fn attrs(&self) -> Value {
+        Value::Object(
+            self.attrs
+                .iter()
+                .map(|(name, value)| (name.clone(), Value::String(value.clone())))
+                .collect(),
+        )
+    }
+
+    /// Whether the reference is to a specific revision, or a tarball or file which cannot follow a branch
+    pub fn is_locked(&self) -> bool {
+        self.attrs.contains_key("rev")
+            || matches!(
+                self.attrs.get("type").map(String::as_str),
+                Some("tarball" | "file" | "path")
+            )
+    }
+}
+
+impl Display for FlakeRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.original)
+    }
+}
+
+impl FromStr for FlakeRef {
+    type Err = FlakeRefError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).map_err(|e| FlakeRefError::Url(s.to_string(), e))?;
+        let mut attrs = BTreeMap::new();
+        let mut query = url.query_pairs().into_owned().collect::<Vec<_>>();
+        // Pulls the attributes `names` out of the query, leaving the rest
+        let mut take_query = |attrs: &mut BTreeMap<String, String>, names: &[&str]| {
+            query.retain(|(name, value)| match names.contains(&name.as_str()) {
+                true => {
+                    attrs.insert(name.clone(), value.clone());
+                    false
+                },
+                false => true,
+            });
+        };
+        match url.scheme() {
+            scheme @ ("github" | "gitlab" | "sourcehut") => {
+                let segments = url.path().split('/').collect::<Vec<_>>();
+                let (owner, repo, ref_or_rev) = match segments[..] {
+                    [owner, repo] => (owner, repo, None),
+                    [owner, repo, ref_or_rev] => (owner, repo, Some(ref_or_rev)),
+                    _ => return Err(FlakeRefError::Repository(s.to_string())),
+                };
+                if owner.is_empty() || repo.is_empty() {
+                    return Err(FlakeRefError::Repository(s.to_string()));
+                }
+                attrs.insert("type".into(), scheme.into());
+                attrs.insert("owner".into(), owner.into());
+                attrs.insert("repo".into(), repo.into());
+                match ref_or_rev {
+                    Some(rev) if is_rev(rev) => attrs.insert("rev".into(), rev.into()),
+                    Some(git_ref) if !git_ref.is_empty() => {
+                        attrs.insert("ref".into(), git_ref.into())
+                    },
+                    _ => None,
+                };
+                take_query(&mut attrs, &["rev", "ref", "host", "dir", "narHash"]);
+                if let Some((name, _)) = query.first() {
+                    return Err(FlakeRefError::Parameter(s.to_string(), name.clone()));
+                }
+            },
+            "path" => {
+                if !url.path().starts_with('/') {
+                    return Err(FlakeRefError::RelativePath(s.to_string()));
+                }
+                attrs.insert("type".into(), "path".into());
+                attrs.insert("path".into(), url.path().into());
+                take_query(&mut attrs, &["narHash"]);
+            },
+            "flake" => return Err(FlakeRefError::Indirect(s.to_string())),
+            scheme => {
+                let (fetcher, transport) = match scheme.split_once('+') {
+                    Some((fetcher @ ("git" | "tarball" | "file"), transport)) => {
+                        (fetcher, transport)
+                    },
+                    Some(_) => return Err(FlakeRefError::UnsupportedScheme(s.to_string())),
+                    None => {
+                        let fetcher = match TARBALL_SUFFIXES
+                            .iter()
+                            .any(|suffix| url.path().ends_with(suffix))
+                        {
+                            true => "tarball",
+                            false => "file",
+                        };
+                        (fetcher, scheme)
+                    },
+                };
+                if !matches!(transport, "https" | "http" | "ssh" | "file")
+                    || (fetcher != "git" && transport == "ssh")
+                {
+                    return Err(FlakeRefError::UnsupportedScheme(s.to_string()));
+                }
+                attrs.insert("type".into(), fetcher.into());
+                match fetcher {
+                    "git" => take_query(&mut attrs, &["rev", "ref", "dir", "narHash"]),
+                    _ => take_query(&mut attrs, &["narHash"]),
+                }
+                // The URL to fetch is the flake reference without the `git+` (or such) prefix
+                let mut fetch_url = url[..url::Position::AfterPath].to_string();
+                if scheme != transport {
+                    fetch_url.replace_range(..scheme.len(), transport);
+                }
+                if !query.is_empty() {
+                    let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+                    serializer.extend_pairs(&query);
+                    fetch_url = format!("{fetch_url}?{}", serializer.finish());
+                }
+                attrs.insert("url".into(), fetch_url);
+            },
+        }
+        if let Some(rev) = attrs.get("rev") {
+            if !is_rev(rev) {
+                return Err(FlakeRefError::Rev(s.to_string(), rev.clone()));
+            }
+        }
+        Ok(Self {
+            original: s.to_string(),
+            attrs,
+        })
+    }
+}
+
+impl TryFrom<String> for FlakeRef {
+    type Error = FlakeRefError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<FlakeRef> for String {
+    fn from(value: FlakeRef) -> Self {
+        value.original
+    }
+}
+
+fn is_rev(rev: &str) -> bool {
+    rev.len() == 40 && rev.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// A flake registry entry, written as `ID=FLAKE-REF`, such as
+/// `nixpkgs=github:NixOS/nixpkgs/057f9aecfb71c4437d2b27d3323df7f93c010b7e`
+#[derive(Debug, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RegistryPin {
+    pub id: String,
+    pub to: FlakeRef,
+}
+
+impl RegistryPin {
+    /// The entry as it is written in `registry.json`
+    fn entry(&self) -> Value {
+        json!({
+            "from": { "type": "indirect", "id": self.id },
+            "to": self.to.attrs(),
+        })
+    }
+}
+
+impl Display for RegistryPin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.id, self.to)
+    }
+}
+
+impl FromStr for RegistryPin {
+    type Err = FlakeRefError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (id, to) = s
+            .split_once('=')
+            .ok_or_else(|| FlakeRefError::PinFormat(s.to_string()))?;
+        let valid_id = id.starts_with(|c: char| c.is_ascii_alphabetic())
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_id {
+            return Err(FlakeRefError::PinFormat(s.to_string()));
+        }
+        Ok(Self {
+            id: id.to_string(),
+            to: to.parse()?,
+        })
+    }
+}
+
+impl TryFrom<String> for RegistryPin {
+    type Error = FlakeRefError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<RegistryPin> for String {
+    fn from(value: RegistryPin) -> Self {
+        value.to_string()
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum FlakeRefError {
+    #[error("`{0}` is not a registry entry, expected `ID=FLAKE-REF`, such as `nixpkgs=github:NixOS/nixpkgs/057f9aecfb71c4437d2b27d3323df7f93c010b7e`")]
+    PinFormat(String),
+    #[error("Error parsing flake reference `{0}`")]
+    Url(String, #[source] url::ParseError),
+    #[error("Flake reference `{0}` does not have a supported scheme, such as `github:`, `git+https:` or `https:`")]
+    UnsupportedScheme(String),
+    #[error("Flake reference `{0}` refers to another registry entry, so it cannot be pinned")]
+    Indirect(String),
+    #[error("Flake reference `{0}` is not `OWNER/REPO` or `OWNER/REPO/REF-OR-REV`")]
+    Repository(String),
+    #[error("Flake reference `{0}` has the unknown parameter `{1}`")]
+    Parameter(String, String),
+    #[error("Flake reference `{0}` is not an absolute path")]
+    RelativePath(String),
+    #[error("The revision `{1}` of flake reference `{0}` is not a full commit hash")]
+    Rev(String, String),
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum CreateOrMergeFlakeRegistryError {
+    #[error("Parsing flake registry `{0}`")]
+    Parse(PathBuf, #[source] serde_json::Error),
+    #[error("Flake registry `{0}` is not a version {FLAKE_REGISTRY_VERSION} registry")]
+    Version(PathBuf),
+    #[error("Flake registry `{1}` already has different entries for {}; remove them from `{1}`, or pass `--force` to replace them", .0.iter().map(|v| format!("`{v}`")).collect::<Vec<_>>().join(", "))]
+    Conflict(Vec<String>, PathBuf),
+}
+
+impl From<CreateOrMergeFlakeRegistryError> for ActionErrorKind {
+    fn from(val: CreateOrMergeFlakeRegistryError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+/**
+Create or merge pinned entries into a flake registry (such as `/etc/nix/registry.json`)
+
+Other entries of an existing registry are kept. An existing entry for the same ID with a different
+target is only replaced if `force` is set. On revert only the pinned entries are removed, along with
+the registry if no other entries remain.
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct CreateOrMergeFlakeRegistry {
+    pub(crate) path: PathBuf,
+    pins: Vec<RegistryPin>,
+    force: bool,
+}
+
+impl CreateOrMergeFlakeRegistry {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        path: impl AsRef<Path>,
+        pins: Vec<RegistryPin>,
+        force: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let this = Self {
+            path: path.as_ref().to_path_buf(),
+            pins,
+            force,
+        };
+
+        for pin in &this.pins {
+            if !pin.to.is_locked() {
+                tracing::warn!(
+                    "Flake registry entry `{pin}` is not pinned to a revision, it will follow the latest commit"
+                );
+            }
+        }
+
+        if this.path.exists() {
+            let registry = this.read_registry().await?;
+            let conflicts = this
+                .pins
+                .iter()
+                .filter(|pin| {
+                    entries_for(&registry, &pin.id)
+                        .any(|entry| entry.get("to") != Some(&pin.to.attrs()))
+                })
+                .map(|pin| pin.id.clone())
+                .collect::<Vec<_>>();
+            if !conflicts.is_empty() && !this.force {
+                return Err(Self::error(CreateOrMergeFlakeRegistryError::Conflict(
+                    conflicts, this.path,
+                )));
+            }
+            if conflicts.is_empty() && this.missing_pins(&registry).is_empty() {
+                tracing::debug!(
+                    "Pinning flake registry entries in `{}` already complete",
+                    this.path.display()
+                );
+                return Ok(StatefulAction::completed(this));
+            }
+        }
+
+        Ok(StatefulAction::uncompleted(this))
+    }
+
+    async fn read_registry(&self) -> Result<Value, ActionError> {
+        let buf = tokio::fs::read(&self.path)
+            .await
+            .map_err(|e| ActionErrorKind::Read(self.path.clone(), e))
+            .map_err(Self::error)?;
+        let registry: Value = serde_json::from_slice(&buf)
+            .map_err(|e| CreateOrMergeFlakeRegistryError::Parse(self.path.clone(), e))
+            .map_err(Self::error)?;
+        if registry.get("version").and_then(Value::as_u64) != Some(FLAKE_REGISTRY_VERSION) {
+            return Err(Self::error(CreateOrMergeFlakeRegistryError::Version(
+                self.path.clone(),
+            )));
+        }
+        Ok(registry)
+    }
+
+    /// The IDs of the pins the registry does not have
+    fn missing_pins(&self, registry: &Value) -> Vec<String> {
+        self.pins
+            .iter()
+            .filter(|pin| {
+                !entries_for(registry, &pin.id)
+                    .any(|entry| entry.get("to") == Some(&pin.to.attrs()))
+            })
+            .map(|pin| pin.id.clone())
+            .collect()
+    }
+
+    async fn write_registry(&self, registry: &Value) -> Result<(), ActionError> {
+        let mode = match tokio::fs::metadata(&self.path).await {
+            Ok(metadata) => metadata.permissions().mode() & 0o777,
+            Err(_) => FLAKE_REGISTRY_MODE,
+        };
+        let buf = serde_json::to_string_pretty(registry)
+            .map_err(|e| CreateOrMergeFlakeRegistryError::Parse(self.path.clone(), e))
+            .map_err(Self::error)?;
+
+        // Write a temporary file next to the final one, then rename it into place atomically
+        let parent_dir = self.path.parent().expect("File must be in a directory");
+        let temp_file_path = parent_dir.join(format!(
+            "nix-installer-tmp.{}",
+            rand::thread_rng().gen::<u32>()
+        ));
+        let mut temp_file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .mode(mode)
+            .open(&temp_file_path)
+            .await
+            .map_err(|e| ActionErrorKind::Open(temp_file_path.clone(), e))
+            .map_err(Self::error)?;
+        temp_file
+            .write_all(format!("{buf}\n").as_bytes())
+            .await
+            .map_err(|e| ActionErrorKind::Write(temp_file_path.clone(), e))
+            .map_err(Self::error)?;
+        temp_file
+            .sync_all()
+            .await
+            .map_err(|e| ActionErrorKind::Sync(temp_file_path.clone(), e))
+            .map_err(Self::error)?;
+        tokio::fs::rename(&temp_file_path, &self.path)
+            .await
+            .map_err(|e| ActionErrorKind::Rename(temp_file_path, self.path.clone(), e))
+            .map_err(Self::error)?;
+        Ok(())
+    }
+}
+
+/// The entries of `registry` from the indirect flake `id`
+fn entries_for<'a>(registry: &'a Value, id: &'a str) -> impl Iterator<Item = &'a Value> {
+    registry
+        .get("flakes")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter(move |entry| is_entry_for(entry, id))
+}
+
+fn is_entry_for(entry: &Value, id: &str) -> bool {
+    entry
+        .get("from")
+        .map(|from| {
+            from.get("type").and_then(Value::as_str) == Some("indirect")
+                && from.get("id").and_then(Value::as_str) == Some(id)
+        })
+        .unwrap_or(false)
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "create_or_merge_flake_registry")]
+impl Action for CreateOrMergeFlakeRegistry {
+    fn action_tag() -> ActionTag {
+        ActionTag("create_or_merge_flake_registry")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Pin the flake registry entries in `{}`",
+            self.path.display()
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "create_or_merge_flake_registry",
+            path = tracing::field::display(self.path.display()),
+            pins = tracing::field::display(
+                self.pins
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            self.pins
+                .iter()
+                .map(|pin| format!("Resolve `{}` to `{}`", pin.id, pin.to))
+                .collect(),
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        let mut registry = match self.path.exists() {
+            true => self.read_registry().await?,
+            false => json!({ "version": FLAKE_REGISTRY_VERSION, "flakes": [] }),
+        };
+
+        let flakes = registry
+            .as_object_mut()
+            .expect("The registry was checked to be an object")
+            .entry("flakes")
+            .or_insert_with(|| json!([]));
+        if !flakes.is_array() {
+            *flakes = json!([]);
+        }
+        let flakes = flakes.as_array_mut().expect("Flakes was made an array");
+        flakes.retain(|entry| !self.pins.iter().any(|pin| is_entry_for(entry, &pin.id)));
+        flakes.extend(self.pins.iter().map(RegistryPin::entry));
+
+        self.write_registry(&registry).await
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            format!(
+                "Remove the pinned flake registry entries from `{}`",
+                self.path.display()
+            ),
+            self.pins
+                .iter()
+                .map(|pin| format!("Remove the entry for `{}`", pin.id))
+                .collect(),
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        // The user already deleted it
+        if !self.path.exists() {
+            return Ok(());
+        }
+
+        let mut registry = self.read_registry().await?;
+        let mut remaining = 0;
+        if let Some(flakes) = registry.get_mut("flakes").and_then(Value::as_array_mut) {
+            flakes.retain(|entry| {
+                !self.pins.iter().any(|pin| {
+                    is_entry_for(entry, &pin.id) && entry.get("to") == Some(&pin.to.attrs())
+                })
+            });
+            remaining = flakes.len();
+        }
+
+        if remaining == 0 {
+            remove_file(&self.path)
+                .await
+                .map_err(|e| ActionErrorKind::Remove(self.path.clone(), e))
+                .map_err(Self::error)?;
+        } else {
+            self.write_registry(&registry).await?;
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        let mut drift = Vec::new();
+        let metadata = verify_path(&self.path, None, None, None, &mut drift)
+            .await
+            .map_err(Self::error)?;
+        if metadata.is_none() {
+            return Ok(Verification::from_drift(drift));
+        }
+
+        let registry = self.read_registry().await?;
+        for id in self.missing_pins(&registry) {
+            drift.push(format!(
+                "`{id}` is no longer pinned in `{}`",
+                self.path.display()
+            ))
+        }
+        Ok(Verification::from_drift(drift))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const REV: &str = "057f9aecfb71c4437d2b27d3323df7f93c010b7e";
+
+    #[test]
+    fn parses_flake_refs() -> eyre::Result<()> {
+        let pin = RegistryPin::from_str(&format!("nixpkgs=github:NixOS/nixpkgs/{REV}"))?;
+        assert_eq!(pin.id, "nixpkgs");
+        assert_eq!(
+            pin.to.attrs(),
+            json!({ "type": "github", "owner": "NixOS", "repo": "nixpkgs", "rev": REV })
+        );
+        assert!(pin.to.is_locked());
+
+        let branch = FlakeRef::from_str("github:NixOS/nixpkgs/nixos-23.05")?;
+        assert_eq!(
+            branch.attrs(),
+            json!({ "type": "github", "owner": "NixOS", "repo": "nixpkgs", "ref": "nixos-23.05" })
+        );
+        assert!(!branch.is_locked());
+
+        assert_eq!(
+            FlakeRef::from_str(&format!(
+                "git+https://git.example.com/nixpkgs?ref=main&rev={REV}"
+            ))?
+            .attrs(),
+            json!({ "type": "git", "url": "https://git.example.com/nixpkgs", "ref": "main", "rev": REV })
+        );
+        assert_eq!(
+            FlakeRef::from_str("https://example.com/nixpkgs.tar.gz")?.attrs(),
+            json!({ "type": "tarball", "url": "https://example.com/nixpkgs.tar.gz" })
+        );
+
+        assert!(matches!(
+            RegistryPin::from_str("github:NixOS/nixpkgs"),
+            Err(FlakeRefError::PinFormat(_))
+        ));
+        assert!(matches!(
+            FlakeRef::from_str("flake:nixpkgs"),
+            Err(FlakeRefError::Indirect(_))
+        ));
+        assert!(matches!(
+            FlakeRef::from_str("github:NixOS"),
+            Err(FlakeRefError::Repository(_))
+        ));
+        assert!(matches!(
+            FlakeRef::from_str("github:NixOS/nixpkgs?rev=057f9ae"),
+            Err(FlakeRefError::Rev(..))
+        ));
+        assert!(matches!(
+            FlakeRef::from_str("ftp://example.com/nixpkgs.tar.gz"),
+            Err(FlakeRefError::UnsupportedScheme(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn merges_and_removes_pins() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("registry.json");
+        let theirs = json!({
+            "from": { "type": "indirect", "id": "templates" },
+            "to": { "type": "github", "owner": "NixOS", "repo": "templates" },
+        });
+        tokio::fs::write(
+            &test_file,
+            serde_json::to_string(&json!({ "version": 2, "flakes": [theirs] }))?,
+        )
+        .await?;
+
+        let pins = vec![RegistryPin::from_str(&format!(
+            "nixpkgs=github:NixOS/nixpkgs/{REV}"
+        ))?];
+        let mut action = CreateOrMergeFlakeRegistry::plan(&test_file, pins.clone(), false).await?;
+        action.try_execute().await?;
+
+        let registry: Value = serde_json::from_str(&tokio::fs::read_to_string(&test_file).await?)?;
+        assert_eq!(registry["flakes"][0], theirs);
+        assert_eq!(registry["flakes"][1]["to"]["rev"], REV);
+        assert!(action.action.verify().await?.holds());
+
+        let replanned = CreateOrMergeFlakeRegistry::plan(&test_file, pins, false).await?;
+        assert_eq!(replanned.state, crate::action::ActionState::Completed);
+
+        // A different target for the same entry is only replaced with `force`
+        let other = vec![RegistryPin::from_str("nixpkgs=github:NixOS/nixpkgs")?];
+        assert!(
+            CreateOrMergeFlakeRegistry::plan(&test_file, other.clone(), false)
+                .await
+                .is_err()
+        );
+        assert!(CreateOrMergeFlakeRegistry::plan(&test_file, other, true)
+            .await
+            .is_ok());
+
+        action.try_revert().await?;
+
+        let registry: Value = serde_json::from_str(&tokio::fs::read_to_string(&test_file).await?)?;
+        assert_eq!(registry["flakes"], json!([theirs]));
+
+        Ok(())
+    }
+}
@@ -1,6 +1,8 @@
 use std::{
+    collections::BTreeMap,
     os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
 use nix_config_parser::NixConfig;
@@ -12,13 +14,28 @@ use tokio::{
 use tracing::{span, Span};
 
 use crate::action::{
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    verification::verify_path, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag,
+    StatefulAction, Verification,
 };
 
-/// The `nix.conf` configuration names that are safe to merge.
-// FIXME(@cole-h): make configurable by downstream users?
-const MERGEABLE_CONF_NAMES: &[&str] = &["experimental-features"];
-const NIX_CONF_MODE: u32 = 0o664;
+/// The `nix.conf` configuration names which are lists, and merged with [`MergeStrategy::Union`] by default.
+///
+/// Every `extra-` setting is also a list.
+const MERGEABLE_CONF_NAMES: &[&str] = &[
+    "allowed-users",
+    "experimental-features",
+    "hashed-mirrors",
+    "nix-path",
+    "plugin-files",
+    "sandbox-paths",
+    "secret-key-files",
+    "substituters",
+    "system-features",
+    "trusted-public-keys",
+    "trusted-substituters",
+    "trusted-users",
+];
+pub(crate) const NIX_CONF_MODE: u32 = 0o664;
 const NIX_CONF_COMMENT_CHAR: char = '#';
 
 #[non_exhaustive]
@@ -41,11 +58,96 @@ impl From<CreateOrMergeNixConfigError> for ActionErrorKind {
     }
 }
 
+/// How a setting already in `nix.conf` with a different value is merged with the value being set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergeStrategy {
+    /// Set the space separated values of both, without duplicates
+    Union,
+    /// Leave the existing value as it is
+    PreferExisting,
+    /// Replace the existing value
+    PreferOurs,
+    /// Refuse to change the existing value
+    Fail,
+}
+
+impl MergeStrategy {
+    /// The strategy used for `name` when none was chosen
+    pub fn default_for(name: &str) -> Self {
+        if name.starts_with("extra-") || MERGEABLE_CONF_NAMES.contains(&name) {
+            Self::Union
+        } else {
+            Self::Fail
+        }
+    }
+}
+
+impl std::fmt::Display for MergeStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Union => "union",
+            Self::PreferExisting => "prefer-existing",
+            Self::PreferOurs => "prefer-ours",
+            Self::Fail => "fail",
+        })
+    }
+}
+
+impl FromStr for MergeStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "union" => Ok(Self::Union),
+            "prefer-existing" => Ok(Self::PreferExisting),
+            "prefer-ours" => Ok(Self::PreferOurs),
+            "fail" => Ok(Self::Fail),
+            _ => Err(format!(
+                "Unknown merge strategy `{s}`, expected one of `union`, `prefer-existing`, `prefer-ours` or `fail`"
+            )),
+        }
+    }
+}
+
+/// Parse a `name=strategy` pair, as taken by `--nix-conf-merge`
+pub fn parse_merge_rule(s: &str) -> Result<(String, MergeStrategy), String> {
+    let (name, strategy) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected `name=strategy`, got `{s}`"))?;
+    Ok((name.trim().to_string(), strategy.trim().parse()?))
+}
+
+/// The [`MergeStrategy`] of each setting, those not chosen use [`MergeStrategy::default_for`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct MergePolicy {
+    strategies: BTreeMap<String, MergeStrategy>,
+}
+
+impl MergePolicy {
+    pub fn strategy(&self, name: &str) -> MergeStrategy {
+        self.strategies
+            .get(name)
+            .copied()
+            .unwrap_or_else(|| MergeStrategy::default_for(name))
+    }
+}
+
+impl FromIterator<(String, MergeStrategy)> for MergePolicy {
+    fn from_iter<T: IntoIterator<Item = (String, MergeStrategy)>>(iter: T) -> Self {
+        Self {
+            strategies: iter.into_iter().collect(),
+        }
+    }
+}
+
 /// Create or merge an existing `nix.conf` at the specified path.
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct CreateOrMergeNixConfig {
     pub(crate) path: PathBuf,
     pending_nix_config: NixConfig,
+    #[serde(default)]
+    merge_policy: MergePolicy,
 }
 
 impl CreateOrMergeNixConfig {
@@ -53,17 +155,22 @@ impl CreateOrMergeNixConfig {
     pub async fn plan(
         path: impl AsRef<Path>,
         pending_nix_config: NixConfig,
+        merge_policy: MergePolicy,
     ) -> Result<StatefulAction<Self>, ActionError> {
         let path = path.as_ref().to_path_buf();
 
         let this = Self {
             path,
             pending_nix_config,
+            merge_policy,
         };
 
         if this.path.exists() {
-            let (merged_nix_config, _) =
-                Self::validate_existing_nix_config(&this.pending_nix_config, &this.path)?;
+            let (merged_nix_config, _) = Self::validate_existing_nix_config(
+                &this.pending_nix_config,
+                &this.merge_policy,
+                &this.path,
+            )?;
 
             if !merged_nix_config.settings().is_empty() {
                 return Ok(StatefulAction::uncompleted(this));
@@ -82,6 +189,7 @@ impl CreateOrMergeNixConfig {
     fn merge_pending_and_existing_nix_config(
         pending_nix_config: &NixConfig,
         existing_nix_config: &NixConfig,
+        merge_policy: &MergePolicy,
         path: &Path,
     ) -> Result<(NixConfig, NixConfig), CreateOrMergeNixConfigError> {
         let mut merged_nix_config = NixConfig::new();
@@ -101,20 +209,38 @@ impl CreateOrMergeNixConfig {
                     // merged_nix_config will be empty and this will be marked as completed. We
                     // don't return early here because there may be more config options to
                     // check.
-                } else if MERGEABLE_CONF_NAMES.contains(&pending_conf_name.as_str()) {
-                    let mut merged_conf_value =
-                        Vec::with_capacity(pending_conf_value.len() + existing_conf_value.len());
-                    merged_conf_value.extend(pending_conf_value);
-                    merged_conf_value.extend(existing_conf_value);
-                    merged_conf_value.dedup();
-                    let merged_conf_value = merged_conf_value.join(" ");
-                    let merged_conf_value = merged_conf_value.trim();
-
-                    merged_nix_config
-                        .settings_mut()
-                        .insert(pending_conf_name.to_owned(), merged_conf_value.to_owned());
                 } else {
-                    unmergeable_config_names.push(pending_conf_name.to_owned());
+                    match merge_policy.strategy(pending_conf_name) {
+                        MergeStrategy::Union => {
+                            let mut merged_conf_value = Vec::with_capacity(
+                                pending_conf_value.len() + existing_conf_value.len(),
+                            );
+                            for value in pending_conf_value.into_iter().chain(existing_conf_value) {
+                                if !value.is_empty() && !merged_conf_value.contains(&value) {
+                                    merged_conf_value.push(value);
+                                }
+                            }
+                            let merged_conf_value = merged_conf_value.join(" ");
+
+                            merged_nix_config
+                                .settings_mut()
+                                .insert(pending_conf_name.to_owned(), merged_conf_value);
+                        },
+                        MergeStrategy::PreferExisting => {
+                            tracing::debug!(
+                                "Keeping the existing `{pending_conf_name}` in `{}`",
+                                path.display()
+                            );
+                        },
+                        MergeStrategy::PreferOurs => {
+                            merged_nix_config
+                                .settings_mut()
+                                .insert(pending_conf_name.to_owned(), pending_conf_value.join(" "));
+                        },
+                        MergeStrategy::Fail => {
+                            unmergeable_config_names.push(pending_conf_name.to_owned());
+                        },
+                    }
                 }
             } else {
                 merged_nix_config
@@ -135,6 +261,7 @@ impl CreateOrMergeNixConfig {
 
     fn validate_existing_nix_config(
         pending_nix_config: &NixConfig,
+        merge_policy: &MergePolicy,
         path: &Path,
     ) -> Result<(NixConfig, NixConfig), ActionError> {
         let path = path.to_path_buf();
@@ -166,6 +293,7 @@ impl CreateOrMergeNixConfig {
         let (merged_nix_config, existing_nix_config) = Self::merge_pending_and_existing_nix_config(
             pending_nix_config,
             &existing_nix_config,
+            merge_policy,
             &path,
         )
         .map_err(Self::error)?;
@@ -232,6 +360,7 @@ impl Action for CreateOrMergeNixConfig {
         let Self {
             path,
             pending_nix_config,
+            merge_policy,
         } = self;
 
         if tracing::enabled!(tracing::Level::TRACE) {
@@ -273,7 +402,7 @@ impl Action for CreateOrMergeNixConfig {
 
         let (mut merged_nix_config, mut existing_nix_config) = if path.exists() {
             let (merged_nix_config, existing_nix_config) =
-                Self::validate_existing_nix_config(pending_nix_config, path)?;
+                Self::validate_existing_nix_config(pending_nix_config, merge_policy, path)?;
             (merged_nix_config, Some(existing_nix_config))
         } else {
             (pending_nix_config.clone(), None)
@@ -455,6 +584,7 @@ impl Action for CreateOrMergeNixConfig {
         let Self {
             path,
             pending_nix_config: _,
+            merge_policy: _,
         } = &self;
 
         vec![ActionDescription::new(
@@ -468,6 +598,7 @@ impl Action for CreateOrMergeNixConfig {
         let Self {
             path,
             pending_nix_config: _,
+            merge_policy: _,
         } = self;
 
         remove_file(&path)
@@ -476,6 +607,48 @@ impl Action for CreateOrMergeNixConfig {
 
         Ok(())
     }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        let mut drift = Vec::new();
+        let metadata = verify_path(&self.path, None, None, Some(NIX_CONF_MODE), &mut drift)
+            .await
+            .map_err(Self::error)?;
+        if metadata.is_none() {
+            return Ok(Verification::from_drift(drift));
+        }
+
+        let existing_nix_config = NixConfig::parse_file(&self.path)
+            .map_err(CreateOrMergeNixConfigError::ParseNixConfig)
+            .map_err(Self::error)?;
+        for (pending_conf_name, pending_conf_value) in self.pending_nix_config.settings() {
+            match existing_nix_config.settings().get(pending_conf_name) {
+                // Whatever the value is, it was (or would have been) kept
+                Some(_)
+                    if self.merge_policy.strategy(pending_conf_name)
+                        == MergeStrategy::PreferExisting => {},
+                Some(existing_conf_value) => {
+                    let existing_conf_value = existing_conf_value.split(' ').collect::<Vec<_>>();
+                    let missing = pending_conf_value
+                        .split(' ')
+                        .filter(|e| !existing_conf_value.contains(e))
+                        .collect::<Vec<_>>();
+                    if !missing.is_empty() {
+                        drift.push(format!(
+                            "`{pending_conf_name}` in `{}` is missing `{}`",
+                            self.path.display(),
+                            missing.join(" ")
+                        ))
+                    }
+                },
+                None => drift.push(format!(
+                    "`{pending_conf_name}` is no longer set in `{}`",
+                    self.path.display()
+                )),
+            }
+        }
+        Ok(Verification::from_drift(drift))
+    }
 }
 
 #[cfg(test)]
@@ -492,7 +665,8 @@ mod test {
         nix_config
             .settings_mut()
             .insert("experimental-features".into(), "ca-references".into());
-        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config).await?;
+        let mut action =
+            CreateOrMergeNixConfig::plan(&test_file, nix_config, MergePolicy::default()).await?;
 
         action.try_execute().await?;
 
@@ -518,7 +692,8 @@ mod test {
         nix_config
             .settings_mut()
             .insert("experimental-features".into(), "ca-references".into());
-        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config).await?;
+        let mut action =
+            CreateOrMergeNixConfig::plan(&test_file, nix_config, MergePolicy::default()).await?;
 
         action.try_execute().await?;
 
@@ -546,7 +721,8 @@ mod test {
         nix_config
             .settings_mut()
             .insert("experimental-features".into(), "flakes".into());
-        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config).await?;
+        let mut action =
+            CreateOrMergeNixConfig::plan(&test_file, nix_config, MergePolicy::default()).await?;
 
         action.try_execute().await?;
 
@@ -578,7 +754,8 @@ mod test {
         nix_config
             .settings_mut()
             .insert("allow-dirty".into(), "false".into());
-        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config).await?;
+        let mut action =
+            CreateOrMergeNixConfig::plan(&test_file, nix_config, MergePolicy::default()).await?;
 
         action.try_execute().await?;
 
@@ -623,7 +800,7 @@ mod test {
         nix_config
             .settings_mut()
             .insert("warn-dirty".into(), "false".into());
-        match CreateOrMergeNixConfig::plan(&test_file, nix_config).await {
+        match CreateOrMergeNixConfig::plan(&test_file, nix_config, MergePolicy::default()).await {
             Err(err) => {
                 if let ActionErrorKind::Custom(e) = err.kind() {
                     match e.downcast_ref::<CreateOrMergeNixConfigError>() {
@@ -650,6 +827,57 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn merges_with_policy() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let test_file = temp_dir.path().join("merges_with_policy");
+
+        write(
+            test_file.as_path(),
+            "substituters = https://cache.example.com\nwarn-dirty = true\nmax-jobs = 4\n",
+        )
+        .await?;
+        tokio::fs::set_permissions(&test_file, PermissionsExt::from_mode(NIX_CONF_MODE)).await?;
+
+        let mut nix_config = NixConfig::new();
+        nix_config.settings_mut().insert(
+            "substituters".into(),
+            "https://cache.nixos.org https://cache.example.com".into(),
+        );
+        nix_config
+            .settings_mut()
+            .insert("warn-dirty".into(), "false".into());
+        nix_config
+            .settings_mut()
+            .insert("max-jobs".into(), "auto".into());
+        let merge_policy = ["warn-dirty=prefer-ours", "max-jobs=prefer-existing"]
+            .into_iter()
+            .map(parse_merge_rule)
+            .collect::<Result<MergePolicy, _>>()
+            .map_err(|e| eyre!(e))?;
+        assert_eq!(merge_policy.strategy("substituters"), MergeStrategy::Union);
+        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config, merge_policy).await?;
+
+        action.try_execute().await?;
+
+        let merged = NixConfig::parse_file(&test_file)?;
+        assert_eq!(
+            merged.settings().get("substituters").map(String::as_str),
+            Some("https://cache.nixos.org https://cache.example.com")
+        );
+        assert_eq!(
+            merged.settings().get("warn-dirty").map(String::as_str),
+            Some("false")
+        );
+        assert_eq!(
+            merged.settings().get("max-jobs").map(String::as_str),
+            Some("4")
+        );
+        assert!(action.action.verify().await?.holds());
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn preserves_comments() -> eyre::Result<()> {
         let temp_dir = tempfile::TempDir::new()?;
@@ -665,7 +893,8 @@ mod test {
         nix_config
             .settings_mut()
             .insert("experimental-features".into(), "ca-references".into());
-        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config).await?;
+        let mut action =
+            CreateOrMergeNixConfig::plan(&test_file, nix_config, MergePolicy::default()).await?;
 
         action.try_execute().await?;
 
@@ -697,7 +926,8 @@ mod test {
         nix_config
             .settings_mut()
             .insert("experimental-features".into(), "ca-references".into());
-        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config).await?;
+        let mut action =
+            CreateOrMergeNixConfig::plan(&test_file, nix_config, MergePolicy::default()).await?;
 
         action.try_execute().await?;
 
@@ -0,0 +1,331 @@
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use rand::Rng;
+use tokio::{
+    fs::{remove_file, OpenOptions},
+    io::AsyncWriteExt,
+};
+use tracing::{span, Span};
+
+use crate::action::{
+    verification::verify_path, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag,
+    StatefulAction, Verification,
+};
+
+/// Only the owner (`root`) may read or write a secret file
+pub(crate) const SECRET_FILE_MODE: u32 = 0o600;
+
+/** Create a file only `root` can read at the given location, with the contents of `source`
+
+Unlike [`CreateFile`](crate::action::base::CreateFile) the contents are read from `source` when the
+file is created, so the secret itself is never part of the plan, the receipt, or any logs. Only
+`source` is recorded.
+
+If `force` is set, an existing file with different contents is replaced. It is moved aside rather
+than overwritten, and restored on revert. A file which already existed is never deleted.
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct CreateSecretFile {
+    pub(crate) path: PathBuf,
+    source: PathBuf,
+    force: bool,
+    /// Whether `path` existed before this was planned
+    #[serde(default)]
+    pub(crate) existed: bool,
+}
+
+impl CreateSecretFile {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        path: impl AsRef<Path>,
+        source: impl AsRef<Path>,
+        force: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let path = path.as_ref().to_path_buf();
+        let this = Self {
+            existed: path.exists(),
+            path,
+            source: source.as_ref().to_path_buf(),
+            force,
+        };
+
+        let secret = this.read_source().await?;
+
+        if this.existed {
+            let metadata = this
+                .path
+                .metadata()
+                .map_err(|e| ActionErrorKind::GettingMetadata(this.path.clone(), e))
+                .map_err(Self::error)?;
+            if !metadata.is_file() {
+                return Err(Self::error(ActionErrorKind::PathWasNotFile(this.path)));
+            }
+
+            let discovered = tokio::fs::read(&this.path)
+                .await
+                .map_err(|e| ActionErrorKind::Read(this.path.clone(), e))
+                .map_err(Self::error)?;
+            let discovered_mode = metadata.permissions().mode() & 0o777;
+            if discovered == secret && discovered_mode == SECRET_FILE_MODE {
+                tracing::debug!(
+                    "Creating secret file `{}` already complete",
+                    this.path.display()
+                );
+                return Ok(StatefulAction::completed(this));
+            }
+            if !this.force {
+                return Err(Self::error(ActionErrorKind::DifferentContent(
+                    this.path.clone(),
+                )));
+            }
+        }
+
+        Ok(StatefulAction::uncompleted(this))
+    }
+
+    /// Where the file which existed at `path` is moved while this one replaces it
+    fn replaced_path(&self) -> PathBuf {
+        let mut file_name = self.path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".nix-installer-replaced");
+        self.path.with_file_name(file_name)
+    }
+
+    async fn read_source(&self) -> Result<Vec<u8>, ActionError> {
+        tokio::fs::read(&self.source)
+            .await
+            .map_err(|e| ActionErrorKind::Read(self.source.clone(), e))
+            .map_err(Self::error)
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "create_secret_file")]
+impl Action for CreateSecretFile {
+    fn action_tag() -> ActionTag {
+        ActionTag("create_secret_file")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Create secret file `{}` from `{}`",
+            self.path.display(),
+            self.source.display()
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "create_secret_file",
+            path = tracing::field::display(self.path.display()),
+            source = tracing::field::display(self.source.display()),
+            mode = tracing::field::display(format!("{:#o}", SECRET_FILE_MODE)),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![format!(
+            "Only `root` can read `{}`, its contents are not recorded in the receipt",
+            self.path.display()
+        )];
+        if self.existed {
+            explanation.push(format!(
+                "The existing `{}` is moved to `{}`, and restored on revert",
+                self.path.display(),
+                self.replaced_path().display()
+            ));
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        let secret = self.read_source().await?;
+
+        // It was already moved if an earlier attempt was interrupted
+        let replaced_path = self.replaced_path();
+        if self.existed && self.path.exists() && !replaced_path.exists() {
+            tokio::fs::rename(&self.path, &replaced_path)
+                .await
+                .map_err(|e| ActionErrorKind::Rename(self.path.clone(), replaced_path, e))
+                .map_err(Self::error)?;
+        }
+
+        // Write a temporary file next to the final one, which is only ever readable by its owner,
+        // then rename it into place so the secret is never briefly readable by others
+        let parent_dir = self.path.parent().expect("File must be in a directory");
+        let temp_file_path = parent_dir.join(format!(
+            "nix-installer-tmp.{}",
+            rand::thread_rng().gen::<u32>()
+        ));
+        let mut temp_file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .mode(SECRET_FILE_MODE)
+            .open(&temp_file_path)
+            .await
+            .map_err(|e| ActionErrorKind::Open(temp_file_path.clone(), e))
+            .map_err(Self::error)?;
+        temp_file
+            .write_all(&secret)
+            .await
+            .map_err(|e| ActionErrorKind::Write(temp_file_path.clone(), e))
+            .map_err(Self::error)?;
+        temp_file
+            .sync_all()
+            .await
+            .map_err(|e| ActionErrorKind::Sync(temp_file_path.clone(), e))
+            .map_err(Self::error)?;
+        tokio::fs::rename(&temp_file_path, &self.path)
+            .await
+            .map_err(|e| ActionErrorKind::Rename(temp_file_path, self.path.clone(), e))
+            .map_err(Self::error)?;
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        let description = if self.existed {
+            format!(
+                "Restore the secret file `{}` which existed before",
+                self.path.display()
+            )
+        } else {
+            format!("Delete secret file `{}`", self.path.display())
+        };
+        vec![ActionDescription::new(description, vec![])]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        if self.existed {
+            // Without a replaced file, the existing file was already the one planned
+            let replaced_path = self.replaced_path();
+            if replaced_path.exists() {
+                tokio::fs::rename(&replaced_path, &self.path)
+                    .await
+                    .map_err(|e| ActionErrorKind::Rename(replaced_path, self.path.clone(), e))
+                    .map_err(Self::error)?;
+            }
+            return Ok(());
+        }
+
+        // The user already deleted it
+        if !self.path.exists() {
+            return Ok(());
+        }
+
+        remove_file(&self.path)
+            .await
+            .map_err(|e| ActionErrorKind::Remove(self.path.clone(), e))
+            .map_err(Self::error)?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        // The contents are not compared, `source` may be long gone
+        let mut drift = Vec::new();
+        let metadata = verify_path(&self.path, None, None, Some(SECRET_FILE_MODE), &mut drift)
+            .await
+            .map_err(Self::error)?;
+        if matches!(metadata, Some(metadata) if !metadata.is_file()) {
+            drift.push(format!("`{}` is no longer a file", self.path.display()))
+        }
+        Ok(Verification::from_drift(drift))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn creates_root_only_files_without_recording_them() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let source = temp_dir.path().join("source");
+        let test_file = temp_dir.path().join("netrc");
+        tokio::fs::write(&source, "machine cache.example.com password hunter2\n").await?;
+
+        let mut action = CreateSecretFile::plan(&test_file, &source, false).await?;
+        assert!(!serde_json::to_string(&action)?.contains("hunter2"));
+
+        action.try_execute().await?;
+
+        assert_eq!(
+            tokio::fs::read_to_string(&test_file).await?,
+            "machine cache.example.com password hunter2\n"
+        );
+        assert_eq!(
+            test_file.metadata()?.permissions().mode() & 0o777,
+            SECRET_FILE_MODE
+        );
+
+        let replanned = CreateSecretFile::plan(&test_file, &source, false).await?;
+        assert_eq!(replanned.state, crate::action::ActionState::Completed);
+
+        // Neither verifying nor reverting need the source
+        tokio::fs::remove_file(&source).await?;
+        assert!(action.action.verify().await?.holds());
+
+        action.try_revert().await?;
+
+        assert!(!test_file.exists(), "File should have been deleted");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restores_files_it_replaces() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let source = temp_dir.path().join("source");
+        let test_file = temp_dir.path().join("netrc");
+        tokio::fs::write(&source, "machine cache.example.com password hunter2\n").await?;
+        tokio::fs::write(&test_file, "machine other.example.com password swordfish\n").await?;
+
+        assert!(CreateSecretFile::plan(&test_file, &source, false)
+            .await
+            .is_err());
+
+        let mut action = CreateSecretFile::plan(&test_file, &source, true).await?;
+        action.try_execute().await?;
+        assert_eq!(
+            tokio::fs::read_to_string(&test_file).await?,
+            "machine cache.example.com password hunter2\n"
+        );
+
+        action.try_revert().await?;
+        assert_eq!(
+            tokio::fs::read_to_string(&test_file).await?,
+            "machine other.example.com password swordfish\n"
+        );
+        // Only the source and the restored file
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn leaves_files_it_did_not_create() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let source = temp_dir.path().join("source");
+        let test_file = temp_dir.path().join("netrc");
+        tokio::fs::write(&source, "machine cache.example.com password hunter2\n").await?;
+        tokio::fs::copy(&source, &test_file).await?;
+        tokio::fs::set_permissions(
+            &test_file,
+            std::fs::Permissions::from_mode(SECRET_FILE_MODE),
+        )
+        .await?;
+
+        let mut action = CreateSecretFile::plan(&test_file, &source, false).await?;
+        assert_eq!(action.state, crate::action::ActionState::Completed);
+        action.try_revert().await?;
+        assert!(test_file.exists(), "File should have been left in place");
+
+        Ok(())
+    }
+}
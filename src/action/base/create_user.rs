@@ -1,10 +1,13 @@
-use nix::unistd::User;
+use std::path::{Path, PathBuf};
+
 use target_lexicon::OperatingSystem;
 use tokio::process::Command;
 use tracing::{span, Span};
 
-use crate::action::{ActionError, ActionErrorKind, ActionTag};
+use crate::action::{ActionError, ActionErrorKind, ActionTag, Verification};
 use crate::execute_command;
+use crate::os::passwd::find_user;
+use crate::settings::is_alternate_root;
 
 use crate::action::{Action, ActionDescription, StatefulAction};
 
@@ -18,6 +21,8 @@ pub struct CreateUser {
     groupname: String,
     gid: u32,
     comment: String,
+    #[serde(default = "crate::settings::default_root")]
+    root: PathBuf,
 }
 
 impl CreateUser {
@@ -28,17 +33,29 @@ impl CreateUser {
         groupname: String,
         gid: u32,
         comment: String,
+        root: impl AsRef<Path>,
     ) -> Result<StatefulAction<Self>, ActionError> {
+        let root = root.as_ref().to_path_buf();
         let this = Self {
             name: name.clone(),
             uid,
             groupname,
             gid,
             comment,
+            root: root.clone(),
         };
 
         match OperatingSystem::host() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => (),
+            _ if is_alternate_root(&root) => {
+                // Only the shadow utilities can operate on a root other than the host's
+                if which::which("useradd").is_err() {
+                    return Err(Self::error(ActionErrorKind::MissingUserCreationCommand));
+                }
+                if which::which("userdel").is_err() {
+                    return Err(Self::error(ActionErrorKind::MissingUserDeletionCommand));
+                }
+            },
             _ => {
                 if !(which::which("useradd").is_ok() || which::which("adduser").is_ok()) {
                     return Err(Self::error(ActionErrorKind::MissingUserCreationCommand));
@@ -50,22 +67,19 @@ impl CreateUser {
         }
 
         // Ensure user does not exists
-        if let Some(user) = User::from_name(name.as_str())
-            .map_err(|e| ActionErrorKind::GettingUserId(name.clone(), e))
-            .map_err(Self::error)?
-        {
-            if user.uid.as_raw() != uid {
+        if let Some(user) = find_user(&root, &name).map_err(Self::error)? {
+            if user.uid != uid {
                 return Err(Self::error(ActionErrorKind::UserUidMismatch(
                     name.clone(),
-                    user.uid.as_raw(),
+                    user.uid,
                     uid,
                 )));
             }
 
-            if user.gid.as_raw() != gid {
+            if user.gid != gid {
                 return Err(Self::error(ActionErrorKind::UserGidMismatch(
                     name.clone(),
-                    user.gid.as_raw(),
+                    user.gid,
                     gid,
                 )));
             }
@@ -119,6 +133,7 @@ impl Action for CreateUser {
             groupname,
             gid,
             comment,
+            root,
         } = self;
 
         use OperatingSystem;
@@ -204,8 +219,12 @@ impl Action for CreateUser {
             },
             _ => {
                 if which::which("useradd").is_ok() {
+                    let mut command = Command::new("useradd");
+                    if is_alternate_root(root) {
+                        command.arg("--root").arg(&root);
+                    }
                     execute_command(
-                        Command::new("useradd")
+                        command
                             .process_group(0)
                             .args([
                                 "--home-dir",
@@ -314,6 +333,18 @@ impl Action for CreateUser {
                     },
                 }
             },
+            _ if is_alternate_root(&self.root) => {
+                execute_command(
+                    Command::new("userdel")
+                        .process_group(0)
+                        .arg("--root")
+                        .arg(&self.root)
+                        .arg(&self.name)
+                        .stdin(std::process::Stdio::null()),
+                )
+                .await
+                .map_err(Self::error)?;
+            },
             _ => {
                 if which::which("userdel").is_ok() {
                     execute_command(
@@ -341,4 +372,27 @@ impl Action for CreateUser {
 
         Ok(())
     }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        let mut drift = Vec::new();
+        match find_user(&self.root, &self.name).map_err(Self::error)? {
+            Some(user) => {
+                if user.uid != self.uid {
+                    drift.push(format!(
+                        "User `{}` has UID {}, expected {}",
+                        self.name, user.uid, self.uid
+                    ))
+                }
+                if user.gid != self.gid {
+                    drift.push(format!(
+                        "User `{}` has GID {}, expected {}",
+                        self.name, user.gid, self.gid
+                    ))
+                }
+            },
+            None => drift.push(format!("User `{}` no longer exists", self.name)),
+        }
+        Ok(Verification::from_drift(drift))
+    }
 }
@@ -1,41 +1,92 @@
-use std::path::PathBuf;
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
 
-use bytes::{Buf, Bytes};
+use async_compression::tokio::bufread::{GzipDecoder, XzDecoder, ZstdDecoder};
+use futures_util::{Stream, TryStreamExt};
+use rand::Rng;
 use reqwest::Url;
+use sha2::{Digest, Sha256};
+use tokio::io::{
+    AsyncBufRead, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, ReadBuf,
+};
+use tokio_util::io::{ReaderStream, SyncIoBridge};
 use tracing::{span, Span};
 
 use crate::{
-    action::{Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction},
+    action::{
+        error_chain, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag,
+        StatefulAction,
+    },
+    cache::{PendingEntry, TarballCache},
+    event::InstallEvent,
+    network::{NetworkPolicy, Streamed},
     parse_ssl_cert,
-    settings::UrlOrPath,
+    settings::{builtin_nix_package_sha256, Sha256Digest, UrlOrPath},
 };
 
+type ByteStream = Pin<Box<dyn Stream<Item = Result<Streamed, ActionErrorKind>> + Send>>;
+
 /**
 Fetch a URL to the given path
+
+The tarball may be compressed with xz, zstd, or gzip, or not compressed at all. The compression is
+detected from the first bytes of the tarball rather than its name.
+
+Each of the sources is tried in order until one succeeds, the source which succeeded is recorded.
+
+The tarball is fetched to a scratch directory next to the destination, so it is never held in memory.
+That directory must have room for the tarball as well as what it unpacks to, while it is unpacked,
+which is checked before fetching when the length of the tarball is known. If a SHA-256 digest is set (or known, for the default Nix package URLs) the download is verified
+against it before anything is unpacked, then it is streamed through the decompressor into the unpacker.
+It is only moved to the destination once it has been completely unpacked.
+
+With a [`TarballCache`], tarballs fetched over the network are cached once verified against a SHA-256 digest, and
+reused by later installs which expect the same digest. Without a digest nothing is cached.
 */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct FetchAndUnpackNix {
-    url_or_path: UrlOrPath,
+    sources: Vec<UrlOrPath>,
+    /// The source the tarball was fetched from, once it has been
+    #[serde(default)]
+    fetched_from: Option<UrlOrPath>,
+    #[serde(default)]
+    sha256: Option<Sha256Digest>,
     dest: PathBuf,
     proxy: Option<Url>,
     ssl_cert_file: Option<PathBuf>,
+    #[serde(default)]
+    network: NetworkPolicy,
+    #[serde(default)]
+    cache: Option<TarballCache>,
 }
 
 impl FetchAndUnpackNix {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(
-        url_or_path: UrlOrPath,
+        sources: Vec<UrlOrPath>,
+        sha256: Option<Sha256Digest>,
         dest: PathBuf,
         proxy: Option<Url>,
         ssl_cert_file: Option<PathBuf>,
+        network: NetworkPolicy,
+        cache: Option<TarballCache>,
     ) -> Result<StatefulAction<Self>, ActionError> {
         // TODO(@hoverbear): Check URL exists?
         // TODO(@hoverbear): Check tempdir exists
 
-        if let UrlOrPath::Url(url) = &url_or_path {
-            match url.scheme() {
-                "https" | "http" | "file" => (),
-                _ => return Err(Self::error(ActionErrorKind::UnknownUrlScheme)),
+        if sources.is_empty() {
+            return Err(Self::error(FetchUrlError::NoSources));
+        }
+        for source in &sources {
+            if let UrlOrPath::Url(url) = source {
+                match url.scheme() {
+                    "https" | "http" | "file" => (),
+                    _ => return Err(Self::error(ActionErrorKind::UnknownUrlScheme)),
+                }
             }
         }
 
@@ -50,14 +101,65 @@ impl FetchAndUnpackNix {
             parse_ssl_cert(ssl_cert_file).await.map_err(Self::error)?;
         }
 
+        let sha256 = sha256.or_else(|| sources.iter().find_map(builtin_nix_package_sha256));
+
         Ok(Self {
-            url_or_path,
+            sources,
+            fetched_from: None,
+            sha256,
             dest,
             proxy,
             ssl_cert_file,
+            network,
+            cache,
         }
         .into())
     }
+
+    /// Open a stream of the tarball at `source`, along with its length if it is known
+    async fn open(&self, source: &UrlOrPath) -> Result<(ByteStream, Option<u64>), ActionErrorKind> {
+        let path = match source {
+            UrlOrPath::Url(url) => match url.scheme() {
+                "https" | "http" => {
+                    let client = self
+                        .network
+                        .client(self.proxy.as_ref(), self.ssl_cert_file.as_deref())
+                        .await?;
+                    let (stream, total) = self.network.stream(client, url.clone()).await?;
+                    return Ok((Box::pin(stream.map_err(ActionErrorKind::Network)), total));
+                },
+                "file" => PathBuf::from(url.path()),
+                _ => return Err(ActionErrorKind::UnknownUrlScheme),
+            },
+            UrlOrPath::Path(path) => path.clone(),
+            UrlOrPath::Embedded => {
+                let (path, manifest) = crate::bundle::current()
+                    .await
+                    .map_err(FetchUrlError::Bundle)?
+                    .ok_or(FetchUrlError::NotABundle)?;
+                let payload = crate::bundle::open_payload(&path, &manifest.nix_package)
+                    .await
+                    .map_err(FetchUrlError::Bundle)?;
+                let stream = ReaderStream::with_capacity(payload, 64 * 1024)
+                    .map_ok(Streamed::Chunk)
+                    .map_err(move |e| ActionErrorKind::Read(path.clone(), e));
+                return Ok((Box::pin(stream), Some(manifest.nix_package.len)));
+            },
+        };
+
+        let file = tokio::fs::File::open(&path)
+            .await
+            .map_err(|e| ActionErrorKind::Open(path.clone(), e))?;
+        let total = file
+            .metadata()
+            .await
+            .map_err(|e| ActionErrorKind::GettingMetadata(path.clone(), e))?
+            .len();
+        let stream = ReaderStream::with_capacity(file, 64 * 1024)
+            .map_ok(Streamed::Chunk)
+            .map_err(move |e| ActionErrorKind::Read(path.clone(), e));
+        Ok((Box::pin(stream), Some(total)))
+    }
 }
 
 #[async_trait::async_trait]
@@ -67,18 +169,38 @@ impl Action for FetchAndUnpackNix {
         ActionTag("fetch_and_unpack_nix")
     }
     fn tracing_synopsis(&self) -> String {
-        format!("Fetch `{}` to `{}`", self.url_or_path, self.dest.display())
+        match self.sources.as_slice() {
+            [source] => format!("Fetch `{source}` to `{}`", self.dest.display()),
+            sources => format!(
+                "Fetch the first available of {} to `{}`",
+                sources
+                    .iter()
+                    .map(|source| format!("`{source}`"))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                self.dest.display()
+            ),
+        }
     }
 
     fn tracing_span(&self) -> Span {
         let span = span!(
             tracing::Level::DEBUG,
             "fetch_and_unpack_nix",
-            url_or_path = tracing::field::display(&self.url_or_path),
+            sources = tracing::field::debug(
+                self.sources
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+            ),
+            sha256 = tracing::field::Empty,
             proxy = tracing::field::Empty,
             ssl_cert_file = tracing::field::Empty,
             dest = tracing::field::display(self.dest.display()),
         );
+        if let Some(sha256) = &self.sha256 {
+            span.record("sha256", tracing::field::display(sha256));
+        }
         if let Some(proxy) = &self.proxy {
             span.record("proxy", tracing::field::display(&proxy));
         }
@@ -92,90 +214,318 @@ impl Action for FetchAndUnpackNix {
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
-        vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
+        let mut explanation = vec![];
+        if self.sources.len() > 1 {
+            explanation.push("Each source is tried in order until one succeeds".to_string());
+        }
+        if let Some(sha256) = &self.sha256 {
+            explanation.push(format!("Verify it has the SHA-256 digest `{sha256}`"));
+        }
+        if let (Some(cache), Some(_)) = (&self.cache, &self.sha256) {
+            explanation.push(format!(
+                "Reuse a verified copy cached in `{}` if there is one, otherwise cache it there once verified",
+                cache.dir().display()
+            ));
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
-        let bytes = match &self.url_or_path {
-            UrlOrPath::Url(url) => {
-                let bytes = match url.scheme() {
-                    "https" | "http" => {
-                        let mut buildable_client = reqwest::Client::builder();
-                        if let Some(proxy) = &self.proxy {
-                            buildable_client = buildable_client.proxy(
-                                reqwest::Proxy::all(proxy.clone())
-                                    .map_err(ActionErrorKind::Reqwest)
-                                    .map_err(Self::error)?,
-                            )
-                        }
-                        if let Some(ssl_cert_file) = &self.ssl_cert_file {
-                            let ssl_cert =
-                                parse_ssl_cert(ssl_cert_file).await.map_err(Self::error)?;
-                            buildable_client = buildable_client.add_root_certificate(ssl_cert);
-                        }
-                        let client = buildable_client
-                            .build()
-                            .map_err(ActionErrorKind::Reqwest)
-                            .map_err(Self::error)?;
-                        let req = client
-                            .get(url.clone())
-                            .build()
-                            .map_err(ActionErrorKind::Reqwest)
-                            .map_err(Self::error)?;
-                        let res = client
-                            .execute(req)
-                            .await
-                            .map_err(ActionErrorKind::Reqwest)
-                            .map_err(Self::error)?;
-                        res.bytes()
-                            .await
-                            .map_err(ActionErrorKind::Reqwest)
-                            .map_err(Self::error)?
-                    },
-                    "file" => {
-                        let buf = tokio::fs::read(url.path())
-                            .await
-                            .map_err(|e| ActionErrorKind::Read(PathBuf::from(url.path()), e))
-                            .map_err(Self::error)?;
-                        Bytes::from(buf)
-                    },
-                    _ => return Err(Self::error(ActionErrorKind::UnknownUrlScheme)),
-                };
-                bytes
+        let mut failures = vec![];
+        for source in &self.sources {
+            match self.fetch_and_unpack(source).await {
+                Ok(()) => {
+                    self.fetched_from = Some(source.clone());
+                    return Ok(());
+                },
+                Err(err) if self.sources.len() > 1 => {
+                    tracing::warn!(
+                        "Fetching `{source}` failed, trying the next source: {}",
+                        error_chain(&err)
+                    );
+                    failures.push((source.clone(), err));
+                },
+                Err(err) => return Err(Self::error(err)),
+            }
+        }
+        Err(Self::error(FetchUrlError::AllSourcesFailed(failures)))
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![/* Deliberately empty -- this is a noop */]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        Ok(())
+    }
+}
+
+impl FetchAndUnpackNix {
+    async fn fetch_and_unpack(&self, source: &UrlOrPath) -> Result<(), ActionErrorKind> {
+        // The embedded Nix package is verified against the digest it was bundled with
+        if let UrlOrPath::Embedded = source {
+            let expected = match &self.sha256 {
+                Some(sha256) => sha256.clone(),
+                None => {
+                    let (_, manifest) = crate::bundle::current()
+                        .await
+                        .map_err(FetchUrlError::Bundle)?
+                        .ok_or(FetchUrlError::NotABundle)?;
+                    manifest.nix_package.sha256
+                },
+            };
+            return self.unpack(source, Some(&expected), None).await;
+        }
+
+        // Only tarballs fetched over the network are worth caching, and only if they can be verified
+        let (cache, url, sha256) = match (&self.cache, source, &self.sha256) {
+            (Some(cache), UrlOrPath::Url(url), Some(sha256))
+                if matches!(url.scheme(), "https" | "http") =>
+            {
+                (cache, url, sha256)
             },
-            UrlOrPath::Path(path) => {
-                let buf = tokio::fs::read(path)
-                    .await
-                    .map_err(|e| ActionErrorKind::Read(PathBuf::from(path), e))
-                    .map_err(Self::error)?;
-                Bytes::from(buf)
+            _ => return self.unpack(source, self.sha256.as_ref(), None).await,
+        };
+
+        if let Some(entry) = cache.lookup(url, Some(sha256)).await {
+            tracing::debug!("Using `{}`, cached from `{source}`", entry.path.display());
+            let cached = UrlOrPath::Path(entry.path.clone());
+            match self.unpack(&cached, Some(&entry.sha256), None).await {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    tracing::warn!(
+                        "The cached copy of `{source}` could not be used, fetching it again: {}",
+                        error_chain(&err)
+                    );
+                    cache.remove(&entry).await;
+                },
+            }
+        }
+
+        let pending = match cache.pending(url).await {
+            Ok(pending) => Some(pending),
+            Err(err) => {
+                tracing::warn!(
+                    "Could not cache `{source}`, continuing without caching: {}",
+                    error_chain(&err)
+                );
+                None
             },
         };
+        self.unpack(source, Some(sha256), pending).await
+    }
+
+    /// Unpack the tarball at `source`, which must have the digest `expected` if it is set
+    ///
+    /// The tarball is fetched to a temporary file and verified before anything is unpacked. Once
+    /// verified, the tarball is committed to the `pending` cache entry, if there is one.
+    async fn unpack(
+        &self,
+        source: &UrlOrPath,
+        expected: Option<&Sha256Digest>,
+        mut pending: Option<PendingEntry>,
+    ) -> Result<(), ActionErrorKind> {
+        let parent_dir = self
+            .dest
+            .parent()
+            .ok_or_else(|| FetchUrlError::NoParentDirectory(self.dest.clone()))?;
+        tokio::fs::create_dir_all(parent_dir)
+            .await
+            .map_err(|e| ActionErrorKind::CreateDirectory(parent_dir.to_path_buf(), e))?;
+        // Each attempt gets a scratch directory of its own, so a failed attempt never leaves
+        // anything behind in `dest` for the next source, or a later step, to pick up
+        let scratch_dir = parent_dir.join(format!(
+            "nix-installer-tmp.{}",
+            rand::thread_rng().gen::<u32>()
+        ));
+        tokio::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&scratch_dir)
+            .await
+            .map_err(|e| ActionErrorKind::CreateDirectory(scratch_dir.clone(), e))?;
+        let tarball = scratch_dir.join("nix.tarball");
+        let unpacked_dir = scratch_dir.join("unpacked");
+
+        let verified = match self.fetch(source, &tarball, pending.as_mut()).await {
+            Ok(actual) => match expected {
+                Some(expected) if actual != *expected => Err(ActionErrorKind::Sha256Mismatch {
+                    url_or_path: source.to_string(),
+                    expected: expected.clone(),
+                    actual,
+                }),
+                _ => Ok(actual),
+            },
+            Err(err) => Err(err),
+        };
+        let unpacked = match verified {
+            Ok(actual) => match self.unpack_verified(&tarball, &unpacked_dir).await {
+                Ok(()) => self.move_into_place(&unpacked_dir).await.map(|()| actual),
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        };
+        tokio::fs::remove_dir_all(&scratch_dir).await.ok();
 
-        // TODO(@Hoverbear): Pick directory
-        tracing::trace!("Unpacking tar.xz");
-        let dest_clone = self.dest.clone();
+        let actual = match unpacked {
+            Ok(actual) => actual,
+            Err(err) => {
+                if let Some(pending) = pending {
+                    pending.discard().await;
+                }
+                return Err(err);
+            },
+        };
+        tracing::debug!(sha256 = %actual, "Unpacked");
 
-        let decoder = xz2::read::XzDecoder::new(bytes.reader());
-        let mut archive = tar::Archive::new(decoder);
-        archive.set_preserve_permissions(true);
-        archive.set_preserve_mtime(true);
-        archive.set_unpack_xattrs(true);
-        archive
-            .unpack(&dest_clone)
-            .map_err(FetchUrlError::Unarchive)
-            .map_err(Self::error)?;
+        // Only what was verified against the expected digest is cached, a later install trusts it
+        if let Some(pending) = pending {
+            let Some(expected) = expected else {
+                pending.discard().await;
+                return Ok(());
+            };
+            if let Err(err) = pending.commit(expected).await {
+                tracing::warn!(
+                    "Could not cache `{source}`, continuing: {}",
+                    error_chain(&err)
+                );
+            }
+        }
 
         Ok(())
     }
 
-    fn revert_description(&self) -> Vec<ActionDescription> {
-        vec![/* Deliberately empty -- this is a noop */]
+    /// Fetch the tarball at `source` to `tarball`, returning its digest
+    ///
+    /// The tarball is never held in memory, it is hashed and written as it arrives.
+    async fn fetch(
+        &self,
+        source: &UrlOrPath,
+        tarball: &Path,
+        mut pending: Option<&mut PendingEntry>,
+    ) -> Result<Sha256Digest, ActionErrorKind> {
+        let (mut stream, total) = self.open(source).await?;
+        if let (Some(total), Some(scratch_dir)) = (total, tarball.parent()) {
+            check_free_space(scratch_dir, total)?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .mode(0o600)
+            .open(tarball)
+            .await
+            .map_err(|e| ActionErrorKind::Open(tarball.to_path_buf(), e))?;
+
+        let mut hasher = Sha256::new();
+        let mut received = 0;
+        while let Some(streamed) = stream.try_next().await? {
+            let chunk = match streamed {
+                Streamed::Chunk(chunk) => chunk,
+                Streamed::Restart => {
+                    tracing::debug!("`{source}` could not be resumed, fetching it from the start");
+                    hasher = Sha256::new();
+                    received = 0;
+                    if let Some(pending) = &mut pending {
+                        pending.restart();
+                    }
+                    file.set_len(0)
+                        .await
+                        .map_err(|e| ActionErrorKind::Truncate(tarball.to_path_buf(), e))?;
+                    file.rewind()
+                        .await
+                        .map_err(|e| ActionErrorKind::Seek(tarball.to_path_buf(), e))?;
+                    continue;
+                },
+            };
+            hasher.update(&chunk);
+            if let Some(pending) = &mut pending {
+                pending.write(&chunk);
+            }
+            file.write_all(&chunk)
+                .await
+                .map_err(|e| ActionErrorKind::Write(tarball.to_path_buf(), e))?;
+            received += chunk.len() as u64;
+            crate::event::emit(|| InstallEvent::DownloadProgress {
+                url: source.to_string(),
+                received,
+                total,
+            });
+        }
+        file.flush()
+            .await
+            .map_err(|e| ActionErrorKind::Flush(tarball.to_path_buf(), e))?;
+
+        Ok(Sha256Digest::from_hasher(hasher))
     }
 
-    #[tracing::instrument(level = "debug", skip_all)]
-    async fn revert(&mut self) -> Result<(), ActionError> {
+    /// Unpack the verified `tarball` into `unpacked_dir`, streaming it through the decompressor
+    async fn unpack_verified(
+        &self,
+        tarball: &Path,
+        unpacked_dir: &Path,
+    ) -> Result<(), ActionErrorKind> {
+        let mut file = tokio::fs::File::open(tarball)
+            .await
+            .map_err(|e| ActionErrorKind::Open(tarball.to_path_buf(), e))?;
+
+        // Read just enough to detect the compression, then start again from the beginning
+        let mut magic = Vec::with_capacity(Compression::MAGIC_LEN);
+        (&mut file)
+            .take(Compression::MAGIC_LEN as u64)
+            .read_to_end(&mut magic)
+            .await
+            .map_err(|e| ActionErrorKind::Read(tarball.to_path_buf(), e))?;
+        file.rewind()
+            .await
+            .map_err(|e| ActionErrorKind::Seek(tarball.to_path_buf(), e))?;
+        let compression = Compression::detect(&magic);
+        let decoder = Decoder::new(compression, BufReader::new(file));
+
+        tracing::trace!(%compression, "Unpacking tarball");
+        let unpacked_dir = unpacked_dir.to_path_buf();
+        let reader = SyncIoBridge::new(decoder);
+        tokio::task::spawn_blocking(move || {
+            let mut archive = tar::Archive::new(reader);
+            archive.set_preserve_permissions(true);
+            archive.set_preserve_mtime(true);
+            archive.set_unpack_xattrs(true);
+            archive.unpack(&unpacked_dir)
+        })
+        .await
+        .map_err(ActionErrorKind::Join)?
+        .map_err(|e| FetchUrlError::Unarchive(compression, e).into())
+    }
+
+    /// Move what was unpacked into `dest`, replacing anything of the same name already there
+    async fn move_into_place(&self, unpacked_dir: &Path) -> Result<(), ActionErrorKind> {
+        if !self.dest.exists() {
+            return tokio::fs::rename(unpacked_dir, &self.dest)
+                .await
+                .map_err(|e| {
+                    ActionErrorKind::Rename(unpacked_dir.to_path_buf(), self.dest.clone(), e)
+                });
+        }
+
+        let mut entries = tokio::fs::read_dir(unpacked_dir)
+            .await
+            .map_err(|e| ActionErrorKind::ReadDir(unpacked_dir.to_path_buf(), e))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| ActionErrorKind::ReadDir(unpacked_dir.to_path_buf(), e))?
+        {
+            let to = self.dest.join(entry.file_name());
+            let removed = match tokio::fs::symlink_metadata(&to).await {
+                Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(&to).await,
+                Ok(_) => tokio::fs::remove_file(&to).await,
+                Err(_) => Ok(()),
+            };
+            removed.map_err(|e| ActionErrorKind::Remove(to.clone(), e))?;
+            tokio::fs::rename(entry.path(), &to)
+                .await
+                .map_err(|e| ActionErrorKind::Rename(entry.path(), to, e))?;
+        }
         Ok(())
     }
 }
@@ -183,10 +533,126 @@ impl Action for FetchAndUnpackNix {
 #[non_exhaustive]
 #[derive(Debug, thiserror::Error)]
 pub enum FetchUrlError {
-    #[error("Unarchiving error")]
-    Unarchive(#[source] std::io::Error),
+    #[error("Unarchiving {0} tarball")]
+    Unarchive(Compression, #[source] std::io::Error),
     #[error("Unknown proxy scheme, `https://`, `socks5://`, and `http://` supported")]
     UnknownProxyScheme,
+    #[error("No sources to fetch the Nix package from were given")]
+    NoSources,
+    #[error(
+        "The Nix package is `{}`, but `nix-installer` is not running from a bundle",
+        UrlOrPath::EMBEDDED
+    )]
+    NotABundle,
+    #[error(transparent)]
+    Bundle(crate::bundle::BundleError),
+    #[error("Fetching the Nix package failed from every source:{}", describe_failures(.0))]
+    AllSourcesFailed(Vec<(UrlOrPath, ActionErrorKind)>),
+    #[error("`{0}` has no parent directory to unpack Nix in")]
+    NoParentDirectory(PathBuf),
+    #[error("The tarball is {needed} bytes, but `{}` only has {available} bytes free", .dir.display())]
+    InsufficientSpace {
+        dir: PathBuf,
+        needed: u64,
+        available: u64,
+    },
+}
+
+/// Check `dir` has room for `needed` more bytes
+fn check_free_space(dir: &Path, needed: u64) -> Result<(), ActionErrorKind> {
+    let stat = nix::sys::statvfs::statvfs(dir)
+        .map_err(|e| ActionErrorKind::GettingMetadata(dir.to_path_buf(), e.into()))?;
+    // The widths of these differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    let available = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+    if available < needed {
+        return Err(FetchUrlError::InsufficientSpace {
+            dir: dir.to_path_buf(),
+            needed,
+            available,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+fn describe_failures(failures: &[(UrlOrPath, ActionErrorKind)]) -> String {
+    failures
+        .iter()
+        .map(|(source, err)| format!("\n  `{source}`: {}", error_chain(err)))
+        .collect()
+}
+
+/// How a tarball is compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Xz,
+    Zstd,
+    Gzip,
+    None,
+}
+
+impl Compression {
+    /// How many bytes [`Compression::detect`] needs
+    const MAGIC_LEN: usize = 6;
+
+    /// Detect the compression of a tarball starting with `magic`
+    fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0xFD, b'7', b'z', b'X', b'Z', 0x00]) {
+            Self::Xz
+        } else if magic.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]) {
+            Self::Zstd
+        } else if magic.starts_with(&[0x1F, 0x8B]) {
+            Self::Gzip
+        } else {
+            Self::None
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Xz => write!(f, "xz compressed"),
+            Self::Zstd => write!(f, "zstd compressed"),
+            Self::Gzip => write!(f, "gzip compressed"),
+            Self::None => write!(f, "uncompressed"),
+        }
+    }
+}
+
+/// Decompresses `R` according to a [`Compression`]
+enum Decoder<R> {
+    Xz(XzDecoder<R>),
+    Zstd(ZstdDecoder<R>),
+    Gzip(GzipDecoder<R>),
+    None(R),
+}
+
+impl<R: AsyncBufRead + Unpin> Decoder<R> {
+    fn new(compression: Compression, reader: R) -> Self {
+        match compression {
+            Compression::Xz => Self::Xz(XzDecoder::new(reader)),
+            Compression::Zstd => Self::Zstd(ZstdDecoder::new(reader)),
+            Compression::Gzip => Self::Gzip(GzipDecoder::new(reader)),
+            Compression::None => Self::None(reader),
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for Decoder<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Xz(decoder) => Pin::new(decoder).poll_read(cx, buf),
+            Self::Zstd(decoder) => Pin::new(decoder).poll_read(cx, buf),
+            Self::Gzip(decoder) => Pin::new(decoder).poll_read(cx, buf),
+            Self::None(reader) => Pin::new(reader).poll_read(cx, buf),
+        }
+    }
 }
 
 impl From<FetchUrlError> for ActionErrorKind {
@@ -194,3 +660,391 @@ impl From<FetchUrlError> for ActionErrorKind {
         ActionErrorKind::Custom(Box::new(val))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONTENT: &str = "Nix, but smaller";
+
+    /// A `.tar.xz` containing `nix-2.18.1/install`, returning its contents
+    fn build_tarball() -> eyre::Result<Vec<u8>> {
+        let mut builder = tar::Builder::new(xz2::write::XzEncoder::new(Vec::new(), 6));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(CONTENT.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "nix-2.18.1/install", CONTENT.as_bytes())?;
+        Ok(builder.into_inner()?.finish()?)
+    }
+
+    #[tokio::test]
+    async fn streams_and_unpacks() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let tarball = temp_dir.path().join("nix.tar.xz");
+        let buf = build_tarball()?;
+        tokio::fs::write(&tarball, &buf).await?;
+        let dest = temp_dir.path().join("unpacked");
+
+        let mut action = FetchAndUnpackNix::plan(
+            vec![UrlOrPath::Url(Url::from_file_path(&tarball).unwrap())],
+            Some(Sha256Digest::of(&buf)),
+            dest.clone(),
+            None,
+            None,
+            NetworkPolicy::default(),
+            None,
+        )
+        .await?;
+        action.try_execute().await?;
+
+        assert_eq!(
+            tokio::fs::read_to_string(dest.join("nix-2.18.1/install")).await?,
+            CONTENT
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reuses_cached_tarballs() -> eyre::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let buf = build_tarball()?;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/nix.tar.xz", listener.local_addr()?))?;
+
+        // Only serve the tarball once, the second install must use the cache
+        let served = buf.clone();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await?;
+            let mut request = vec![0; 4096];
+            let _ = socket.read(&mut request).await?;
+            let header = format!(
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n",
+                served.len()
+            );
+            socket.write_all(header.as_bytes()).await?;
+            socket.write_all(&served).await?;
+            socket.shutdown().await?;
+            Ok::<_, eyre::Report>(())
+        });
+
+        let temp_dir = tempfile::tempdir()?;
+        let cache = TarballCache::new(temp_dir.path().join("cache"), 1024 * 1024);
+        for install in ["first", "second"] {
+            let dest = temp_dir.path().join(install);
+            let mut action = FetchAndUnpackNix::plan(
+                vec![UrlOrPath::Url(url.clone())],
+                Some(Sha256Digest::of(&buf)),
+                dest.clone(),
+                None,
+                None,
+                NetworkPolicy {
+                    retries: 0,
+                    ..Default::default()
+                },
+                Some(cache.clone()),
+            )
+            .await?;
+            action.try_execute().await?;
+
+            assert_eq!(
+                tokio::fs::read_to_string(dest.join("nix-2.18.1/install")).await?,
+                CONTENT
+            );
+        }
+        server.await??;
+
+        assert!(cache
+            .lookup(&url, Some(&Sha256Digest::of(&buf)))
+            .await
+            .is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_caches_verified_tarballs() -> eyre::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let buf = build_tarball()?;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/nix.tar.xz", listener.local_addr()?))?;
+
+        let served = buf.clone();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await?;
+            let mut request = vec![0; 4096];
+            let _ = socket.read(&mut request).await?;
+            let header = format!(
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n",
+                served.len()
+            );
+            socket.write_all(header.as_bytes()).await?;
+            socket.write_all(&served).await?;
+            socket.shutdown().await?;
+            Ok::<_, eyre::Report>(())
+        });
+
+        let temp_dir = tempfile::tempdir()?;
+        let cache = TarballCache::new(temp_dir.path().join("cache"), 1024 * 1024);
+        let mut action = FetchAndUnpackNix::plan(
+            vec![UrlOrPath::Url(url.clone())],
+            None,
+            temp_dir.path().join("unpacked"),
+            None,
+            None,
+            NetworkPolicy {
+                retries: 0,
+                ..Default::default()
+            },
+            Some(cache.clone()),
+        )
+        .await?;
+        action.try_execute().await?;
+        server.await??;
+
+        assert!(cache
+            .lookup(&url, Some(&Sha256Digest::of(&buf)))
+            .await
+            .is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn detects_compression() -> eyre::Result<()> {
+        use async_compression::tokio::bufread::{GzipEncoder, ZstdEncoder};
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(CONTENT.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "nix-2.18.1/install", CONTENT.as_bytes())?;
+        let tar = builder.into_inner()?;
+
+        let mut zstd = vec![];
+        ZstdEncoder::new(tar.as_slice())
+            .read_to_end(&mut zstd)
+            .await?;
+        let mut gzip = vec![];
+        GzipEncoder::new(tar.as_slice())
+            .read_to_end(&mut gzip)
+            .await?;
+
+        let temp_dir = tempfile::tempdir()?;
+        for (compression, buf) in [
+            (Compression::Xz, build_tarball()?),
+            (Compression::Zstd, zstd),
+            (Compression::Gzip, gzip),
+            (Compression::None, tar),
+        ] {
+            assert_eq!(Compression::detect(&buf), compression);
+
+            // The name of the tarball is deliberately unhelpful
+            let tarball = temp_dir.path().join(format!("{compression:?}.tar.xz"));
+            tokio::fs::write(&tarball, &buf).await?;
+            let dest = temp_dir.path().join(format!("{compression:?}"));
+            let mut action = FetchAndUnpackNix::plan(
+                vec![UrlOrPath::Path(tarball)],
+                None,
+                dest.clone(),
+                None,
+                None,
+                NetworkPolicy::default(),
+                None,
+            )
+            .await?;
+            action.try_execute().await?;
+
+            assert_eq!(
+                tokio::fs::read_to_string(dest.join("nix-2.18.1/install")).await?,
+                CONTENT
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn names_the_compression_when_unpacking_fails() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let tarball = temp_dir.path().join("nix.tar.xz");
+        let mut buf = vec![0x28, 0xB5, 0x2F, 0xFD];
+        buf.extend_from_slice(b"Not really zstd");
+        tokio::fs::write(&tarball, &buf).await?;
+
+        let mut action = FetchAndUnpackNix::plan(
+            vec![UrlOrPath::Path(tarball)],
+            None,
+            temp_dir.path().join("unpacked"),
+            None,
+            None,
+            NetworkPolicy::default(),
+            None,
+        )
+        .await?;
+        let err = action.try_execute().await.unwrap_err();
+
+        let message = error_chain(&err);
+        assert!(message.contains("zstd compressed"), "{message}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_next_source() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let missing = temp_dir.path().join("missing.tar.xz");
+        let corrupted = temp_dir.path().join("corrupted.tar.xz");
+        tokio::fs::write(&corrupted, "Not the tarball you were looking for").await?;
+        let tarball = temp_dir.path().join("nix.tar.xz");
+        let buf = build_tarball()?;
+        tokio::fs::write(&tarball, &buf).await?;
+        let dest = temp_dir.path().join("unpacked");
+
+        let mut action = FetchAndUnpackNix::plan(
+            vec![
+                UrlOrPath::Path(missing),
+                UrlOrPath::Path(corrupted),
+                UrlOrPath::Path(tarball.clone()),
+            ],
+            Some(Sha256Digest::of(&buf)),
+            dest.clone(),
+            None,
+            None,
+            NetworkPolicy::default(),
+            None,
+        )
+        .await?;
+        action.try_execute().await?;
+
+        assert_eq!(action.action.fetched_from, Some(UrlOrPath::Path(tarball)));
+        assert_eq!(
+            tokio::fs::read_to_string(dest.join("nix-2.18.1/install")).await?,
+            CONTENT
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn falls_back_without_leaving_partial_unpacks() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        // An uncompressed tarball which is cut off after its first file has been unpacked
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, len) in [("nix-2.18.1/stale", 16), ("nix-2.18.1/truncated", 4096)] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(len as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, vec![0; len].as_slice())?;
+        }
+        let mut truncated = builder.into_inner()?;
+        truncated.truncate(2048);
+        let truncated_path = temp_dir.path().join("truncated.tar");
+        tokio::fs::write(&truncated_path, &truncated).await?;
+        let tarball = temp_dir.path().join("nix.tar.xz");
+        tokio::fs::write(&tarball, build_tarball()?).await?;
+        let dest = temp_dir.path().join("unpacked");
+
+        let mut action = FetchAndUnpackNix::plan(
+            vec![
+                UrlOrPath::Path(truncated_path),
+                UrlOrPath::Path(tarball.clone()),
+            ],
+            None,
+            dest.clone(),
+            None,
+            None,
+            NetworkPolicy::default(),
+            None,
+        )
+        .await?;
+        action.try_execute().await?;
+
+        assert_eq!(action.action.fetched_from, Some(UrlOrPath::Path(tarball)));
+        assert!(!dest.join("nix-2.18.1/stale").exists());
+        assert_eq!(
+            tokio::fs::read_to_string(dest.join("nix-2.18.1/install")).await?,
+            CONTENT
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_mismatched_sha256() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let tarball = temp_dir.path().join("nix.tar.xz");
+        tokio::fs::write(&tarball, "Not the tarball you were looking for").await?;
+        let dest = temp_dir.path().join("unpacked");
+
+        let mut action = FetchAndUnpackNix::plan(
+            vec![UrlOrPath::Path(tarball)],
+            Some(Sha256Digest::of(b"The tarball")),
+            dest.clone(),
+            None,
+            None,
+            NetworkPolicy::default(),
+            None,
+        )
+        .await?;
+
+        let err = action.try_execute().await.unwrap_err();
+        assert!(
+            matches!(err.kind(), ActionErrorKind::Sha256Mismatch { .. }),
+            "Expected a SHA-256 mismatch, got {err:?}"
+        );
+        assert!(!dest.exists(), "Nothing should have been unpacked");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verifies_before_unpacking() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let tarball = temp_dir.path().join("nix.tar.xz");
+        // A well formed tarball, but not the one which was expected
+        tokio::fs::write(&tarball, build_tarball()?).await?;
+        let dest = temp_dir.path().join("unpacked");
+        tokio::fs::create_dir(&dest).await?;
+
+        let mut action = FetchAndUnpackNix::plan(
+            vec![UrlOrPath::Path(tarball)],
+            Some(Sha256Digest::of(b"The tarball")),
+            dest.clone(),
+            None,
+            None,
+            NetworkPolicy::default(),
+            None,
+        )
+        .await?;
+
+        let err = action.try_execute().await.unwrap_err();
+        assert!(
+            matches!(err.kind(), ActionErrorKind::Sha256Mismatch { .. }),
+            "Expected a SHA-256 mismatch, got {err:?}"
+        );
+        assert_eq!(std::fs::read_dir(&dest)?.count(), 0);
+        // Only the tarball and the destination, the download was removed
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn checks_free_space() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        check_free_space(temp_dir.path(), 0)?;
+        let err = check_free_space(temp_dir.path(), u64::MAX).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ActionErrorKind::Custom(ref e)
+                    if matches!(e.downcast_ref(), Some(FetchUrlError::InsufficientSpace { .. }))
+            ),
+            "Expected insufficient space, got {err:?}"
+        );
+        Ok(())
+    }
+}
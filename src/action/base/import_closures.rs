@@ -0,0 +1,496 @@
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::{
+    action::{
+        base::setup_default_profile::DEFAULT_PROFILE, verification::verify_path, Action,
+        ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction, Verification,
+    },
+    execute_command,
+    settings::{in_root, is_alternate_root},
+};
+
+/// Where the garbage collector roots of the imported store paths are kept
+const GC_ROOTS_DIR: &str = "/nix/var/nix/gcroots/nix-installer";
+
+/// A store closure on disk
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClosureSource {
+    /// The output of `nix-store --export`
+    Export(PathBuf),
+    /// A binary cache directory, as written by `nix copy --to file://...`
+    BinaryCache(PathBuf),
+}
+
+impl ClosureSource {
+    /// Find out what kind of closure `path` holds
+    async fn detect(path: &Path) -> Result<Self, ActionErrorKind> {
+        let metadata = tokio::fs::metadata(path)
+            .await
+            .map_err(|e| ActionErrorKind::GettingMetadata(path.to_path_buf(), e))?;
+        if metadata.is_file() {
+            Ok(Self::Export(path.to_path_buf()))
+        } else if metadata.is_dir() && path.join("nix-cache-info").is_file() {
+            Ok(Self::BinaryCache(path.to_path_buf()))
+        } else {
+            Err(ImportClosuresError::UnknownSource(path.to_path_buf()).into())
+        }
+    }
+}
+
+impl std::fmt::Display for ClosureSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Export(path) => write!(f, "`{}` (a `nix-store --export` file)", path.display()),
+            Self::BinaryCache(path) => write!(f, "`{}` (a binary cache)", path.display()),
+        }
+    }
+}
+
+/**
+Import extra store closures from disk, once the default profile is set up, optionally installing some
+of their store paths into the default profile
+
+Every imported store path is kept from garbage collection by a root in `/nix/var/nix/gcroots/nix-installer`.
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ImportClosures {
+    sources: Vec<ClosureSource>,
+    install: Vec<PathBuf>,
+    root: PathBuf,
+    /// The store paths which were imported, once they have been
+    #[serde(default)]
+    imported: Vec<PathBuf>,
+    /// The generation of the default profile before `install` was installed into it
+    #[serde(default)]
+    previous_generation: Option<u64>,
+}
+
+impl ImportClosures {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        sources: Vec<PathBuf>,
+        install: Vec<PathBuf>,
+        root: impl AsRef<Path>,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let root = root.as_ref().to_path_buf();
+
+        let mut detected = Vec::with_capacity(sources.len());
+        for source in sources {
+            let source = ClosureSource::detect(&source).await.map_err(Self::error)?;
+            if let ClosureSource::BinaryCache(path) = &source {
+                // The binary cache would need to be visible from inside the root to be copied from
+                if is_alternate_root(&root) {
+                    return Err(Self::error(
+                        ImportClosuresError::BinaryCacheInAlternateRoot(path.clone()),
+                    ));
+                }
+            }
+            detected.push(source);
+        }
+
+        for path in &install {
+            if !path.starts_with("/nix/store") {
+                return Err(Self::error(ImportClosuresError::NotAStorePath(
+                    path.clone(),
+                )));
+            }
+        }
+
+        Ok(Self {
+            sources: detected,
+            install,
+            root,
+            imported: vec![],
+            previous_generation: None,
+        }
+        .into())
+    }
+
+    /// A command running `bin` from the default profile, chrooted into an alternate root
+    fn command(&self, bin: &str) -> Result<Command, ActionErrorKind> {
+        let bin = Path::new(DEFAULT_PROFILE).join("bin").join(bin);
+        let mut command = if is_alternate_root(&self.root) {
+            let mut command = Command::new("chroot");
+            command.arg(&self.root).arg(bin);
+            command
+        } else {
+            Command::new(bin)
+        };
+        command.process_group(0);
+        command.env(
+            "HOME",
+            dirs::home_dir().ok_or(ImportClosuresError::NoRootHome)?,
+        );
+        Ok(command)
+    }
+
+    /// Import `source`, returning the store paths it held
+    async fn import(&self, source: &ClosureSource) -> Result<Vec<PathBuf>, ActionErrorKind> {
+        match source {
+            ClosureSource::Export(path) => {
+                let file = std::fs::File::open(path)
+                    .map_err(|e| ActionErrorKind::Open(path.clone(), e))?;
+                let output = execute_command(
+                    self.command("nix-store")?
+                        .arg("--import")
+                        .stdin(std::process::Stdio::from(file)),
+                )
+                .await?;
+                Ok(String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(PathBuf::from)
+                    .collect())
+            },
+            ClosureSource::BinaryCache(path) => {
+                let store_paths = binary_cache_store_paths(path).await?;
+                if store_paths.is_empty() {
+                    return Ok(store_paths);
+                }
+                execute_command(
+                    self.command("nix")?
+                        .args(["--extra-experimental-features", "nix-command", "copy"])
+                        .arg("--from")
+                        .arg(format!("file://{}", path.display()))
+                        // The closures were given to the installer by root, there is nothing to check them against yet
+                        .arg("--no-check-sigs")
+                        .args(&store_paths)
+                        .stdin(std::process::Stdio::null()),
+                )
+                .await?;
+                Ok(store_paths)
+            },
+        }
+    }
+
+    /// The generation the default profile currently points to
+    async fn current_generation(&self) -> Result<Option<u64>, ActionErrorKind> {
+        let profile = in_root(&self.root, DEFAULT_PROFILE);
+        let link = match tokio::fs::read_link(&profile).await {
+            Ok(link) => link,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(ActionErrorKind::ReadSymlink(profile, e)),
+        };
+        // Generations are named like `default-2-link`
+        let generation = link
+            .file_name()
+            .and_then(|v| v.to_str())
+            .and_then(|v| v.strip_prefix("default-"))
+            .and_then(|v| v.strip_suffix("-link"))
+            .and_then(|v| v.parse().ok())
+            .ok_or(ImportClosuresError::ProfileGeneration(link.clone()))?;
+        Ok(Some(generation))
+    }
+
+    /// The garbage collector root of `store_path`
+    fn gc_root(&self, store_path: &Path) -> PathBuf {
+        in_root(&self.root, GC_ROOTS_DIR).join(store_path.file_name().unwrap_or_default())
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "import_closures")]
+impl Action for ImportClosures {
+    fn action_tag() -> ActionTag {
+        ActionTag("import_closures")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Import store closures from {}",
+            self.sources
+                .iter()
+                .map(|source| match source {
+                    ClosureSource::Export(path) | ClosureSource::BinaryCache(path) =>
+                        format!("`{}`", path.display()),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "import_closures",
+            sources = tracing::field::debug(&self.sources),
+            install = tracing::field::debug(&self.install),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = self
+            .sources
+            .iter()
+            .map(|source| format!("Import {source}"))
+            .collect::<Vec<_>>();
+        explanation.push(format!(
+            "Keep the imported store paths from garbage collection with roots in `{GC_ROOTS_DIR}`"
+        ));
+        for path in &self.install {
+            explanation.push(format!(
+                "Install `{}` into the default profile",
+                path.display()
+            ));
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        let mut imported = vec![];
+        for source in &self.sources {
+            let mut store_paths = self.import(source).await.map_err(Self::error)?;
+            tracing::debug!("Imported {} store paths from {source}", store_paths.len());
+            imported.append(&mut store_paths);
+        }
+        imported.sort();
+        imported.dedup();
+
+        let gc_roots_dir = in_root(&self.root, GC_ROOTS_DIR);
+        tokio::fs::create_dir_all(&gc_roots_dir)
+            .await
+            .map_err(|e| ActionErrorKind::CreateDirectory(gc_roots_dir.clone(), e))
+            .map_err(Self::error)?;
+        for store_path in &imported {
+            let gc_root = self.gc_root(store_path);
+            // Importing the same closures again should not fail
+            tokio::fs::remove_file(&gc_root).await.ok();
+            tokio::fs::symlink(store_path, &gc_root)
+                .await
+                .map_err(|e| ActionErrorKind::Symlink(store_path.clone(), gc_root.clone(), e))
+                .map_err(Self::error)?;
+        }
+        self.imported = imported;
+
+        if !self.install.is_empty() {
+            if let Some(missing) = self
+                .install
+                .iter()
+                .find(|path| !self.imported.contains(path))
+            {
+                return Err(Self::error(ImportClosuresError::NotImported(
+                    missing.clone(),
+                )));
+            }
+
+            self.previous_generation = self.current_generation().await.map_err(Self::error)?;
+            execute_command(
+                self.command("nix-env")
+                    .map_err(Self::error)?
+                    .args(["-p", DEFAULT_PROFILE])
+                    .arg("-i")
+                    .args(&self.install)
+                    .stdin(std::process::Stdio::null()),
+            )
+            .await
+            .map_err(Self::error)?;
+        }
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![format!(
+            "Remove the garbage collector roots in `{GC_ROOTS_DIR}`"
+        )];
+        if !self.install.is_empty() {
+            explanation.push(
+                "Roll the default profile back to before the store paths were installed".into(),
+            );
+        }
+        vec![ActionDescription::new(
+            "Remove the imported store closures".to_string(),
+            explanation,
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+
+        if let (false, Some(generation)) = (self.install.is_empty(), self.previous_generation) {
+            // If the default profile is already gone there is nothing to roll back
+            if in_root(&self.root, DEFAULT_PROFILE).exists() {
+                let res = match self.command("nix-env") {
+                    Ok(mut command) => {
+                        execute_command(
+                            command
+                                .args(["-p", DEFAULT_PROFILE])
+                                .arg("--switch-generation")
+                                .arg(generation.to_string())
+                                .stdin(std::process::Stdio::null()),
+                        )
+                        .await
+                    },
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = res {
+                    errors.push(e);
+                }
+            }
+        }
+
+        let gc_roots_dir = in_root(&self.root, GC_ROOTS_DIR);
+        if gc_roots_dir.exists() {
+            if let Err(e) = tokio::fs::remove_dir_all(&gc_roots_dir).await {
+                errors.push(ActionErrorKind::Remove(gc_roots_dir, e));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(Self::error(errors.into_iter().next().unwrap()))
+        } else {
+            Err(Self::error(ActionErrorKind::Multiple(errors)))
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        let mut drift = vec![];
+        for store_path in &self.imported {
+            if !in_root(&self.root, store_path).exists() {
+                drift.push(format!(
+                    "Imported store path `{}` no longer exists",
+                    store_path.display()
+                ));
+            }
+            verify_path(&self.gc_root(store_path), None, None, None, &mut drift)
+                .await
+                .map_err(Self::error)?;
+        }
+        Ok(Verification::from_drift(drift))
+    }
+}
+
+/// The store paths described by the `.narinfo` files of the binary cache at `path`
+async fn binary_cache_store_paths(path: &Path) -> Result<Vec<PathBuf>, ActionErrorKind> {
+    let mut store_paths = vec![];
+    let mut read_dir = tokio::fs::read_dir(path)
+        .await
+        .map_err(|e| ActionErrorKind::ReadDir(path.to_path_buf(), e))?;
+    while let Some(entry) = read_dir
+        .next_entry()
+        .await
+        .map_err(|e| ActionErrorKind::ReadDir(path.to_path_buf(), e))?
+    {
+        let narinfo = entry.path();
+        if narinfo.extension().and_then(|v| v.to_str()) != Some("narinfo") {
+            continue;
+        }
+        let buf = tokio::fs::read_to_string(&narinfo)
+            .await
+            .map_err(|e| ActionErrorKind::Read(narinfo.clone(), e))?;
+        let store_path = buf
+            .lines()
+            .find_map(|line| line.strip_prefix("StorePath:"))
+            .map(|v| PathBuf::from(v.trim()))
+            .ok_or(ImportClosuresError::Narinfo(narinfo))?;
+        store_paths.push(store_path);
+    }
+    store_paths.sort();
+    Ok(store_paths)
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum ImportClosuresError {
+    #[error("`{0}` is neither a `nix-store --export` file nor a binary cache directory (with a `nix-cache-info` file)")]
+    UnknownSource(PathBuf),
+    #[error("The binary cache `{0}` cannot be imported into an alternate root, use a `nix-store --export` file instead")]
+    BinaryCacheInAlternateRoot(PathBuf),
+    #[error("`{0}` is not a store path, store paths start with `/nix/store`")]
+    NotAStorePath(PathBuf),
+    #[error("`{0}` was not in any of the imported store closures, so cannot be installed")]
+    NotImported(PathBuf),
+    #[error("`{0}` does not have a `StorePath`")]
+    Narinfo(PathBuf),
+    #[error("Could not determine the generation of the default profile from `{0}`")]
+    ProfileGeneration(PathBuf),
+    #[error("No root home found to run Nix with")]
+    NoRootHome,
+}
+
+impl From<ImportClosuresError> for ActionErrorKind {
+    fn from(val: ImportClosuresError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn detects_sources() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let export = temp_dir.path().join("tools.closure");
+        tokio::fs::write(&export, b"").await?;
+        let binary_cache = temp_dir.path().join("cache");
+        tokio::fs::create_dir(&binary_cache).await?;
+        tokio::fs::write(
+            binary_cache.join("nix-cache-info"),
+            "StoreDir: /nix/store\n",
+        )
+        .await?;
+        tokio::fs::write(
+            binary_cache.join("0000000000000000000000000000000a.narinfo"),
+            "StorePath: /nix/store/0000000000000000000000000000000a-direnv-2.32.3\nURL: nar/a.nar.xz\n",
+        )
+        .await?;
+
+        let action =
+            ImportClosures::plan(vec![export.clone(), binary_cache.clone()], vec![], "/").await?;
+        assert_eq!(
+            action.action.sources,
+            vec![
+                ClosureSource::Export(export),
+                ClosureSource::BinaryCache(binary_cache.clone())
+            ]
+        );
+        assert_eq!(
+            binary_cache_store_paths(&binary_cache).await?,
+            vec![PathBuf::from(
+                "/nix/store/0000000000000000000000000000000a-direnv-2.32.3"
+            )]
+        );
+
+        // Any other directory is not a closure
+        let err = ImportClosures::plan(vec![temp_dir.path().to_path_buf()], vec![], "/")
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err.kind(), ActionErrorKind::Custom(e) if e.downcast_ref::<ImportClosuresError>().is_some()),
+            "{err:?}"
+        );
+        // A binary cache is not visible from inside an alternate root
+        assert!(
+            ImportClosures::plan(vec![binary_cache], vec![], temp_dir.path())
+                .await
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn installs_only_store_paths() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let export = temp_dir.path().join("tools.closure");
+        tokio::fs::write(&export, b"").await?;
+
+        assert!(
+            ImportClosures::plan(vec![export], vec![PathBuf::from("/usr/bin/direnv")], "/")
+                .await
+                .is_err()
+        );
+
+        Ok(())
+    }
+}
@@ -5,10 +5,13 @@ pub(crate) mod create_directory;
 pub(crate) mod create_file;
 pub(crate) mod create_group;
 pub(crate) mod create_or_insert_into_file;
+pub(crate) mod create_or_merge_flake_registry;
 pub(crate) mod create_or_merge_nix_config;
+pub(crate) mod create_secret_file;
 pub(crate) mod create_user;
 pub(crate) mod delete_user;
 pub(crate) mod fetch_and_unpack_nix;
+pub(crate) mod import_closures;
 pub(crate) mod move_unpacked_nix;
 pub(crate) mod remove_directory;
 pub(crate) mod setup_default_profile;
@@ -18,10 +21,16 @@ pub use create_directory::CreateDirectory;
 pub use create_file::CreateFile;
 pub use create_group::CreateGroup;
 pub use create_or_insert_into_file::CreateOrInsertIntoFile;
-pub use create_or_merge_nix_config::CreateOrMergeNixConfig;
+pub use create_or_merge_flake_registry::{
+    CreateOrMergeFlakeRegistry, CreateOrMergeFlakeRegistryError, FlakeRef, FlakeRefError,
+    RegistryPin,
+};
+pub use create_or_merge_nix_config::{CreateOrMergeNixConfig, MergePolicy, MergeStrategy};
+pub use create_secret_file::CreateSecretFile;
 pub use create_user::CreateUser;
 pub use delete_user::DeleteUser;
-pub use fetch_and_unpack_nix::{FetchAndUnpackNix, FetchUrlError};
+pub use fetch_and_unpack_nix::{Compression, FetchAndUnpackNix, FetchUrlError};
+pub use import_closures::{ClosureSource, ImportClosures, ImportClosuresError};
 pub use move_unpacked_nix::{MoveUnpackedNix, MoveUnpackedNixError};
 pub use remove_directory::RemoveDirectory;
 pub use setup_default_profile::{SetupDefaultProfile, SetupDefaultProfileError};
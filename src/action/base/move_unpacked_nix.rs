@@ -7,30 +7,40 @@ use std::{
 use tracing::{span, Span};
 use walkdir::WalkDir;
 
-use crate::action::{
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+use crate::{
+    action::{Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction},
+    settings::in_root,
 };
 
 pub(crate) const DEST: &str = "/nix/";
 
 /**
-Move an unpacked Nix at `src` to `/nix`
+Move an unpacked Nix at `src` to `/nix` inside `root`
 */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct MoveUnpackedNix {
     unpacked_path: PathBuf,
+    #[serde(default = "crate::settings::default_root")]
+    root: PathBuf,
 }
 
 impl MoveUnpackedNix {
     #[tracing::instrument(level = "debug", skip_all)]
-    pub async fn plan(unpacked_path: PathBuf) -> Result<StatefulAction<Self>, ActionError> {
+    pub async fn plan(
+        unpacked_path: PathBuf,
+        root: impl AsRef<Path>,
+    ) -> Result<StatefulAction<Self>, ActionError> {
         // Note: Do NOT try to check for the src/dest since the installer creates those
-        Ok(Self { unpacked_path }.into())
+        Ok(Self {
+            unpacked_path,
+            root: root.as_ref().to_path_buf(),
+        }
+        .into())
     }
 }
 
 #[async_trait::async_trait]
-#[typetag::serde(name = "mount_unpacked_nix")]
+#[typetag::serde(name = "move_unpacked_nix")]
 impl Action for MoveUnpackedNix {
     fn action_tag() -> ActionTag {
         ActionTag("move_unpacked_nix")
@@ -42,9 +52,9 @@ impl Action for MoveUnpackedNix {
     fn tracing_span(&self) -> Span {
         span!(
             tracing::Level::DEBUG,
-            "mount_unpacked_nix",
+            "move_unpacked_nix",
             src = tracing::field::display(self.unpacked_path.display()),
-            dest = DEST,
+            dest = tracing::field::display(in_root(&self.root, DEST).display()),
         )
     }
 
@@ -60,7 +70,10 @@ impl Action for MoveUnpackedNix {
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
-        let Self { unpacked_path } = self;
+        let Self {
+            unpacked_path,
+            root,
+        } = self;
 
         // This is the `nix-$VERSION` folder which unpacks from the tarball, not a nix derivation
         let found_nix_paths = glob::glob(&format!("{}/nix-*", unpacked_path.display()))
@@ -76,7 +89,7 @@ impl Action for MoveUnpackedNix {
             .await
             .map_err(|e| ActionErrorKind::ReadDir(src_store.clone(), e))
             .map_err(Self::error)?;
-        let dest_store = Path::new(DEST).join("store");
+        let dest_store = in_root(root, DEST).join("store");
         if dest_store.exists() {
             if !dest_store.is_dir() {
                 return Err(Self::error(ActionErrorKind::PathWasNotDirectory(
@@ -21,6 +21,7 @@ impl RemoveDirectory {
         Ok(StatefulAction {
             action: Self { path },
             state: ActionState::Uncompleted,
+            checkpoint: None,
         })
     }
 }
@@ -1,8 +1,9 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::{
     action::{ActionError, ActionErrorKind, ActionTag, StatefulAction},
     execute_command, set_env,
+    settings::is_alternate_root,
 };
 
 use glob::glob;
@@ -12,18 +13,52 @@ use tracing::{span, Span};
 
 use crate::action::{Action, ActionDescription};
 
+pub(crate) const DEFAULT_PROFILE: &str = "/nix/var/nix/profiles/default";
+
 /**
 Setup the default Nix profile with `nss-cacert` and `nix` itself.
  */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct SetupDefaultProfile {
     unpacked_path: PathBuf,
+    #[serde(default = "crate::settings::default_root")]
+    root: PathBuf,
 }
 
 impl SetupDefaultProfile {
     #[tracing::instrument(level = "debug", skip_all)]
-    pub async fn plan(unpacked_path: PathBuf) -> Result<StatefulAction<Self>, ActionError> {
-        Ok(Self { unpacked_path }.into())
+    pub async fn plan(
+        unpacked_path: PathBuf,
+        root: impl AsRef<Path>,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        Ok(Self {
+            unpacked_path,
+            root: root.as_ref().to_path_buf(),
+        }
+        .into())
+    }
+
+    /// A command running `bin` from the `pkg` store path
+    ///
+    /// The binaries in the store expect to find their dependencies under `/nix/store`, so when installing into an
+    /// alternate root they are run chrooted into it.
+    fn command(&self, pkg: &Path, bin: &str) -> Command {
+        if is_alternate_root(&self.root) {
+            let mut command = Command::new("chroot");
+            command.arg(&self.root).arg(self.store_path(pkg).join(bin));
+            command
+        } else {
+            Command::new(pkg.join(bin))
+        }
+    }
+
+    /// The location of `pkg` as seen by commands created with [`Self::command`]
+    fn store_path(&self, pkg: &Path) -> PathBuf {
+        if is_alternate_root(&self.root) {
+            Path::new("/").join(pkg.strip_prefix(&self.root).unwrap_or(pkg))
+        } else {
+            pkg.to_path_buf()
+        }
     }
 }
 
@@ -121,7 +156,7 @@ impl Action for SetupDefaultProfile {
             .await
             .map_err(|e| ActionErrorKind::Read(reginfo_path.to_path_buf(), e))
             .map_err(Self::error)?;
-        let mut load_db_command = Command::new(nix_pkg.join("bin/nix-store"));
+        let mut load_db_command = self.command(&nix_pkg, "bin/nix-store");
         load_db_command.process_group(0);
         load_db_command.arg("--load-db");
         load_db_command.stdin(std::process::Stdio::piped());
@@ -170,50 +205,53 @@ impl Action for SetupDefaultProfile {
             )));
         };
 
+        let nss_ca_cert_file = self
+            .store_path(&nss_ca_cert_pkg)
+            .join("etc/ssl/certs/ca-bundle.crt");
+
         // Install `nix` itself into the store
         execute_command(
-            Command::new(nix_pkg.join("bin/nix-env"))
+            self.command(&nix_pkg, "bin/nix-env")
                 .process_group(0)
+                .args(["-p", DEFAULT_PROFILE])
                 .arg("-i")
-                .arg(&nix_pkg)
+                .arg(self.store_path(&nix_pkg))
                 .stdin(std::process::Stdio::null())
                 .env(
                     "HOME",
                     dirs::home_dir()
                         .ok_or_else(|| Self::error(SetupDefaultProfileError::NoRootHome))?,
                 )
-                .env(
-                    "NIX_SSL_CERT_FILE",
-                    nss_ca_cert_pkg.join("etc/ssl/certs/ca-bundle.crt"),
-                ), /* This is apparently load bearing... */
+                .env("NIX_SSL_CERT_FILE", &nss_ca_cert_file), /* This is apparently load bearing... */
         )
         .await
         .map_err(Self::error)?;
 
-        // Install `nix` itself into the store
+        // Install `nss-cacert` into the store
         execute_command(
-            Command::new(nix_pkg.join("bin/nix-env"))
+            self.command(&nix_pkg, "bin/nix-env")
                 .process_group(0)
+                .args(["-p", DEFAULT_PROFILE])
                 .arg("-i")
-                .arg(&nss_ca_cert_pkg)
+                .arg(self.store_path(&nss_ca_cert_pkg))
                 .stdin(std::process::Stdio::null())
                 .env(
                     "HOME",
                     dirs::home_dir()
                         .ok_or_else(|| Self::error(SetupDefaultProfileError::NoRootHome))?,
                 )
-                .env(
-                    "NIX_SSL_CERT_FILE",
-                    nss_ca_cert_pkg.join("etc/ssl/certs/ca-bundle.crt"),
-                ), /* This is apparently load bearing... */
+                .env("NIX_SSL_CERT_FILE", &nss_ca_cert_file), /* This is apparently load bearing... */
         )
         .await
         .map_err(Self::error)?;
 
-        set_env(
-            "NIX_SSL_CERT_FILE",
-            "/nix/var/nix/profiles/default/etc/ssl/certs/ca-bundle.crt",
-        );
+        // The installer's own environment only describes the host, not an alternate root
+        if !is_alternate_root(&self.root) {
+            set_env(
+                "NIX_SSL_CERT_FILE",
+                "/nix/var/nix/profiles/default/etc/ssl/certs/ca-bundle.crt",
+            );
+        }
 
         Ok(())
     }
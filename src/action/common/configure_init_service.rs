@@ -6,11 +6,11 @@ use std::path::PathBuf;
 use tokio::process::Command;
 use tracing::{span, Span};
 
-use crate::action::{ActionError, ActionErrorKind, ActionTag, StatefulAction};
+use crate::action::{ActionError, ActionErrorKind, ActionTag, StatefulAction, Verification};
 use crate::execute_command;
 
 use crate::action::{Action, ActionDescription};
-use crate::settings::InitSystem;
+use crate::settings::{in_root, is_alternate_root, InitSystem};
 
 #[cfg(target_os = "linux")]
 const SERVICE_SRC: &str = "/nix/var/nix/profiles/default/lib/systemd/system/nix-daemon.service";
@@ -47,34 +47,38 @@ Configure the init to run the Nix daemon
 pub struct ConfigureInitService {
     init: InitSystem,
     start_daemon: bool,
+    #[serde(default = "crate::settings::default_root")]
+    root: PathBuf,
 }
 
 impl ConfigureInitService {
     #[cfg(target_os = "linux")]
-    async fn check_if_systemd_unit_exists(src: &str, dest: &str) -> Result<(), ActionErrorKind> {
+    async fn check_if_systemd_unit_exists(
+        root: &Path,
+        src: &str,
+        dest: &str,
+    ) -> Result<(), ActionErrorKind> {
         // TODO: once we have a way to communicate interaction between the library and the cli,
         // interactively ask for permission to remove the file
 
         let unit_src = PathBuf::from(src);
         // NOTE: Check if the unit file already exists...
-        let unit_dest = PathBuf::from(dest);
-        if unit_dest.exists() {
-            if unit_dest.is_symlink() {
-                let link_dest = tokio::fs::read_link(&unit_dest)
-                    .await
-                    .map_err(|e| ActionErrorKind::ReadSymlink(unit_dest.clone(), e))?;
-                if link_dest != unit_src {
-                    return Err(ActionErrorKind::SymlinkExists(unit_dest));
-                }
-            } else {
-                return Err(ActionErrorKind::FileExists(unit_dest));
+        let unit_dest = in_root(root, dest);
+        // The link target is only meaningful inside `root`, so a link may dangle when seen from outside of it
+        if unit_dest.is_symlink() {
+            let link_dest = tokio::fs::read_link(&unit_dest)
+                .await
+                .map_err(|e| ActionErrorKind::ReadSymlink(unit_dest.clone(), e))?;
+            if link_dest != unit_src {
+                return Err(ActionErrorKind::SymlinkExists(unit_dest));
             }
+        } else if unit_dest.exists() {
+            return Err(ActionErrorKind::FileExists(unit_dest));
         }
         // NOTE: ...and if there are any overrides in the most well-known places for systemd
-        if Path::new(&format!("{dest}.d")).exists() {
-            return Err(ActionErrorKind::DirExists(PathBuf::from(format!(
-                "{dest}.d"
-            ))));
+        let overrides = in_root(root, format!("{dest}.d"));
+        if overrides.exists() {
+            return Err(ActionErrorKind::DirExists(overrides));
         }
 
         Ok(())
@@ -102,8 +106,33 @@ impl ConfigureInitService {
     pub async fn plan(
         init: InitSystem,
         start_daemon: bool,
+        root: impl AsRef<Path>,
     ) -> Result<StatefulAction<Self>, ActionError> {
+        let root = root.as_ref().to_path_buf();
         match init {
+            #[cfg(target_os = "linux")]
+            InitSystem::Systemd if is_alternate_root(&root) => {
+                // Nothing is running in the alternate root, the units are only enabled offline
+                if which::which("systemctl").is_err() {
+                    return Err(Self::error(ActionErrorKind::SystemdMissing));
+                }
+
+                Self::check_if_systemd_unit_exists(&root, SERVICE_SRC, SERVICE_DEST)
+                    .await
+                    .map_err(Self::error)?;
+                Self::check_if_systemd_unit_exists(&root, SOCKET_SRC, SOCKET_DEST)
+                    .await
+                    .map_err(Self::error)?;
+            },
+            #[cfg(target_os = "linux")]
+            InitSystem::None => {
+                // Nothing here, no init system
+            },
+            _ if is_alternate_root(&root) => {
+                return Err(Self::error(
+                    ConfigureNixDaemonServiceError::AlternateRootNotSupported(init),
+                ));
+            },
             #[cfg(target_os = "macos")]
             InitSystem::Launchd => {
                 // No plan checks, yet
@@ -120,10 +149,10 @@ impl ConfigureInitService {
                     return Err(Self::error(ActionErrorKind::SystemdMissing));
                 }
 
-                Self::check_if_systemd_unit_exists(SERVICE_SRC, SERVICE_DEST)
+                Self::check_if_systemd_unit_exists(&root, SERVICE_SRC, SERVICE_DEST)
                     .await
                     .map_err(Self::error)?;
-                Self::check_if_systemd_unit_exists(SOCKET_SRC, SOCKET_DEST)
+                Self::check_if_systemd_unit_exists(&root, SOCKET_SRC, SOCKET_DEST)
                     .await
                     .map_err(Self::error)?;
             },
@@ -155,13 +184,14 @@ impl ConfigureInitService {
                     .await
                     .map_err(Self::error)?;
             },
-            #[cfg(target_os = "linux")]
-            InitSystem::None => {
-                // Nothing here, no init system
-            },
         };
 
-        Ok(Self { init, start_daemon }.into())
+        Ok(Self {
+            init,
+            start_daemon,
+            root,
+        }
+        .into())
     }
 }
 
@@ -245,7 +275,11 @@ impl Action for ConfigureInitService {
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
-        let Self { init, start_daemon } = self;
+        let Self {
+            init,
+            start_daemon,
+            root,
+        } = self;
 
         match init {
             #[cfg(target_os = "macos")]
@@ -303,6 +337,58 @@ impl Action for ConfigureInitService {
                 }
             },
             #[cfg(target_os = "linux")]
+            InitSystem::Systemd if is_alternate_root(root) => {
+                // Nothing is running in the alternate root, so there is nothing to stop, reload, or start
+                let tmpfiles_dest = in_root(root, TMPFILES_DEST);
+                tracing::trace!(src = TMPFILES_SRC, dest = %tmpfiles_dest.display(), "Symlinking");
+                if !tmpfiles_dest.is_symlink() {
+                    tokio::fs::symlink(TMPFILES_SRC, &tmpfiles_dest)
+                        .await
+                        .map_err(|e| {
+                            ActionErrorKind::Symlink(
+                                PathBuf::from(TMPFILES_SRC),
+                                tmpfiles_dest.clone(),
+                                e,
+                            )
+                        })
+                        .map_err(Self::error)?;
+                }
+
+                execute_command(
+                    Command::new("systemd-tmpfiles")
+                        .process_group(0)
+                        .arg("--create")
+                        .arg("--prefix=/nix/var/nix")
+                        .arg(format!("--root={}", root.display()))
+                        .stdin(std::process::Stdio::null()),
+                )
+                .await
+                .map_err(Self::error)?;
+
+                for (src, dest) in [(SERVICE_SRC, SERVICE_DEST), (SOCKET_SRC, SOCKET_DEST)] {
+                    Self::check_if_systemd_unit_exists(root, src, dest)
+                        .await
+                        .map_err(Self::error)?;
+                    let dest = in_root(root, dest);
+                    if dest.is_symlink() {
+                        tracing::trace!(path = %dest.display(), "Removing");
+                        tokio::fs::remove_file(&dest)
+                            .await
+                            .map_err(|e| ActionErrorKind::Remove(dest.clone(), e))
+                            .map_err(Self::error)?;
+                    }
+                    tracing::trace!(src = %src, dest = %dest.display(), "Symlinking");
+                    tokio::fs::symlink(src, &dest)
+                        .await
+                        .map_err(|e| ActionErrorKind::Symlink(PathBuf::from(src), dest.clone(), e))
+                        .map_err(Self::error)?;
+                }
+
+                enable(root, "nix-daemon.socket", false)
+                    .await
+                    .map_err(Self::error)?;
+            },
+            #[cfg(target_os = "linux")]
             InitSystem::Systemd => {
                 if *start_daemon {
                     execute_command(
@@ -315,8 +401,11 @@ impl Action for ConfigureInitService {
                     .map_err(Self::error)?;
                 }
                 // The goal state is the `socket` enabled and active, the service not enabled and stopped (it activates via socket activation)
-                if is_enabled("nix-daemon.socket").await.map_err(Self::error)? {
-                    disable("nix-daemon.socket", false)
+                if is_enabled(root, "nix-daemon.socket")
+                    .await
+                    .map_err(Self::error)?
+                {
+                    disable(root, "nix-daemon.socket", false)
                         .await
                         .map_err(Self::error)?;
                 }
@@ -327,12 +416,12 @@ impl Action for ConfigureInitService {
                     } else {
                         false
                     };
-                if is_enabled("nix-daemon.service")
+                if is_enabled(root, "nix-daemon.service")
                     .await
                     .map_err(Self::error)?
                 {
                     let now = is_active("nix-daemon.service").await.map_err(Self::error)?;
-                    disable("nix-daemon.service", now)
+                    disable(root, "nix-daemon.service", now)
                         .await
                         .map_err(Self::error)?;
                 } else if is_active("nix-daemon.service").await.map_err(Self::error)? {
@@ -366,7 +455,7 @@ impl Action for ConfigureInitService {
                 // TODO: once we have a way to communicate interaction between the library and the
                 // cli, interactively ask for permission to remove the file
 
-                Self::check_if_systemd_unit_exists(SERVICE_SRC, SERVICE_DEST)
+                Self::check_if_systemd_unit_exists(root, SERVICE_SRC, SERVICE_DEST)
                     .await
                     .map_err(Self::error)?;
                 if Path::new(SERVICE_DEST).exists() {
@@ -387,7 +476,7 @@ impl Action for ConfigureInitService {
                         )
                     })
                     .map_err(Self::error)?;
-                Self::check_if_systemd_unit_exists(SOCKET_SRC, SOCKET_DEST)
+                Self::check_if_systemd_unit_exists(root, SOCKET_SRC, SOCKET_DEST)
                     .await
                     .map_err(Self::error)?;
                 if Path::new(SOCKET_DEST).exists() {
@@ -422,9 +511,9 @@ impl Action for ConfigureInitService {
                 }
 
                 if *start_daemon || socket_was_active {
-                    enable(SOCKET_SRC, true).await.map_err(Self::error)?;
+                    enable(root, SOCKET_SRC, true).await.map_err(Self::error)?;
                 } else {
-                    enable(SOCKET_SRC, false).await.map_err(Self::error)?;
+                    enable(root, SOCKET_SRC, false).await.map_err(Self::error)?;
                 }
             },
             #[cfg(target_os = "linux")]
@@ -583,16 +672,45 @@ impl Action for ConfigureInitService {
                 .map_err(Self::error)?;
             },
             #[cfg(target_os = "linux")]
+            InitSystem::Systemd if is_alternate_root(&self.root) => {
+                // Nothing is running in the alternate root, so there is nothing to stop or reload
+                if let Err(err) = disable(&self.root, "nix-daemon.socket", false).await {
+                    errors.push(err);
+                }
+
+                if let Err(err) = execute_command(
+                    Command::new("systemd-tmpfiles")
+                        .process_group(0)
+                        .arg("--remove")
+                        .arg("--prefix=/nix/var/nix")
+                        .arg(format!("--root={}", self.root.display()))
+                        .stdin(std::process::Stdio::null()),
+                )
+                .await
+                {
+                    errors.push(err);
+                }
+
+                let tmpfiles_dest = in_root(&self.root, TMPFILES_DEST);
+                if let Err(err) = tokio::fs::remove_file(&tmpfiles_dest)
+                    .await
+                    .map_err(|e| ActionErrorKind::Remove(tmpfiles_dest, e))
+                {
+                    errors.push(err);
+                }
+            },
+            #[cfg(target_os = "linux")]
             InitSystem::Systemd => {
                 // We separate stop and disable (instead of using `--now`) to avoid cases where the service isn't started, but is enabled.
 
                 // These have to fail fast.
                 let socket_is_active = is_active("nix-daemon.socket").await.map_err(Self::error)?;
-                let socket_is_enabled =
-                    is_enabled("nix-daemon.socket").await.map_err(Self::error)?;
+                let socket_is_enabled = is_enabled(&self.root, "nix-daemon.socket")
+                    .await
+                    .map_err(Self::error)?;
                 let service_is_active =
                     is_active("nix-daemon.service").await.map_err(Self::error)?;
-                let service_is_enabled = is_enabled("nix-daemon.service")
+                let service_is_enabled = is_enabled(&self.root, "nix-daemon.service")
                     .await
                     .map_err(Self::error)?;
 
@@ -755,6 +873,70 @@ impl Action for ConfigureInitService {
             Err(Self::error(ActionErrorKind::Multiple(errors)))
         }
     }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        let mut drift = Vec::new();
+        match self.init {
+            #[cfg(target_os = "macos")]
+            InitSystem::Launchd => {
+                if !Path::new(DARWIN_NIX_DAEMON_DEST).exists() {
+                    drift.push(format!("`{DARWIN_NIX_DAEMON_DEST}` no longer exists"))
+                }
+            },
+            #[cfg(target_os = "linux")]
+            InitSystem::Systemd => {
+                for (src, dest) in [
+                    (TMPFILES_SRC, TMPFILES_DEST),
+                    (SERVICE_SRC, SERVICE_DEST),
+                    (SOCKET_SRC, SOCKET_DEST),
+                ] {
+                    match tokio::fs::read_link(in_root(&self.root, dest)).await {
+                        Ok(target) if target == Path::new(src) => (),
+                        Ok(target) => drift.push(format!(
+                            "`{dest}` links to `{}`, expected `{src}`",
+                            target.display()
+                        )),
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                            drift.push(format!("`{dest}` no longer exists"))
+                        },
+                        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+                            drift.push(format!("`{dest}` is no longer a symlink to `{src}`"))
+                        },
+                        Err(e) => {
+                            return Err(Self::error(ActionErrorKind::GettingMetadata(
+                                dest.into(),
+                                e,
+                            )))
+                        },
+                    }
+                }
+                if !is_enabled(&self.root, "nix-daemon.socket")
+                    .await
+                    .map_err(Self::error)?
+                {
+                    drift.push("`nix-daemon.socket` is no longer enabled".to_string())
+                }
+            },
+            #[cfg(target_os = "linux")]
+            InitSystem::OpenRC => {
+                if !Path::new(OPENRC_SERVICE).exists() {
+                    drift.push(format!("`{OPENRC_SERVICE}` no longer exists"))
+                }
+            },
+            #[cfg(target_os = "linux")]
+            InitSystem::Runit => {
+                for path in [RUNIT_RUN_PATH, RUNIT_SYMLINK] {
+                    if !Path::new(path).exists() {
+                        drift.push(format!("`{path}` no longer exists"))
+                    }
+                }
+            },
+            #[cfg(not(target_os = "macos"))]
+            InitSystem::None => return Ok(Verification::Unsupported),
+        };
+        Ok(Verification::from_drift(drift))
+    }
 }
 
 #[non_exhaustive]
@@ -762,6 +944,24 @@ impl Action for ConfigureInitService {
 pub enum ConfigureNixDaemonServiceError {
     #[error("No supported init system found")]
     InitNotSupported,
+    #[error("Configuring the Nix daemon with {0} is not supported when installing into an alternate root, only systemd or no init system are")]
+    AlternateRootNotSupported(InitSystem),
+}
+
+impl From<ConfigureNixDaemonServiceError> for ActionErrorKind {
+    fn from(val: ConfigureNixDaemonServiceError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+/// A `systemctl` operating offline on `root` if it is an alternate root
+#[cfg(target_os = "linux")]
+fn systemctl(root: &Path) -> Command {
+    let mut command = Command::new("systemctl");
+    if is_alternate_root(root) {
+        command.arg(format!("--root={}", root.display()));
+    }
+    command
 }
 
 #[cfg(target_os = "linux")]
@@ -783,8 +983,8 @@ async fn stop(unit: &str) -> Result<(), ActionErrorKind> {
 }
 
 #[cfg(target_os = "linux")]
-async fn enable(unit: &str, now: bool) -> Result<(), ActionErrorKind> {
-    let mut command = Command::new("systemctl");
+async fn enable(root: &Path, unit: &str, now: bool) -> Result<(), ActionErrorKind> {
+    let mut command = systemctl(root);
     command.arg("enable");
     command.arg(unit);
     if now {
@@ -804,8 +1004,8 @@ async fn enable(unit: &str, now: bool) -> Result<(), ActionErrorKind> {
 }
 
 #[cfg(target_os = "linux")]
-async fn disable(unit: &str, now: bool) -> Result<(), ActionErrorKind> {
-    let mut command = Command::new("systemctl");
+async fn disable(root: &Path, unit: &str, now: bool) -> Result<(), ActionErrorKind> {
+    let mut command = systemctl(root);
     command.arg("disable");
     command.arg(unit);
     if now {
@@ -843,8 +1043,8 @@ async fn is_active(unit: &str) -> Result<bool, ActionErrorKind> {
 }
 
 #[cfg(target_os = "linux")]
-async fn is_enabled(unit: &str) -> Result<bool, ActionErrorKind> {
-    let mut command = Command::new("systemctl");
+async fn is_enabled(root: &Path, unit: &str) -> Result<bool, ActionErrorKind> {
+    let mut command = systemctl(root);
     command.arg("is-enabled");
     command.arg(unit);
     let output = command
@@ -1,25 +1,33 @@
-use std::path::PathBuf;
-
 use crate::{
     action::{
-        base::SetupDefaultProfile,
-        common::{ConfigureShellProfile, PlaceNixConfiguration},
+        base::{CreateOrMergeFlakeRegistry, ImportClosures, SetupDefaultProfile},
+        common::{ConfigureRemoteBuilders, ConfigureShellProfile, PlaceNixConfiguration},
         Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+        Verification,
     },
     planner::ShellProfileLocations,
-    settings::{CommonSettings, SCRATCH_DIR},
+    settings::{in_root, CommonSettings, SCRATCH_DIR},
 };
 
 use tracing::{span, Instrument, Span};
 
+/// The system flake registry
+const FLAKE_REGISTRY: &str = "/etc/nix/registry.json";
+
 /**
 Configure Nix and start it
  */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct ConfigureNix {
     setup_default_profile: StatefulAction<SetupDefaultProfile>,
+    #[serde(default)]
+    import_closures: Option<StatefulAction<ImportClosures>>,
     configure_shell_profile: Option<StatefulAction<ConfigureShellProfile>>,
     place_nix_configuration: StatefulAction<PlaceNixConfiguration>,
+    #[serde(default)]
+    configure_remote_builders: Option<StatefulAction<ConfigureRemoteBuilders>>,
+    #[serde(default)]
+    create_or_merge_flake_registry: Option<StatefulAction<CreateOrMergeFlakeRegistry>>,
 }
 
 impl ConfigureNix {
@@ -28,32 +36,70 @@ impl ConfigureNix {
         shell_profile_locations: ShellProfileLocations,
         settings: &CommonSettings,
     ) -> Result<StatefulAction<Self>, ActionError> {
-        let setup_default_profile = SetupDefaultProfile::plan(PathBuf::from(SCRATCH_DIR))
-            .await
-            .map_err(Self::error)?;
+        let setup_default_profile =
+            SetupDefaultProfile::plan(in_root(&settings.root, SCRATCH_DIR), &settings.root)
+                .await
+                .map_err(Self::error)?;
+
+        let import_closures = if settings.extra_closures.is_empty() {
+            None
+        } else {
+            Some(
+                ImportClosures::plan(
+                    settings.extra_closures.clone(),
+                    settings.extra_closure_install.clone(),
+                    &settings.root,
+                )
+                .await
+                .map_err(Self::error)?,
+            )
+        };
 
         let configure_shell_profile = if settings.modify_profile {
             Some(
-                ConfigureShellProfile::plan(shell_profile_locations)
+                ConfigureShellProfile::plan(shell_profile_locations, &settings.root)
                     .await
                     .map_err(Self::error)?,
             )
         } else {
             None
         };
-        let place_nix_configuration = PlaceNixConfiguration::plan(
-            settings.nix_build_group_name.clone(),
-            settings.proxy.clone(),
-            settings.ssl_cert_file.clone(),
-            settings.extra_conf.clone(),
-            settings.force,
-        )
-        .await
-        .map_err(Self::error)?;
+        let extra_conf = settings
+            .extra_conf_with_embedded()
+            .await
+            .map_err(|e| Self::error(ActionErrorKind::Custom(Box::new(e))))?;
+        let place_nix_configuration = PlaceNixConfiguration::plan(settings, extra_conf)
+            .await
+            .map_err(Self::error)?;
+        let configure_remote_builders = if settings.remote_builders.is_empty() {
+            None
+        } else {
+            Some(
+                ConfigureRemoteBuilders::plan(settings)
+                    .await
+                    .map_err(Self::error)?,
+            )
+        };
+        let create_or_merge_flake_registry = if settings.flake_registry_pins.is_empty() {
+            None
+        } else {
+            Some(
+                CreateOrMergeFlakeRegistry::plan(
+                    in_root(&settings.root, FLAKE_REGISTRY),
+                    settings.flake_registry_pins.clone(),
+                    settings.force,
+                )
+                .await
+                .map_err(Self::error)?,
+            )
+        };
 
         Ok(Self {
             place_nix_configuration,
+            configure_remote_builders,
+            create_or_merge_flake_registry,
             setup_default_profile,
+            import_closures,
             configure_shell_profile,
         }
         .into())
@@ -77,12 +123,24 @@ impl Action for ConfigureNix {
     fn execute_description(&self) -> Vec<ActionDescription> {
         let Self {
             setup_default_profile,
+            import_closures,
             place_nix_configuration,
+            configure_remote_builders,
+            create_or_merge_flake_registry,
             configure_shell_profile,
         } = &self;
 
         let mut buf = setup_default_profile.describe_execute();
+        if let Some(import_closures) = import_closures {
+            buf.append(&mut import_closures.describe_execute());
+        }
         buf.append(&mut place_nix_configuration.describe_execute());
+        if let Some(configure_remote_builders) = configure_remote_builders {
+            buf.append(&mut configure_remote_builders.describe_execute());
+        }
+        if let Some(create_or_merge_flake_registry) = create_or_merge_flake_registry {
+            buf.append(&mut create_or_merge_flake_registry.describe_execute());
+        }
         if let Some(configure_shell_profile) = configure_shell_profile {
             buf.append(&mut configure_shell_profile.describe_execute());
         }
@@ -93,7 +151,10 @@ impl Action for ConfigureNix {
     async fn execute(&mut self) -> Result<(), ActionError> {
         let Self {
             setup_default_profile,
+            import_closures,
             place_nix_configuration,
+            configure_remote_builders,
+            create_or_merge_flake_registry,
             configure_shell_profile,
         } = self;
 
@@ -107,16 +168,41 @@ impl Action for ConfigureNix {
                 async move {
                     setup_default_profile
                         .try_execute()
-                        .instrument(setup_default_profile_span)
+                        .instrument(setup_default_profile_span.clone())
                         .await
-                        .map_err(Self::error)
+                        .map_err(Self::error)?;
+                    // The closures are imported with the Nix in the default profile
+                    if let Some(import_closures) = import_closures {
+                        import_closures
+                            .try_execute()
+                            .instrument(setup_default_profile_span)
+                            .await
+                            .map_err(Self::error)?;
+                    }
+                    Ok(())
                 },
                 async move {
                     place_nix_configuration
                         .try_execute()
-                        .instrument(place_nix_configuration_span)
+                        .instrument(place_nix_configuration_span.clone())
                         .await
-                        .map_err(Self::error)
+                        .map_err(Self::error)?;
+                    // The machines and registry are placed in the `/etc/nix` created with the configuration
+                    if let Some(configure_remote_builders) = configure_remote_builders {
+                        configure_remote_builders
+                            .try_execute()
+                            .instrument(place_nix_configuration_span.clone())
+                            .await
+                            .map_err(Self::error)?;
+                    }
+                    if let Some(create_or_merge_flake_registry) = create_or_merge_flake_registry {
+                        create_or_merge_flake_registry
+                            .try_execute()
+                            .instrument(place_nix_configuration_span)
+                            .await
+                            .map_err(Self::error)?;
+                    }
+                    Ok(())
                 },
                 async move {
                     configure_shell_profile
@@ -133,16 +219,41 @@ impl Action for ConfigureNix {
                 async move {
                     setup_default_profile
                         .try_execute()
-                        .instrument(setup_default_profile_span)
+                        .instrument(setup_default_profile_span.clone())
                         .await
-                        .map_err(Self::error)
+                        .map_err(Self::error)?;
+                    // The closures are imported with the Nix in the default profile
+                    if let Some(import_closures) = import_closures {
+                        import_closures
+                            .try_execute()
+                            .instrument(setup_default_profile_span)
+                            .await
+                            .map_err(Self::error)?;
+                    }
+                    Ok(())
                 },
                 async move {
                     place_nix_configuration
                         .try_execute()
-                        .instrument(place_nix_configuration_span)
+                        .instrument(place_nix_configuration_span.clone())
                         .await
-                        .map_err(Self::error)
+                        .map_err(Self::error)?;
+                    // The machines and registry are placed in the `/etc/nix` created with the configuration
+                    if let Some(configure_remote_builders) = configure_remote_builders {
+                        configure_remote_builders
+                            .try_execute()
+                            .instrument(place_nix_configuration_span.clone())
+                            .await
+                            .map_err(Self::error)?;
+                    }
+                    if let Some(create_or_merge_flake_registry) = create_or_merge_flake_registry {
+                        create_or_merge_flake_registry
+                            .try_execute()
+                            .instrument(place_nix_configuration_span)
+                            .await
+                            .map_err(Self::error)?;
+                    }
+                    Ok(())
                 },
             )?;
         };
@@ -153,7 +264,10 @@ impl Action for ConfigureNix {
     fn revert_description(&self) -> Vec<ActionDescription> {
         let Self {
             setup_default_profile,
+            import_closures,
             place_nix_configuration,
+            configure_remote_builders,
+            create_or_merge_flake_registry,
             configure_shell_profile,
         } = &self;
 
@@ -161,7 +275,16 @@ impl Action for ConfigureNix {
         if let Some(configure_shell_profile) = configure_shell_profile {
             buf.append(&mut configure_shell_profile.describe_revert());
         }
+        if let Some(create_or_merge_flake_registry) = create_or_merge_flake_registry {
+            buf.append(&mut create_or_merge_flake_registry.describe_revert());
+        }
+        if let Some(configure_remote_builders) = configure_remote_builders {
+            buf.append(&mut configure_remote_builders.describe_revert());
+        }
         buf.append(&mut place_nix_configuration.describe_revert());
+        if let Some(import_closures) = import_closures {
+            buf.append(&mut import_closures.describe_revert());
+        }
         buf.append(&mut setup_default_profile.describe_revert());
 
         buf
@@ -175,9 +298,24 @@ impl Action for ConfigureNix {
                 errors.push(err);
             }
         }
+        if let Some(create_or_merge_flake_registry) = &mut self.create_or_merge_flake_registry {
+            if let Err(err) = create_or_merge_flake_registry.try_revert().await {
+                errors.push(err);
+            }
+        }
+        if let Some(configure_remote_builders) = &mut self.configure_remote_builders {
+            if let Err(err) = configure_remote_builders.try_revert().await {
+                errors.push(err);
+            }
+        }
         if let Err(err) = self.place_nix_configuration.try_revert().await {
             errors.push(err);
         }
+        if let Some(import_closures) = &mut self.import_closures {
+            if let Err(err) = import_closures.try_revert().await {
+                errors.push(err);
+            }
+        }
         if let Err(err) = self.setup_default_profile.try_revert().await {
             errors.push(err);
         }
@@ -193,4 +331,23 @@ impl Action for ConfigureNix {
             Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
         }
     }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        let mut children = vec![self.setup_default_profile.try_verify().await];
+        if let Some(import_closures) = &self.import_closures {
+            children.push(import_closures.try_verify().await);
+        }
+        if let Some(configure_shell_profile) = &self.configure_shell_profile {
+            children.push(configure_shell_profile.try_verify().await);
+        }
+        children.push(self.place_nix_configuration.try_verify().await);
+        if let Some(configure_remote_builders) = &self.configure_remote_builders {
+            children.push(configure_remote_builders.try_verify().await);
+        }
+        if let Some(create_or_merge_flake_registry) = &self.create_or_merge_flake_registry {
+            children.push(create_or_merge_flake_registry.try_verify().await);
+        }
+        Ok(Verification::Children { children })
+    }
 }
@@ -0,0 +1,235 @@
+use tracing::{span, Span};
+
+use crate::action::base::create_or_insert_into_file::Position;
+use crate::action::base::{CreateFile, CreateOrInsertIntoFile, CreateSecretFile};
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    Verification,
+};
+use crate::settings::{in_root, CommonSettings, RemoteBuilder};
+use std::path::Path;
+
+/// The machines Nix distributes builds to, set as `builders = @/etc/nix/machines`
+pub(crate) const NIX_MACHINES: &str = "/etc/nix/machines";
+const BUILDER_SSH_KEY: &str = "/etc/nix/builder_key";
+const SSH_KNOWN_HOSTS: &str = "/etc/ssh/ssh_known_hosts";
+
+/**
+Configure the remote builders Nix distributes builds to
+
+The builders are written to `/etc/nix/machines`, which [`PlaceNixConfiguration`](super::PlaceNixConfiguration)
+points `builders` at. The SSH key `root` connects with is placed with [`CreateSecretFile`], and the host
+keys of the builders are added to `/etc/ssh/ssh_known_hosts`.
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ConfigureRemoteBuilders {
+    remote_builders: Vec<RemoteBuilder>,
+    create_machines: StatefulAction<CreateFile>,
+    create_ssh_key: Option<StatefulAction<CreateSecretFile>>,
+    insert_known_hosts: Option<StatefulAction<CreateOrInsertIntoFile>>,
+}
+
+impl ConfigureRemoteBuilders {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(settings: &CommonSettings) -> Result<StatefulAction<Self>, ActionError> {
+        let CommonSettings {
+            remote_builders,
+            remote_builder_ssh_key,
+            remote_builder_known_hosts,
+            root,
+            force,
+            ..
+        } = settings;
+
+        let ssh_key = remote_builder_ssh_key
+            .as_ref()
+            .map(|_| Path::new(BUILDER_SSH_KEY));
+        let machines = remote_builders
+            .iter()
+            .map(|remote_builder| remote_builder.machines_entry(ssh_key) + "\n")
+            .collect::<String>();
+        let create_machines = CreateFile::plan(
+            in_root(root, NIX_MACHINES),
+            None,
+            None,
+            0o644,
+            machines,
+            *force,
+        )
+        .await
+        .map_err(Self::error)?;
+
+        let create_ssh_key = match remote_builder_ssh_key {
+            Some(remote_builder_ssh_key) => Some(
+                CreateSecretFile::plan(
+                    in_root(root, BUILDER_SSH_KEY),
+                    remote_builder_ssh_key,
+                    *force,
+                )
+                .await
+                .map_err(Self::error)?,
+            ),
+            None => None,
+        };
+
+        let insert_known_hosts = if remote_builder_known_hosts.is_empty() {
+            None
+        } else {
+            let known_hosts = remote_builder_known_hosts
+                .iter()
+                .map(|known_host| format!("{known_host}\n"))
+                .collect::<String>();
+            Some(
+                CreateOrInsertIntoFile::plan(
+                    in_root(root, SSH_KNOWN_HOSTS),
+                    None,
+                    None,
+                    0o644,
+                    known_hosts,
+                    Position::End,
+                )
+                .await
+                .map_err(Self::error)?,
+            )
+        };
+
+        Ok(Self {
+            remote_builders: remote_builders.clone(),
+            create_machines,
+            create_ssh_key,
+            insert_known_hosts,
+        }
+        .into())
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "configure_remote_builders")]
+impl Action for ConfigureRemoteBuilders {
+    fn action_tag() -> ActionTag {
+        ActionTag("configure_remote_builders")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!("Configure the remote builders in `{NIX_MACHINES}`")
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "configure_remote_builders",
+            remote_builders = tracing::field::display(
+                self.remote_builders
+                    .iter()
+                    .map(|remote_builder| remote_builder.uri().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let Self {
+            remote_builders,
+            create_machines: _,
+            create_ssh_key,
+            insert_known_hosts,
+        } = self;
+
+        let mut explanation = remote_builders
+            .iter()
+            .map(|remote_builder| format!("Build on `{remote_builder}`"))
+            .collect::<Vec<_>>();
+        if let Some(create_ssh_key) = create_ssh_key {
+            explanation.push(create_ssh_key.tracing_synopsis());
+        }
+        if let Some(insert_known_hosts) = insert_known_hosts {
+            explanation.push(insert_known_hosts.tracing_synopsis());
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        let Self {
+            remote_builders: _,
+            create_machines,
+            create_ssh_key,
+            insert_known_hosts,
+        } = self;
+
+        if let Some(create_ssh_key) = create_ssh_key {
+            create_ssh_key.try_execute().await.map_err(Self::error)?;
+        }
+        if let Some(insert_known_hosts) = insert_known_hosts {
+            insert_known_hosts
+                .try_execute()
+                .await
+                .map_err(Self::error)?;
+        }
+        create_machines.try_execute().await.map_err(Self::error)?;
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![];
+        for val in self.create_machines.describe_revert().iter() {
+            explanation.push(val.description.clone())
+        }
+        if let Some(create_ssh_key) = &self.create_ssh_key {
+            for val in create_ssh_key.describe_revert().iter() {
+                explanation.push(val.description.clone())
+            }
+        }
+        if let Some(insert_known_hosts) = &self.insert_known_hosts {
+            for val in insert_known_hosts.describe_revert().iter() {
+                explanation.push(val.description.clone())
+            }
+        }
+        vec![ActionDescription::new(
+            format!("Remove the remote builders in `{NIX_MACHINES}`"),
+            explanation,
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+        if let Err(err) = self.create_machines.try_revert().await {
+            errors.push(err);
+        }
+        if let Some(insert_known_hosts) = &mut self.insert_known_hosts {
+            if let Err(err) = insert_known_hosts.try_revert().await {
+                errors.push(err);
+            }
+        }
+        if let Some(create_ssh_key) = &mut self.create_ssh_key {
+            if let Err(err) = create_ssh_key.try_revert().await {
+                errors.push(err);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors
+                .into_iter()
+                .next()
+                .expect("Expected 1 len Vec to have at least 1 item"))
+        } else {
+            Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        let mut children = vec![self.create_machines.try_verify().await];
+        if let Some(create_ssh_key) = &self.create_ssh_key {
+            children.push(create_ssh_key.try_verify().await);
+        }
+        if let Some(insert_known_hosts) = &self.insert_known_hosts {
+            children.push(insert_known_hosts.try_verify().await);
+        }
+        Ok(Verification::Children { children })
+    }
+}
@@ -1,11 +1,13 @@
 use crate::action::base::{create_or_insert_into_file, CreateDirectory, CreateOrInsertIntoFile};
 use crate::action::{
     Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    Verification,
 };
 use crate::planner::ShellProfileLocations;
+use crate::settings::{in_root, is_alternate_root};
 
 use nix::unistd::User;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use tokio::task::JoinSet;
 use tracing::{span, Instrument, Span};
 
@@ -26,7 +28,9 @@ impl ConfigureShellProfile {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(
         locations: ShellProfileLocations,
+        root: impl AsRef<Path>,
     ) -> Result<StatefulAction<Self>, ActionError> {
+        let root = root.as_ref();
         let mut create_or_insert_files = Vec::default();
         let mut create_directories = Vec::default();
 
@@ -42,7 +46,7 @@ impl ConfigureShellProfile {
         );
 
         for profile_target in locations.bash.iter().chain(locations.zsh.iter()) {
-            let profile_target_path = Path::new(profile_target);
+            let profile_target_path = in_root(root, profile_target);
             if let Some(parent) = profile_target_path.parent() {
                 if !parent.exists() {
                     create_directories.push(
@@ -53,7 +57,7 @@ impl ConfigureShellProfile {
                 }
                 create_or_insert_files.push(
                     CreateOrInsertIntoFile::plan(
-                        profile_target_path,
+                        &profile_target_path,
                         None,
                         None,
                         0o644,
@@ -78,7 +82,7 @@ impl ConfigureShellProfile {
         );
 
         for fish_prefix in &locations.fish.confd_prefixes {
-            let fish_prefix_path = PathBuf::from(fish_prefix);
+            let fish_prefix_path = in_root(root, fish_prefix);
 
             if !fish_prefix_path.exists() {
                 // If the prefix doesn't exist, don't create the `conf.d/nix.fish`
@@ -107,7 +111,7 @@ impl ConfigureShellProfile {
             );
         }
         for fish_prefix in &locations.fish.vendor_confd_prefixes {
-            let fish_prefix_path = PathBuf::from(fish_prefix);
+            let fish_prefix_path = in_root(root, fish_prefix);
 
             if !fish_prefix_path.exists() {
                 // If the prefix doesn't exist, don't create the `conf.d/nix.fish`
@@ -137,8 +141,9 @@ impl ConfigureShellProfile {
         }
 
         // If the `$GITHUB_PATH` environment exists, we're almost certainly running on Github
-        // Actions, and almost certainly wants the relevant `$PATH` additions added.
-        if let Ok(github_path) = std::env::var("GITHUB_PATH") {
+        // Actions, and almost certainly wants the relevant `$PATH` additions added. That does not apply to
+        // an alternate root, which the runner will not be using.
+        if let (false, Ok(github_path)) = (is_alternate_root(root), std::env::var("GITHUB_PATH")) {
             let mut buf = "/nix/var/nix/profiles/default/bin\n".to_string();
             // Actions runners operate as `runner` user by default
             if let Ok(Some(runner)) = User::from_name("runner") {
@@ -290,4 +295,16 @@ impl Action for ConfigureShellProfile {
             Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
         }
     }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        let mut children = Vec::new();
+        for create_directory in self.create_directories.iter() {
+            children.push(create_directory.try_verify().await);
+        }
+        for create_or_insert_into_file in self.create_or_insert_into_files.iter() {
+            children.push(create_or_insert_into_file.try_verify().await);
+        }
+        Ok(Verification::Children { children })
+    }
 }
@@ -1,9 +1,13 @@
+use std::path::Path;
+
 use tracing::{span, Span};
 
 use crate::action::base::CreateDirectory;
 use crate::action::{
     Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    Verification,
 };
+use crate::settings::in_root;
 
 const PATHS: &[&str] = &[
     "/nix/var",
@@ -31,14 +35,20 @@ pub struct CreateNixTree {
 
 impl CreateNixTree {
     #[tracing::instrument(level = "debug", skip_all)]
-    pub async fn plan() -> Result<StatefulAction<Self>, ActionError> {
+    pub async fn plan(root: impl AsRef<Path>) -> Result<StatefulAction<Self>, ActionError> {
         let mut create_directories = Vec::default();
         for path in PATHS {
             // We use `create_dir` over `create_dir_all` to ensure we always set permissions right
             create_directories.push(
-                CreateDirectory::plan(path, String::from("root"), None, 0o0755, true)
-                    .await
-                    .map_err(Self::error)?,
+                CreateDirectory::plan(
+                    in_root(root.as_ref(), path),
+                    String::from("root"),
+                    None,
+                    0o0755,
+                    true,
+                )
+                .await
+                .map_err(Self::error)?,
             )
         }
 
@@ -126,4 +136,13 @@ impl Action for CreateNixTree {
             Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
         }
     }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        let mut children = Vec::with_capacity(self.create_directories.len());
+        for create_directory in self.create_directories.iter() {
+            children.push(create_directory.try_verify().await);
+        }
+        Ok(Verification::Children { children })
+    }
 }
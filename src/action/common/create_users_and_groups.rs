@@ -2,6 +2,7 @@ use crate::{
     action::{
         base::{AddUserToGroup, CreateGroup, CreateUser},
         Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+        Verification,
     },
     settings::CommonSettings,
 };
@@ -25,6 +26,7 @@ impl CreateUsersAndGroups {
         let create_group = CreateGroup::plan(
             settings.nix_build_group_name.clone(),
             settings.nix_build_group_id,
+            &settings.root,
         )?;
         let mut create_users = Vec::with_capacity(settings.nix_build_user_count as usize);
         let mut add_users_to_groups = Vec::with_capacity(settings.nix_build_user_count as usize);
@@ -36,6 +38,7 @@ impl CreateUsersAndGroups {
                     settings.nix_build_group_name.clone(),
                     settings.nix_build_group_id,
                     format!("Nix build user {index}"),
+                    &settings.root,
                 )
                 .await
                 .map_err(Self::error)?,
@@ -46,6 +49,7 @@ impl CreateUsersAndGroups {
                     settings.nix_build_user_id_base + index,
                     settings.nix_build_group_name.clone(),
                     settings.nix_build_group_id,
+                    &settings.root,
                 )
                 .await
                 .map_err(Self::error)?,
@@ -66,10 +70,10 @@ impl CreateUsersAndGroups {
 }
 
 #[async_trait::async_trait]
-#[typetag::serde(name = "create_users_and_group")]
+#[typetag::serde(name = "create_users_and_groups")]
 impl Action for CreateUsersAndGroups {
     fn action_tag() -> ActionTag {
-        ActionTag("create_users_and_group")
+        ActionTag("create_users_and_groups")
     }
     fn tracing_synopsis(&self) -> String {
         if self.create_users.is_empty() {
@@ -87,7 +91,7 @@ impl Action for CreateUsersAndGroups {
     fn tracing_span(&self) -> Span {
         span!(
             tracing::Level::DEBUG,
-            "create_users_and_group",
+            "create_users_and_groups",
             nix_build_user_count = self.nix_build_user_count,
             nix_build_group_name = self.nix_build_group_name,
             nix_build_group_id = self.nix_build_group_id,
@@ -286,4 +290,16 @@ impl Action for CreateUsersAndGroups {
             Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
         }
     }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        let mut children = vec![self.create_group.try_verify().await];
+        for create_user in self.create_users.iter() {
+            children.push(create_user.try_verify().await);
+        }
+        for add_user_to_group in self.add_users_to_groups.iter() {
+            children.push(add_user_to_group.try_verify().await);
+        }
+        Ok(Verification::Children { children })
+    }
 }
@@ -2,6 +2,7 @@
 
 pub(crate) mod configure_init_service;
 pub(crate) mod configure_nix;
+pub(crate) mod configure_remote_builders;
 pub(crate) mod configure_shell_profile;
 pub(crate) mod create_nix_tree;
 pub(crate) mod create_users_and_groups;
@@ -11,6 +12,7 @@ pub(crate) mod provision_nix;
 
 pub use configure_init_service::{ConfigureInitService, ConfigureNixDaemonServiceError};
 pub use configure_nix::ConfigureNix;
+pub use configure_remote_builders::ConfigureRemoteBuilders;
 pub use configure_shell_profile::ConfigureShellProfile;
 pub use create_nix_tree::CreateNixTree;
 pub use create_users_and_groups::CreateUsersAndGroups;
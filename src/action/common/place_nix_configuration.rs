@@ -1,73 +1,107 @@
 use tracing::{span, Span};
-use url::Url;
 
-use crate::action::base::create_or_merge_nix_config::CreateOrMergeNixConfigError;
-use crate::action::base::{CreateDirectory, CreateOrMergeNixConfig};
+use crate::action::base::create_or_insert_into_file::Position;
+use crate::action::base::create_or_merge_nix_config::{CreateOrMergeNixConfigError, NIX_CONF_MODE};
+use crate::action::base::{
+    CreateDirectory, CreateFile, CreateOrInsertIntoFile, CreateOrMergeNixConfig, CreateSecretFile,
+};
+use crate::action::common::configure_remote_builders::NIX_MACHINES;
 use crate::action::{
     Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    Verification,
+};
+use crate::nix_settings::{self, InvalidNixSettings, NixConfValidation};
+use crate::settings::{
+    in_root, CommonSettings, NixConfDefault, NixConfMode, Substituter, TrustedPublicKey,
+    UrlOrPathOrString,
 };
-use crate::parse_ssl_cert;
-use crate::settings::UrlOrPathOrString;
 use indexmap::map::Entry;
 use std::path::PathBuf;
 
 const NIX_CONF_FOLDER: &str = "/etc/nix";
 const NIX_CONF: &str = "/etc/nix/nix.conf";
+/// The administrator's own settings, included by an installer owned `nix.conf`, relative to it
+const NIX_CUSTOM_CONF: &str = "nix.custom.conf";
+const NIX_NETRC: &str = "/etc/nix/netrc";
+/// Secret settings, included by `nix.conf`, relative to it
+const NIX_SECRET_CONF: &str = "secrets.conf";
 
 /**
 Place the `/etc/nix.conf` file
+
+With [`NixConfMode::Merge`] the settings are merged into any existing `nix.conf`, with
+[`NixConfMode::Own`] `nix.conf` is written outright and ends by including `nix.custom.conf`. When
+forced to own an existing `nix.conf`, it is moved to `nix.custom.conf` so its settings are kept, and
+moved back on revert.
+
+Secrets (a netrc file, and secret settings included from `nix.conf`) are placed in files only `root`
+can read with [`CreateSecretFile`], so they never appear in the world readable `nix.conf`.
  */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct PlaceNixConfiguration {
     create_directory: StatefulAction<CreateDirectory>,
-    create_or_merge_nix_config: StatefulAction<CreateOrMergeNixConfig>,
+    #[serde(default)]
+    create_or_merge_nix_config: Option<StatefulAction<CreateOrMergeNixConfig>>,
+    #[serde(default)]
+    create_nix_config: Option<StatefulAction<CreateFile>>,
+    /// Whether an existing `nix.conf` is moved to `nix.custom.conf` before `create_nix_config`
+    #[serde(default)]
+    move_nix_conf_to_custom: bool,
+    #[serde(default)]
+    create_secret_files: Vec<StatefulAction<CreateSecretFile>>,
+    #[serde(default)]
+    include_secret_conf: Option<StatefulAction<CreateOrInsertIntoFile>>,
+    /// The experimental features enabled, `None` in receipts from before they could be chosen
+    #[serde(default)]
+    experimental_features: Option<Vec<String>>,
+    /// The opinionated settings which were set, `None` in receipts from before they could be skipped
+    #[serde(default)]
+    defaults: Option<Vec<NixConfDefault>>,
+    #[serde(default)]
+    substituters: Vec<Substituter>,
+    #[serde(default)]
+    trusted_public_keys: Vec<TrustedPublicKey>,
 }
 
 impl PlaceNixConfiguration {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(
-        nix_build_group_name: String,
-        proxy: Option<Url>,
-        ssl_cert_file: Option<PathBuf>,
+        settings: &CommonSettings,
         extra_conf: Vec<UrlOrPathOrString>,
-        force: bool,
     ) -> Result<StatefulAction<Self>, ActionError> {
+        let CommonSettings {
+            nix_build_group_name,
+            proxy,
+            ssl_cert_file,
+            force,
+            root,
+            network,
+            nix_conf_mode,
+            substituters,
+            trusted_public_keys,
+            nix_netrc_file,
+            nix_secret_conf,
+            experimental_features,
+            skip_nix_conf_defaults,
+            nix_conf_validation,
+            remote_builders,
+            auto_allocate_uids,
+            auto_allocate_uids_start,
+            auto_allocate_uids_count,
+            ..
+        } = settings;
+        let merge_policy = settings.nix_conf_merge_policy();
         let mut extra_conf_text = vec![];
         for extra in extra_conf {
             let buf = match &extra {
                 UrlOrPathOrString::Url(url) => match url.scheme() {
                     "https" | "http" => {
-                        let mut buildable_client = reqwest::Client::builder();
-                        if let Some(proxy) = &proxy {
-                            buildable_client = buildable_client.proxy(
-                                reqwest::Proxy::all(proxy.clone())
-                                    .map_err(ActionErrorKind::Reqwest)
-                                    .map_err(Self::error)?,
-                            )
-                        }
-                        if let Some(ssl_cert_file) = &ssl_cert_file {
-                            let ssl_cert =
-                                parse_ssl_cert(ssl_cert_file).await.map_err(Self::error)?;
-                            buildable_client = buildable_client.add_root_certificate(ssl_cert);
-                        }
-                        let client = buildable_client
-                            .build()
-                            .map_err(ActionErrorKind::Reqwest)
-                            .map_err(Self::error)?;
-                        let req = client
-                            .get(url.clone())
-                            .build()
-                            .map_err(ActionErrorKind::Reqwest)
-                            .map_err(Self::error)?;
-                        let res = client
-                            .execute(req)
+                        let client = network
+                            .client(proxy.as_ref(), ssl_cert_file.as_deref())
                             .await
-                            .map_err(ActionErrorKind::Reqwest)
                             .map_err(Self::error)?;
-                        res.text()
-                            .await
-                            .map_err(ActionErrorKind::Reqwest)
-                            .map_err(Self::error)?
+                        let buf = network.fetch(&client, url).await.map_err(Self::error)?;
+                        String::from_utf8_lossy(&buf).into_owned()
                     },
                     "file" => tokio::fs::read_to_string(url.path())
                         .await
@@ -88,57 +122,190 @@ impl PlaceNixConfiguration {
         let mut nix_config = nix_config_parser::NixConfig::parse_string(extra_conf, None)
             .map_err(CreateOrMergeNixConfigError::ParseNixConfig)
             .map_err(Self::error)?;
-        let settings = nix_config.settings_mut();
-
-        settings.insert("build-users-group".to_string(), nix_build_group_name);
-        let experimental_features = ["nix-command", "flakes", "repl-flake"];
-        match settings.entry("experimental-features".to_string()) {
-            Entry::Occupied(mut slot) => {
-                let slot_mut = slot.get_mut();
-                for experimental_feature in experimental_features {
-                    if !slot_mut.contains(experimental_feature) {
-                        *slot_mut += " ";
-                        *slot_mut += experimental_feature;
+        if *nix_conf_validation != NixConfValidation::Off {
+            let version = settings
+                .nix_package_version()
+                .await
+                .map_err(|e| Self::error(ActionErrorKind::Custom(Box::new(e))))?;
+            let problems = nix_settings::validate(nix_config.settings(), version.as_ref());
+            match nix_conf_validation {
+                NixConfValidation::Deny if !problems.is_empty() => {
+                    return Err(Self::error(ActionErrorKind::Custom(Box::new(
+                        InvalidNixSettings(problems),
+                    ))));
+                },
+                _ => {
+                    for problem in problems {
+                        tracing::warn!("{problem}");
                     }
-                }
-            },
-            Entry::Vacant(slot) => {
-                let _ = slot.insert(experimental_features.join(" "));
-            },
-        };
-
-        // https://github.com/DeterminateSystems/nix-installer/issues/449#issuecomment-1551782281
-        #[cfg(not(target_os = "macos"))]
-        settings.insert("auto-optimise-store".to_string(), "true".to_string());
+                },
+            }
+        }
+        let settings = nix_config.settings_mut();
 
         settings.insert(
-            "bash-prompt-prefix".to_string(),
-            "(nix:$name)\\040".to_string(),
+            "build-users-group".to_string(),
+            nix_build_group_name.clone(),
         );
-        settings.insert("max-jobs".to_string(), "auto".to_string());
+        let mut experimental_features = experimental_features
+            .iter()
+            .flat_map(|features| features.split_whitespace())
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        if *auto_allocate_uids {
+            if !experimental_features
+                .iter()
+                .any(|f| f == "auto-allocate-uids")
+            {
+                experimental_features.push("auto-allocate-uids".to_string());
+            }
+            settings.insert("auto-allocate-uids".to_string(), "true".to_string());
+            settings.insert("start-id".to_string(), auto_allocate_uids_start.to_string());
+            settings.insert("id-count".to_string(), auto_allocate_uids_count.to_string());
+        }
+        append_values(
+            settings,
+            "experimental-features",
+            experimental_features.iter().cloned(),
+        );
+
+        let mut defaults = vec![];
+        for default in NixConfDefault::ALL {
+            // https://github.com/DeterminateSystems/nix-installer/issues/449#issuecomment-1551782281
+            if cfg!(target_os = "macos") && *default == NixConfDefault::AutoOptimiseStore {
+                continue;
+            }
+            if skip_nix_conf_defaults.contains(default) {
+                continue;
+            }
+            settings.insert(default.name().to_string(), default.value().to_string());
+            defaults.push(*default);
+        }
         if let Some(ssl_cert_file) = ssl_cert_file {
-            let ssl_cert_file_canonical = ssl_cert_file
-                .canonicalize()
-                .map_err(|e| Self::error(ActionErrorKind::Canonicalize(ssl_cert_file, e)))?;
+            let ssl_cert_file_canonical = ssl_cert_file.canonicalize().map_err(|e| {
+                Self::error(ActionErrorKind::Canonicalize(ssl_cert_file.clone(), e))
+            })?;
             settings.insert(
                 "ssl-cert-file".to_string(),
                 ssl_cert_file_canonical.display().to_string(),
             );
         }
-        settings.insert(
-            "extra-nix-path".to_string(),
-            "nixpkgs=flake:nixpkgs".to_string(),
+        if !substituters.is_empty() && trusted_public_keys.is_empty() {
+            tracing::warn!(
+                "No trusted public keys were given for the substituters, store paths from them will only be used if they are signed by an already trusted key"
+            );
+        }
+        append_values(
+            settings,
+            "extra-substituters",
+            substituters.iter().map(ToString::to_string),
+        );
+        append_values(
+            settings,
+            "extra-trusted-public-keys",
+            trusted_public_keys.iter().map(ToString::to_string),
         );
 
-        let create_directory = CreateDirectory::plan(NIX_CONF_FOLDER, None, None, 0o0755, force)
-            .await
-            .map_err(Self::error)?;
-        let create_or_merge_nix_config = CreateOrMergeNixConfig::plan(NIX_CONF, nix_config)
-            .await
-            .map_err(Self::error)?;
+        if !remote_builders.is_empty() {
+            settings.insert("builders".to_string(), format!("@{NIX_MACHINES}"));
+        }
+
+        let mut create_secret_files = vec![];
+        if let Some(nix_netrc_file) = nix_netrc_file {
+            create_secret_files.push(
+                CreateSecretFile::plan(in_root(root, NIX_NETRC), nix_netrc_file, *force)
+                    .await
+                    .map_err(Self::error)?,
+            );
+            settings.insert("netrc-file".to_string(), NIX_NETRC.to_string());
+        }
+        if let Some(nix_secret_conf) = nix_secret_conf {
+            create_secret_files.push(
+                CreateSecretFile::plan(
+                    in_root(root, NIX_CONF_FOLDER).join(NIX_SECRET_CONF),
+                    nix_secret_conf,
+                    *force,
+                )
+                .await
+                .map_err(Self::error)?,
+            );
+        }
+
+        let mut move_nix_conf_to_custom = false;
+        let (create_or_merge_nix_config, create_nix_config) = match nix_conf_mode {
+            NixConfMode::Merge => {
+                let create_or_merge_nix_config =
+                    CreateOrMergeNixConfig::plan(in_root(root, NIX_CONF), nix_config, merge_policy)
+                        .await
+                        .map_err(Self::error)?;
+                (Some(create_or_merge_nix_config), None)
+            },
+            NixConfMode::Own => {
+                let nix_conf = in_root(root, NIX_CONF);
+                let buf = owned_nix_conf(&nix_config, nix_secret_conf.is_some());
+                let existing = match tokio::fs::read_to_string(&nix_conf).await {
+                    Ok(existing) => Some(existing),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                    Err(e) => return Err(Self::error(ActionErrorKind::Read(nix_conf, e))),
+                };
+                let create_nix_config = match existing {
+                    // The existing settings are kept in `nix.custom.conf`, rather than lost
+                    Some(existing) if *force && existing != buf => {
+                        let nix_custom_conf = nix_conf.with_file_name(NIX_CUSTOM_CONF);
+                        if nix_custom_conf.exists() {
+                            return Err(Self::error(ActionErrorKind::FileExists(nix_custom_conf)));
+                        }
+                        move_nix_conf_to_custom = true;
+                        CreateFile::plan_replacing(nix_conf, None, None, NIX_CONF_MODE, buf, *force)
+                    },
+                    _ => CreateFile::plan(nix_conf, None, None, NIX_CONF_MODE, buf, *force)
+                        .await
+                        .map_err(Self::error)?,
+                };
+                (None, Some(create_nix_config))
+            },
+        };
+        // What was there before is restored on revert, so the directory holding it must be kept
+        let restores_existing = move_nix_conf_to_custom
+            || create_secret_files
+                .iter()
+                .any(|create_secret_file| create_secret_file.action.existed);
+        let create_directory = CreateDirectory::plan(
+            in_root(root, NIX_CONF_FOLDER),
+            None,
+            None,
+            0o0755,
+            *force && !restores_existing,
+        )
+        .await
+        .map_err(Self::error)?;
+        // An owned `nix.conf` includes the secret settings itself
+        let include_secret_conf = match (nix_secret_conf, nix_conf_mode) {
+            (Some(_), NixConfMode::Merge) => Some(
+                CreateOrInsertIntoFile::plan(
+                    in_root(root, NIX_CONF),
+                    None,
+                    None,
+                    NIX_CONF_MODE,
+                    format!("\n!include {NIX_SECRET_CONF}\n"),
+                    Position::End,
+                )
+                .await
+                .map_err(Self::error)?,
+            ),
+            _ => None,
+        };
         Ok(Self {
             create_directory,
             create_or_merge_nix_config,
+            create_nix_config,
+            move_nix_conf_to_custom,
+            create_secret_files,
+            include_secret_conf,
+            experimental_features: Some(experimental_features),
+            defaults: Some(defaults),
+            substituters: substituters.clone(),
+            trusted_public_keys: trusted_public_keys.clone(),
         }
         .into())
     }
@@ -161,7 +328,15 @@ impl Action for PlaceNixConfiguration {
     fn execute_description(&self) -> Vec<ActionDescription> {
         let Self {
             create_or_merge_nix_config,
+            create_nix_config,
+            move_nix_conf_to_custom,
             create_directory,
+            create_secret_files,
+            include_secret_conf,
+            experimental_features,
+            defaults,
+            substituters,
+            trusted_public_keys,
         } = self;
 
         let mut explanation = vec![
@@ -169,14 +344,95 @@ impl Action for PlaceNixConfiguration {
                 .to_string(),
         ];
 
+        match experimental_features.as_deref() {
+            Some([]) => explanation.push("Enable no experimental features".to_string()),
+            Some(experimental_features) => explanation.push(format!(
+                "Enable the experimental features {}",
+                experimental_features
+                    .iter()
+                    .map(|feature| format!("`{feature}`"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+            None => (),
+        }
+        match defaults.as_deref() {
+            Some([]) => explanation.push("Set none of the opinionated defaults".to_string()),
+            Some(defaults) => explanation.push(format!(
+                "Set the opinionated defaults {} (each can be skipped with `--skip-nix-conf-default`)",
+                defaults
+                    .iter()
+                    .map(|default| format!("`{} = {}`", default.name(), default.value()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+            None => (),
+        }
         if let Some(val) = create_directory.describe_execute().first() {
             explanation.push(val.description.clone())
         }
-        for val in create_or_merge_nix_config.describe_execute().iter() {
-            explanation.push(val.description.clone())
+        for create_secret_file in create_secret_files {
+            for val in create_secret_file.describe_execute().iter() {
+                explanation.push(val.description.clone())
+            }
+        }
+        if let Some(create_or_merge_nix_config) = create_or_merge_nix_config {
+            for val in create_or_merge_nix_config.describe_execute().iter() {
+                explanation.push(val.description.clone())
+            }
+        }
+        if *move_nix_conf_to_custom {
+            explanation.push(format!(
+                "Move the existing `{NIX_CONF}` to `/etc/nix/{NIX_CUSTOM_CONF}`, so its settings are kept"
+            ));
+        }
+        if let Some(create_nix_config) = create_nix_config {
+            for val in create_nix_config.describe_execute().iter() {
+                explanation.push(val.description.clone())
+            }
+            explanation.push(format!(
+                "Settings of your own can be placed in `/etc/nix/{NIX_CUSTOM_CONF}`, which is included and never changed"
+            ));
+        }
+        if include_secret_conf.is_some() {
+            explanation.push(format!(
+                "Include `/etc/nix/{NIX_SECRET_CONF}` from `{NIX_CONF}`"
+            ));
         }
 
-        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+        let mut descriptions = vec![ActionDescription::new(self.tracing_synopsis(), explanation)];
+        if !substituters.is_empty() || !trusted_public_keys.is_empty() {
+            let mut explanation = substituters
+                .iter()
+                .map(|substituter| format!("Substitute from `{substituter}`"))
+                .collect::<Vec<_>>();
+            explanation.extend(
+                trusted_public_keys
+                    .iter()
+                    .map(|key| format!("Trust signatures by `{}` (`{key}`)", key.name())),
+            );
+            let description = if substituters.is_empty() {
+                format!(
+                    "Trust the public keys {}",
+                    trusted_public_keys
+                        .iter()
+                        .map(|key| format!("`{}`", key.name()))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            } else {
+                format!(
+                    "Use the binary caches {}",
+                    substituters
+                        .iter()
+                        .map(|substituter| format!("`{substituter}`"))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            };
+            descriptions.push(ActionDescription::new(description, explanation));
+        }
+        descriptions
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
@@ -185,29 +441,94 @@ impl Action for PlaceNixConfiguration {
             .try_execute()
             .await
             .map_err(Self::error)?;
-        self.create_or_merge_nix_config
-            .try_execute()
-            .await
-            .map_err(Self::error)?;
+        for create_secret_file in &mut self.create_secret_files {
+            create_secret_file
+                .try_execute()
+                .await
+                .map_err(Self::error)?;
+        }
+        if let Some(create_or_merge_nix_config) = &mut self.create_or_merge_nix_config {
+            create_or_merge_nix_config
+                .try_execute()
+                .await
+                .map_err(Self::error)?;
+        }
+        if let Some((nix_conf, nix_custom_conf)) = self.moved_nix_conf() {
+            // It was already moved if an earlier attempt was interrupted
+            if !nix_custom_conf.exists() {
+                tokio::fs::rename(&nix_conf, &nix_custom_conf)
+                    .await
+                    .map_err(|e| ActionErrorKind::Rename(nix_conf, nix_custom_conf, e))
+                    .map_err(Self::error)?;
+            }
+        }
+        if let Some(create_nix_config) = &mut self.create_nix_config {
+            create_nix_config.try_execute().await.map_err(Self::error)?;
+        }
+        if let Some(include_secret_conf) = &mut self.include_secret_conf {
+            include_secret_conf
+                .try_execute()
+                .await
+                .map_err(Self::error)?;
+        }
 
         Ok(())
     }
 
     fn revert_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![
+            "This file is read by the Nix daemon to set its configuration options at runtime."
+                .to_string(),
+        ];
+        if self.move_nix_conf_to_custom {
+            explanation.push(format!(
+                "Move `/etc/nix/{NIX_CUSTOM_CONF}` back to `{NIX_CONF}`"
+            ));
+        }
+        for create_secret_file in &self.create_secret_files {
+            for val in create_secret_file.describe_revert().iter() {
+                explanation.push(val.description.clone())
+            }
+        }
         vec![ActionDescription::new(
             format!("Remove the Nix configuration in `{NIX_CONF}`"),
-            vec![
-                "This file is read by the Nix daemon to set its configuration options at runtime."
-                    .to_string(),
-            ],
+            explanation,
         )]
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
         let mut errors = vec![];
-        if let Err(err) = self.create_or_merge_nix_config.try_revert().await {
-            errors.push(err);
+        if let Some(include_secret_conf) = &mut self.include_secret_conf {
+            if let Err(err) = include_secret_conf.try_revert().await {
+                errors.push(err);
+            }
+        }
+        if let Some(create_or_merge_nix_config) = &mut self.create_or_merge_nix_config {
+            if let Err(err) = create_or_merge_nix_config.try_revert().await {
+                errors.push(err);
+            }
+        }
+        if let Some(create_nix_config) = &mut self.create_nix_config {
+            if let Err(err) = create_nix_config.try_revert().await {
+                errors.push(err);
+            }
+        }
+        if let Some((nix_conf, nix_custom_conf)) = self.moved_nix_conf() {
+            if nix_custom_conf.exists() && !nix_conf.exists() {
+                if let Err(e) = tokio::fs::rename(&nix_custom_conf, &nix_conf).await {
+                    errors.push(Self::error(ActionErrorKind::Rename(
+                        nix_custom_conf,
+                        nix_conf,
+                        e,
+                    )));
+                }
+            }
+        }
+        for create_secret_file in self.create_secret_files.iter_mut().rev() {
+            if let Err(err) = create_secret_file.try_revert().await {
+                errors.push(err);
+            }
         }
         if let Err(err) = self.create_directory.try_revert().await {
             errors.push(err);
@@ -224,4 +545,232 @@ impl Action for PlaceNixConfiguration {
             Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
         }
     }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        let mut children = vec![self.create_directory.try_verify().await];
+        for create_secret_file in &self.create_secret_files {
+            children.push(create_secret_file.try_verify().await);
+        }
+        if let Some(create_or_merge_nix_config) = &self.create_or_merge_nix_config {
+            children.push(create_or_merge_nix_config.try_verify().await);
+        }
+        if let Some(create_nix_config) = &self.create_nix_config {
+            children.push(create_nix_config.try_verify().await);
+        }
+        if let Some(include_secret_conf) = &self.include_secret_conf {
+            children.push(include_secret_conf.try_verify().await);
+        }
+        Ok(Verification::Children { children })
+    }
+}
+
+impl PlaceNixConfiguration {
+    /// The paths of `nix.conf` and `nix.custom.conf`, if an existing `nix.conf` is moved to `nix.custom.conf`
+    fn moved_nix_conf(&self) -> Option<(PathBuf, PathBuf)> {
+        let create_nix_config = self.create_nix_config.as_ref()?;
+        if !self.move_nix_conf_to_custom {
+            return None;
+        }
+        let nix_conf = create_nix_config.action.path.clone();
+        let nix_custom_conf = nix_conf.with_file_name(NIX_CUSTOM_CONF);
+        Some((nix_conf, nix_custom_conf))
+    }
+}
+
+/// Append the `values` which are not already in the space separated list `name` of `settings`
+fn append_values(
+    settings: &mut indexmap::IndexMap<String, String>,
+    name: &str,
+    values: impl Iterator<Item = String>,
+) {
+    for value in values {
+        match settings.entry(name.to_string()) {
+            Entry::Occupied(mut slot) => {
+                let slot_mut = slot.get_mut();
+                if !slot_mut
+                    .split_whitespace()
+                    .any(|existing| existing == value)
+                {
+                    *slot_mut += " ";
+                    *slot_mut += &value;
+                }
+            },
+            Entry::Vacant(slot) => {
+                let _ = slot.insert(value);
+            },
+        }
+    }
+}
+
+/// The contents of an installer owned `nix.conf` with `nix_config`, including `nix.custom.conf` (and the secret
+/// settings, if there are any)
+fn owned_nix_conf(nix_config: &nix_config_parser::NixConfig, include_secret_conf: bool) -> String {
+    let mut buf = format!(
+        "# Generated by https://github.com/DeterminateSystems/nix-installer, version {version}.\n\
+        # This file is replaced when Nix is reinstalled, settings of your own belong in `{NIX_CUSTOM_CONF}`.\n",
+        version = env!("CARGO_PKG_VERSION"),
+    );
+    for (name, value) in nix_config.settings() {
+        buf.push_str(&format!("{name} = {value}\n"));
+    }
+    if include_secret_conf {
+        buf.push_str(&format!("\n!include {NIX_SECRET_CONF}\n"));
+    }
+    buf.push_str(&format!("\n!include {NIX_CUSTOM_CONF}\n"));
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Settings which place the Nix configuration inside `root`
+    async fn settings_in(root: &std::path::Path) -> eyre::Result<CommonSettings> {
+        let mut settings = CommonSettings::default().await?;
+        settings.root = root.to_path_buf();
+        settings.nix_conf_validation = NixConfValidation::Off;
+        Ok(settings)
+    }
+
+    #[tokio::test]
+    async fn owns_nix_conf() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut settings = settings_in(temp_dir.path()).await?;
+        settings.nix_conf_mode = NixConfMode::Own;
+        let nix_conf = in_root(temp_dir.path(), NIX_CONF);
+        tokio::fs::create_dir(temp_dir.path().join("etc")).await?;
+
+        let mut action = PlaceNixConfiguration::plan(&settings, vec![]).await?;
+        action.try_execute().await?;
+        let written = tokio::fs::read_to_string(&nix_conf).await?;
+        assert!(written.starts_with("# Generated by"));
+        assert!(written.contains("\nbuild-users-group = nixbld\n"));
+        // Settings of the administrator's own come last, so they take precedence
+        assert!(written.ends_with(&format!("\n!include {NIX_CUSTOM_CONF}\n")));
+
+        action.try_revert().await?;
+        assert!(!nix_conf.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keeps_the_nix_conf_it_replaces() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut settings = settings_in(temp_dir.path()).await?;
+        settings.nix_conf_mode = NixConfMode::Own;
+        let nix_conf = in_root(temp_dir.path(), NIX_CONF);
+        let nix_custom_conf = nix_conf.with_file_name(NIX_CUSTOM_CONF);
+        tokio::fs::create_dir_all(nix_conf.parent().unwrap()).await?;
+        tokio::fs::write(&nix_conf, "trusted-users = alice\n").await?;
+
+        assert!(PlaceNixConfiguration::plan(&settings, vec![])
+            .await
+            .is_err());
+
+        settings.force = true;
+        let mut action = PlaceNixConfiguration::plan(&settings, vec![]).await?;
+        action.try_execute().await?;
+        assert_eq!(
+            tokio::fs::read_to_string(&nix_custom_conf).await?,
+            "trusted-users = alice\n"
+        );
+        assert!(tokio::fs::read_to_string(&nix_conf)
+            .await?
+            .ends_with(&format!("\n!include {NIX_CUSTOM_CONF}\n")));
+
+        action.try_revert().await?;
+        assert_eq!(
+            tokio::fs::read_to_string(&nix_conf).await?,
+            "trusted-users = alice\n"
+        );
+        assert!(!nix_custom_conf.exists());
+        Ok(())
+    }
+
+    /// The `nix.conf` placed with `settings`, inside `settings.root`
+    async fn placed_nix_conf(settings: &CommonSettings) -> eyre::Result<String> {
+        tokio::fs::create_dir_all(settings.root.join("etc")).await?;
+        let mut action = PlaceNixConfiguration::plan(settings, vec![]).await?;
+        action.try_execute().await?;
+        Ok(tokio::fs::read_to_string(in_root(&settings.root, NIX_CONF)).await?)
+    }
+
+    #[tokio::test]
+    async fn configures_auto_allocated_uids() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut settings = settings_in(temp_dir.path()).await?;
+        settings.auto_allocate_uids = true;
+        settings.auto_allocate_uids_start = 1_000_000;
+        settings.auto_allocate_uids_count = 65_536;
+
+        let nix_conf = placed_nix_conf(&settings).await?;
+        let lines = nix_conf.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&"auto-allocate-uids = true"));
+        assert!(lines.contains(&"start-id = 1000000"));
+        assert!(lines.contains(&"id-count = 65536"));
+        assert!(lines
+            .contains(&"experimental-features = nix-command flakes repl-flake auto-allocate-uids"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chooses_experimental_features() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut settings = settings_in(temp_dir.path()).await?;
+        settings.experimental_features = vec!["nix-command ca-derivations".into()];
+        let nix_conf = placed_nix_conf(&settings).await?;
+        assert!(nix_conf
+            .lines()
+            .any(|line| line == "experimental-features = nix-command ca-derivations"));
+
+        let temp_dir = tempfile::tempdir()?;
+        let mut settings = settings_in(temp_dir.path()).await?;
+        settings.experimental_features = vec![];
+        let nix_conf = placed_nix_conf(&settings).await?;
+        assert!(!nix_conf.contains("experimental-features"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skips_opinionated_defaults() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut settings = settings_in(temp_dir.path()).await?;
+        settings.skip_nix_conf_defaults =
+            vec![NixConfDefault::BashPromptPrefix, NixConfDefault::MaxJobs];
+        let nix_conf = placed_nix_conf(&settings).await?;
+        assert!(!nix_conf.contains(NixConfDefault::BashPromptPrefix.name()));
+        assert!(!nix_conf.contains(NixConfDefault::MaxJobs.name()));
+        assert!(nix_conf
+            .lines()
+            .any(|line| line == "extra-nix-path = nixpkgs=flake:nixpkgs"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn explains_the_chosen_features_and_defaults() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut settings = settings_in(temp_dir.path()).await?;
+        settings.experimental_features = vec!["nix-command".into()];
+        settings.skip_nix_conf_defaults = vec![
+            NixConfDefault::AutoOptimiseStore,
+            NixConfDefault::BashPromptPrefix,
+            NixConfDefault::ExtraNixPath,
+        ];
+        let action = PlaceNixConfiguration::plan(&settings, vec![]).await?;
+        let explanation = &action.describe_execute()[0].explanation;
+        assert!(explanation.contains(&"Enable the experimental features `nix-command`".to_string()));
+        assert!(explanation.contains(
+            &"Set the opinionated defaults `max-jobs = auto` (each can be skipped with `--skip-nix-conf-default`)"
+                .to_string()
+        ));
+
+        settings.experimental_features = vec![];
+        settings.skip_nix_conf_defaults = NixConfDefault::ALL.to_vec();
+        let action = PlaceNixConfiguration::plan(&settings, vec![]).await?;
+        let explanation = &action.describe_execute()[0].explanation;
+        assert!(explanation.contains(&"Enable no experimental features".to_string()));
+        assert!(explanation.contains(&"Set none of the opinionated defaults".to_string()));
+        Ok(())
+    }
 }
@@ -5,16 +5,20 @@ use crate::{
     action::{
         base::{FetchAndUnpackNix, MoveUnpackedNix},
         Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+        Verification,
     },
-    settings::{CommonSettings, SCRATCH_DIR},
+    release::NixRelease,
+    settings::{in_root, CommonSettings, SCRATCH_DIR},
 };
-use std::path::PathBuf;
 
 /**
 Place Nix and it's requirements onto the target
  */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct ProvisionNix {
+    /// The release `nix_version` resolved to, if it was set
+    #[serde(default)]
+    release: Option<NixRelease>,
     fetch_nix: StatefulAction<FetchAndUnpackNix>,
     create_nix_tree: StatefulAction<CreateNixTree>,
     move_unpacked_nix: StatefulAction<MoveUnpackedNix>,
@@ -23,19 +27,38 @@ pub struct ProvisionNix {
 impl ProvisionNix {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(settings: &CommonSettings) -> Result<StatefulAction<Self>, ActionError> {
+        let release = settings
+            .nix_release()
+            .await
+            .map_err(|e| Self::error(ActionErrorKind::Custom(Box::new(e))))?;
+        let sources = settings
+            .nix_package_sources(release.as_ref())
+            .await
+            .map_err(|e| Self::error(ActionErrorKind::Custom(Box::new(e))))?;
+        let sha256 = settings
+            .nix_package_digest(release.as_ref())
+            .await
+            .map_err(|e| Self::error(ActionErrorKind::Custom(Box::new(e))))?;
         let fetch_nix = FetchAndUnpackNix::plan(
-            settings.nix_package_url.clone(),
-            PathBuf::from(SCRATCH_DIR),
+            sources,
+            sha256,
+            in_root(&settings.root, SCRATCH_DIR),
             settings.proxy.clone(),
             settings.ssl_cert_file.clone(),
+            settings.network.clone(),
+            settings.tarball_cache(),
         )
         .await?;
 
-        let create_nix_tree = CreateNixTree::plan().await.map_err(Self::error)?;
-        let move_unpacked_nix = MoveUnpackedNix::plan(PathBuf::from(SCRATCH_DIR))
+        let create_nix_tree = CreateNixTree::plan(&settings.root)
             .await
             .map_err(Self::error)?;
+        let move_unpacked_nix =
+            MoveUnpackedNix::plan(in_root(&settings.root, SCRATCH_DIR), &settings.root)
+                .await
+                .map_err(Self::error)?;
         Ok(Self {
+            release,
             fetch_nix,
             create_nix_tree,
             move_unpacked_nix,
@@ -51,15 +74,27 @@ impl Action for ProvisionNix {
         ActionTag("provision_nix")
     }
     fn tracing_synopsis(&self) -> String {
-        "Provision Nix".to_string()
+        match &self.release {
+            Some(release) => format!("Provision Nix {}", release.version),
+            None => "Provision Nix".to_string(),
+        }
     }
 
     fn tracing_span(&self) -> Span {
-        span!(tracing::Level::DEBUG, "provision_nix",)
+        let span = span!(
+            tracing::Level::DEBUG,
+            "provision_nix",
+            version = tracing::field::Empty,
+        );
+        if let Some(release) = &self.release {
+            span.record("version", tracing::field::display(&release.version));
+        }
+        span
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
         let Self {
+            release: _,
             fetch_nix,
             create_nix_tree,
             move_unpacked_nix,
@@ -78,10 +113,14 @@ impl Action for ProvisionNix {
     async fn execute(&mut self) -> Result<(), ActionError> {
         // We fetch nix while doing the rest, then move it over.
         let mut fetch_nix_clone = self.fetch_nix.clone();
-        let fetch_nix_handle = tokio::task::spawn(async {
-            fetch_nix_clone.try_execute().await.map_err(Self::error)?;
-            Result::<_, ActionError>::Ok(fetch_nix_clone)
-        });
+        // The spawned task keeps sending events and recording the receipt for this plan
+        let fetch_nix_handle = tokio::task::spawn(crate::event::scope(
+            crate::event::current(),
+            crate::checkpoint::scope(crate::checkpoint::current(), async {
+                fetch_nix_clone.try_execute().await.map_err(Self::error)?;
+                Result::<_, ActionError>::Ok(fetch_nix_clone)
+            }),
+        ));
 
         self.create_nix_tree
             .try_execute()
@@ -102,6 +141,7 @@ impl Action for ProvisionNix {
 
     fn revert_description(&self) -> Vec<ActionDescription> {
         let Self {
+            release: _,
             fetch_nix,
             create_nix_tree,
             move_unpacked_nix,
@@ -142,4 +182,15 @@ impl Action for ProvisionNix {
             Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
         }
     }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        Ok(Verification::Children {
+            children: vec![
+                self.fetch_nix.try_verify().await,
+                self.create_nix_tree.try_verify().await,
+                self.move_unpacked_nix.try_verify().await,
+            ],
+        })
+    }
 }
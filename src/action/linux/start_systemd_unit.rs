@@ -43,6 +43,7 @@ impl StartSystemdUnit {
                 enable,
             },
             state,
+            checkpoint: None,
         })
     }
 }
@@ -52,7 +52,7 @@ impl CreateApfsVolume {
 }
 
 #[async_trait::async_trait]
-#[typetag::serde(name = "create_volume")]
+#[typetag::serde(name = "create_apfs_volume")]
 impl Action for CreateApfsVolume {
     fn action_tag() -> ActionTag {
         ActionTag("create_apfs_volume")
@@ -68,7 +68,7 @@ impl Action for CreateApfsVolume {
     fn tracing_span(&self) -> Span {
         span!(
             tracing::Level::DEBUG,
-            "create_volume",
+            "create_apfs_volume",
             disk = %self.disk.display(),
             name = %self.name,
             case_sensitive = %self.case_sensitive,
@@ -5,6 +5,7 @@ use crate::action::{
         EncryptApfsVolume, UnmountApfsVolume,
     },
     Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    Verification,
 };
 use std::{
     path::{Path, PathBuf},
@@ -120,7 +121,7 @@ impl CreateNixVolume {
 }
 
 #[async_trait::async_trait]
-#[typetag::serde(name = "create_apfs_volume")]
+#[typetag::serde(name = "create_nix_volume")]
 impl Action for CreateNixVolume {
     fn action_tag() -> ActionTag {
         ActionTag("create_nix_volume")
@@ -137,7 +138,7 @@ impl Action for CreateNixVolume {
     fn tracing_span(&self) -> Span {
         span!(
             tracing::Level::DEBUG,
-            "create_apfs_volume",
+            "create_nix_volume",
             disk = tracing::field::display(self.disk.display()),
             name = self.name
         )
@@ -335,4 +336,25 @@ impl Action for CreateNixVolume {
             Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
         }
     }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        let mut children = vec![
+            self.create_or_append_synthetic_conf.try_verify().await,
+            self.create_synthetic_objects.try_verify().await,
+            self.unmount_volume.try_verify().await,
+            self.create_volume.try_verify().await,
+            self.create_fstab_entry.try_verify().await,
+        ];
+        if let Some(encrypt_volume) = &self.encrypt_volume {
+            children.push(encrypt_volume.try_verify().await);
+        }
+        children.extend([
+            self.setup_volume_daemon.try_verify().await,
+            self.bootstrap_volume.try_verify().await,
+            self.kickstart_launchctl_service.try_verify().await,
+            self.enable_ownership.try_verify().await,
+        ]);
+        Ok(Verification::Children { children })
+    }
 }
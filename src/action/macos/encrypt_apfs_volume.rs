@@ -98,7 +98,7 @@ impl EncryptApfsVolume {
 }
 
 #[async_trait::async_trait]
-#[typetag::serde(name = "encrypt_volume")]
+#[typetag::serde(name = "encrypt_apfs_volume")]
 impl Action for EncryptApfsVolume {
     fn action_tag() -> ActionTag {
         ActionTag("encrypt_apfs_volume")
@@ -114,7 +114,7 @@ impl Action for EncryptApfsVolume {
     fn tracing_span(&self) -> Span {
         span!(
             tracing::Level::DEBUG,
-            "encrypt_volume",
+            "encrypt_apfs_volume",
             disk = tracing::field::display(self.disk.display()),
         )
     }
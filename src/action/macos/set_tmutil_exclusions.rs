@@ -4,6 +4,7 @@ use tracing::{span, Span};
 
 use crate::action::{
     Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    Verification,
 };
 
 use super::SetTmutilExclusion;
@@ -129,4 +130,13 @@ impl Action for SetTmutilExclusions {
             Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
         }
     }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        let mut children = Vec::with_capacity(self.set_tmutil_exclusions.len());
+        for set_tmutil_exclusion in self.set_tmutil_exclusions.iter() {
+            children.push(set_tmutil_exclusion.try_verify().await);
+        }
+        Ok(Verification::Children { children })
+    }
 }
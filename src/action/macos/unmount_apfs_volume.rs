@@ -31,7 +31,7 @@ impl UnmountApfsVolume {
 }
 
 #[async_trait::async_trait]
-#[typetag::serde(name = "unmount_volume")]
+#[typetag::serde(name = "unmount_apfs_volume")]
 impl Action for UnmountApfsVolume {
     fn action_tag() -> ActionTag {
         ActionTag("unmount_apfs_volume")
@@ -43,7 +43,7 @@ impl Action for UnmountApfsVolume {
     fn tracing_span(&self) -> Span {
         span!(
             tracing::Level::DEBUG,
-            "unmount_volume",
+            "unmount_apfs_volume",
             disk = tracing::field::display(self.disk.display()),
             name = self.name,
         )
@@ -164,6 +164,7 @@ impl Planner for MyPlanner {
                 .into_keys()
                 .collect::<Vec<_>>(),
             self.common.ssl_cert_file.clone(),
+            self.common.network.clone(),
         )?)
     }
 }
@@ -193,13 +194,21 @@ pub mod common;
 pub mod linux;
 pub mod macos;
 mod stateful;
+mod verification;
 
 pub use stateful::{ActionState, StatefulAction};
 use std::{error::Error, process::Output};
 use tokio::task::JoinError;
 use tracing::Span;
-
-use crate::{error::HasExpectedErrors, settings::UrlOrPathError, CertificateError};
+pub(crate) use verification::error_chain;
+pub use verification::{Verification, VerificationReport};
+
+use crate::{
+    error::HasExpectedErrors,
+    network::NetworkError,
+    settings::{Sha256Digest, UrlOrPathError},
+    CertificateError,
+};
 
 /// An action which can be reverted or completed, with an action state
 ///
@@ -246,6 +255,14 @@ pub trait Action: Send + Sync + std::fmt::Debug + dyn_clone::DynClone {
     ///
     /// This is called by [`InstallPlan::uninstall`](crate::InstallPlan::uninstall) through [`StatefulAction::try_revert`] which handles tracing as well as if the action needs to revert based on its `action_state`.
     async fn revert(&mut self) -> Result<(), ActionError>;
+    /// Check whether the effects of [`execute`][Action::execute] still hold on the system
+    ///
+    /// If this action calls sub-[`Action`]s, care should be taken to return a [`Verification::Children`] built from [`try_verify`][StatefulAction::try_verify] on those actions, not [`verify`][Action::verify], so that [`ActionState`] is handled correctly.
+    ///
+    /// This is called by [`InstallPlan::verify`](crate::InstallPlan::verify) through [`StatefulAction::try_verify`] which only calls it on completed actions. Actions which do not override it report [`Verification::Unsupported`].
+    async fn verify(&self) -> Result<Verification, ActionError> {
+        Ok(Verification::Unsupported)
+    }
 
     fn stateful(self) -> StatefulAction<Self>
     where
@@ -254,6 +271,7 @@ pub trait Action: Send + Sync + std::fmt::Debug + dyn_clone::DynClone {
         StatefulAction {
             action: self,
             state: ActionState::Uncompleted,
+            checkpoint: None,
         }
     }
 
@@ -288,8 +306,18 @@ impl ActionDescription {
 }
 
 /// A 'tag' name an action has that corresponds to the one we serialize in [`typetag]`
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct ActionTag(&'static str);
 
+impl serde::Serialize for ActionTag {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.0)
+    }
+}
+
 impl std::fmt::Display for ActionTag {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(self.0)
@@ -578,6 +606,14 @@ pub enum ActionErrorKind {
     ),
     #[error("Unknown url scheme")]
     UnknownUrlScheme,
+    #[error(transparent)]
+    Network(#[from] NetworkError),
+    #[error("The SHA-256 digest of `{url_or_path}` was `{actual}`, expected `{expected}`; the download may be corrupted or tampered with")]
+    Sha256Mismatch {
+        url_or_path: String,
+        expected: Sha256Digest,
+        actual: Sha256Digest,
+    },
 }
 
 impl ActionErrorKind {
@@ -606,6 +642,7 @@ impl HasExpectedErrors for ActionErrorKind {
             | Self::PathGroupMismatch(_, _, _)
             | Self::PathModeMismatch(_, _, _) => Some(Box::new(self)),
             Self::SystemdMissing => Some(Box::new(self)),
+            Self::Sha256Mismatch { .. } => Some(Box::new(self)),
             _ => None,
         }
     }
@@ -642,6 +679,7 @@ impl crate::diagnostics::ErrorDiagnostic for ActionErrorKind {
             Self::NoGroup(name) | Self::NoUser(name) => {
                 vec![name.clone()]
             },
+            Self::Sha256Mismatch { url_or_path, .. } => vec![url_or_path.clone()],
             Self::Command {
                 program,
                 command: _,
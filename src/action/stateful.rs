@@ -1,7 +1,12 @@
 use serde::{Deserialize, Serialize};
 use tracing::{Instrument, Span};
 
-use super::{Action, ActionDescription, ActionError, ActionTag};
+use crate::event::InstallEvent;
+
+use super::{
+    verification::error_chain, Action, ActionDescription, ActionError, ActionTag, Verification,
+    VerificationReport,
+};
 
 /// A wrapper around an [`Action`](crate::action::Action) which tracks the [`ActionState`] and
 /// handles some tracing output
@@ -9,6 +14,9 @@ use super::{Action, ActionDescription, ActionError, ActionTag};
 pub struct StatefulAction<A> {
     pub(crate) action: A,
     pub(crate) state: ActionState,
+    /// Where this action lives in the receipt, set while [`InstallPlan::resume`](crate::InstallPlan::resume) is recording it
+    #[serde(default, skip_serializing)]
+    pub(crate) checkpoint: Option<String>,
 }
 
 impl<A> From<A> for StatefulAction<A>
@@ -19,6 +27,24 @@ where
         Self {
             action,
             state: ActionState::Uncompleted,
+            checkpoint: None,
+        }
+    }
+}
+
+impl<A> StatefulAction<A>
+where
+    A: Serialize,
+{
+    /// Record the current [`ActionState`] in the receipt and the event stream, if they are being recorded
+    async fn record(&self, action: ActionTag, synopsis: impl FnOnce() -> String) {
+        crate::event::emit(|| InstallEvent::ActionStateChanged {
+            action,
+            synopsis: synopsis(),
+            state: self.state,
+        });
+        if let Some(checkpoint) = &self.checkpoint {
+            crate::checkpoint::record(checkpoint, &self.action, self.state).await
         }
     }
 }
@@ -71,9 +97,17 @@ impl StatefulAction<Box<dyn Action>> {
             },
             _ => {
                 self.state = ActionState::Progress;
+                self.record(ActionTag(self.action.typetag_name()), || {
+                    self.action.tracing_synopsis()
+                })
+                .await;
                 tracing::debug!("Executing: {}", self.action.tracing_synopsis());
                 self.action.execute().await?;
                 self.state = ActionState::Completed;
+                self.record(ActionTag(self.action.typetag_name()), || {
+                    self.action.tracing_synopsis()
+                })
+                .await;
                 tracing::debug!("Completed: {}", self.action.tracing_synopsis());
                 Ok(())
             },
@@ -98,14 +132,46 @@ impl StatefulAction<Box<dyn Action>> {
             },
             _ => {
                 self.state = ActionState::Progress;
+                self.record(ActionTag(self.action.typetag_name()), || {
+                    self.action.tracing_synopsis()
+                })
+                .await;
                 tracing::debug!("Reverting: {}", self.action.tracing_synopsis());
                 self.action.revert().await?;
                 tracing::debug!("Reverted: {}", self.action.tracing_synopsis());
                 self.state = ActionState::Uncompleted;
+                self.record(ActionTag(self.action.typetag_name()), || {
+                    self.action.tracing_synopsis()
+                })
+                .await;
                 Ok(())
             },
         }
     }
+    /// Check whether the effects of this action still hold
+    ///
+    /// You should prefer this ([`try_verify`][StatefulAction::try_verify]) over [`verify`][Action::verify] as it handles [`ActionState`]
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn try_verify(&self) -> VerificationReport {
+        let verification = match self.state {
+            ActionState::Completed => {
+                tracing::debug!("Verifying: {}", self.action.tracing_synopsis());
+                self.action
+                    .verify()
+                    .await
+                    .unwrap_or_else(|err| Verification::Failed {
+                        error: error_chain(&err),
+                    })
+            },
+            _ => Verification::Unchecked,
+        };
+        VerificationReport {
+            action: self.action.typetag_name().to_string(),
+            synopsis: self.action.tracing_synopsis(),
+            state: self.state,
+            verification,
+        }
+    }
 }
 
 impl<A> StatefulAction<A>
@@ -138,6 +204,7 @@ where
         StatefulAction {
             action: Box::new(self.action),
             state: self.state,
+            checkpoint: self.checkpoint,
         }
     }
     /// A description of what this action would do during execution
@@ -157,7 +224,10 @@ where
     /// Perform any execution steps
     ///
     /// You should prefer this ([`try_execute`][StatefulAction::try_execute]) over [`execute`][Action::execute] as it handles [`ActionState`] and does tracing
-    pub async fn try_execute(&mut self) -> Result<(), ActionError> {
+    pub async fn try_execute(&mut self) -> Result<(), ActionError>
+    where
+        A: Serialize,
+    {
         let span = self.action.tracing_span();
         match self.state {
             ActionState::Completed => {
@@ -174,6 +244,8 @@ where
             },
             _ => {
                 self.state = ActionState::Progress;
+                self.record(A::action_tag(), || self.action.tracing_synopsis())
+                    .await;
                 tracing::debug!(
                     parent: &span,
                     "Executing: {}",
@@ -181,6 +253,8 @@ where
                 );
                 self.action.execute().instrument(span.clone()).await?;
                 self.state = ActionState::Completed;
+                self.record(A::action_tag(), || self.action.tracing_synopsis())
+                    .await;
                 tracing::debug!(
                     parent: &span,
                     "Completed: {}",
@@ -193,7 +267,10 @@ where
     /// Perform any revert steps
     ///
     /// You should prefer this ([`try_revert`][StatefulAction::try_revert]) over [`revert`][Action::revert] as it handles [`ActionState`] and does tracing
-    pub async fn try_revert(&mut self) -> Result<(), ActionError> {
+    pub async fn try_revert(&mut self) -> Result<(), ActionError>
+    where
+        A: Serialize,
+    {
         let span = self.action.tracing_span();
         match self.state {
             ActionState::Uncompleted => {
@@ -210,6 +287,8 @@ where
             },
             _ => {
                 self.state = ActionState::Progress;
+                self.record(A::action_tag(), || self.action.tracing_synopsis())
+                    .await;
                 tracing::debug!(
                     parent: &span,
                     "Reverting: {}",
@@ -222,15 +301,48 @@ where
                     self.action.tracing_synopsis()
                 );
                 self.state = ActionState::Uncompleted;
+                self.record(A::action_tag(), || self.action.tracing_synopsis())
+                    .await;
                 Ok(())
             },
         }
     }
 
+    /// Check whether the effects of this action still hold
+    ///
+    /// You should prefer this ([`try_verify`][StatefulAction::try_verify]) over [`verify`][Action::verify] as it handles [`ActionState`]
+    pub async fn try_verify(&self) -> VerificationReport {
+        let span = self.action.tracing_span();
+        let verification = match self.state {
+            ActionState::Completed => {
+                tracing::debug!(
+                    parent: &span,
+                    "Verifying: {}",
+                    self.action.tracing_synopsis()
+                );
+                self.action
+                    .verify()
+                    .instrument(span.clone())
+                    .await
+                    .unwrap_or_else(|err| Verification::Failed {
+                        error: error_chain(&err),
+                    })
+            },
+            _ => Verification::Unchecked,
+        };
+        VerificationReport {
+            action: A::action_tag().to_string(),
+            synopsis: self.action.tracing_synopsis(),
+            state: self.state,
+            verification,
+        }
+    }
+
     pub fn completed(action: A) -> Self {
         Self {
             state: ActionState::Completed,
             action,
+            checkpoint: None,
         }
     }
 
@@ -238,6 +350,7 @@ where
         Self {
             state: ActionState::Skipped,
             action,
+            checkpoint: None,
         }
     }
 
@@ -245,6 +358,7 @@ where
         Self {
             state: ActionState::Uncompleted,
             action,
+            checkpoint: None,
         }
     }
 }
@@ -0,0 +1,139 @@
+use std::{
+    fs::Metadata,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::Path,
+};
+
+use nix::unistd::{Group, User};
+
+use super::{ActionErrorKind, ActionState};
+
+/** Whether the effects of an [`Action`](crate::action::Action) still hold on the system
+
+Returned by [`Action::verify`](crate::action::Action::verify).
+*/
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Verification {
+    /// The effects still hold
+    Verified,
+    /// The effects no longer hold, described by each item of `drift`
+    Drifted { drift: Vec<String> },
+    /// The action has no way to check its effects, for example because they are temporary
+    Unsupported,
+    /// The action was not checked because it was not completed
+    Unchecked,
+    /// Checking the effects failed
+    Failed { error: String },
+    /// The effects of the action are the effects of its sub-actions
+    Children { children: Vec<VerificationReport> },
+}
+
+impl Verification {
+    /// [`Verified`](Verification::Verified) if there is no `drift`, [`Drifted`](Verification::Drifted) otherwise
+    pub fn from_drift(drift: Vec<String>) -> Self {
+        if drift.is_empty() {
+            Self::Verified
+        } else {
+            Self::Drifted { drift }
+        }
+    }
+
+    /// If nothing, including any sub-action, drifted or failed to be checked
+    pub fn holds(&self) -> bool {
+        match self {
+            Self::Verified | Self::Unsupported | Self::Unchecked => true,
+            Self::Drifted { .. } | Self::Failed { .. } => false,
+            Self::Children { children } => children.iter().all(|v| v.verification.holds()),
+        }
+    }
+}
+
+/** The [`Verification`] of a single [`Action`](crate::action::Action), as produced by
+[`StatefulAction::try_verify`](crate::action::StatefulAction::try_verify)
+*/
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct VerificationReport {
+    /// The tag of the action
+    pub action: String,
+    /// The [`tracing_synopsis`](crate::action::Action::tracing_synopsis) of the action
+    pub synopsis: String,
+    /// The state of the action in the receipt
+    pub state: ActionState,
+    /// The outcome of checking the action
+    pub verification: Verification,
+}
+
+/// Render an error along with its sources
+pub(crate) fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut buf = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        buf.push_str(&format!(": {err}"));
+        source = err.source();
+    }
+    buf
+}
+
+/// Check that `path` still exists with the given ownership and mode, returning its metadata if it exists
+pub(crate) async fn verify_path(
+    path: &Path,
+    user: Option<&str>,
+    group: Option<&str>,
+    mode: Option<u32>,
+    drift: &mut Vec<String>,
+) -> Result<Option<Metadata>, ActionErrorKind> {
+    let metadata = match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            drift.push(format!("`{}` no longer exists", path.display()));
+            return Ok(None);
+        },
+        Err(e) => return Err(ActionErrorKind::GettingMetadata(path.to_path_buf(), e)),
+    };
+
+    if let Some(mode) = mode {
+        // We only care about user-group-other permissions
+        let discovered_mode = metadata.permissions().mode() & 0o777;
+        if discovered_mode != mode {
+            drift.push(format!(
+                "`{}` has mode `{discovered_mode:#o}`, expected `{mode:#o}`",
+                path.display()
+            ));
+        }
+    }
+    if let Some(user) = user {
+        match User::from_name(user).map_err(|e| ActionErrorKind::GettingUserId(user.into(), e))? {
+            Some(found) if found.uid.as_raw() == metadata.uid() => (),
+            Some(found) => drift.push(format!(
+                "`{}` is owned by UID {}, expected `{user}` (UID {})",
+                path.display(),
+                metadata.uid(),
+                found.uid,
+            )),
+            None => drift.push(format!(
+                "`{}` should be owned by `{user}`, which no longer exists",
+                path.display()
+            )),
+        }
+    }
+    if let Some(group) = group {
+        match Group::from_name(group)
+            .map_err(|e| ActionErrorKind::GettingGroupId(group.into(), e))?
+        {
+            Some(found) if found.gid.as_raw() == metadata.gid() => (),
+            Some(found) => drift.push(format!(
+                "`{}` is owned by GID {}, expected `{group}` (GID {})",
+                path.display(),
+                metadata.gid(),
+                found.gid,
+            )),
+            None => drift.push(format!(
+                "`{}` should be owned by `{group}`, which no longer exists",
+                path.display()
+            )),
+        }
+    }
+
+    Ok(Some(metadata))
+}
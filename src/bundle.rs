@@ -0,0 +1,356 @@
+/*! Self-contained installers, carrying the Nix package as a payload
+
+A bundle is a `nix-installer` executable with a Nix package tarball, and optionally some `nix.conf`
+fragments, appended to it. Installing from a bundle needs no network access: the embedded Nix package
+is tried before any other source (as [`UrlOrPath::Embedded`](crate::settings::UrlOrPath::Embedded)), and
+the embedded fragments are added to [`extra_conf`](crate::settings::CommonSettings::extra_conf).
+
+The payload is laid out as:
+
+```text
+executable | Nix package | nix.conf fragments... | manifest (JSON) | manifest length (u64, little endian) | magic
+```
+*/
+
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, ReadBuf};
+
+use crate::settings::Sha256Digest;
+
+/// Marks the end of a bundle
+const MAGIC: &[u8; 8] = b"NIXBNDL1";
+/// The length of the manifest length and the magic, which close a bundle
+const TRAILER_LEN: u64 = 8 + MAGIC.len() as u64;
+
+/// A part of a bundle's payload
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Payload {
+    /// Where the payload starts, from the start of the bundle
+    pub offset: u64,
+    pub len: u64,
+    pub sha256: Sha256Digest,
+}
+
+/// A description of what a bundle carries, and where
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct BundleManifest {
+    /// The version of `nix-installer` the bundle was made from
+    pub version: String,
+    /// Where the executable ends and the payload starts
+    pub executable_len: u64,
+    /// Where the Nix package was fetched from when the bundle was made
+    pub nix_package_source: String,
+    pub nix_package: Payload,
+    pub extra_conf: Vec<Payload>,
+}
+
+impl BundleManifest {
+    /// Read the manifest of the bundle at `path`, if it is one
+    #[tracing::instrument(level = "debug", skip_all, fields(path = %path.display()))]
+    pub async fn read(path: &Path) -> Result<Option<Self>, BundleError> {
+        let mut file = tokio::fs::File::open(path)
+            .await
+            .map_err(|e| BundleError::Read(path.to_path_buf(), e))?;
+        let len = file
+            .metadata()
+            .await
+            .map_err(|e| BundleError::Read(path.to_path_buf(), e))?
+            .len();
+        if len < TRAILER_LEN {
+            return Ok(None);
+        }
+
+        let mut trailer = [0; TRAILER_LEN as usize];
+        file.seek(SeekFrom::Start(len - TRAILER_LEN))
+            .await
+            .map_err(|e| BundleError::Read(path.to_path_buf(), e))?;
+        file.read_exact(&mut trailer)
+            .await
+            .map_err(|e| BundleError::Read(path.to_path_buf(), e))?;
+        let (manifest_len, magic) = trailer.split_at(8);
+        if magic != MAGIC {
+            return Ok(None);
+        }
+        let manifest_len = u64::from_le_bytes(manifest_len.try_into().expect("Split at 8"));
+        let manifest_start = (len - TRAILER_LEN)
+            .checked_sub(manifest_len)
+            .ok_or_else(|| BundleError::Truncated(path.to_path_buf()))?;
+
+        let mut manifest = vec![0; manifest_len as usize];
+        file.seek(SeekFrom::Start(manifest_start))
+            .await
+            .map_err(|e| BundleError::Read(path.to_path_buf(), e))?;
+        file.read_exact(&mut manifest)
+            .await
+            .map_err(|e| BundleError::Read(path.to_path_buf(), e))?;
+        let manifest: Self = serde_json::from_slice(&manifest)?;
+
+        let payloads = std::iter::once(&manifest.nix_package).chain(&manifest.extra_conf);
+        for payload in payloads {
+            // The manifest is untrusted, so the payload's end may not even fit in a `u64`
+            let end = payload
+                .offset
+                .checked_add(payload.len)
+                .ok_or_else(|| BundleError::Corrupted(path.to_path_buf()))?;
+            if end > manifest_start {
+                return Err(BundleError::Truncated(path.to_path_buf()));
+            }
+        }
+        Ok(Some(manifest))
+    }
+}
+
+/// The running executable and its manifest, if it is a bundle
+pub(crate) async fn current() -> Result<Option<(PathBuf, BundleManifest)>, BundleError> {
+    let path = std::env::current_exe().map_err(BundleError::CurrentExe)?;
+    Ok(BundleManifest::read(&path)
+        .await?
+        .map(|manifest| (path, manifest)))
+}
+
+/// Open the `payload` of the bundle at `path`
+pub(crate) async fn open_payload(
+    path: &Path,
+    payload: &Payload,
+) -> Result<tokio::io::Take<tokio::fs::File>, BundleError> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| BundleError::Read(path.to_path_buf(), e))?;
+    file.seek(SeekFrom::Start(payload.offset))
+        .await
+        .map_err(|e| BundleError::Read(path.to_path_buf(), e))?;
+    Ok(file.take(payload.len))
+}
+
+/// Read the `nix.conf` fragments embedded in the bundle at `path`
+pub(crate) async fn read_extra_conf(
+    path: &Path,
+    manifest: &BundleManifest,
+) -> Result<Vec<String>, BundleError> {
+    let mut extra_conf = vec![];
+    for payload in &manifest.extra_conf {
+        let mut buf = String::new();
+        open_payload(path, payload)
+            .await?
+            .read_to_string(&mut buf)
+            .await
+            .map_err(|e| BundleError::Read(path.to_path_buf(), e))?;
+        if Sha256Digest::of(buf.as_bytes()) != payload.sha256 {
+            return Err(BundleError::Corrupted(path.to_path_buf()));
+        }
+        extra_conf.push(buf);
+    }
+    Ok(extra_conf)
+}
+
+/// Write a bundle of the executable at `executable`, `nix_package` and `extra_conf` to `out`
+///
+/// If `executable` is already a bundle, its payload is replaced. The Nix package is streamed into the
+/// bundle, and hashed as it is copied.
+#[tracing::instrument(level = "debug", skip_all, fields(
+    executable = %executable.display(),
+    out = %out.display(),
+))]
+pub async fn write(
+    executable: &Path,
+    out: &Path,
+    nix_package_source: String,
+    nix_package: impl AsyncRead + Unpin,
+    extra_conf: &[String],
+) -> Result<BundleManifest, BundleError> {
+    let executable_len = match BundleManifest::read(executable).await? {
+        Some(manifest) => manifest.executable_len,
+        None => tokio::fs::metadata(executable)
+            .await
+            .map_err(|e| BundleError::Read(executable.to_path_buf(), e))?
+            .len(),
+    };
+
+    let mut file = tokio::fs::File::create(out)
+        .await
+        .map_err(|e| BundleError::Write(out.to_path_buf(), e))?;
+    let mut exe = tokio::fs::File::open(executable)
+        .await
+        .map_err(|e| BundleError::Read(executable.to_path_buf(), e))?
+        .take(executable_len);
+    tokio::io::copy(&mut exe, &mut file)
+        .await
+        .map_err(|e| BundleError::Write(out.to_path_buf(), e))?;
+
+    let mut nix_package = HashingReader {
+        inner: nix_package,
+        hasher: Sha256::new(),
+    };
+    let nix_package_len = tokio::io::copy(&mut nix_package, &mut file)
+        .await
+        .map_err(|e| BundleError::Write(out.to_path_buf(), e))?;
+
+    let mut offset = executable_len + nix_package_len;
+    let extra_conf_payloads = extra_conf
+        .iter()
+        .map(|v| {
+            let payload = Payload {
+                offset,
+                len: v.len() as u64,
+                sha256: Sha256Digest::of(v.as_bytes()),
+            };
+            offset += payload.len;
+            payload
+        })
+        .collect();
+    let manifest = BundleManifest {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        executable_len,
+        nix_package_source,
+        nix_package: Payload {
+            offset: executable_len,
+            len: nix_package_len,
+            sha256: Sha256Digest::from_hasher(nix_package.hasher),
+        },
+        extra_conf: extra_conf_payloads,
+    };
+    let manifest_json = serde_json::to_vec(&manifest)?;
+    let manifest_len = (manifest_json.len() as u64).to_le_bytes();
+
+    let parts = extra_conf.iter().map(|v| v.as_bytes()).chain([
+        manifest_json.as_slice(),
+        &manifest_len,
+        MAGIC,
+    ]);
+    for part in parts {
+        file.write_all(part)
+            .await
+            .map_err(|e| BundleError::Write(out.to_path_buf(), e))?;
+    }
+    file.flush()
+        .await
+        .map_err(|e| BundleError::Write(out.to_path_buf(), e))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(out, std::fs::Permissions::from_mode(0o755))
+            .await
+            .map_err(|e| BundleError::Write(out.to_path_buf(), e))?;
+    }
+
+    Ok(manifest)
+}
+
+/// A reader which hashes everything read through it
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.hasher.update(&buf.filled()[filled..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum BundleError {
+    #[error("Finding the running executable")]
+    CurrentExe(#[source] std::io::Error),
+    #[error("Reading bundle `{0}`")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("Writing bundle `{0}`")]
+    Write(PathBuf, #[source] std::io::Error),
+    #[error("The bundle `{0}` is truncated")]
+    Truncated(PathBuf),
+    #[error("The payload of the bundle `{0}` does not match its manifest")]
+    Corrupted(PathBuf),
+    #[error("Serializing or deserializing the bundle manifest")]
+    Manifest(
+        #[from]
+        #[source]
+        serde_json::Error,
+    ),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trips() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let executable = temp_dir.path().join("nix-installer");
+        tokio::fs::write(&executable, b"#!/bin/sh\n").await?;
+        assert_eq!(BundleManifest::read(&executable).await?, None);
+
+        let bundle = temp_dir.path().join("bundle");
+        let extra_conf = vec!["max-jobs = 4\n".to_string()];
+        let manifest = write(
+            &executable,
+            &bundle,
+            "https://example.com/nix.tar.xz".into(),
+            &b"Nix"[..],
+            &extra_conf,
+        )
+        .await?;
+
+        assert_eq!(BundleManifest::read(&bundle).await?, Some(manifest.clone()));
+        assert_eq!(manifest.nix_package.len, 3);
+        assert_eq!(manifest.nix_package.sha256, Sha256Digest::of(b"Nix"));
+        let mut nix_package = vec![];
+        open_payload(&bundle, &manifest.nix_package)
+            .await?
+            .read_to_end(&mut nix_package)
+            .await?;
+        assert_eq!(nix_package, b"Nix");
+        assert_eq!(read_extra_conf(&bundle, &manifest).await?, extra_conf);
+
+        // Bundling a bundle replaces its payload, rather than adding to it
+        let rebundle = temp_dir.path().join("rebundle");
+        let manifest = write(&bundle, &rebundle, "nix.tar.xz".into(), &b"Lix"[..], &[]).await?;
+        assert_eq!(manifest.executable_len, 10);
+        assert_eq!(manifest.nix_package.offset, 10);
+        assert!(manifest.extra_conf.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_overflowing_payloads() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let bundle = temp_dir.path().join("bundle");
+        let manifest = BundleManifest {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            executable_len: 0,
+            nix_package_source: "nix.tar.xz".into(),
+            nix_package: Payload {
+                offset: u64::MAX,
+                len: 1,
+                sha256: Sha256Digest::of(b"Nix"),
+            },
+            extra_conf: vec![],
+        };
+        let manifest = serde_json::to_vec(&manifest)?;
+        let mut buf = manifest.clone();
+        buf.extend_from_slice(&(manifest.len() as u64).to_le_bytes());
+        buf.extend_from_slice(MAGIC);
+        tokio::fs::write(&bundle, buf).await?;
+
+        let err = BundleManifest::read(&bundle).await.unwrap_err();
+        assert!(matches!(err, BundleError::Corrupted(_)), "{err:?}");
+
+        Ok(())
+    }
+}
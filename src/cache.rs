@@ -0,0 +1,394 @@
+/*! A local cache of verified Nix package tarballs
+
+When a [`TarballCache`] is configured (with `--cache-dir`), each Nix package tarball fetched over the network is
+stored once it has been verified against a known SHA-256 digest, keyed by its URL and that digest. Tarballs without
+a known digest are never cached, as a later install could not verify them. Later installs reuse the cached copy
+instead of fetching it again, which helps when repeatedly installing and uninstalling on the same machine.
+
+The cache is bounded in size, the least recently used tarballs are evicted first.
+*/
+
+use std::{
+    io::{Seek, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::settings::Sha256Digest;
+
+/// The extension of complete, verified, entries
+const ENTRY_EXTENSION: &str = "tarball";
+/// The extension of entries which are still being written
+const PARTIAL_EXTENSION: &str = "partial";
+/// How long a partial entry is left alone before it is assumed to be left over from an interrupted install
+const PARTIAL_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A size bounded directory of verified tarballs, keyed by their URL and SHA-256 digest
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TarballCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+/// A tarball found in a [`TarballCache`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CacheEntry {
+    pub(crate) path: PathBuf,
+    pub(crate) sha256: Sha256Digest,
+}
+
+impl TarballCache {
+    /// A cache in `dir` which evicts tarballs once they exceed `max_size` bytes in total
+    pub fn new(dir: impl Into<PathBuf>, max_size: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            max_size,
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// The part of an entry's file name derived from the URL it was fetched from
+    fn key(url: &Url) -> String {
+        Sha256Digest::of(url.as_str().as_bytes()).to_string()
+    }
+
+    fn entry_path(&self, url: &Url, sha256: &Sha256Digest) -> PathBuf {
+        self.dir
+            .join(format!("{}-{sha256}.{ENTRY_EXTENSION}", Self::key(url)))
+    }
+
+    /// Find a tarball fetched from `url`, which must have the digest `sha256`
+    ///
+    /// Without a digest nothing is found, as there would be nothing to verify the cached tarball against.
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url))]
+    pub(crate) async fn lookup(
+        &self,
+        url: &Url,
+        sha256: Option<&Sha256Digest>,
+    ) -> Option<CacheEntry> {
+        let sha256 = sha256?;
+        let path = self.entry_path(url, sha256);
+        let found = path.is_file().then(|| CacheEntry {
+            path,
+            sha256: sha256.clone(),
+        })?;
+
+        // Mark the entry as used, so it is evicted last
+        if let Err(err) = touch(&found.path) {
+            tracing::debug!(
+                "Could not update the modification time of `{}`: {err}",
+                found.path.display()
+            );
+        }
+        Some(found)
+    }
+
+    /// Start writing a tarball fetched from `url`, which is only added to the cache once [`PendingEntry::commit`]ed
+    pub(crate) async fn pending(&self, url: &Url) -> Result<PendingEntry, CacheError> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .map_err(|e| CacheError::CreateDirectory(self.dir.clone(), e))?;
+        let path = self.dir.join(format!(
+            "{}-{}.{PARTIAL_EXTENSION}",
+            Self::key(url),
+            std::process::id()
+        ));
+        let file = std::fs::File::create(&path).map_err(|e| CacheError::Write(path.clone(), e))?;
+        Ok(PendingEntry {
+            cache: self.clone(),
+            url: url.clone(),
+            path,
+            file: Some(file),
+        })
+    }
+
+    /// Remove an entry, for example because it could not be unpacked
+    pub(crate) async fn remove(&self, entry: &CacheEntry) {
+        if let Err(err) = tokio::fs::remove_file(&entry.path).await {
+            tracing::debug!("Could not remove `{}`: {err}", entry.path.display());
+        }
+    }
+
+    /// The complete entries, with their modification time and size
+    async fn entries(&self) -> Result<Vec<(PathBuf, SystemTime, u64)>, CacheError> {
+        let mut entries = vec![];
+        let mut read_dir = match tokio::fs::read_dir(&self.dir).await {
+            Ok(read_dir) => read_dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(entries),
+            Err(e) => return Err(CacheError::ReadDirectory(self.dir.clone(), e)),
+        };
+        while let Some(entry) = read_dir
+            .next_entry()
+            .await
+            .map_err(|e| CacheError::ReadDirectory(self.dir.clone(), e))?
+        {
+            let path = entry.path();
+            let extension = path.extension().and_then(|v| v.to_str());
+            if extension != Some(ENTRY_EXTENSION) && extension != Some(PARTIAL_EXTENSION) {
+                continue;
+            }
+            let metadata = match entry.metadata().await {
+                Ok(metadata) if metadata.is_file() => metadata,
+                _ => continue,
+            };
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+
+            if extension == Some(PARTIAL_EXTENSION) {
+                let age = SystemTime::now()
+                    .duration_since(modified)
+                    .unwrap_or_default();
+                if age > PARTIAL_MAX_AGE {
+                    tracing::debug!("Removing abandoned `{}`", path.display());
+                    tokio::fs::remove_file(&path).await.ok();
+                }
+                continue;
+            }
+            entries.push((path, modified, metadata.len()));
+        }
+        Ok(entries)
+    }
+
+    /// Remove the least recently used entries until the cache fits in `max_size`
+    #[tracing::instrument(level = "debug", skip_all, fields(dir = %self.dir.display(), max_size = self.max_size))]
+    async fn evict(&self) -> Result<(), CacheError> {
+        let mut entries = self.entries().await?;
+        entries.sort_by_key(|(_, modified, _)| *modified);
+
+        let mut size: u64 = entries.iter().map(|(_, _, len)| len).sum();
+        for (path, _, len) in entries {
+            if size <= self.max_size {
+                break;
+            }
+            tracing::debug!("Evicting `{}`", path.display());
+            tokio::fs::remove_file(&path)
+                .await
+                .map_err(|e| CacheError::Remove(path.clone(), e))?;
+            size -= len;
+        }
+        Ok(())
+    }
+}
+
+/// A tarball being written to a [`TarballCache`] as it is fetched
+#[derive(Debug)]
+pub(crate) struct PendingEntry {
+    cache: TarballCache,
+    url: Url,
+    path: PathBuf,
+    /// Unset once a write has failed, the entry is then discarded rather than committed
+    file: Option<std::fs::File>,
+}
+
+impl PendingEntry {
+    /// Append a chunk of the tarball
+    ///
+    /// Failing to write to the cache does not fail the fetch, the entry is discarded instead.
+    pub(crate) fn write(&mut self, chunk: &[u8]) {
+        if let Some(file) = &mut self.file {
+            if let Err(err) = file.write_all(chunk) {
+                tracing::warn!(
+                    "Could not write to the cache at `{}`, continuing without caching: {err}",
+                    self.path.display()
+                );
+                self.file = None;
+            }
+        }
+    }
+
+    /// Start the tarball again from the beginning, discarding what was written so far
+    pub(crate) fn restart(&mut self) {
+        if let Some(file) = &mut self.file {
+            if let Err(err) = file.set_len(0).and_then(|()| file.rewind()) {
+                tracing::warn!(
+                    "Could not truncate `{}`, continuing without caching: {err}",
+                    self.path.display()
+                );
+                self.file = None;
+            }
+        }
+    }
+
+    /// Add the tarball to the cache, now it has been verified to have the digest `sha256`
+    ///
+    /// The written tarball is hashed again, as it is only useful to the cache if it is exactly what was verified.
+    pub(crate) async fn commit(mut self, sha256: &Sha256Digest) -> Result<(), CacheError> {
+        match self.store(sha256).await {
+            Ok(Some(dest)) => {
+                tracing::debug!("Cached `{}` at `{}`", self.url, dest.display());
+                self.cache.evict().await
+            },
+            Ok(None) => {
+                self.discard().await;
+                Ok(())
+            },
+            Err(err) => {
+                self.discard().await;
+                Err(err)
+            },
+        }
+    }
+
+    /// Move the written tarball into place, if it was completely written
+    async fn store(&mut self, sha256: &Sha256Digest) -> Result<Option<PathBuf>, CacheError> {
+        let Some(mut file) = self.file.take() else {
+            return Ok(None);
+        };
+        file.flush()
+            .map_err(|e| CacheError::Write(self.path.clone(), e))?;
+        drop(file);
+
+        if hash_file(&self.path).await? != *sha256 {
+            return Err(CacheError::Incomplete(self.path.clone()));
+        }
+
+        let dest = self.cache.entry_path(&self.url, sha256);
+        tokio::fs::rename(&self.path, &dest)
+            .await
+            .map_err(|e| CacheError::Rename(self.path.clone(), dest.clone(), e))?;
+        Ok(Some(dest))
+    }
+
+    /// Remove what has been written so far
+    pub(crate) async fn discard(self) {
+        drop(self.file);
+        tokio::fs::remove_file(&self.path).await.ok();
+    }
+}
+
+async fn hash_file(path: &Path) -> Result<Sha256Digest, CacheError> {
+    use tokio::io::AsyncReadExt;
+
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| CacheError::Read(path.to_path_buf(), e))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = file
+            .read(&mut buf)
+            .await
+            .map_err(|e| CacheError::Read(path.to_path_buf(), e))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(Sha256Digest::from_hasher(hasher))
+}
+
+fn touch(path: &Path) -> std::io::Result<()> {
+    std::fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum CacheError {
+    #[error("Creating cache directory `{0}`")]
+    CreateDirectory(PathBuf, #[source] std::io::Error),
+    #[error("Reading cache directory `{0}`")]
+    ReadDirectory(PathBuf, #[source] std::io::Error),
+    #[error("Reading `{0}`")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("Writing `{0}`")]
+    Write(PathBuf, #[source] std::io::Error),
+    #[error("Renaming `{0}` to `{1}`")]
+    Rename(PathBuf, PathBuf, #[source] std::io::Error),
+    #[error("Removing `{0}`")]
+    Remove(PathBuf, #[source] std::io::Error),
+    #[error("`{0}` does not match the tarball which was verified")]
+    Incomplete(PathBuf),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn insert(cache: &TarballCache, url: &Url, buf: &[u8]) -> eyre::Result<Sha256Digest> {
+        let sha256 = Sha256Digest::of(buf);
+        let mut pending = cache.pending(url).await?;
+        pending.write(buf);
+        pending.commit(&sha256).await?;
+        Ok(sha256)
+    }
+
+    #[tokio::test]
+    async fn keyed_by_url_and_digest() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let cache = TarballCache::new(temp_dir.path().join("cache"), 1024 * 1024);
+        let url = Url::parse("https://example.com/nix.tar.xz")?;
+        let other = Url::parse("https://example.org/nix.tar.xz")?;
+
+        let sha256 = Sha256Digest::of(b"Nix");
+        assert_eq!(cache.lookup(&url, Some(&sha256)).await, None);
+        insert(&cache, &url, b"Nix").await?;
+
+        let entry = cache.lookup(&url, Some(&sha256)).await.unwrap();
+        assert_eq!(tokio::fs::read(&entry.path).await?, b"Nix");
+        assert_eq!(
+            cache.lookup(&url, Some(&Sha256Digest::of(b"Lix"))).await,
+            None
+        );
+        assert_eq!(cache.lookup(&other, Some(&sha256)).await, None);
+        // Without a digest to verify it against, a cached tarball is never used
+        assert_eq!(cache.lookup(&url, None).await, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let cache = TarballCache::new(temp_dir.path(), 8);
+        let first = Url::parse("https://example.com/first.tar.xz")?;
+        let second = Url::parse("https://example.com/second.tar.xz")?;
+        let third = Url::parse("https://example.com/third.tar.xz")?;
+
+        let first_sha256 = insert(&cache, &first, b"1111").await?;
+        let second_sha256 = insert(&cache, &second, b"2222").await?;
+        for (url, sha256, age) in [(&first, &first_sha256, 60), (&second, &second_sha256, 30)] {
+            let entry = cache.lookup(url, Some(sha256)).await.unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&entry.path)?
+                .set_modified(SystemTime::now() - Duration::from_secs(age))?;
+        }
+        // Using the first makes the second the least recently used
+        cache.lookup(&first, Some(&first_sha256)).await.unwrap();
+        let third_sha256 = insert(&cache, &third, b"3333").await?;
+
+        assert!(cache.lookup(&first, Some(&first_sha256)).await.is_some());
+        assert_eq!(cache.lookup(&second, Some(&second_sha256)).await, None);
+        assert!(cache.lookup(&third, Some(&third_sha256)).await.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn discards_unverified_writes() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let cache = TarballCache::new(temp_dir.path(), 1024);
+        let url = Url::parse("https://example.com/nix.tar.xz")?;
+
+        let mut pending = cache.pending(&url).await?;
+        pending.write(b"Half of Nix");
+        let err = pending.commit(&Sha256Digest::of(b"Nix")).await.unwrap_err();
+
+        assert!(matches!(err, CacheError::Incomplete(_)), "{err:?}");
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,360 @@
+/*! Incremental receipt recording used by [`InstallPlan::resume`](crate::InstallPlan::resume)
+
+While a resumable install runs, each [`StatefulAction`](crate::action::StatefulAction) in the plan
+carries a [JSON Pointer](https://datatracker.ietf.org/doc/html/rfc6901) to its own location in the
+receipt. Whenever its [`ActionState`] changes the receipt is patched at that location and written
+back to disk, so an interrupted install leaves behind a receipt which is accurate down to the
+sub-actions of composite actions.
+*/
+
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tokio::{io::AsyncWriteExt, task::futures::TaskLocalFuture};
+
+use crate::{action::ActionState, NixInstallerError};
+
+/// The key each `StatefulAction` reads its receipt location from during deserialization
+pub(crate) const CHECKPOINT_KEY: &str = "checkpoint";
+
+tokio::task_local! {
+    /// The journal of the plan the current task is carrying out
+    static JOURNAL: Option<Arc<Journal>>;
+}
+
+/// The receipt of a resumable install, recording each state transition
+#[derive(Debug)]
+pub(crate) struct Journal {
+    path: PathBuf,
+    receipt: tokio::sync::Mutex<Value>,
+}
+
+impl Journal {
+    /// Start recording state transitions into the receipt at `path`, writing `receipt` immediately
+    pub(crate) async fn start(
+        path: impl AsRef<Path>,
+        receipt: Value,
+    ) -> Result<Arc<Self>, NixInstallerError> {
+        let path = path.as_ref().to_path_buf();
+        write(&path, &receipt).await?;
+        Ok(Arc::new(Self {
+            path,
+            receipt: tokio::sync::Mutex::new(receipt),
+        }))
+    }
+}
+
+/// Run `future`, recording the state transitions of its actions into `journal` if there is one
+///
+/// Tasks spawned by `future` do not inherit `journal`, they must be run in a scope of their own.
+pub(crate) fn scope<F: Future>(
+    journal: Option<Arc<Journal>>,
+    future: F,
+) -> TaskLocalFuture<Option<Arc<Journal>>, F> {
+    JOURNAL.scope(journal, future)
+}
+
+/// The journal of the current task, to keep recording from the tasks it spawns
+pub(crate) fn current() -> Option<Arc<Journal>> {
+    JOURNAL.try_with(Clone::clone).ok().flatten()
+}
+
+/// Record that the action at `pointer` transitioned to `state`
+///
+/// This is a no-op when the current task has no journal. Failures are logged rather than returned, an
+/// unrecorded transition only means a resumed install repeats a little more work.
+pub(crate) async fn record(pointer: &str, action: &(impl Serialize + ?Sized), state: ActionState) {
+    let Some(journal) = current() else {
+        return;
+    };
+    let action = match serde_json::to_value(action) {
+        Ok(action) => action,
+        Err(err) => {
+            tracing::warn!(%pointer, "Could not serialize action for receipt: {err}");
+            return;
+        },
+    };
+
+    let mut receipt = journal.receipt.lock().await;
+    match receipt.pointer_mut(pointer) {
+        Some(slot) => *slot = serde_json::json!({ "action": action, "state": state }),
+        None => {
+            tracing::warn!(%pointer, "Action was not found in receipt");
+            return;
+        },
+    }
+    if let Err(err) = write(&journal.path, &receipt).await {
+        tracing::warn!("Error saving receipt: {err:?}");
+    }
+}
+
+/// Atomically replace the receipt at `path`, skipping the write if its parent does not exist yet
+async fn write(path: &Path, receipt: &Value) -> Result<(), NixInstallerError> {
+    let parent = path.parent().unwrap_or_else(|| Path::new("/"));
+    if !parent.exists() {
+        tracing::trace!(
+            "Not recording receipt, `{}` does not exist yet",
+            parent.display()
+        );
+        return Ok(());
+    }
+    let buf = serde_json::to_string_pretty(receipt)?;
+    let temp_path = parent.join(format!(
+        ".{}.tmp",
+        path.file_name()
+            .map(|v| v.to_string_lossy())
+            .unwrap_or_default()
+    ));
+
+    let mut temp_file = tokio::fs::File::create(&temp_path)
+        .await
+        .map_err(|e| NixInstallerError::RecordingReceipt(temp_path.clone(), e))?;
+    temp_file
+        .write_all(format!("{buf}\n").as_bytes())
+        .await
+        .map_err(|e| NixInstallerError::RecordingReceipt(temp_path.clone(), e))?;
+    temp_file
+        .sync_all()
+        .await
+        .map_err(|e| NixInstallerError::RecordingReceipt(temp_path.clone(), e))?;
+    tokio::fs::rename(&temp_path, path)
+        .await
+        .map_err(|e| NixInstallerError::RecordingReceipt(path.to_path_buf(), e))?;
+    Ok(())
+}
+
+/// Tag every serialized `StatefulAction` in `value` with its own location in `value`
+pub(crate) fn annotate(value: &mut Value, pointer: &str) {
+    match value {
+        Value::Object(map) => {
+            let stateful = is_stateful(map);
+            for (key, child) in map.iter_mut() {
+                annotate(
+                    child,
+                    &format!("{pointer}/{}", key.replace('~', "~0").replace('/', "~1")),
+                );
+            }
+            if stateful {
+                map.insert(
+                    CHECKPOINT_KEY.to_string(),
+                    Value::String(pointer.to_string()),
+                );
+            }
+        },
+        Value::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                annotate(item, &format!("{pointer}/{index}"));
+            }
+        },
+        _ => (),
+    }
+}
+
+/// If any serialized `StatefulAction` in `value` was left in [`ActionState::Progress`]
+pub(crate) fn has_progress(value: &Value) -> bool {
+    match value {
+        Value::Object(map) => {
+            (is_stateful(map) && state(map) == Some(ActionState::Progress))
+                || map.values().any(has_progress)
+        },
+        Value::Array(items) => items.iter().any(has_progress),
+        _ => false,
+    }
+}
+
+/// Re-check actions in `receipt` which were left in [`ActionState::Progress`] against a `fresh`
+/// plan from the same planner
+///
+/// Composite actions keep their state so their completed sub-actions are not repeated, while
+/// sub-actions with no children of their own take the state their `plan` detected on the system.
+pub(crate) fn reconcile(receipt: &mut Value, fresh: &Value) {
+    match (receipt, fresh) {
+        (Value::Object(receipt), Value::Object(fresh)) => {
+            if is_stateful(receipt)
+                && state(receipt) == Some(ActionState::Progress)
+                && !receipt.get("action").is_some_and(contains_stateful)
+            {
+                if is_stateful(fresh) && receipt.get("action") == fresh.get("action") {
+                    if let Some(fresh_state) = fresh.get("state") {
+                        tracing::debug!(
+                            state = %fresh_state,
+                            "Re-checked action left in progress"
+                        );
+                        receipt.insert("state".to_string(), fresh_state.clone());
+                    }
+                }
+                return;
+            }
+            for (key, child) in receipt.iter_mut() {
+                if let Some(fresh_child) = fresh.get(key) {
+                    reconcile(child, fresh_child);
+                }
+            }
+        },
+        (Value::Array(receipt), Value::Array(fresh)) => {
+            for (child, fresh_child) in receipt.iter_mut().zip(fresh.iter()) {
+                reconcile(child, fresh_child);
+            }
+        },
+        _ => (),
+    }
+}
+
+fn is_stateful(map: &Map<String, Value>) -> bool {
+    map.contains_key("action") && state(map).is_some()
+}
+
+fn state(map: &Map<String, Value>) -> Option<ActionState> {
+    map.get("state")
+        .and_then(|v| serde_json::from_value(v.clone()).ok())
+}
+
+fn contains_stateful(value: &Value) -> bool {
+    match value {
+        Value::Object(map) => is_stateful(map) || map.values().any(contains_stateful),
+        Value::Array(items) => items.iter().any(contains_stateful),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+    use crate::action::{base::CreateDirectory, StatefulAction};
+
+    #[test]
+    fn annotate_tags_nested_actions() -> eyre::Result<()> {
+        let mut value = json!({
+            "actions": [{
+                "action": {
+                    "action": "composite",
+                    "child": { "action": { "path": "/a" }, "state": "Completed" },
+                },
+                "state": "Progress",
+            }],
+        });
+        annotate(&mut value, "");
+        assert_eq!(
+            value.pointer("/actions/0/checkpoint"),
+            Some(&json!("/actions/0"))
+        );
+        assert_eq!(
+            value.pointer("/actions/0/action/child/checkpoint"),
+            Some(&json!("/actions/0/action/child"))
+        );
+        assert_eq!(value.pointer("/actions/0/action/checkpoint"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn annotated_actions_deserialize_with_checkpoint() -> eyre::Result<()> {
+        let mut value = json!({
+            "action": {
+                "path": "/nix",
+                "user": null,
+                "group": null,
+                "mode": 493,
+                "is_mountpoint": false,
+                "force_prune_on_revert": false,
+            },
+            "state": "Uncompleted",
+        });
+        annotate(&mut value, "/actions/1");
+        let action: StatefulAction<CreateDirectory> = serde_json::from_value(value)?;
+        assert_eq!(action.checkpoint.as_deref(), Some("/actions/1"));
+        // The location is never written back into the receipt
+        assert!(serde_json::to_value(&action)?.get(CHECKPOINT_KEY).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn records_into_the_journal_of_its_scope() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let receipt = json!({ "actions": [{ "action": {}, "state": "Uncompleted" }] });
+        let first_path = temp_dir.path().join("first.json");
+        let second_path = temp_dir.path().join("second.json");
+        let first = Journal::start(&first_path, receipt.clone()).await?;
+        let second = Journal::start(&second_path, receipt.clone()).await?;
+
+        let (a, b, c) = (
+            json!({ "path": "/a" }),
+            json!({ "path": "/b" }),
+            json!({ "path": "/c" }),
+        );
+        tokio::join!(
+            scope(
+                Some(first),
+                record("/actions/0", &a, ActionState::Completed)
+            ),
+            scope(
+                Some(second),
+                record("/actions/0", &b, ActionState::Progress)
+            ),
+        );
+        // Outside of a scope nothing is recorded
+        record("/actions/0", &c, ActionState::Completed).await;
+
+        let first: Value = serde_json::from_str(&tokio::fs::read_to_string(&first_path).await?)?;
+        assert_eq!(
+            first.pointer("/actions/0"),
+            Some(&json!({ "action": { "path": "/a" }, "state": "Completed" }))
+        );
+        let second: Value = serde_json::from_str(&tokio::fs::read_to_string(&second_path).await?)?;
+        assert_eq!(
+            second.pointer("/actions/0"),
+            Some(&json!({ "action": { "path": "/b" }, "state": "Progress" }))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reconcile_rechecks_progress() -> eyre::Result<()> {
+        let mut receipt = json!([
+            { "action": { "path": "/a" }, "state": "Completed" },
+            {
+                "action": {
+                    "first": { "action": { "path": "/b" }, "state": "Completed" },
+                    "second": { "action": { "path": "/c" }, "state": "Progress" },
+                    "third": { "action": { "path": "/d" }, "state": "Progress" },
+                },
+                "state": "Progress",
+            },
+        ]);
+        let fresh = json!([
+            { "action": { "path": "/a" }, "state": "Completed" },
+            {
+                "action": {
+                    "first": { "action": { "path": "/b" }, "state": "Uncompleted" },
+                    "second": { "action": { "path": "/c" }, "state": "Completed" },
+                    "third": { "action": { "path": "/different" }, "state": "Completed" },
+                },
+                "state": "Uncompleted",
+            },
+        ]);
+        assert!(has_progress(&receipt));
+        reconcile(&mut receipt, &fresh);
+        assert_eq!(receipt.pointer("/1/state"), Some(&json!("Progress")));
+        assert_eq!(
+            receipt.pointer("/1/action/first/state"),
+            Some(&json!("Completed"))
+        );
+        assert_eq!(
+            receipt.pointer("/1/action/second/state"),
+            Some(&json!("Completed"))
+        );
+        // Mismatched actions are left to be executed again
+        assert_eq!(
+            receipt.pointer("/1/action/third/state"),
+            Some(&json!("Progress"))
+        );
+        assert!(has_progress(&receipt));
+        Ok(())
+    }
+}
@@ -46,9 +46,11 @@ impl CommandExecute for NixInstallerCli {
         match subcommand {
             NixInstallerSubcommand::Plan(plan) => plan.execute().await,
             NixInstallerSubcommand::SelfTest(self_test) => self_test.execute().await,
+            NixInstallerSubcommand::Verify(verify) => verify.execute().await,
             NixInstallerSubcommand::Install(install) => install.execute().await,
             NixInstallerSubcommand::Repair(restore_shell) => restore_shell.execute().await,
             NixInstallerSubcommand::Uninstall(revert) => revert.execute().await,
+            NixInstallerSubcommand::Bundle(bundle) => bundle.execute().await,
         }
     }
 }
@@ -102,7 +104,12 @@ pub fn ensure_root() -> eyre::Result<()> {
         let mut arg_vec_cstring = vec![];
         arg_vec_cstring.push(sudo_cstring.clone());
 
+        // The token for authenticated fetches may be in a variable of the user's choosing
+        let auth_token_env = std::env::var("NIX_INSTALLER_AUTH_TOKEN_ENV")
+            .unwrap_or_else(|_| crate::network::DEFAULT_AUTH_TOKEN_ENV.to_string());
+
         let mut env_list = vec![];
+        let mut redacted_env_list = vec![];
         for (key, value) in std::env::vars() {
             let preserve = match key.as_str() {
                 // Rust logging/backtrace bits we use
@@ -115,10 +122,16 @@ pub fn ensure_root() -> eyre::Result<()> {
                 "HTTP_PROXY" | "http_proxy" | "HTTPS_PROXY" | "https_proxy" => true,
                 // Our own environments
                 key if key.starts_with("NIX_INSTALLER") => true,
+                key if key == auth_token_env => true,
                 _ => false,
             };
             if preserve {
                 env_list.push(format!("{key}={value}"));
+                if key == auth_token_env {
+                    redacted_env_list.push(format!("{key}=<redacted>"));
+                } else {
+                    redacted_env_list.push(format!("{key}={value}"));
+                }
             }
         }
 
@@ -127,24 +140,27 @@ pub fn ensure_root() -> eyre::Result<()> {
             // Normally `sudo` would erase those envs, so we detect and pass that along specifically to avoid having to pass around
             // a bunch of environment variables
             env_list.push("NIX_INSTALLER_CI=1".to_string());
+            redacted_env_list.push("NIX_INSTALLER_CI=1".to_string());
         }
 
         if !env_list.is_empty() {
             arg_vec_cstring
                 .push(CString::new("env").wrap_err("Building a `env` argument for `sudo`")?);
-            for env in env_list {
-                arg_vec_cstring.push(
-                    CString::new(env.clone())
-                        .wrap_err_with(|| format!("Building a `{}` argument for `sudo`", env))?,
-                );
+            for (env, redacted_env) in env_list.into_iter().zip(&redacted_env_list) {
+                arg_vec_cstring.push(CString::new(env).wrap_err_with(|| {
+                    format!("Building a `{}` argument for `sudo`", redacted_env)
+                })?);
             }
         }
 
-        for arg in args {
-            arg_vec_cstring.push(CString::new(arg).wrap_err("Making arg into C string")?);
+        let args = args.collect::<Vec<_>>();
+        for arg in &args {
+            arg_vec_cstring.push(CString::new(arg.clone()).wrap_err("Making arg into C string")?);
         }
 
-        tracing::trace!("Execvp'ing `{sudo_cstring:?}` with args `{arg_vec_cstring:?}`");
+        tracing::trace!(
+            "Execvp'ing `{sudo_cstring:?}` with env `{redacted_env_list:?}` and args `{args:?}`"
+        );
         nix::unistd::execvp(&sudo_cstring, &arg_vec_cstring)
             .wrap_err("Executing `nix-installer` as `root` via `sudo`")?;
     }
@@ -0,0 +1,211 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::{ArgAction, Parser};
+use eyre::{eyre, WrapErr};
+use futures_util::StreamExt;
+use owo_colors::OwoColorize;
+use reqwest::Url;
+use tokio::io::AsyncRead;
+use tokio_util::io::StreamReader;
+
+use crate::{
+    bundle::BundleManifest,
+    cli::CommandExecute,
+    network::{NetworkPolicy, Streamed},
+    settings::{
+        default_nix_package_sha256, CommonSettings, Sha256Digest, UrlOrPath, UrlOrPathOrString,
+    },
+};
+
+/**
+Write a self-contained installer, which carries the Nix package so it can install without network access
+
+The bundle is this `nix-installer` with the Nix package, and any `--extra-conf`, appended to it.
+When installing, the bundle's Nix package is used before any other, and its `--extra-conf` is added
+to any given at install time.
+*/
+#[derive(Debug, Parser)]
+pub struct Bundle {
+    /// Where to write the bundle
+    #[clap(long, env = "NIX_INSTALLER_BUNDLE_OUT")]
+    pub out: PathBuf,
+
+    /// The Nix package to embed, by default the Nix package for this host
+    #[clap(long, env = "NIX_INSTALLER_NIX_PACKAGE_URL", value_parser = clap::value_parser!(UrlOrPath))]
+    pub nix_package_url: Option<UrlOrPath>,
+
+    /// The SHA-256 digest the Nix package must match
+    ///
+    /// When this is unset, a default Nix package URL is checked against the digest published next to
+    /// it. Any other Nix package is embedded unverified.
+    #[clap(long, env = "NIX_INSTALLER_NIX_PACKAGE_SHA256")]
+    pub nix_package_sha256: Option<Sha256Digest>,
+
+    /// Extra configuration lines for `/etc/nix.conf` to embed
+    #[clap(long, action = ArgAction::Append, num_args = 0.., env = "NIX_INSTALLER_EXTRA_CONF")]
+    pub extra_conf: Vec<UrlOrPathOrString>,
+
+    /// The proxy to use (if any), valid proxy bases are `https://$URL`, `http://$URL` and `socks5://$URL`
+    #[clap(long, env = "NIX_INSTALLER_PROXY")]
+    pub proxy: Option<Url>,
+
+    /// An SSL cert to use (if any), used for fetching Nix and `--extra-conf`
+    #[clap(long, env = "NIX_INSTALLER_SSL_CERT_FILE")]
+    pub ssl_cert_file: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub network: NetworkPolicy,
+}
+
+#[async_trait::async_trait]
+impl CommandExecute for Bundle {
+    #[tracing::instrument(level = "debug", skip_all, fields())]
+    async fn execute(self) -> eyre::Result<ExitCode> {
+        let Self {
+            out,
+            nix_package_url,
+            nix_package_sha256,
+            extra_conf,
+            proxy,
+            ssl_cert_file,
+            network,
+        } = self;
+
+        let nix_package_url = match nix_package_url {
+            Some(nix_package_url) => nix_package_url,
+            None => CommonSettings::default()
+                .await?
+                .nix_package_url
+                .into_iter()
+                .next()
+                .ok_or_else(|| eyre!("No Nix package URL for this host"))?,
+        };
+        let client = network
+            .client(proxy.as_ref(), ssl_cert_file.as_deref())
+            .await?;
+
+        let expected = match nix_package_sha256 {
+            Some(nix_package_sha256) => Some(nix_package_sha256),
+            None => {
+                default_nix_package_sha256(
+                    &nix_package_url,
+                    &network,
+                    proxy.as_ref(),
+                    ssl_cert_file.as_deref(),
+                )
+                .await?
+            },
+        };
+
+        let mut extra_conf_text = vec![];
+        for extra in extra_conf {
+            let buf = match &extra {
+                UrlOrPathOrString::Url(url) => {
+                    String::from_utf8_lossy(&fetch(&network, &client, url).await?).into_owned()
+                },
+                UrlOrPathOrString::Path(path) => tokio::fs::read_to_string(path)
+                    .await
+                    .wrap_err_with(|| format!("Reading `{}`", path.display()))?,
+                UrlOrPathOrString::String(string) => string.clone(),
+            };
+            extra_conf_text.push(buf);
+        }
+
+        tracing::info!("Fetching `{nix_package_url}`");
+        let nix_package = open(&network, &client, &nix_package_url).await?;
+        let executable = std::env::current_exe().wrap_err("Finding the running executable")?;
+        let written = crate::bundle::write(
+            &executable,
+            &out,
+            nix_package_url.to_string(),
+            nix_package,
+            &extra_conf_text,
+        )
+        .await;
+        let BundleManifest { nix_package, .. } = match written {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                // Don't leave a partially written bundle behind
+                let _ = tokio::fs::remove_file(&out).await;
+                return Err(e.into());
+            },
+        };
+
+        if let Some(expected) = expected {
+            if nix_package.sha256 != expected {
+                tokio::fs::remove_file(&out)
+                    .await
+                    .wrap_err_with(|| format!("Removing `{}`", out.display()))?;
+                eprintln!(
+                    "{}",
+                    format!(
+                        "`{nix_package_url}` has the SHA-256 digest `{}`, but `{expected}` was expected",
+                        nix_package.sha256
+                    )
+                    .red()
+                );
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+
+        tracing::info!(
+            sha256 = %nix_package.sha256,
+            "Wrote a bundle of `{nix_package_url}` to `{}`",
+            out.display()
+        );
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+/// Open the Nix package at `nix_package_url`, streaming it if it is fetched
+async fn open(
+    network: &NetworkPolicy,
+    client: &reqwest::Client,
+    nix_package_url: &UrlOrPath,
+) -> eyre::Result<Box<dyn AsyncRead + Unpin + Send>> {
+    let path = match nix_package_url {
+        UrlOrPath::Url(url) => match url.scheme() {
+            "https" | "http" => {
+                let (stream, _) = network.stream(client.clone(), url.clone()).await?;
+                // The bundle is written as the Nix package arrives, so it cannot start over
+                let stream = stream.map(|streamed| match streamed {
+                    Ok(Streamed::Chunk(chunk)) => Ok(chunk),
+                    Ok(Streamed::Restart) => Err(std::io::Error::other(
+                        "The Nix package changed while it was being fetched",
+                    )),
+                    Err(e) => Err(std::io::Error::other(e)),
+                });
+                return Ok(Box::new(StreamReader::new(Box::pin(stream))));
+            },
+            "file" => PathBuf::from(url.path()),
+            _ => return Err(eyre!("Unknown URL scheme in `{url}`")),
+        },
+        UrlOrPath::Path(path) => path.clone(),
+        UrlOrPath::Embedded => {
+            let (path, manifest) = crate::bundle::current()
+                .await?
+                .ok_or_else(|| eyre!("`nix-installer` is not running from a bundle"))?;
+            return Ok(Box::new(
+                crate::bundle::open_payload(&path, &manifest.nix_package).await?,
+            ));
+        },
+    };
+    let file = tokio::fs::File::open(&path)
+        .await
+        .wrap_err_with(|| format!("Opening `{}`", path.display()))?;
+    Ok(Box::new(file))
+}
+
+async fn fetch(
+    network: &NetworkPolicy,
+    client: &reqwest::Client,
+    url: &Url,
+) -> eyre::Result<Vec<u8>> {
+    match url.scheme() {
+        "https" | "http" => Ok(network.fetch(client, url).await?.to_vec()),
+        "file" => tokio::fs::read(url.path())
+            .await
+            .wrap_err_with(|| format!("Reading `{}`", url.path())),
+        _ => Err(eyre!("Unknown URL scheme in `{url}`")),
+    }
+}
@@ -14,7 +14,7 @@ use crate::{
     error::HasExpectedErrors,
     plan::RECEIPT_LOCATION,
     planner::Planner,
-    settings::CommonSettings,
+    settings::{in_root, CommonSettings},
     BuiltinPlanner, InstallPlan, NixInstallerError,
 };
 use clap::{ArgAction, Parser};
@@ -61,6 +61,16 @@ pub struct Install {
     )]
     pub explain: bool,
 
+    /// Record the receipt after every step, and continue an interrupted install from its receipt
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_RESUME",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        global = true
+    )]
+    pub resume: bool,
+
     #[clap(env = "NIX_INSTALLER_PLAN")]
     pub plan: Option<PathBuf>,
 
@@ -78,25 +88,28 @@ impl CommandExecute for Install {
             planner,
             settings,
             explain,
+            resume,
         } = self;
 
         ensure_root()?;
 
-        let existing_receipt: Option<InstallPlan> = match Path::new(RECEIPT_LOCATION).exists() {
+        let receipt_location = in_root(&settings.root, RECEIPT_LOCATION);
+        let existing_receipt: Option<InstallPlan> = match receipt_location.exists() {
             true => {
                 tracing::trace!("Reading existing receipt");
-                let install_plan_string = tokio::fs::read_to_string(&RECEIPT_LOCATION)
+                let install_plan_string = tokio::fs::read_to_string(&receipt_location)
                     .await
                     .wrap_err("Reading plan")?;
                 Some(
-                    serde_json::from_str(&install_plan_string).wrap_err_with(|| {
-                        format!("Unable to parse existing receipt `{RECEIPT_LOCATION}`, it may be from an incompatible version of `nix-installer`. Try running `/nix/nix-installer uninstall`, then installing again.")
+                    InstallPlan::from_receipt(&install_plan_string).wrap_err_with(|| {
+                        format!("Unable to parse existing receipt `{}`, it may be from an incompatible version of `nix-installer`. Try running `/nix/nix-installer uninstall`, then installing again.", receipt_location.display())
                     })?,
                 )
             },
             false => None,
         };
 
+        let receipt_location = receipt_location.display();
         let uninstall_command = match Path::new("/nix/nix-installer").exists() {
             true => "/nix/nix-installer uninstall".into(),
             false => format!("curl --proto '=https' --tlsv1.2 -sSf -L https://install.determinate.systems/nix/tag/v{} | sh -s -- uninstall", env!("CARGO_PKG_VERSION")),
@@ -114,22 +127,25 @@ impl CommandExecute for Install {
                                 format!("\
                                     {e}\n\
                                     \n\
-                                    Found existing plan in `{RECEIPT_LOCATION}` which was created by a version incompatible `nix-installer`.\n\
+                                    Found existing plan in `{receipt_location}` which was created by a version incompatible `nix-installer`.\n\
                                     {EXISTING_INCOMPATIBLE_PLAN_GUIDANCE}\n\
                                 ").red()
                             );
                             return Ok(ExitCode::FAILURE)
                         }
                         if existing_receipt.planner.typetag_name() != chosen_planner.typetag_name() {
-                            eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}` which used a different planner, try uninstalling the existing install with `{uninstall_command}`").red());
+                            eprintln!("{}", format!("Found existing plan in `{receipt_location}` which used a different planner, try uninstalling the existing install with `{uninstall_command}`").red());
                             return Ok(ExitCode::FAILURE)
                         }
                         if existing_receipt.planner.settings().map_err(|e| eyre!(e))? != chosen_planner.settings().map_err(|e| eyre!(e))? {
-                            eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}` which used different planner settings, try uninstalling the existing install with `{uninstall_command}`").red());
+                            eprintln!("{}", format!("Found existing plan in `{receipt_location}` which used different planner settings, try uninstalling the existing install with `{uninstall_command}`").red());
                             return Ok(ExitCode::FAILURE)
                         }
-                        eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}`, with the same settings, already completed. Try uninstalling (`{uninstall_command}`) and reinstalling if Nix isn't working").red());
-                        return Ok(ExitCode::SUCCESS)
+                        if existing_receipt.actions.iter().all(|v| v.state == ActionState::Completed) || !resume {
+                            eprintln!("{}", format!("Found existing plan in `{receipt_location}`, with the same settings, already completed. Try uninstalling (`{uninstall_command}`) and reinstalling if Nix isn't working").red());
+                            return Ok(ExitCode::SUCCESS)
+                        }
+                        existing_receipt
                     },
                     None => {
                         let res = planner.plan().await;
@@ -150,7 +166,7 @@ impl CommandExecute for Install {
                 let install_plan_string = tokio::fs::read_to_string(&plan_path)
                 .await
                 .wrap_err("Reading plan")?;
-                serde_json::from_str(&install_plan_string)?
+                InstallPlan::from_receipt(&install_plan_string)?
             },
             (None, None) => {
                 let builtin_planner = BuiltinPlanner::from_common_settings(settings.clone())
@@ -165,22 +181,22 @@ impl CommandExecute for Install {
                                 format!("\
                                     {e}\n\
                                     \n\
-                                    Found existing plan in `{RECEIPT_LOCATION}` which was created by a version incompatible `nix-installer`.\n\
+                                    Found existing plan in `{receipt_location}` which was created by a version incompatible `nix-installer`.\n\
                                     {EXISTING_INCOMPATIBLE_PLAN_GUIDANCE}\n\
                                 ").red()
                             );
                             return Ok(ExitCode::FAILURE)
                         }
                         if existing_receipt.planner.typetag_name() != builtin_planner.typetag_name() {
-                            eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}` which used a different planner, try uninstalling the existing install with `{uninstall_command}`").red());
+                            eprintln!("{}", format!("Found existing plan in `{receipt_location}` which used a different planner, try uninstalling the existing install with `{uninstall_command}`").red());
                             return Ok(ExitCode::FAILURE)
                         }
                         if existing_receipt.planner.settings().map_err(|e| eyre!(e))? != builtin_planner.settings().map_err(|e| eyre!(e))? {
-                            eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}` which used different planner settings, try uninstalling the existing install with `{uninstall_command}`").red());
+                            eprintln!("{}", format!("Found existing plan in `{receipt_location}` which used different planner settings, try uninstalling the existing install with `{uninstall_command}`").red());
                             return Ok(ExitCode::FAILURE)
                         }
                         if existing_receipt.actions.iter().all(|v| v.state == ActionState::Completed) {
-                            eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}`, with the same settings, already completed. Try uninstalling (`{uninstall_command}`) and reinstalling if Nix isn't working").yellow());
+                            eprintln!("{}", format!("Found existing plan in `{receipt_location}`, with the same settings, already completed. Try uninstalling (`{uninstall_command}`) and reinstalling if Nix isn't working").yellow());
                             return Ok(ExitCode::SUCCESS)
                         }
                         existing_receipt
@@ -235,10 +251,18 @@ impl CommandExecute for Install {
 
         let (tx, rx1) = signal_channel().await?;
 
-        match install_plan.install(rx1).await {
+        let res = if resume {
+            install_plan.resume(rx1).await
+        } else {
+            install_plan.install(rx1).await
+        };
+
+        match res {
             Err(err) => {
                 // Attempt to copy self to the store if possible, but since the install failed, this might not work, that's ok.
-                copy_self_to_nix_dir().await.ok();
+                copy_self_to_nix_dir(&install_plan.planner.root())
+                    .await
+                    .ok();
 
                 if !no_confirm {
                     let mut was_expected = false;
@@ -313,7 +337,7 @@ impl CommandExecute for Install {
                 }
             },
             Ok(_) => {
-                copy_self_to_nix_dir()
+                copy_self_to_nix_dir(&install_plan.planner.root())
                     .await
                     .wrap_err("Copying `nix-installer` to `/nix/nix-installer`")?;
                 println!(
@@ -337,9 +361,10 @@ impl CommandExecute for Install {
 }
 
 #[tracing::instrument(level = "debug")]
-async fn copy_self_to_nix_dir() -> Result<(), std::io::Error> {
+async fn copy_self_to_nix_dir(root: &Path) -> Result<(), std::io::Error> {
     let path = std::env::current_exe()?;
-    tokio::fs::copy(path, "/nix/nix-installer").await?;
-    tokio::fs::set_permissions("/nix/nix-installer", PermissionsExt::from_mode(0o0755)).await?;
+    let dest = in_root(root, "/nix/nix-installer");
+    tokio::fs::copy(path, &dest).await?;
+    tokio::fs::set_permissions(&dest, PermissionsExt::from_mode(0o0755)).await?;
     Ok(())
 }
@@ -8,6 +8,10 @@ mod uninstall;
 use uninstall::Uninstall;
 mod self_test;
 use self_test::SelfTest;
+mod verify;
+use verify::Verify;
+mod bundle;
+use bundle::Bundle;
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, clap::Subcommand)]
@@ -16,5 +20,7 @@ pub enum NixInstallerSubcommand {
     Repair(Repair),
     Uninstall(Uninstall),
     SelfTest(SelfTest),
+    Verify(Verify),
     Plan(Plan),
+    Bundle(Bundle),
 }
@@ -31,7 +31,7 @@ impl CommandExecute for Repair {
 
         ensure_root()?;
 
-        let mut reconfigure = ConfigureShellProfile::plan(ShellProfileLocations::default())
+        let mut reconfigure = ConfigureShellProfile::plan(ShellProfileLocations::default(), "/")
             .await
             .map_err(PlannerError::Action)?
             .boxed();
@@ -0,0 +1,112 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use crate::{
+    action::{Verification, VerificationReport},
+    error::HasExpectedErrors,
+    plan::RECEIPT_LOCATION,
+    InstallPlan,
+};
+use clap::{ArgAction, Parser};
+use eyre::WrapErr;
+use owo_colors::OwoColorize;
+
+use crate::cli::CommandExecute;
+
+/**
+Check that a previously `nix-installer` installed Nix has not drifted from its receipt
+
+Exits with a failure if the effect of any completed action no longer holds on the system.
+*/
+#[derive(Debug, Parser)]
+pub struct Verify {
+    /// Emit the verification report as JSON
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_JSON",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub json: bool,
+
+    #[clap(default_value = RECEIPT_LOCATION)]
+    pub receipt: PathBuf,
+}
+
+#[async_trait::async_trait]
+impl CommandExecute for Verify {
+    #[tracing::instrument(level = "debug", skip_all, fields())]
+    async fn execute(self) -> eyre::Result<ExitCode> {
+        let Self { json, receipt } = self;
+
+        let install_receipt_string = tokio::fs::read_to_string(&receipt)
+            .await
+            .wrap_err("Reading receipt")?;
+        let plan = InstallPlan::from_receipt(&install_receipt_string)?;
+
+        let reports = match plan.verify().await {
+            Ok(reports) => reports,
+            Err(err) => {
+                if let Some(expected) = err.expected() {
+                    eprintln!("{}", expected.red());
+                    return Ok(ExitCode::FAILURE);
+                }
+                return Err(err)?;
+            },
+        };
+        let holds = reports.iter().all(|v| v.verification.holds());
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&reports)?);
+        } else {
+            let mut buf = String::new();
+            for report in &reports {
+                describe_report(&mut buf, report, 0);
+            }
+            print!("{buf}");
+            if holds {
+                println!("{}", "No drift from the receipt was found".green());
+            } else {
+                println!(
+                    "{}",
+                    format!("Drift from the receipt `{}` was found", receipt.display()).red()
+                );
+            }
+        }
+
+        Ok(if holds {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        })
+    }
+}
+
+fn describe_report(buf: &mut String, report: &VerificationReport, depth: usize) {
+    let indent = "  ".repeat(depth);
+    let status = match &report.verification {
+        Verification::Verified => "ok".green().to_string(),
+        Verification::Children { .. } if report.verification.holds() => "ok".green().to_string(),
+        Verification::Children { .. } | Verification::Drifted { .. } => "drifted".red().to_string(),
+        Verification::Failed { .. } => "failed".red().to_string(),
+        Verification::Unsupported => "unsupported".dimmed().to_string(),
+        Verification::Unchecked => "unchecked".yellow().to_string(),
+    };
+    buf.push_str(&format!(
+        "{indent}[{status}] {}: {}\n",
+        report.action, report.synopsis
+    ));
+    match &report.verification {
+        Verification::Drifted { drift } => {
+            for item in drift {
+                buf.push_str(&format!("{indent}  * {item}\n"));
+            }
+        },
+        Verification::Failed { error } => buf.push_str(&format!("{indent}  * {error}\n")),
+        Verification::Children { children } => {
+            for child in children {
+                describe_report(buf, child, depth + 1);
+            }
+        },
+        Verification::Verified | Verification::Unsupported | Verification::Unchecked => (),
+    }
+}
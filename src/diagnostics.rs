@@ -11,10 +11,16 @@ use os_release::OsRelease;
 use reqwest::Url;
 
 use crate::{
-    action::ActionError, parse_ssl_cert, planner::PlannerError, settings::InstallSettingsError,
+    action::ActionError,
+    network::{NetworkError, NetworkPolicy},
+    planner::PlannerError,
+    settings::InstallSettingsError,
     CertificateError, NixInstallerError,
 };
 
+/// How long sending a diagnostic may take, it is best effort so it should never hold up an install
+const DIAGNOSTIC_TIMEOUT: Duration = Duration::from_millis(3000);
+
 /// The static of an action attempt
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub enum DiagnosticStatus {
@@ -61,6 +67,8 @@ pub struct DiagnosticData {
     is_ci: bool,
     endpoint: Option<Url>,
     ssl_cert_file: Option<PathBuf>,
+    #[serde(default)]
+    network: NetworkPolicy,
     /// Generally this includes the [`strum::IntoStaticStr`] representation of the error, we take special care not to include parameters of the error (which may include secrets)
     failure_chain: Option<Vec<String>>,
 }
@@ -72,6 +80,7 @@ impl DiagnosticData {
         planner: String,
         configured_settings: Vec<String>,
         ssl_cert_file: Option<PathBuf>,
+        network: NetworkPolicy,
     ) -> Result<Self, DiagnosticError> {
         let endpoint = match endpoint {
             Some(endpoint) => diagnostic_endpoint_parser(&endpoint)?,
@@ -94,6 +103,7 @@ impl DiagnosticData {
             triple: target_lexicon::HOST.to_string(),
             is_ci,
             ssl_cert_file: ssl_cert_file.and_then(|v| v.canonicalize().ok()),
+            network,
             failure_chain: None,
         })
     }
@@ -145,6 +155,7 @@ impl DiagnosticData {
             is_ci,
             endpoint: _,
             ssl_cert_file: _,
+            network: _,
             failure_chain,
         } = self;
         DiagnosticReport {
@@ -178,21 +189,30 @@ impl DiagnosticData {
         match endpoint.scheme() {
             "https" | "http" => {
                 tracing::debug!("Sending diagnostic to `{endpoint}`");
-                let mut buildable_client = reqwest::Client::builder();
-                if let Some(ssl_cert_file) = &self.ssl_cert_file {
-                    let ssl_cert = parse_ssl_cert(ssl_cert_file).await.ok();
-                    if let Some(ssl_cert) = ssl_cert {
-                        buildable_client = buildable_client.add_root_certificate(ssl_cert);
-                    }
-                }
-                let client = buildable_client.build().map_err(DiagnosticError::Reqwest)?;
-
-                let res = client
-                    .post(endpoint.clone())
-                    .body(serialized)
-                    .header("Content-Type", "application/json")
-                    .timeout(Duration::from_millis(3000))
-                    .send()
+                // Diagnostics are never retried, an offline install shouldn't wait to report itself
+                let network = NetworkPolicy {
+                    connect_timeout: self
+                        .network
+                        .connect_timeout
+                        .min(DIAGNOSTIC_TIMEOUT.as_secs()),
+                    read_timeout: self.network.read_timeout.min(DIAGNOSTIC_TIMEOUT.as_secs()),
+                    retries: 0,
+                    ..self.network.clone()
+                };
+                let client = match network.client(None, self.ssl_cert_file.as_deref()).await {
+                    Ok(client) => client,
+                    // An unusable certificate shouldn't stop the diagnostic from being sent
+                    Err(_) => network.client(None, None).await?,
+                };
+
+                let res = network
+                    .send(&endpoint, || {
+                        client
+                            .post(endpoint.clone())
+                            .body(serialized.clone())
+                            .header("Content-Type", "application/json")
+                            .timeout(DIAGNOSTIC_TIMEOUT)
+                    })
                     .await;
 
                 if let Err(_err) = res {
@@ -242,6 +262,8 @@ pub enum DiagnosticError {
     ),
     #[error(transparent)]
     Certificate(#[from] CertificateError),
+    #[error(transparent)]
+    Network(#[from] NetworkError),
 }
 
 pub trait ErrorDiagnostic {
@@ -275,3 +297,34 @@ pub fn diagnostic_endpoint_validator(input: &str) -> Result<String, DiagnosticEr
     let _ = diagnostic_endpoint_parser(input)?;
     Ok(input.to_string())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn gives_up_quickly() -> eyre::Result<()> {
+        // Nothing listens on a port which was bound then released
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let endpoint = format!("http://{}/diagnostics", listener.local_addr()?);
+        drop(listener);
+
+        let diagnostic_data = DiagnosticData::new(
+            None,
+            Some(endpoint),
+            "linux".into(),
+            vec![],
+            None,
+            NetworkPolicy::default(),
+        )?;
+        let started = std::time::Instant::now();
+        diagnostic_data
+            .send(DiagnosticAction::Install, DiagnosticStatus::Failure)
+            .await?;
+
+        // With the default retries, the backoff alone would take 15s
+        assert!(started.elapsed() < DIAGNOSTIC_TIMEOUT * 2);
+
+        Ok(())
+    }
+}
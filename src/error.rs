@@ -1,10 +1,13 @@
+// The derived implementations of `NixInstallerError` use its deprecated variants
+#![allow(deprecated)]
+
 use std::{error::Error, path::PathBuf};
 
 use semver::Version;
 
 use crate::{
-    action::ActionError, planner::PlannerError, self_test::SelfTestError,
-    settings::InstallSettingsError,
+    action::ActionError, migration::MigrationError, planner::PlannerError,
+    self_test::SelfTestError, settings::InstallSettingsError,
 };
 
 /// An error occurring during a call defined in this crate
@@ -83,14 +86,43 @@ pub enum NixInstallerError {
         crate::diagnostics::DiagnosticError,
     ),
     /// Could not parse the value as a version requirement in order to ensure it's compatible
+    #[deprecated(
+        note = "Plans are no longer checked against the version of `nix-installer`, only against its receipt schema version, see `IncompatibleSchemaVersion`"
+    )]
     #[error("Could not parse `{0}` as a version requirement in order to ensure it's compatible")]
     InvalidVersionRequirement(String, semver::Error),
     /// Could not parse `nix-installer`'s version as a valid version according to Semantic Versioning, therefore the plan version compatibility cannot be checked
     #[error("Could not parse `nix-installer`'s version `{0}` as a valid version according to Semantic Versioning, therefore the plan version compatibility cannot be checked")]
     InvalidCurrentVersion(String, semver::Error),
+    /// An error while migrating a receipt to the current schema version
+    #[error("Migrating receipt")]
+    Migration(
+        #[from]
+        #[source]
+        MigrationError,
+    ),
     /// This version of `nix-installer` is not compatible with this plan's version
+    #[deprecated(
+        note = "Plans are no longer checked against the version of `nix-installer`, only against its receipt schema version, see `IncompatibleSchemaVersion`"
+    )]
     #[error("`nix-installer` version `{}` is not compatible with this plan's version `{}`", .binary, .plan)]
     IncompatibleVersion { binary: Version, plan: Version },
+    /// This version of `nix-installer` is not compatible with this plan's receipt schema version
+    #[error("`nix-installer` uses receipt schema version `{}`, which is not compatible with this plan's schema version `{}`", .binary, .plan)]
+    IncompatibleSchemaVersion { binary: u32, plan: u32 },
+    /// The plan does not list the dependencies of each of its actions
+    #[error("Plan has {actions} actions, but lists the dependencies of {dependencies} actions")]
+    DependencyCountMismatch { actions: usize, dependencies: usize },
+    /// An action in the plan depends on itself or an action planned after it
+    #[error("Action {action} in the plan depends on action {dependency}, actions may only depend on actions planned before them")]
+    InvalidDependency { action: usize, dependency: usize },
+    /// A task executing or reverting an action panicked or was cancelled
+    #[error("Joining spawned async task")]
+    Join(
+        #[source]
+        #[from]
+        tokio::task::JoinError,
+    ),
 }
 
 pub(crate) trait HasExpectedErrors: std::error::Error + Sized + Send + Sync {
@@ -115,6 +147,13 @@ impl HasExpectedErrors for NixInstallerError {
             this @ NixInstallerError::IncompatibleVersion { binary: _, plan: _ } => {
                 Some(Box::new(this))
             },
+            NixInstallerError::Migration(migration_error) => migration_error.expected(),
+            this @ NixInstallerError::IncompatibleSchemaVersion { binary: _, plan: _ } => {
+                Some(Box::new(this))
+            },
+            NixInstallerError::DependencyCountMismatch { .. } => None,
+            NixInstallerError::InvalidDependency { .. } => None,
+            NixInstallerError::Join(_) => None,
             #[cfg(feature = "diagnostics")]
             NixInstallerError::Diagnostic(_) => None,
         }
@@ -0,0 +1,129 @@
+/*! Typed progress events emitted while an [`InstallPlan`](crate::InstallPlan) is carried out
+
+Front-ends can pass an [`EventSender`] to [`InstallPlan::install_with_events`](crate::InstallPlan::install_with_events)
+(or [`uninstall_with_events`](crate::InstallPlan::uninstall_with_events)) and render progress from the
+[`InstallEvent`]s it receives, instead of scraping the `tracing` output.
+
+```rust,no_run
+use nix_installer::{event::InstallEvent, InstallPlan};
+
+# async fn install_with_progress() -> color_eyre::Result<()> {
+let mut plan = InstallPlan::default().await?;
+let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+let progress = tokio::spawn(async move {
+    while let Some(event) = receiver.recv().await {
+        if let InstallEvent::ActionFinished { index, synopsis, .. } = event {
+            println!("Finished step {index}: {synopsis}");
+        }
+    }
+});
+
+plan.install_with_events(None, sender).await?;
+progress.await?;
+# Ok(())
+# }
+```
+*/
+
+use std::future::Future;
+
+use serde::Serialize;
+use tokio::{sync::mpsc::UnboundedSender, task::futures::TaskLocalFuture};
+
+use crate::{
+    action::{ActionState, ActionTag},
+    self_test::Shell,
+};
+
+/// Where [`InstallEvent`]s are sent to
+pub type EventSender = UnboundedSender<InstallEvent>;
+
+tokio::task_local! {
+    /// Where the current plan's events are sent, so concurrent plans each have their own
+    static SINK: Option<EventSender>;
+}
+
+/// What an [`InstallPlan`](crate::InstallPlan) is doing with its actions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Install,
+    Uninstall,
+}
+
+/// Something which happened while an [`InstallPlan`](crate::InstallPlan) was carried out
+#[non_exhaustive]
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum InstallEvent {
+    /// The plan started, `actions` is the number of planned actions
+    PlanStarted {
+        operation: Operation,
+        actions: usize,
+    },
+    /// The planned action at `index` started
+    ActionStarted {
+        index: usize,
+        action: ActionTag,
+        synopsis: String,
+    },
+    /// The planned action at `index` finished, leaving it in `state`
+    ///
+    /// If it failed, `error` describes why.
+    ActionFinished {
+        index: usize,
+        action: ActionTag,
+        synopsis: String,
+        state: ActionState,
+        error: Option<String>,
+    },
+    /// An action, including the sub-actions of composite actions, transitioned to `state`
+    ActionStateChanged {
+        action: ActionTag,
+        synopsis: String,
+        state: ActionState,
+    },
+    /// `received` bytes of `url` were downloaded, out of `total` if the size is known
+    DownloadProgress {
+        url: String,
+        received: u64,
+        total: Option<u64>,
+    },
+    /// The self-test of `shell` finished, if it failed `error` describes why
+    SelfTest { shell: Shell, error: Option<String> },
+    /// The plan finished, if it failed or was cancelled `error` describes why
+    PlanFinished {
+        operation: Operation,
+        error: Option<String>,
+    },
+}
+
+/// Run `future`, sending the events it emits to `sender` if there is one
+///
+/// Tasks spawned by `future` do not inherit `sender`, they must be run in a scope of their own.
+pub(crate) fn scope<F: Future>(
+    sender: Option<EventSender>,
+    future: F,
+) -> TaskLocalFuture<Option<EventSender>, F> {
+    SINK.scope(sender, future)
+}
+
+/// The sender of the events emitted by the current task, to keep sending events from the tasks it spawns
+pub(crate) fn current() -> Option<EventSender> {
+    SINK.try_with(Clone::clone).ok().flatten()
+}
+
+/// Send the event created by `event` to `sender`, which is only called if there is a sender
+///
+/// A receiver which went away is not an error, progress is only informational.
+pub(crate) fn send(sender: Option<&EventSender>, event: impl FnOnce() -> InstallEvent) {
+    if let Some(sender) = sender {
+        let _ = sender.send(event());
+    }
+}
+
+/// Send the event created by `event` to the sender of the current task, see [`send`]
+pub(crate) fn emit(event: impl FnOnce() -> InstallEvent) {
+    let _ = SINK.try_with(|sender| send(sender.as_ref(), event));
+}
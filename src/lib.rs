@@ -70,14 +70,22 @@ match plan.install(None).await {
 */
 
 pub mod action;
+pub mod bundle;
+pub mod cache;
+mod checkpoint;
 #[cfg(feature = "cli")]
 pub mod cli;
 #[cfg(feature = "diagnostics")]
 pub mod diagnostics;
 mod error;
+pub mod event;
+pub mod migration;
+pub mod network;
+pub mod nix_settings;
 mod os;
 mod plan;
 pub mod planner;
+pub mod release;
 pub mod self_test;
 pub mod settings;
 
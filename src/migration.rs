@@ -0,0 +1,479 @@
+/*! Forward migrations for [`InstallPlan`](crate::InstallPlan) receipts
+
+Every receipt records the `schema_version` of the layout it was written in. Receipts written before
+schema versions existed are treated as schema version `0`.
+
+[`migrate`] upgrades a receipt one schema version at a time until it matches
+[`RECEIPT_SCHEMA_VERSION`], so a receipt from an older `nix-installer` can still be resumed or
+uninstalled by the current one. When a change alters the serialized layout of an
+[`Action`](crate::action::Action) or [`Planner`](crate::planner::Planner), bump
+[`RECEIPT_SCHEMA_VERSION`] and append a step to `MIGRATIONS` which rewrites the previous layout.
+*/
+
+use serde_json::{Map, Value};
+
+/// The receipt schema version written by this `nix-installer`
+pub const RECEIPT_SCHEMA_VERSION: u32 = 4;
+
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// A step upgrading a receipt from one schema version to the next
+type Migration = fn(&mut Map<String, Value>) -> Result<(), MigrationError>;
+
+/// Migration steps, the step at index `n` upgrades a receipt from schema version `n` to `n + 1`
+const MIGRATIONS: &[Migration] = &[v0_to_v1, v1_to_v2, v2_to_v3, v3_to_v4];
+
+/// Upgrade a receipt to [`RECEIPT_SCHEMA_VERSION`]
+///
+/// Receipts which are already current are returned unchanged.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn migrate(mut receipt: Value) -> Result<Value, MigrationError> {
+    let mut schema_version = schema_version(&receipt)?;
+    if schema_version > RECEIPT_SCHEMA_VERSION {
+        return Err(MigrationError::UnsupportedSchemaVersion {
+            receipt: schema_version,
+            supported: RECEIPT_SCHEMA_VERSION,
+        });
+    }
+
+    let map = receipt.as_object_mut().ok_or(MigrationError::NotAnObject)?;
+    while schema_version < RECEIPT_SCHEMA_VERSION {
+        tracing::debug!(
+            "Migrating receipt from schema version {schema_version} to {}",
+            schema_version + 1
+        );
+        MIGRATIONS[schema_version as usize](map)?;
+        schema_version += 1;
+        map.insert(SCHEMA_VERSION_KEY.to_string(), Value::from(schema_version));
+    }
+
+    Ok(receipt)
+}
+
+/// The schema version of a receipt, `0` if it predates schema versions
+pub fn schema_version(receipt: &Value) -> Result<u32, MigrationError> {
+    let map = receipt.as_object().ok_or(MigrationError::NotAnObject)?;
+    match map.get(SCHEMA_VERSION_KEY) {
+        None => Ok(0),
+        Some(value) => value
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| MigrationError::InvalidSchemaVersion(value.clone())),
+    }
+}
+
+/// Typetag names which were renamed to match their [`ActionTag`](crate::action::ActionTag)
+const V1_RENAMED_ACTIONS: &[(&str, &str)] = &[
+    ("mount_unpacked_nix", "move_unpacked_nix"),
+    ("create_users_and_group", "create_users_and_groups"),
+    ("unmount_volume", "unmount_apfs_volume"),
+    ("encrypt_volume", "encrypt_apfs_volume"),
+    ("create_apfs_volume", "create_nix_volume"),
+    ("create_volume", "create_apfs_volume"),
+];
+
+/// Schema version `1` introduced `schema_version` itself, along with:
+///
+/// * Action typetag names which match their action tags
+/// * The build group being created by `create_users_and_groups`, rather than `provision_nix`
+/// * `provision_nix` no longer deleting the build users of a previous install
+/// * `configure_init_service` no longer storing an unused `ssl_cert_file`
+fn v0_to_v1(receipt: &mut Map<String, Value>) -> Result<(), MigrationError> {
+    rename_actions(receipt, V1_RENAMED_ACTIONS);
+
+    let settings = receipt
+        .get("planner")
+        .and_then(|v| v.get("settings"))
+        .cloned()
+        .unwrap_or_default();
+    let actions = match receipt.get_mut("actions") {
+        Some(Value::Array(actions)) => actions,
+        _ => {
+            return Err(MigrationError::Migrating(
+                0,
+                "`actions` was not a list".into(),
+            ))
+        },
+    };
+
+    let mut index = 0;
+    while index < actions.len() {
+        let action = actions[index]
+            .get_mut("action")
+            .and_then(Value::as_object_mut);
+        let Some(action) = action else {
+            index += 1;
+            continue;
+        };
+        match action.get("action").and_then(Value::as_str) {
+            Some("provision_nix") => {
+                // Linux receipts had `delete_users`, macOS receipts `delete_users_in_group`
+                action.remove("delete_users");
+                action.remove("delete_users_in_group");
+                if let Some(create_group) = action.remove("create_group") {
+                    let name = create_group
+                        .pointer("/action/name")
+                        .cloned()
+                        .unwrap_or_default();
+                    let gid = create_group
+                        .pointer("/action/gid")
+                        .cloned()
+                        .unwrap_or_default();
+                    let state = create_group.get("state").cloned().unwrap_or_default();
+                    let create_users_and_groups = serde_json::json!({
+                        "action": {
+                            "action": "create_users_and_groups",
+                            "nix_build_user_count": 0,
+                            "nix_build_group_name": name,
+                            "nix_build_group_id": gid,
+                            "nix_build_user_prefix": settings
+                                .get("nix_build_user_prefix")
+                                .cloned()
+                                .unwrap_or_else(|| name.clone()),
+                            "nix_build_user_id_base": settings
+                                .get("nix_build_user_id_base")
+                                .cloned()
+                                .unwrap_or_else(|| gid.clone()),
+                            "create_group": create_group,
+                            "create_users": [],
+                            "add_users_to_groups": [],
+                        },
+                        "state": state,
+                    });
+                    index += 1;
+                    actions.insert(index, create_users_and_groups);
+                }
+            },
+            Some("configure_init_service") => {
+                action.remove("ssl_cert_file");
+            },
+            _ => (),
+        }
+        index += 1;
+    }
+
+    Ok(())
+}
+
+/// Schema version `2` introduced `dependencies` between the actions of a plan
+///
+/// Older plans were always executed in order, so each action depends on the one before it.
+fn v1_to_v2(receipt: &mut Map<String, Value>) -> Result<(), MigrationError> {
+    let action_count = match receipt.get("actions") {
+        Some(Value::Array(actions)) => actions.len(),
+        _ => {
+            return Err(MigrationError::Migrating(
+                1,
+                "`actions` was not a list".into(),
+            ))
+        },
+    };
+    receipt.insert(
+        "dependencies".to_string(),
+        serde_json::to_value(crate::planner::sequential_dependencies(action_count))
+            .map_err(|e| MigrationError::Migrating(1, e.to_string()))?,
+    );
+    Ok(())
+}
+
+/// Schema version `3` allowed the Nix package to be fetched from several sources
+///
+/// * The `nix_package_url` setting became a list
+/// * `fetch_and_unpack_nix` replaced `url_or_path` with a list of `sources`
+fn v2_to_v3(receipt: &mut Map<String, Value>) -> Result<(), MigrationError> {
+    if let Some(settings) = receipt
+        .get_mut("planner")
+        .and_then(|v| v.get_mut("settings"))
+        .and_then(Value::as_object_mut)
+    {
+        if let Some(url) = settings.remove("nix_package_url") {
+            let urls = match url {
+                Value::Array(_) => url,
+                url => Value::Array(vec![url]),
+            };
+            settings.insert("nix_package_url".to_string(), urls);
+        }
+    }
+
+    fn wrap_url_or_path(fetch: &mut Map<String, Value>) {
+        if let Some(url_or_path) = fetch.remove("url_or_path") {
+            fetch.insert("sources".to_string(), Value::Array(vec![url_or_path]));
+        }
+    }
+    // `provision_nix` holds its `fetch_and_unpack_nix` without a typetag
+    visit_actions(receipt, "provision_nix", &mut |action| {
+        if let Some(fetch) = action
+            .get_mut("fetch_nix")
+            .and_then(|v| v.get_mut("action"))
+            .and_then(Value::as_object_mut)
+        {
+            wrap_url_or_path(fetch)
+        }
+    });
+    visit_actions(receipt, "fetch_and_unpack_nix", &mut wrap_url_or_path);
+    Ok(())
+}
+
+/// Schema version `4` recorded the actions and settings added since, such as imported closures,
+/// remote builders, flake registry pins and auto-allocated build UIDs
+///
+/// Each of these has a default, so an older receipt is parsed as it is. The version only stops an
+/// older `nix-installer` from uninstalling what it does not know about.
+fn v3_to_v4(_receipt: &mut Map<String, Value>) -> Result<(), MigrationError> {
+    Ok(())
+}
+
+/// Call `visit` with every action tagged `tag` found in `value`
+fn visit_actions(
+    value: &mut Map<String, Value>,
+    tag: &str,
+    visit: &mut dyn FnMut(&mut Map<String, Value>),
+) {
+    if value.get("action").and_then(Value::as_str) == Some(tag) {
+        visit(value);
+    }
+    for child in value.values_mut() {
+        match child {
+            Value::Object(map) => visit_actions(map, tag, visit),
+            Value::Array(items) => {
+                for item in items.iter_mut() {
+                    if let Value::Object(map) = item {
+                        visit_actions(map, tag, visit)
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Rename any typetag `action` keys found in `value` according to `renames`
+fn rename_actions(value: &mut Map<String, Value>, renames: &[(&str, &str)]) {
+    for (key, child) in value.iter_mut() {
+        match child {
+            Value::String(tag) if key == "action" => {
+                if let Some((_, new)) = renames.iter().find(|(old, _)| old == tag) {
+                    *tag = new.to_string();
+                }
+            },
+            Value::Object(map) => rename_actions(map, renames),
+            Value::Array(items) => {
+                for item in items.iter_mut() {
+                    if let Value::Object(map) = item {
+                        rename_actions(map, renames)
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+/// An error occurring while migrating a receipt
+#[non_exhaustive]
+#[derive(thiserror::Error, Debug, strum::IntoStaticStr)]
+pub enum MigrationError {
+    /// The receipt was not a JSON object
+    #[error("Receipt was not a JSON object")]
+    NotAnObject,
+    /// The receipt's `schema_version` was not a valid schema version
+    #[error("Receipt had an invalid `schema_version` of `{0}`")]
+    InvalidSchemaVersion(Value),
+    /// The receipt was written by a newer `nix-installer`
+    #[error("Receipt has schema version {receipt}, which is newer than the schema version {supported} this `nix-installer` supports")]
+    UnsupportedSchemaVersion { receipt: u32, supported: u32 },
+    /// A migration step could not understand the receipt
+    #[error("Migrating receipt from schema version {0}: {1}")]
+    Migrating(u32, String),
+}
+
+impl crate::error::HasExpectedErrors for MigrationError {
+    fn expected<'a>(&'a self) -> Option<Box<dyn std::error::Error + 'a>> {
+        match self {
+            MigrationError::NotAnObject => None,
+            MigrationError::InvalidSchemaVersion(_) => None,
+            this @ MigrationError::UnsupportedSchemaVersion { .. } => Some(Box::new(this)),
+            MigrationError::Migrating(_, _) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn renames_nested_actions() -> eyre::Result<()> {
+        let receipt = json!({
+            "actions": [{
+                "action": {
+                    "action": "create_apfs_volume",
+                    "create_volume": {
+                        "action": { "name": "Nix Store" },
+                        "state": "Completed",
+                    },
+                    "others": [{ "action": { "action": "create_volume" }, "state": "Completed" }],
+                },
+                "state": "Completed",
+            }],
+        });
+        let migrated = migrate(receipt)?;
+        assert_eq!(schema_version(&migrated)?, RECEIPT_SCHEMA_VERSION);
+        assert_eq!(
+            migrated.pointer("/actions/0/action/action"),
+            Some(&json!("create_nix_volume"))
+        );
+        // Fields which happen to share a name with a renamed tag are left alone
+        assert!(migrated
+            .pointer("/actions/0/action/create_volume/action/name")
+            .is_some());
+        assert_eq!(
+            migrated.pointer("/actions/0/action/others/0/action/action"),
+            Some(&json!("create_apfs_volume"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_newer_schema() {
+        let receipt = json!({ "schema_version": RECEIPT_SCHEMA_VERSION + 1, "actions": [] });
+        assert!(matches!(
+            migrate(receipt),
+            Err(MigrationError::UnsupportedSchemaVersion { .. })
+        ));
+    }
+
+    #[test]
+    fn wraps_nix_package_url_in_sources() -> eyre::Result<()> {
+        let url = json!({ "Url": "https://releases.nixos.org/nix/nix-2.18.1/nix-2.18.1-x86_64-linux.tar.xz" });
+        let receipt = json!({
+            "schema_version": 2,
+            "planner": { "planner": "linux", "settings": { "nix_package_url": url } },
+            "actions": [{
+                "action": {
+                    "action": "provision_nix",
+                    "fetch_nix": {
+                        "action": { "url_or_path": url },
+                        "state": "Completed",
+                    },
+                },
+                "state": "Completed",
+            }],
+        });
+        let migrated = migrate(receipt)?;
+        assert_eq!(
+            migrated.pointer("/planner/settings/nix_package_url"),
+            Some(&json!([url]))
+        );
+        assert_eq!(
+            migrated.pointer("/actions/0/action/fetch_nix/action/sources"),
+            Some(&json!([url]))
+        );
+        assert!(migrated
+            .pointer("/actions/0/action/fetch_nix/action/url_or_path")
+            .is_none());
+        Ok(())
+    }
+
+    /// A receipt in the layout of schema version `3`, holding only the parts later versions change
+    fn v3_receipt() -> Value {
+        json!({
+            "schema_version": 3,
+            "planner": { "planner": "linux", "settings": { "nix_build_group_name": "nixbld" } },
+            "actions": [{
+                "action": {
+                    "action": "configure_nix",
+                    "setup_default_profile": { "action": {}, "state": "Completed" },
+                    "configure_shell_profile": null,
+                    "place_nix_configuration": {
+                        "action": {
+                            "create_directory": { "action": {}, "state": "Completed" },
+                            "create_or_merge_nix_config": {
+                                "action": { "pending_nix_config": { "settings": {} } },
+                                "state": "Completed",
+                            },
+                        },
+                        "state": "Completed",
+                    },
+                },
+                "state": "Completed",
+            }, {
+                "action": {
+                    "action": "provision_nix",
+                    "fetch_nix": { "action": { "sources": [] }, "state": "Completed" },
+                },
+                "state": "Completed",
+            }],
+        })
+    }
+
+    #[test]
+    fn leaves_added_fields_to_their_defaults() -> eyre::Result<()> {
+        let mut expected = v3_receipt();
+        expected["schema_version"] = json!(RECEIPT_SCHEMA_VERSION);
+        assert_eq!(migrate(v3_receipt())?, expected);
+        Ok(())
+    }
+
+    /// Ensure every value in `migrated` survives a round trip through `reserialized`
+    fn assert_retained(migrated: &Value, reserialized: &Value, pointer: &str) {
+        match (migrated, reserialized) {
+            (Value::Object(migrated), Value::Object(reserialized)) => {
+                for (key, value) in migrated {
+                    let pointer = format!("{pointer}/{key}");
+                    match reserialized.get(key) {
+                        Some(reserialized) => assert_retained(value, reserialized, &pointer),
+                        None => panic!("`{pointer}` was lost when parsing the migrated receipt"),
+                    }
+                }
+            },
+            (Value::Array(migrated), Value::Array(reserialized)) => {
+                assert_eq!(
+                    migrated.len(),
+                    reserialized.len(),
+                    "`{pointer}` changed length"
+                );
+                for (index, (value, reserialized)) in migrated.iter().zip(reserialized).enumerate()
+                {
+                    assert_retained(value, reserialized, &format!("{pointer}/{index}"));
+                }
+            },
+            (migrated, reserialized) => {
+                assert_eq!(migrated, reserialized, "`{pointer}` changed")
+            },
+        }
+    }
+
+    #[test]
+    fn migrates_macos_actions() -> eyre::Result<()> {
+        use crate::action::{Action, StatefulAction};
+
+        // The macOS planner (and init system) is only built on macOS, but the actions which
+        // are migrated can be parsed anywhere
+        let receipt: Value =
+            serde_json::from_str(include_str!("../tests/fixtures/macos/macos.json"))?;
+        let migrated = migrate(receipt)?;
+        let actions = migrated
+            .get("actions")
+            .and_then(Value::as_array)
+            .expect("Receipt has actions");
+        let mut checked = vec![];
+        for (index, action) in actions.iter().enumerate() {
+            let tag = action.pointer("/action/action").and_then(Value::as_str);
+            if !matches!(tag, Some("provision_nix" | "create_users_and_groups")) {
+                continue;
+            }
+            checked.extend(tag);
+            let parsed: StatefulAction<Box<dyn Action>> = serde_json::from_value(action.clone())?;
+            assert_retained(
+                action,
+                &serde_json::to_value(&parsed)?,
+                &format!("/actions/{index}"),
+            );
+        }
+        assert_eq!(checked, vec!["provision_nix", "create_users_and_groups"]);
+        Ok(())
+    }
+}
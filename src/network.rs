@@ -0,0 +1,909 @@
+/*! Fetching over the network with timeouts, retries, resumption and authentication
+
+Every fetch `nix-installer` makes (the Nix package, `extra_conf` URLs, and diagnostics) follows the
+same [`NetworkPolicy`], so a flaky proxy is retried rather than failing the install outright.
+
+Fetches may be authenticated with a netrc file or a bearer token. Only where the credentials are kept is
+part of the policy, the credentials themselves are read when a fetch is made, so they are never
+recorded in the receipt. Diagnostics are never sent with credentials.
+*/
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures_util::Stream;
+use reqwest::{header, Client, RequestBuilder, Response, StatusCode};
+use url::Url;
+
+use crate::{action::error_chain, parse_ssl_cert, CertificateError};
+
+/// The longest time waited between two attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The default [`auth_token_env`](NetworkPolicy::auth_token_env)
+pub const DEFAULT_AUTH_TOKEN_ENV: &str = "NIX_INSTALLER_AUTH_TOKEN";
+
+/// How network fetches are timed out, retried and authenticated
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[cfg_attr(feature = "cli", derive(clap::Parser))]
+pub struct NetworkPolicy {
+    /// How long to wait for a connection to be established, in seconds
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            default_value_t = 15,
+            env = "NIX_INSTALLER_CONNECT_TIMEOUT",
+            global = true
+        )
+    )]
+    pub connect_timeout: u64,
+
+    /// How long to wait for a response, or for more of a response, before trying again, in seconds
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            default_value_t = 30,
+            env = "NIX_INSTALLER_READ_TIMEOUT",
+            global = true
+        )
+    )]
+    pub read_timeout: u64,
+
+    /// How many times a failed fetch is retried, waiting exponentially longer between each attempt
+    ///
+    /// Interrupted downloads of the Nix package resume from where they stopped, if the server confirms it is unchanged.
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            default_value_t = 4,
+            env = "NIX_INSTALLER_RETRIES",
+            global = true
+        )
+    )]
+    pub retries: u32,
+
+    /// A netrc file with the credentials for the hosts fetched from
+    ///
+    /// The `login` and `password` of a host's `machine` entry are sent to it with HTTP basic
+    /// authentication, only over `https://`. The `default` entry is only sent to the hosts in
+    /// `auth_token_hosts`.
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_NETRC_FILE", global = true)
+    )]
+    pub netrc_file: Option<PathBuf>,
+
+    /// The environment variable holding a token sent with HTTP bearer authentication
+    ///
+    /// The token is only sent if the variable is set, only over `https://` to the hosts in `auth_token_hosts`,
+    /// and not to hosts which have a `machine` entry in `netrc_file`.
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            default_value = DEFAULT_AUTH_TOKEN_ENV,
+            env = "NIX_INSTALLER_AUTH_TOKEN_ENV",
+            global = true
+        )
+    )]
+    pub auth_token_env: String,
+
+    /// The hosts the token in `auth_token_env` (or the `default` netrc entry) is sent to, by default it
+    /// is sent to none of them
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long = "auth-token-host",
+            env = "NIX_INSTALLER_AUTH_TOKEN_HOSTS",
+            value_delimiter = ',',
+            global = true
+        )
+    )]
+    pub auth_token_hosts: Vec<String>,
+}
+
+impl Default for NetworkPolicy {
+    fn default() -> Self {
+        Self {
+            connect_timeout: 15,
+            read_timeout: 30,
+            retries: 4,
+            netrc_file: None,
+            auth_token_env: DEFAULT_AUTH_TOKEN_ENV.to_string(),
+            auth_token_hosts: vec![],
+        }
+    }
+}
+
+impl NetworkPolicy {
+    /// A client following this policy, going through `proxy` and trusting `ssl_cert_file` if they are set
+    pub(crate) async fn client(
+        &self,
+        proxy: Option<&Url>,
+        ssl_cert_file: Option<&Path>,
+    ) -> Result<Client, NetworkError> {
+        let mut buildable_client =
+            Client::builder().connect_timeout(Duration::from_secs(self.connect_timeout));
+        if let Some(proxy) = proxy {
+            buildable_client = buildable_client.proxy(reqwest::Proxy::all(proxy.clone())?);
+        }
+        if let Some(ssl_cert_file) = ssl_cert_file {
+            let ssl_cert = parse_ssl_cert(ssl_cert_file).await?;
+            buildable_client = buildable_client.add_root_certificate(ssl_cert);
+        }
+        Ok(buildable_client.build()?)
+    }
+
+    /// Send the request built by `request` to `url`, retrying until it succeeds or the retries are exhausted
+    pub(crate) async fn send(
+        &self,
+        url: &Url,
+        request: impl Fn() -> RequestBuilder,
+    ) -> Result<Response, NetworkError> {
+        Attempts::new(self, url).send(request).await
+    }
+
+    /// The credentials to present to the host of `url`, if there are any
+    async fn authorization(&self, url: &Url) -> Result<Option<Authorization>, NetworkError> {
+        let Some(host) = url.host_str() else {
+            return Ok(None);
+        };
+        // Credentials are never sent in the clear
+        if url.scheme() != "https" {
+            return Ok(None);
+        }
+        let netrc = match &self.netrc_file {
+            Some(netrc_file) => {
+                let contents = tokio::fs::read_to_string(netrc_file)
+                    .await
+                    .map_err(|e| NetworkError::Netrc(netrc_file.clone(), e))?;
+                Netrc::parse(&contents)
+            },
+            None => Netrc::default(),
+        };
+        if let Some(entry) = netrc
+            .machines
+            .into_iter()
+            .find(|(machine, _)| machine == host)
+        {
+            tracing::debug!("Authenticating to `{host}` with its netrc entry");
+            return Ok(Some(entry.1));
+        }
+        // Nor to a host they were not meant for, such as a mirror
+        if !self.auth_token_hosts.iter().any(|h| h == host) {
+            return Ok(None);
+        }
+        if let Some(token) = std::env::var_os(&self.auth_token_env) {
+            tracing::debug!(
+                "Authenticating to `{host}` with the token in `{}`",
+                self.auth_token_env
+            );
+            return Ok(Some(Authorization::Bearer(
+                token.to_string_lossy().into_owned(),
+            )));
+        }
+        if let Some(default) = netrc.default {
+            tracing::debug!("Authenticating to `{host}` with the default netrc entry");
+            return Ok(Some(default));
+        }
+        Ok(None)
+    }
+
+    /// Fetch the entire body of `url`
+    pub(crate) async fn fetch(&self, client: &Client, url: &Url) -> Result<Bytes, NetworkError> {
+        let authorization = self.authorization(url).await?;
+        let mut attempts = Attempts::new(self, url);
+        loop {
+            let mut res = attempts
+                .send(|| Authorization::apply(&authorization, client.get(url.clone())))
+                .await?;
+            let mut buf = Vec::new();
+            let failure = loop {
+                match self.chunk(&mut res).await {
+                    Ok(Some(chunk)) => buf.extend_from_slice(&chunk),
+                    Ok(None) => return Ok(Bytes::from(buf)),
+                    Err(failure) => break failure,
+                }
+            };
+            attempts.failed(failure).await?;
+        }
+    }
+
+    /// Stream the body of `url` along with its length if it is known, resuming with HTTP range requests
+    /// if the transfer is interrupted
+    ///
+    /// A transfer is only resumed if the server confirms the body is unchanged (with `If-Range`), otherwise
+    /// it starts again from the beginning after a [`Streamed::Restart`].
+    pub(crate) async fn stream(
+        &self,
+        client: Client,
+        url: Url,
+    ) -> Result<
+        (
+            impl Stream<Item = Result<Streamed, NetworkError>> + Send,
+            Option<u64>,
+        ),
+        NetworkError,
+    > {
+        let authorization = self.authorization(&url).await?;
+        let mut attempts = Attempts::new(self, &url);
+        let res = attempts
+            .send(|| Authorization::apply(&authorization, client.get(url.clone())))
+            .await?;
+        let total = res.content_length();
+        let transfer = Transfer {
+            client,
+            url,
+            authorization,
+            attempts,
+            validator: validator(&res),
+            res: Some(res),
+            received: 0,
+        };
+        let stream = futures_util::stream::try_unfold(transfer, |mut transfer| async move {
+            let chunk = transfer.next().await?;
+            Ok(chunk.map(|chunk| (chunk, transfer)))
+        });
+        Ok((stream, total))
+    }
+
+    fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout)
+    }
+
+    /// How long to wait after the `failures`th failed attempt
+    fn backoff(failures: usize) -> Duration {
+        let exponent = u32::try_from(failures.saturating_sub(1)).unwrap_or(u32::MAX);
+        2u64.checked_pow(exponent)
+            .map(Duration::from_secs)
+            .unwrap_or(MAX_BACKOFF)
+            .min(MAX_BACKOFF)
+    }
+
+    async fn chunk(&self, res: &mut Response) -> Result<Option<Bytes>, String> {
+        match tokio::time::timeout(self.read_timeout(), res.chunk()).await {
+            Ok(Ok(chunk)) => Ok(chunk),
+            Ok(Err(e)) => Err(error_chain(&e)),
+            Err(_) => Err(format!("No data received for {}s", self.read_timeout)),
+        }
+    }
+}
+
+/// The failed attempts at fetching a URL
+struct Attempts {
+    policy: NetworkPolicy,
+    url: String,
+    failures: Vec<String>,
+}
+
+impl Attempts {
+    fn new(policy: &NetworkPolicy, url: &Url) -> Self {
+        Self {
+            policy: policy.clone(),
+            url: url.to_string(),
+            failures: vec![],
+        }
+    }
+
+    async fn send(
+        &mut self,
+        request: impl Fn() -> RequestBuilder,
+    ) -> Result<Response, NetworkError> {
+        loop {
+            let failure =
+                match tokio::time::timeout(self.policy.read_timeout(), request().send()).await {
+                    Ok(Ok(res)) if res.status().is_success() => return Ok(res),
+                    Ok(Ok(res)) if is_transient(res.status()) => {
+                        format!("Received HTTP status {}", res.status())
+                    },
+                    Ok(Ok(res)) => {
+                        return Err(NetworkError::Status {
+                            url: self.url.clone(),
+                            status: res.status(),
+                        })
+                    },
+                    Ok(Err(e)) if e.is_builder() => return Err(NetworkError::Reqwest(e)),
+                    Ok(Err(e)) => error_chain(&e),
+                    Err(_) => format!("No response received for {}s", self.policy.read_timeout),
+                };
+            self.failed(failure).await?;
+        }
+    }
+
+    /// Record a failed attempt, waiting before the next one or erroring if there are no retries left
+    async fn failed(&mut self, failure: String) -> Result<(), NetworkError> {
+        self.failures.push(failure);
+        if self.failures.len() > self.policy.retries as usize {
+            return Err(NetworkError::RetriesExhausted {
+                url: self.url.clone(),
+                attempts: std::mem::take(&mut self.failures),
+            });
+        }
+        let backoff = NetworkPolicy::backoff(self.failures.len());
+        tracing::warn!(
+            "Fetching `{}` failed ({}), retrying in {}s",
+            self.url,
+            self.failures.last().map(String::as_str).unwrap_or_default(),
+            backoff.as_secs()
+        );
+        tokio::time::sleep(backoff).await;
+        Ok(())
+    }
+}
+
+/// Credentials presented to a host, which are never displayed
+#[derive(Clone, PartialEq, Eq)]
+enum Authorization {
+    Basic { login: String, password: String },
+    Bearer(String),
+}
+
+impl Authorization {
+    fn apply(authorization: &Option<Self>, request: RequestBuilder) -> RequestBuilder {
+        match authorization {
+            Some(Self::Basic { login, password }) => request.basic_auth(login, Some(password)),
+            Some(Self::Bearer(token)) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
+
+impl std::fmt::Debug for Authorization {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Basic { login, .. } => f
+                .debug_struct("Basic")
+                .field("login", login)
+                .finish_non_exhaustive(),
+            Self::Bearer(_) => f.write_str("Bearer"),
+        }
+    }
+}
+
+/// The entries of a netrc file
+///
+/// Like `curl`, tokens which are not understood are skipped, and `macdef` definitions are ignored.
+#[derive(Debug, Default)]
+struct Netrc {
+    machines: Vec<(String, Authorization)>,
+    default: Option<Authorization>,
+}
+
+impl Netrc {
+    fn parse(contents: &str) -> Self {
+        let mut netrc = Self::default();
+        let mut entry: Option<(Option<String>, String, String)> = None;
+        let finish = |netrc: &mut Self, entry: Option<(Option<String>, String, String)>| {
+            if let Some((machine, login, password)) = entry {
+                let authorization = Authorization::Basic { login, password };
+                match machine {
+                    Some(machine) => netrc.machines.push((machine, authorization)),
+                    None => netrc.default = Some(authorization),
+                }
+            }
+        };
+        let mut lines = contents.lines();
+        while let Some(line) = lines.next() {
+            let mut tokens = line.split_whitespace();
+            while let Some(token) = tokens.next() {
+                match token {
+                    "machine" => {
+                        finish(&mut netrc, entry.take());
+                        entry = tokens.next().map(|machine| {
+                            (Some(machine.to_string()), String::new(), String::new())
+                        });
+                    },
+                    "default" => {
+                        finish(&mut netrc, entry.take());
+                        entry = Some((None, String::new(), String::new()));
+                    },
+                    "login" => {
+                        if let (Some(entry), Some(login)) = (&mut entry, tokens.next()) {
+                            entry.1 = login.to_string();
+                        }
+                    },
+                    "password" => {
+                        if let (Some(entry), Some(password)) = (&mut entry, tokens.next()) {
+                            entry.2 = password.to_string();
+                        }
+                    },
+                    "account" => {
+                        tokens.next();
+                    },
+                    "macdef" => {
+                        finish(&mut netrc, entry.take());
+                        // A macro definition continues until an empty line
+                        for line in lines.by_ref() {
+                            if line.trim().is_empty() {
+                                break;
+                            }
+                        }
+                        break;
+                    },
+                    comment if comment.starts_with('#') => break,
+                    _ => (),
+                }
+            }
+        }
+        finish(&mut netrc, entry);
+        netrc
+    }
+}
+
+/// A part of a body streamed by [`NetworkPolicy::stream`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Streamed {
+    Chunk(Bytes),
+    /// The body could not be resumed, perhaps because it changed, and is being sent again from the start,
+    /// so everything received before must be discarded
+    Restart,
+}
+
+/// A download which can be resumed if it is interrupted
+struct Transfer {
+    client: Client,
+    url: Url,
+    authorization: Option<Authorization>,
+    attempts: Attempts,
+    res: Option<Response>,
+    received: u64,
+    /// The entity tag or modification time of the body being received, so only the same body is resumed
+    validator: Option<String>,
+}
+
+impl Transfer {
+    async fn next(&mut self) -> Result<Option<Streamed>, NetworkError> {
+        loop {
+            let mut res = match self.res.take() {
+                Some(res) => res,
+                None => {
+                    // Without a validator the server can't tell if the body changed, so it can't be resumed
+                    let resume = self.validator.clone().filter(|_| self.received > 0);
+                    let range = format!("bytes={}-", self.received);
+                    let res = self
+                        .attempts
+                        .send(|| {
+                            let request = Authorization::apply(
+                                &self.authorization,
+                                self.client.get(self.url.clone()),
+                            );
+                            match &resume {
+                                Some(validator) => request
+                                    .header(header::RANGE, &range)
+                                    .header(header::IF_RANGE, validator),
+                                None => request,
+                            }
+                        })
+                        .await?;
+                    tracing::debug!(
+                        "Resuming `{}` from byte {}, received HTTP status {}",
+                        self.url,
+                        self.received,
+                        res.status(),
+                    );
+                    if res.status() != StatusCode::PARTIAL_CONTENT {
+                        // The whole body is being sent again, which may not be the one received so far
+                        self.validator = validator(&res);
+                        if self.received > 0 {
+                            self.received = 0;
+                            self.res = Some(res);
+                            return Ok(Some(Streamed::Restart));
+                        }
+                    }
+                    res
+                },
+            };
+
+            match self.attempts.policy.chunk(&mut res).await {
+                Ok(Some(chunk)) => {
+                    self.res = Some(res);
+                    self.received += chunk.len() as u64;
+                    return Ok(Some(Streamed::Chunk(chunk)));
+                },
+                Ok(None) => return Ok(None),
+                Err(failure) => self.attempts.failed(failure).await?,
+            }
+        }
+    }
+}
+
+/// What identifies the body of `res`, for an `If-Range` header
+///
+/// Weak entity tags can't be used to resume a transfer, the modification time is used instead.
+fn validator(res: &Response) -> Option<String> {
+    let header = |name| {
+        res.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    header(header::ETAG)
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| header(header::LAST_MODIFIED))
+        .map(ToString::to_string)
+}
+
+/// If a request which received `status` may succeed if it is tried again
+fn is_transient(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+fn describe_attempts(attempts: &[String]) -> String {
+    attempts
+        .iter()
+        .enumerate()
+        .map(|(index, failure)| format!("\n  Attempt {}: {failure}", index + 1))
+        .collect()
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum NetworkError {
+    #[error("Reading netrc file `{0}`")]
+    Netrc(PathBuf, #[source] std::io::Error),
+    #[error("Request error")]
+    Reqwest(
+        #[from]
+        #[source]
+        reqwest::Error,
+    ),
+    #[error(transparent)]
+    Certificate(#[from] CertificateError),
+    #[error("Fetching `{url}` failed with HTTP status {status}")]
+    Status { url: String, status: StatusCode },
+    #[error("Fetching `{url}` failed after {} attempts:{}", attempts.len(), describe_attempts(attempts))]
+    RetriesExhausted { url: String, attempts: Vec<String> },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backs_off_exponentially() {
+        let backoffs = (1..=7)
+            .map(|failures| NetworkPolicy::backoff(failures).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(backoffs, vec![1, 2, 4, 8, 16, 30, 30]);
+        assert_eq!(NetworkPolicy::backoff(usize::MAX), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn names_exhausted_attempts() -> eyre::Result<()> {
+        // Nothing listens on a port which was bound then released
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let url = Url::parse(&format!("http://{}/nix.tar.xz", listener.local_addr()?))?;
+        drop(listener);
+
+        let policy = NetworkPolicy {
+            retries: 1,
+            ..Default::default()
+        };
+        let client = policy.client(None, None).await?;
+        let err = policy.fetch(&client, &url).await.unwrap_err();
+
+        let NetworkError::RetriesExhausted { attempts, .. } = &err else {
+            panic!("Expected the retries to be exhausted, got {err:?}");
+        };
+        assert_eq!(attempts.len(), 2);
+        let message = err.to_string();
+        assert!(message.contains(&format!("Fetching `{url}` failed after 2 attempts")));
+        assert!(message.contains("Attempt 1: ") && message.contains("Attempt 2: "));
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_netrc() {
+        let netrc = Netrc::parse(
+            "# Artifacts\n\
+            machine artifacts.example.com login ci password hunter2\n\
+            machine other.example.com\n  login someone\n  account ignored\n  password swordfish\n\
+            macdef init\nmachine not.a.machine login no password no\n\n\
+            default login anonymous password guest\n",
+        );
+        assert_eq!(
+            netrc.machines,
+            vec![
+                (
+                    "artifacts.example.com".to_string(),
+                    Authorization::Basic {
+                        login: "ci".into(),
+                        password: "hunter2".into()
+                    }
+                ),
+                (
+                    "other.example.com".to_string(),
+                    Authorization::Basic {
+                        login: "someone".into(),
+                        password: "swordfish".into()
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            netrc.default,
+            Some(Authorization::Basic {
+                login: "anonymous".into(),
+                password: "guest".into()
+            })
+        );
+        // Credentials are never displayed
+        assert!(!format!("{netrc:?}").contains("hunter2"));
+        assert!(!format!("{:?}", Authorization::Bearer("hunter2".into())).contains("hunter2"));
+    }
+
+    #[tokio::test]
+    async fn authenticates_fetches() -> eyre::Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/nix.conf", listener.local_addr()?))?;
+        let server = tokio::spawn(async move {
+            let mut authorizations = vec![];
+            for _ in 0..3 {
+                let (mut socket, _) = listener.accept().await?;
+                let mut buf = vec![0; 4096];
+                let read = socket.read(&mut buf).await?;
+                let request = String::from_utf8_lossy(&buf[..read]).to_string();
+                authorizations.push(request.lines().find_map(|line| {
+                    line.to_lowercase()
+                        .starts_with("authorization: ")
+                        .then(|| line["authorization: ".len()..].to_string())
+                }));
+                socket
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                    .await?;
+                socket.shutdown().await?;
+            }
+            Ok::<_, eyre::Report>(authorizations)
+        });
+
+        let temp_dir = tempfile::tempdir()?;
+        let netrc_file = temp_dir.path().join("netrc");
+        tokio::fs::write(&netrc_file, "machine 127.0.0.1 login ci password hunter2\n").await?;
+        let token_env = "NIX_INSTALLER_TEST_AUTHENTICATES_FETCHES_TOKEN";
+        std::env::set_var(token_env, "s3cr3t");
+
+        let unset = NetworkPolicy {
+            auth_token_env: token_env.to_string(),
+            ..Default::default()
+        };
+        // The token is meant for this host, but it is never sent over `http://`
+        let insecure = NetworkPolicy {
+            auth_token_hosts: vec!["127.0.0.1".into()],
+            ..unset.clone()
+        };
+        // Nor are netrc credentials
+        let netrc = NetworkPolicy {
+            netrc_file: Some(netrc_file),
+            ..insecure.clone()
+        };
+        for policy in [unset, insecure, netrc] {
+            let client = policy.client(None, None).await?;
+            policy.fetch(&client, &url).await?;
+        }
+
+        assert_eq!(server.await??, vec![None, None, None]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_sends_the_token_to_its_hosts() -> eyre::Result<()> {
+        let token_env = "NIX_INSTALLER_TEST_ONLY_SENDS_THE_TOKEN_TO_ITS_HOSTS_TOKEN";
+        std::env::set_var(token_env, "s3cr3t");
+        let artifacts = Url::parse("https://artifacts.example.com/nix.tar.xz")?;
+        let mirror = Url::parse("https://mirror.example.org/nix.tar.xz")?;
+
+        let unset = NetworkPolicy {
+            auth_token_env: token_env.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(unset.authorization(&artifacts).await?, None);
+        assert_eq!(unset.authorization(&mirror).await?, None);
+
+        let policy = NetworkPolicy {
+            auth_token_hosts: vec!["artifacts.example.com".into()],
+            ..unset
+        };
+        assert_eq!(
+            policy.authorization(&artifacts).await?,
+            Some(Authorization::Bearer("s3cr3t".into()))
+        );
+        assert_eq!(policy.authorization(&mirror).await?, None);
+        let insecure = Url::parse("http://artifacts.example.com/nix.tar.xz")?;
+        assert_eq!(policy.authorization(&insecure).await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_sends_netrc_credentials_to_their_hosts() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let netrc_file = temp_dir.path().join("netrc");
+        tokio::fs::write(
+            &netrc_file,
+            "machine artifacts.example.com login ci password hunter2\n\
+            default login anonymous password guest\n",
+        )
+        .await?;
+        let artifacts = Url::parse("https://artifacts.example.com/nix.tar.xz")?;
+        let cache = Url::parse("https://cache.example.com/nix.tar.xz")?;
+        let mirror = Url::parse("https://mirror.example.org/nix.tar.xz")?;
+
+        let policy = NetworkPolicy {
+            netrc_file: Some(netrc_file),
+            auth_token_env: "NIX_INSTALLER_TEST_ONLY_SENDS_NETRC_CREDENTIALS_TO_THEIR_HOSTS_TOKEN"
+                .to_string(),
+            auth_token_hosts: vec!["cache.example.com".into()],
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.authorization(&artifacts).await?,
+            Some(Authorization::Basic {
+                login: "ci".into(),
+                password: "hunter2".into()
+            })
+        );
+        assert_eq!(
+            policy.authorization(&cache).await?,
+            Some(Authorization::Basic {
+                login: "anonymous".into(),
+                password: "guest".into()
+            })
+        );
+        // A fallback host, such as a mirror, is sent no credentials
+        assert_eq!(policy.authorization(&mirror).await?, None);
+        let insecure = Url::parse("http://artifacts.example.com/nix.tar.xz")?;
+        assert_eq!(policy.authorization(&insecure).await?, None);
+
+        Ok(())
+    }
+
+    /// Serve `bodies` in turn, hanging up halfway through the first, returning the `Range` and `If-Range`
+    /// of each request
+    ///
+    /// Ranges are only honoured if `If-Range` matches the entity tag of the body being served, its digest.
+    async fn serve_interrupted(
+        bodies: Vec<Vec<u8>>,
+    ) -> eyre::Result<(
+        Url,
+        tokio::task::JoinHandle<eyre::Result<Vec<(Option<usize>, Option<String>)>>>,
+    )> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/nix.tar.xz", listener.local_addr()?))?;
+        let server = tokio::spawn(async move {
+            let mut requests = vec![];
+            for (index, served) in bodies.iter().enumerate() {
+                let etag = format!("\"{}\"", crate::settings::Sha256Digest::of(served));
+                let (mut socket, _) = listener.accept().await?;
+                let mut buf = vec![0; 4096];
+                let read = socket.read(&mut buf).await?;
+                let request = String::from_utf8_lossy(&buf[..read]).to_lowercase();
+                let range = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("range: bytes="))
+                    .map(|range| range.trim_end_matches('-').parse::<usize>())
+                    .transpose()?;
+                let if_range = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("if-range: "))
+                    .map(ToString::to_string);
+                requests.push((range, if_range.clone()));
+                match range.filter(|_| if_range.as_ref() == Some(&etag)) {
+                    Some(start) => {
+                        let header = format!(
+                            "HTTP/1.1 206 Partial Content\r\netag: {etag}\r\ncontent-length: {}\r\ncontent-range: bytes {start}-{}/{}\r\n\r\n",
+                            served.len() - start,
+                            served.len() - 1,
+                            served.len()
+                        );
+                        socket.write_all(header.as_bytes()).await?;
+                        socket.write_all(&served[start..]).await?;
+                    },
+                    None => {
+                        let header = format!(
+                            "HTTP/1.1 200 OK\r\netag: {etag}\r\ncontent-length: {}\r\n\r\n",
+                            served.len()
+                        );
+                        socket.write_all(header.as_bytes()).await?;
+                        match index {
+                            0 => socket.write_all(&served[..served.len() / 2]).await?,
+                            _ => socket.write_all(served).await?,
+                        }
+                    },
+                }
+                socket.shutdown().await?;
+            }
+            Ok::<_, eyre::Report>(requests)
+        });
+        Ok((url, server))
+    }
+
+    /// Collect a streamed body, discarding what was received before a restart
+    async fn collect(
+        stream: impl Stream<Item = Result<Streamed, NetworkError>>,
+    ) -> eyre::Result<(Vec<u8>, usize)> {
+        use futures_util::TryStreamExt;
+
+        let mut body = vec![];
+        let mut restarts = 0;
+        futures_util::pin_mut!(stream);
+        while let Some(streamed) = stream.try_next().await? {
+            match streamed {
+                Streamed::Chunk(chunk) => body.extend_from_slice(&chunk),
+                Streamed::Restart => {
+                    body.clear();
+                    restarts += 1;
+                },
+            }
+        }
+        Ok((body, restarts))
+    }
+
+    #[tokio::test]
+    async fn resumes_interrupted_streams() -> eyre::Result<()> {
+        let body = (0..=255u8).cycle().take(64 * 1024).collect::<Vec<_>>();
+        let (url, server) = serve_interrupted(vec![body.clone(), body.clone()]).await?;
+
+        let policy = NetworkPolicy::default();
+        let client = policy.client(None, None).await?;
+        let (stream, total) = policy.stream(client, url).await?;
+        let (received, restarts) = collect(stream).await?;
+
+        assert_eq!(total, Some(body.len() as u64));
+        assert_eq!(received, body);
+        assert_eq!(restarts, 0);
+        assert_eq!(
+            server.await??,
+            vec![
+                (None, None),
+                (
+                    Some(body.len() / 2),
+                    Some(format!("\"{}\"", crate::settings::Sha256Digest::of(&body)))
+                )
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restarts_changed_streams() -> eyre::Result<()> {
+        let body = (0..=255u8).cycle().take(64 * 1024).collect::<Vec<_>>();
+        let changed = body.iter().rev().copied().collect::<Vec<_>>();
+        let (url, server) = serve_interrupted(vec![body.clone(), changed.clone()]).await?;
+
+        let policy = NetworkPolicy::default();
+        let client = policy.client(None, None).await?;
+        let (stream, _) = policy.stream(client, url).await?;
+        let (received, restarts) = collect(stream).await?;
+
+        // Nothing of the first body is spliced onto the second
+        assert_eq!(received, changed);
+        assert_eq!(restarts, 1);
+        assert_eq!(
+            server.await??,
+            vec![
+                (None, None),
+                (
+                    Some(body.len() / 2),
+                    Some(format!("\"{}\"", crate::settings::Sha256Digest::of(&body)))
+                )
+            ]
+        );
+
+        Ok(())
+    }
+}
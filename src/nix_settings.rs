@@ -0,0 +1,472 @@
+/*! The settings Nix knows, used to check [`extra_conf`](crate::settings::CommonSettings::extra_conf)
+
+Nix only warns about an unknown setting (such as a mistyped `substituter`) when it is next run, long after
+`nix-installer` has written it to `/etc/nix/nix.conf`. Checking the settings against [`KNOWN_SETTINGS`]
+when planning catches these, and values of the wrong type, before anything is installed.
+
+Any setting may be given with an `extra-` prefix, which appends to its value instead of replacing it. Some
+settings also have older names, which Nix still accepts, listed in [`SETTING_ALIASES`].
+*/
+
+use semver::Version;
+use url::Url;
+
+/// The type of value a Nix setting takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingType {
+    /// `true` or `false`
+    Bool,
+    /// A non-negative integer
+    Integer,
+    /// A non-negative integer, or `auto`
+    IntegerOrAuto,
+    /// `true`, `false`, or `relaxed`
+    Sandbox,
+    /// Any string
+    String,
+    /// A whitespace separated list of strings
+    Strings,
+    /// A whitespace separated list of store URLs (or absolute paths)
+    Urls,
+}
+
+impl std::fmt::Display for SettingType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettingType::Bool => write!(f, "`true` or `false`"),
+            SettingType::Integer => write!(f, "an integer"),
+            SettingType::IntegerOrAuto => write!(f, "an integer or `auto`"),
+            SettingType::Sandbox => write!(f, "`true`, `false` or `relaxed`"),
+            SettingType::String => write!(f, "a string"),
+            SettingType::Strings => write!(f, "a list of strings"),
+            SettingType::Urls => write!(f, "a list of URLs"),
+        }
+    }
+}
+
+impl SettingType {
+    /// Whether `value` is a valid value of this type
+    pub fn accepts(&self, value: &str) -> bool {
+        match self {
+            SettingType::Bool => matches!(value, "true" | "false"),
+            SettingType::Integer => value.parse::<u64>().is_ok(),
+            SettingType::IntegerOrAuto => value == "auto" || value.parse::<u64>().is_ok(),
+            SettingType::Sandbox => matches!(value, "true" | "false" | "relaxed"),
+            SettingType::String | SettingType::Strings => true,
+            SettingType::Urls => value
+                .split_whitespace()
+                .all(|url| url.starts_with('/') || Url::parse(url).is_ok()),
+        }
+    }
+}
+
+/// A setting Nix knows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KnownSetting {
+    pub name: &'static str,
+    pub setting_type: SettingType,
+    /// The first Nix version with the setting, `None` if every supported version has it
+    pub since: Option<(u64, u64, u64)>,
+}
+
+const fn setting(name: &'static str, setting_type: SettingType) -> KnownSetting {
+    KnownSetting {
+        name,
+        setting_type,
+        since: None,
+    }
+}
+
+const fn setting_since(
+    name: &'static str,
+    setting_type: SettingType,
+    since: (u64, u64, u64),
+) -> KnownSetting {
+    KnownSetting {
+        name,
+        setting_type,
+        since: Some(since),
+    }
+}
+
+/// The settings of the Nix versions `nix-installer` installs
+pub const KNOWN_SETTINGS: &[KnownSetting] = &[
+    setting("accept-flake-config", SettingType::Bool),
+    setting("access-tokens", SettingType::Strings),
+    setting("allow-dirty", SettingType::Bool),
+    setting("allow-import-from-derivation", SettingType::Bool),
+    setting("allow-symlinked-store", SettingType::Bool),
+    setting(
+        "allow-unsafe-native-code-during-evaluation",
+        SettingType::Bool,
+    ),
+    setting("allowed-impure-host-deps", SettingType::Strings),
+    setting("allowed-uris", SettingType::Strings),
+    setting("allowed-users", SettingType::Strings),
+    setting("always-allow-substitutes", SettingType::Bool),
+    setting("auto-allocate-uids", SettingType::Bool),
+    setting("auto-optimise-store", SettingType::Bool),
+    setting("bash-prompt", SettingType::String),
+    setting("bash-prompt-prefix", SettingType::String),
+    setting("bash-prompt-suffix", SettingType::String),
+    setting("build-hook", SettingType::Strings),
+    setting("build-poll-interval", SettingType::Integer),
+    setting("build-users-group", SettingType::String),
+    setting("builders", SettingType::String),
+    setting("builders-use-substitutes", SettingType::Bool),
+    setting("commit-lockfile-summary", SettingType::String),
+    setting("compress-build-log", SettingType::Bool),
+    setting("connect-timeout", SettingType::Integer),
+    setting("cores", SettingType::Integer),
+    setting("darwin-log-sandbox-violations", SettingType::Bool),
+    setting("diff-hook", SettingType::String),
+    setting("download-attempts", SettingType::Integer),
+    setting("download-speed", SettingType::Integer),
+    setting("eval-cache", SettingType::Bool),
+    setting("experimental-features", SettingType::Strings),
+    setting("extra-platforms", SettingType::Strings),
+    setting("fallback", SettingType::Bool),
+    setting("filter-syscalls", SettingType::Bool),
+    setting("flake-registry", SettingType::String),
+    setting("fsync-metadata", SettingType::Bool),
+    setting("gc-reserved-space", SettingType::Integer),
+    setting("hashed-mirrors", SettingType::Urls),
+    setting("http-connections", SettingType::Integer),
+    setting("http2", SettingType::Bool),
+    setting("id-count", SettingType::Integer),
+    setting("ignore-try", SettingType::Bool),
+    setting("ignored-acls", SettingType::Strings),
+    setting("impersonate-linux-26", SettingType::Bool),
+    setting_since("impure-env", SettingType::Strings, (2, 19, 0)),
+    setting("keep-build-log", SettingType::Bool),
+    setting("keep-derivations", SettingType::Bool),
+    setting("keep-env-derivations", SettingType::Bool),
+    setting("keep-failed", SettingType::Bool),
+    setting("keep-going", SettingType::Bool),
+    setting("keep-outputs", SettingType::Bool),
+    setting("log-lines", SettingType::Integer),
+    setting("max-build-log-size", SettingType::Integer),
+    setting("max-call-depth", SettingType::Integer),
+    setting("max-free", SettingType::Integer),
+    setting("max-jobs", SettingType::IntegerOrAuto),
+    setting("max-silent-time", SettingType::Integer),
+    setting_since("max-substitution-jobs", SettingType::Integer, (2, 16, 0)),
+    setting("min-free", SettingType::Integer),
+    setting("min-free-check-interval", SettingType::Integer),
+    setting("narinfo-cache-negative-ttl", SettingType::Integer),
+    setting("narinfo-cache-positive-ttl", SettingType::Integer),
+    setting("netrc-file", SettingType::String),
+    setting("nix-path", SettingType::Strings),
+    setting("plugin-files", SettingType::Strings),
+    setting("post-build-hook", SettingType::String),
+    setting("pre-build-hook", SettingType::String),
+    setting("preallocate-contents", SettingType::Bool),
+    setting("print-missing", SettingType::Bool),
+    setting("pure-eval", SettingType::Bool),
+    setting("require-drop-supplementary-groups", SettingType::Bool),
+    setting("require-sigs", SettingType::Bool),
+    setting("restrict-eval", SettingType::Bool),
+    setting("run-diff-hook", SettingType::Bool),
+    setting("sandbox", SettingType::Sandbox),
+    setting("sandbox-build-dir", SettingType::String),
+    setting("sandbox-dev-shm-size", SettingType::String),
+    setting("sandbox-fallback", SettingType::Bool),
+    setting("sandbox-paths", SettingType::Strings),
+    setting("secret-key-files", SettingType::Strings),
+    setting("show-trace", SettingType::Bool),
+    setting("ssl-cert-file", SettingType::String),
+    setting("stalled-download-timeout", SettingType::Integer),
+    setting("start-id", SettingType::Integer),
+    setting("store", SettingType::String),
+    setting("substitute", SettingType::Bool),
+    setting("substituters", SettingType::Urls),
+    setting("sync-before-registering", SettingType::Bool),
+    setting("system", SettingType::String),
+    setting("system-features", SettingType::Strings),
+    setting("tarball-ttl", SettingType::Integer),
+    setting("timeout", SettingType::Integer),
+    setting("trace-function-calls", SettingType::Bool),
+    setting("trace-verbose", SettingType::Bool),
+    setting("trusted-public-keys", SettingType::Strings),
+    setting("trusted-substituters", SettingType::Urls),
+    setting("trusted-users", SettingType::Strings),
+    setting("upgrade-nix-store-path-url", SettingType::String),
+    setting("use-case-hack", SettingType::Bool),
+    setting("use-cgroups", SettingType::Bool),
+    setting("use-registries", SettingType::Bool),
+    setting("use-sqlite-wal", SettingType::Bool),
+    setting_since("use-xdg-base-directories", SettingType::Bool, (2, 14, 0)),
+    setting("user-agent-suffix", SettingType::String),
+    setting("warn-dirty", SettingType::Bool),
+];
+
+/// The older names of settings Nix still accepts, and the settings they are aliases of
+pub const SETTING_ALIASES: &[(&str, &str)] = &[
+    ("binary-cache-public-keys", "trusted-public-keys"),
+    ("binary-caches", "substituters"),
+    ("build-chroot-dirs", "sandbox-paths"),
+    ("build-compress-log", "compress-build-log"),
+    ("build-cores", "cores"),
+    ("build-fallback", "fallback"),
+    ("build-impersonate-linux-26", "impersonate-linux-26"),
+    ("build-keep-log", "keep-build-log"),
+    ("build-max-jobs", "max-jobs"),
+    ("build-max-log-size", "max-build-log-size"),
+    ("build-max-silent-time", "max-silent-time"),
+    ("build-sandbox-paths", "sandbox-paths"),
+    ("build-timeout", "timeout"),
+    ("build-use-chroot", "sandbox"),
+    ("build-use-sandbox", "sandbox"),
+    ("build-use-substitutes", "substitute"),
+    ("env-keep-derivations", "keep-env-derivations"),
+    ("gc-keep-derivations", "keep-derivations"),
+    ("gc-keep-outputs", "keep-outputs"),
+    ("trusted-binary-caches", "trusted-substituters"),
+];
+
+/// The known setting named `name`, or aliased by it
+///
+/// If there is no such setting, `name` may be a known setting with an `extra-` prefix. The prefix is only
+/// stripped then, as some settings (such as `extra-platforms`) have names starting with it.
+pub fn known_setting(name: &str) -> Option<&'static KnownSetting> {
+    let find = |name: &str| {
+        let name = SETTING_ALIASES
+            .iter()
+            .find(|(alias, _)| *alias == name)
+            .map(|(_, aliased)| *aliased)
+            .unwrap_or(name);
+        KNOWN_SETTINGS.iter().find(|setting| setting.name == name)
+    };
+    find(name).or_else(|| find(name.strip_prefix("extra-")?))
+}
+
+/// The known setting most similar to the unknown `name`, if any is similar enough
+///
+/// If `name` has an `extra-` prefix, the setting with the prefix is suggested.
+pub fn closest_setting(name: &str) -> Option<String> {
+    let (prefix, unprefixed) = match name.strip_prefix("extra-") {
+        Some(unprefixed) => ("extra-", unprefixed),
+        None => ("", name),
+    };
+    KNOWN_SETTINGS
+        .iter()
+        .flat_map(|setting| {
+            [
+                (strsim::jaro_winkler(name, setting.name), setting.name.to_string()),
+                (
+                    strsim::jaro_winkler(unprefixed, setting.name),
+                    format!("{prefix}{}", setting.name),
+                ),
+            ]
+        })
+        // The same threshold `clap` uses for its suggestions
+        .filter(|(confidence, _)| *confidence > 0.8)
+        .max_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, closest)| closest)
+}
+
+/// A problem with a setting, found by [`validate`]
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum NixSettingProblem {
+    #[error("`{name}` is not a Nix setting{}", suggestion.as_ref().map(|s| format!(", did you mean `{s}`?")).unwrap_or_default())]
+    Unknown {
+        name: String,
+        suggestion: Option<String>,
+    },
+    #[error("`{name} = {value}` is invalid, `{name}` must be {expected}")]
+    InvalidValue {
+        name: String,
+        value: String,
+        expected: SettingType,
+    },
+    #[error("`{name}` is a Nix setting since Nix {since}, but Nix {version} is being installed")]
+    Unavailable {
+        name: String,
+        since: Version,
+        version: Version,
+    },
+}
+
+/// The problems with `settings`, for installing Nix `version` (when it is known)
+pub fn validate<'a>(
+    settings: impl IntoIterator<Item = (&'a String, &'a String)>,
+    version: Option<&Version>,
+) -> Vec<NixSettingProblem> {
+    let mut problems = vec![];
+    for (name, value) in settings {
+        let Some(known) = known_setting(name) else {
+            problems.push(NixSettingProblem::Unknown {
+                name: name.clone(),
+                suggestion: closest_setting(name),
+            });
+            continue;
+        };
+        if let (Some((major, minor, patch)), Some(version)) = (known.since, version) {
+            let since = Version::new(major, minor, patch);
+            if *version < since {
+                problems.push(NixSettingProblem::Unavailable {
+                    name: name.clone(),
+                    since,
+                    version: version.clone(),
+                });
+                continue;
+            }
+        }
+        if !known.setting_type.accepts(value.trim()) {
+            problems.push(NixSettingProblem::InvalidValue {
+                name: name.clone(),
+                value: value.clone(),
+                expected: known.setting_type,
+            });
+        }
+    }
+    problems
+}
+
+/// What to do about problems with the settings in `extra_conf`
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum NixConfValidation {
+    /// Warn about each problem, and install anyway
+    #[default]
+    Warn,
+    /// Fail to plan the installation if there is any problem
+    Deny,
+    /// Do not check the settings, such as when a Nix plugin adds its own
+    Off,
+}
+
+impl std::fmt::Display for NixConfValidation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NixConfValidation::Warn => write!(f, "warn"),
+            NixConfValidation::Deny => write!(f, "deny"),
+            NixConfValidation::Off => write!(f, "off"),
+        }
+    }
+}
+
+/// The problems with the settings in `extra_conf`, with [`NixConfValidation::Deny`]
+#[derive(Debug, thiserror::Error)]
+#[error("The Nix settings in `extra_conf` have problems (pass `--nix-conf-validation warn` to install anyway):\n{}", .0.iter().map(|problem| format!("* {problem}")).collect::<Vec<_>>().join("\n"))]
+pub struct InvalidNixSettings(pub Vec<NixSettingProblem>);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn problems(conf: &[(&str, &str)], version: Option<Version>) -> Vec<NixSettingProblem> {
+        let settings = conf
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>();
+        validate(
+            settings.iter().map(|(name, value)| (name, value)),
+            version.as_ref(),
+        )
+    }
+
+    #[test]
+    fn validates_settings() {
+        assert_eq!(
+            problems(
+                &[
+                    ("substituters", "https://cache.example.com /nix/store"),
+                    ("extra-trusted-users", "alice bob"),
+                    ("max-jobs", "auto"),
+                    ("sandbox", "relaxed"),
+                    ("use-xdg-base-directories", "true"),
+                ],
+                Some(Version::new(2, 18, 1))
+            ),
+            vec![]
+        );
+
+        // Settings starting with `extra-` are not mistaken for a prefixed setting, and can be prefixed themselves
+        assert_eq!(
+            problems(
+                &[
+                    ("extra-platforms", "aarch64-linux i686-linux"),
+                    ("extra-extra-platforms", "armv7l-linux"),
+                    ("binary-caches", "https://cache.example.com"),
+                    ("extra-binary-caches", "https://cache.example.org"),
+                    ("build-use-sandbox", "relaxed"),
+                    ("gc-reserved-space", "8388608"),
+                ],
+                None
+            ),
+            vec![]
+        );
+        assert_eq!(
+            problems(&[("extra-platform", "aarch64-linux")], None),
+            vec![NixSettingProblem::Unknown {
+                name: "extra-platform".into(),
+                suggestion: Some("extra-platforms".into()),
+            }]
+        );
+
+        assert_eq!(
+            problems(&[("substituter", "https://cache.example.com")], None),
+            vec![NixSettingProblem::Unknown {
+                name: "substituter".into(),
+                suggestion: Some("substituters".into()),
+            }]
+        );
+        assert_eq!(
+            problems(&[("extra-substituter", "https://cache.example.com")], None),
+            vec![NixSettingProblem::Unknown {
+                name: "extra-substituter".into(),
+                suggestion: Some("extra-substituters".into()),
+            }]
+        );
+        assert_eq!(
+            problems(&[("frobnicate", "yes")], None),
+            vec![NixSettingProblem::Unknown {
+                name: "frobnicate".into(),
+                suggestion: None,
+            }]
+        );
+
+        assert_eq!(
+            problems(
+                &[
+                    ("keep-outputs", "yes"),
+                    ("cores", "-1"),
+                    ("extra-substituters", "cache.example.com"),
+                ],
+                None
+            ),
+            vec![
+                NixSettingProblem::InvalidValue {
+                    name: "keep-outputs".into(),
+                    value: "yes".into(),
+                    expected: SettingType::Bool,
+                },
+                NixSettingProblem::InvalidValue {
+                    name: "cores".into(),
+                    value: "-1".into(),
+                    expected: SettingType::Integer,
+                },
+                NixSettingProblem::InvalidValue {
+                    name: "extra-substituters".into(),
+                    value: "cache.example.com".into(),
+                    expected: SettingType::Urls,
+                },
+            ]
+        );
+
+        assert_eq!(
+            problems(
+                &[("max-substitution-jobs", "16")],
+                Some(Version::new(2, 15, 0))
+            ),
+            vec![NixSettingProblem::Unavailable {
+                name: "max-substitution-jobs".into(),
+                since: Version::new(2, 16, 0),
+                version: Version::new(2, 15, 0),
+            }]
+        );
+    }
+}
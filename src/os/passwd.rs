@@ -0,0 +1,215 @@
+/*! Root filesystem aware user and group database lookups
+
+When installing into an alternate root the host's user database (as seen by `getpwnam(3)`) is not the
+one being modified, so the `etc/passwd` and `etc/group` files inside the root are read directly instead.
+*/
+use std::{ops::Range, path::Path};
+
+use nix::unistd::{Gid, Group, Uid, User};
+
+use crate::{
+    action::ActionErrorKind,
+    settings::{in_root, is_alternate_root},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PasswdUser {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PasswdGroup {
+    pub name: String,
+    pub gid: u32,
+    pub members: Vec<String>,
+}
+
+/// Look up the user `name` in the user database of the root filesystem at `root`
+pub(crate) fn find_user(root: &Path, name: &str) -> Result<Option<PasswdUser>, ActionErrorKind> {
+    if !is_alternate_root(root) {
+        let user =
+            User::from_name(name).map_err(|e| ActionErrorKind::GettingUserId(name.into(), e))?;
+        return Ok(user.map(|user| PasswdUser {
+            name: user.name,
+            uid: user.uid.as_raw(),
+            gid: user.gid.as_raw(),
+        }));
+    }
+
+    let Some(buf) = read_database(root, "/etc/passwd")? else {
+        return Ok(None);
+    };
+    let user = parse_passwd(&buf).find(|user| user.name == name);
+    Ok(user)
+}
+
+/// Look up the group `name` in the group database of the root filesystem at `root`
+pub(crate) fn find_group(root: &Path, name: &str) -> Result<Option<PasswdGroup>, ActionErrorKind> {
+    if !is_alternate_root(root) {
+        let group =
+            Group::from_name(name).map_err(|e| ActionErrorKind::GettingGroupId(name.into(), e))?;
+        return Ok(group.map(|group| PasswdGroup {
+            name: group.name,
+            gid: group.gid.as_raw(),
+            members: group.mem,
+        }));
+    }
+
+    let Some(buf) = read_database(root, "/etc/group")? else {
+        return Ok(None);
+    };
+    let group = parse_group(&buf).find(|group| group.name == name);
+    Ok(group)
+}
+
+/// The users and groups of the root filesystem at `root` with an ID in `ids`
+///
+/// Every user and group of the local `/etc/passwd` and `/etc/group` is checked. Users and groups from
+/// other sources (such as LDAP) cannot be listed, so for the host only the first and last IDs of `ids`
+/// are looked up in them with `getpwuid(3)` and `getgrgid(3)`.
+pub(crate) fn ids_in_range(root: &Path, ids: &Range<u32>) -> Result<Vec<String>, ActionErrorKind> {
+    let mut found = vec![];
+    if let Some(buf) = read_database(root, "/etc/passwd")? {
+        found.extend(
+            parse_passwd(&buf)
+                .filter(|user| ids.contains(&user.uid))
+                .map(|user| format!("user `{}` (UID {})", user.name, user.uid)),
+        );
+    }
+    if let Some(buf) = read_database(root, "/etc/group")? {
+        found.extend(
+            parse_group(&buf)
+                .filter(|group| ids.contains(&group.gid))
+                .map(|group| format!("group `{}` (GID {})", group.name, group.gid)),
+        );
+    }
+    if !is_alternate_root(root) && !ids.is_empty() {
+        let mut endpoints = vec![ids.start, ids.end - 1];
+        endpoints.dedup();
+        for id in endpoints {
+            let user = User::from_uid(Uid::from_raw(id))
+                .map_err(|e| ActionErrorKind::GettingUserId(id.to_string(), e))?;
+            let user = user.map(|user| format!("user `{}` (UID {id})", user.name));
+            let group = Group::from_gid(Gid::from_raw(id))
+                .map_err(|e| ActionErrorKind::GettingGroupId(id.to_string(), e))?;
+            let group = group.map(|group| format!("group `{}` (GID {id})", group.name));
+            // Local users and groups were already found
+            for entry in user.into_iter().chain(group) {
+                if !found.contains(&entry) {
+                    found.push(entry);
+                }
+            }
+        }
+    }
+    Ok(found)
+}
+
+fn read_database(root: &Path, path: &str) -> Result<Option<String>, ActionErrorKind> {
+    let path = in_root(root, path);
+    match std::fs::read_to_string(&path) {
+        Ok(buf) => Ok(Some(buf)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(ActionErrorKind::Read(path, e)),
+    }
+}
+
+fn parse_passwd(buf: &str) -> impl Iterator<Item = PasswdUser> + '_ {
+    buf.lines().filter_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        let _password = fields.next()?;
+        let uid = fields.next()?.parse().ok()?;
+        let gid = fields.next()?.parse().ok()?;
+        Some(PasswdUser {
+            name: name.to_string(),
+            uid,
+            gid,
+        })
+    })
+}
+
+fn parse_group(buf: &str) -> impl Iterator<Item = PasswdGroup> + '_ {
+    buf.lines().filter_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        let _password = fields.next()?;
+        let gid = fields.next()?.parse().ok()?;
+        let members = fields
+            .next()
+            .unwrap_or_default()
+            .split(',')
+            .filter(|member| !member.is_empty())
+            .map(ToString::to_string)
+            .collect();
+        Some(PasswdGroup {
+            name: name.to_string(),
+            gid,
+            members,
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_alternate_root_databases() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir_all(root.path().join("etc"))?;
+        std::fs::write(
+            root.path().join("etc/passwd"),
+            "root:x:0:0:root:/root:/bin/sh\n\
+            nixbld1:x:30001:30000:Nix build user 1:/var/empty:/sbin/nologin\n",
+        )?;
+        std::fs::write(
+            root.path().join("etc/group"),
+            "root:x:0:\nnixbld:!:30000:nixbld1,nixbld2\n",
+        )?;
+
+        assert_eq!(
+            find_user(root.path(), "nixbld1")?,
+            Some(PasswdUser {
+                name: "nixbld1".into(),
+                uid: 30001,
+                gid: 30000,
+            })
+        );
+        assert_eq!(find_user(root.path(), "nixbld2")?, None);
+        assert_eq!(
+            find_group(root.path(), "nixbld")?,
+            Some(PasswdGroup {
+                name: "nixbld".into(),
+                gid: 30000,
+                members: vec!["nixbld1".into(), "nixbld2".into()],
+            })
+        );
+        assert_eq!(
+            find_group(root.path(), "root")?.map(|g| g.members),
+            Some(vec![])
+        );
+        assert_eq!(
+            ids_in_range(root.path(), &(30_000..30_001))?,
+            vec!["group `nixbld` (GID 30000)".to_string()]
+        );
+        assert!(ids_in_range(root.path(), &(40_000..50_000))?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn looks_up_host_ids() -> eyre::Result<()> {
+        // `root` is in the host's user database, wherever it comes from
+        let found = ids_in_range(Path::new("/"), &(0..1))?;
+        assert_eq!(
+            found
+                .iter()
+                .filter(|entry| *entry == "user `root` (UID 0)")
+                .count(),
+            1
+        );
+        Ok(())
+    }
+}
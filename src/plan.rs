@@ -1,13 +1,21 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{future::Future, str::FromStr, sync::Arc};
 
 use crate::{
-    action::{Action, ActionDescription, StatefulAction},
-    planner::{BuiltinPlanner, Planner},
+    action::{
+        error_chain, Action, ActionDescription, ActionError, ActionState, ActionTag,
+        StatefulAction, VerificationReport,
+    },
+    checkpoint::{self, Journal},
+    event::{self, EventSender, InstallEvent, Operation},
+    migration::{self, RECEIPT_SCHEMA_VERSION},
+    planner::{ActionGraph, BuiltinPlanner, Planner},
+    settings::{in_root, is_alternate_root},
     NixInstallerError,
 };
 use owo_colors::OwoColorize;
-use semver::{Version, VersionReq};
-use tokio::sync::broadcast::Receiver;
+use semver::Version;
+use tokio::{sync::broadcast::Receiver, task::JoinSet};
+use tracing::Instrument;
 
 pub const RECEIPT_LOCATION: &str = "/nix/receipt.json";
 
@@ -19,8 +27,13 @@ revert
 pub struct InstallPlan {
     pub(crate) version: Version,
 
+    pub(crate) schema_version: u32,
+
     pub(crate) actions: Vec<StatefulAction<Box<dyn Action>>>,
 
+    /// For each of the `actions`, the indexes of the actions it depends on
+    pub(crate) dependencies: Vec<Vec<usize>>,
+
     pub(crate) planner: Box<dyn Planner>,
 
     #[cfg(feature = "diagnostics")]
@@ -35,12 +48,17 @@ impl InstallPlan {
         let diagnostic_data = Some(planner.diagnostic_data().await?);
 
         let planner = planner.boxed();
-        let actions = planner.plan().await?;
+        let ActionGraph {
+            actions,
+            dependencies,
+        } = planner.plan_graph().await?;
 
         Ok(Self {
             planner,
             actions,
+            dependencies,
             version: current_version()?,
+            schema_version: RECEIPT_SCHEMA_VERSION,
             #[cfg(feature = "diagnostics")]
             diagnostic_data,
         })
@@ -56,16 +74,30 @@ impl InstallPlan {
         // Some Action `plan` calls may fail if we don't do these checks
         planner.pre_install_check().await?;
 
-        let actions = planner.plan().await?;
+        let ActionGraph {
+            actions,
+            dependencies,
+        } = planner.plan_graph().await?;
         Ok(Self {
             planner: planner.boxed(),
             actions,
+            dependencies,
             version: current_version()?,
+            schema_version: RECEIPT_SCHEMA_VERSION,
             #[cfg(feature = "diagnostics")]
             diagnostic_data,
         })
     }
 
+    /// Parse a receipt, migrating it forward from an older receipt schema version if required
+    ///
+    /// See [`migration`](crate::migration) for details.
+    pub fn from_receipt(receipt: &str) -> Result<Self, NixInstallerError> {
+        let receipt = serde_json::from_str(receipt)?;
+        let receipt = migration::migrate(receipt)?;
+        Ok(serde_json::from_value(receipt)?)
+    }
+
     pub async fn pre_uninstall_check(&self) -> Result<(), NixInstallerError> {
         self.planner.pre_uninstall_check().await?;
         Ok(())
@@ -154,65 +186,133 @@ impl InstallPlan {
     pub async fn install(
         &mut self,
         cancel_channel: impl Into<Option<Receiver<()>>>,
+    ) -> Result<(), NixInstallerError> {
+        self.install_with_events(cancel_channel, None).await
+    }
+
+    /// Install, sending [`InstallEvent`]s describing the progress of the install to `events`
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn install_with_events(
+        &mut self,
+        cancel_channel: impl Into<Option<Receiver<()>>>,
+        events: impl Into<Option<EventSender>>,
+    ) -> Result<(), NixInstallerError> {
+        self.install_journaled(cancel_channel, events.into(), None)
+            .await
+    }
+
+    /// Install, recording each state transition into `journal` if there is one
+    async fn install_journaled(
+        &mut self,
+        cancel_channel: impl Into<Option<Receiver<()>>>,
+        events: Option<EventSender>,
+        journal: Option<Arc<Journal>>,
+    ) -> Result<(), NixInstallerError> {
+        event::send(events.as_ref(), || InstallEvent::PlanStarted {
+            operation: Operation::Install,
+            actions: self.actions.len(),
+        });
+        let res = event::scope(
+            events.clone(),
+            checkpoint::scope(
+                journal.clone(),
+                self.run_install(cancel_channel, events.as_ref(), journal.as_ref()),
+            ),
+        )
+        .await;
+        event::send(events.as_ref(), || InstallEvent::PlanFinished {
+            operation: Operation::Install,
+            error: res.as_ref().err().map(|err| error_chain(err)),
+        });
+        res
+    }
+
+    async fn run_install(
+        &mut self,
+        cancel_channel: impl Into<Option<Receiver<()>>>,
+        events: Option<&EventSender>,
+        journal: Option<&Arc<Journal>>,
     ) -> Result<(), NixInstallerError> {
         self.check_compatible()?;
         self.planner.pre_install_check().await?;
 
-        let Self { actions, .. } = self;
+        let Self {
+            actions,
+            dependencies,
+            ..
+        } = self;
         let mut cancel_channel = cancel_channel.into();
 
-        // This is **deliberately sequential**.
-        // Actions which are parallelizable are represented by "group actions" like CreateUsers
-        // The plan itself represents the concept of the sequence of stages.
-        for action in actions {
-            if let Some(ref mut cancel_channel) = cancel_channel {
-                if cancel_channel.try_recv()
-                    != Err(tokio::sync::broadcast::error::TryRecvError::Empty)
-                {
-                    if let Err(err) = write_receipt(self.clone()).await {
-                        tracing::error!("Error saving receipt: {:?}", err);
-                    }
-
-                    #[cfg(feature = "diagnostics")]
-                    if let Some(diagnostic_data) = &self.diagnostic_data {
-                        diagnostic_data
-                            .clone()
-                            .send(
-                                crate::diagnostics::DiagnosticAction::Install,
-                                crate::diagnostics::DiagnosticStatus::Cancelled,
-                            )
-                            .await?;
-                    }
-
-                    return Err(NixInstallerError::Cancelled);
+        // Actions which must happen in sequence either depend on each other, or are represented
+        // by "group actions" like CreateUsersAndGroups. Everything else happens concurrently.
+        let (walk, errors) = walk(
+            actions,
+            dependencies,
+            &mut cancel_channel,
+            true,
+            events,
+            journal,
+            |mut action| {
+                tracing::info!("Step: {}", action.tracing_synopsis());
+                async move {
+                    let res = action.try_execute().await;
+                    (action, res)
                 }
+            },
+        )
+        .await?;
+
+        let mut errors = errors.into_iter();
+        if let Some(err) = errors.next() {
+            for err in errors {
+                tracing::error!(
+                    "Error executing action concurrently with another failure: {err:?}"
+                );
+            }
+            if let Err(err) = write_receipt(self.clone()).await {
+                tracing::error!("Error saving receipt: {:?}", err);
+            }
+            let err = NixInstallerError::Action(err);
+            #[cfg(feature = "diagnostics")]
+            if let Some(diagnostic_data) = &self.diagnostic_data {
+                diagnostic_data
+                    .clone()
+                    .failure(&err)
+                    .send(
+                        crate::diagnostics::DiagnosticAction::Install,
+                        crate::diagnostics::DiagnosticStatus::Failure,
+                    )
+                    .await?;
             }
 
-            tracing::info!("Step: {}", action.tracing_synopsis());
-            if let Err(err) = action.try_execute().await {
-                if let Err(err) = write_receipt(self.clone()).await {
-                    tracing::error!("Error saving receipt: {:?}", err);
-                }
-                let err = NixInstallerError::Action(err);
-                #[cfg(feature = "diagnostics")]
-                if let Some(diagnostic_data) = &self.diagnostic_data {
-                    diagnostic_data
-                        .clone()
-                        .failure(&err)
-                        .send(
-                            crate::diagnostics::DiagnosticAction::Install,
-                            crate::diagnostics::DiagnosticStatus::Failure,
-                        )
-                        .await?;
-                }
+            return Err(err);
+        }
+
+        if walk == Walk::Cancelled {
+            if let Err(err) = write_receipt(self.clone()).await {
+                tracing::error!("Error saving receipt: {:?}", err);
+            }
 
-                return Err(err);
+            #[cfg(feature = "diagnostics")]
+            if let Some(diagnostic_data) = &self.diagnostic_data {
+                diagnostic_data
+                    .clone()
+                    .send(
+                        crate::diagnostics::DiagnosticAction::Install,
+                        crate::diagnostics::DiagnosticStatus::Cancelled,
+                    )
+                    .await?;
             }
+
+            return Err(NixInstallerError::Cancelled);
         }
 
         write_receipt(self.clone()).await?;
 
-        if let Err(err) = crate::self_test::self_test()
+        if is_alternate_root(&self.planner.root()) {
+            // The shells of the host cannot run the Nix installed into another root
+            tracing::debug!("Skipping self-test of an install into an alternate root");
+        } else if let Err(err) = crate::self_test::self_test()
             .await
             .map_err(NixInstallerError::SelfTest)
         {
@@ -245,6 +345,56 @@ impl InstallPlan {
         Ok(())
     }
 
+    /// Install while recording the receipt after every [`ActionState`](crate::action::ActionState) transition
+    ///
+    /// [`install`](InstallPlan::install) only records the receipt when it stops, this records it each time any
+    /// action, including the sub-actions of composite actions, changes state. If the install is interrupted
+    /// (for example, by a power loss) the receipt it leaves behind can be loaded and resumed again, continuing
+    /// from the first action which was not completed.
+    ///
+    /// Actions left in [`Progress`](crate::action::ActionState::Progress) are re-checked against a fresh plan
+    /// from the same planner first, so steps which finished just before the interruption are not repeated.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn resume(
+        &mut self,
+        cancel_channel: impl Into<Option<Receiver<()>>>,
+    ) -> Result<(), NixInstallerError> {
+        self.resume_with_events(cancel_channel, None).await
+    }
+
+    /// Resume, sending [`InstallEvent`]s describing the progress of the install to `events`
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn resume_with_events(
+        &mut self,
+        cancel_channel: impl Into<Option<Receiver<()>>>,
+        events: impl Into<Option<EventSender>>,
+    ) -> Result<(), NixInstallerError> {
+        self.check_compatible()?;
+
+        let mut receipt = serde_json::to_value(&*self)?;
+        if checkpoint::has_progress(&receipt) {
+            match self.planner.plan().await {
+                Ok(fresh_actions) => {
+                    let fresh_actions = serde_json::to_value(&fresh_actions)?;
+                    if let Some(actions) = receipt.get_mut("actions") {
+                        checkpoint::reconcile(actions, &fresh_actions);
+                    }
+                },
+                Err(err) => tracing::warn!(
+                    "Could not re-check actions left in progress, they will be executed again: {err}"
+                ),
+            }
+        }
+
+        let receipt_location = in_root(&self.planner.root(), RECEIPT_LOCATION);
+        let journal = Journal::start(receipt_location, receipt.clone()).await?;
+        checkpoint::annotate(&mut receipt, "");
+        *self = serde_json::from_value(receipt)?;
+
+        self.install_journaled(cancel_channel, events.into(), Some(journal))
+            .await
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn describe_uninstall(&self, explain: bool) -> Result<String, NixInstallerError> {
         let Self {
@@ -325,44 +475,92 @@ impl InstallPlan {
     pub async fn uninstall(
         &mut self,
         cancel_channel: impl Into<Option<Receiver<()>>>,
+    ) -> Result<(), NixInstallerError> {
+        self.uninstall_with_events(cancel_channel, None).await
+    }
+
+    /// Uninstall, sending [`InstallEvent`]s describing the progress of the uninstall to `events`
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn uninstall_with_events(
+        &mut self,
+        cancel_channel: impl Into<Option<Receiver<()>>>,
+        events: impl Into<Option<EventSender>>,
+    ) -> Result<(), NixInstallerError> {
+        let events = events.into();
+        event::send(events.as_ref(), || InstallEvent::PlanStarted {
+            operation: Operation::Uninstall,
+            actions: self.actions.len(),
+        });
+        let res = event::scope(
+            events.clone(),
+            self.run_uninstall(cancel_channel, events.as_ref()),
+        )
+        .await;
+        event::send(events.as_ref(), || InstallEvent::PlanFinished {
+            operation: Operation::Uninstall,
+            error: res.as_ref().err().map(|err| error_chain(err)),
+        });
+        res
+    }
+
+    async fn run_uninstall(
+        &mut self,
+        cancel_channel: impl Into<Option<Receiver<()>>>,
+        events: Option<&EventSender>,
     ) -> Result<(), NixInstallerError> {
         self.check_compatible()?;
         self.planner.pre_uninstall_check().await?;
 
-        let Self { actions, .. } = self;
+        let Self {
+            actions,
+            dependencies,
+            ..
+        } = self;
         let mut cancel_channel = cancel_channel.into();
-        let mut errors = vec![];
-
-        // This is **deliberately sequential**.
-        // Actions which are parallelizable are represented by "group actions" like CreateUsers
-        // The plan itself represents the concept of the sequence of stages.
-        for action in actions.iter_mut().rev() {
-            if let Some(ref mut cancel_channel) = cancel_channel {
-                if cancel_channel.try_recv()
-                    != Err(tokio::sync::broadcast::error::TryRecvError::Empty)
-                {
-                    if let Err(err) = write_receipt(self.clone()).await {
-                        tracing::error!("Error saving receipt: {:?}", err);
-                    }
 
-                    #[cfg(feature = "diagnostics")]
-                    if let Some(diagnostic_data) = &self.diagnostic_data {
-                        diagnostic_data
-                            .clone()
-                            .send(
-                                crate::diagnostics::DiagnosticAction::Uninstall,
-                                crate::diagnostics::DiagnosticStatus::Cancelled,
-                            )
-                            .await?;
-                    }
-                    return Err(NixInstallerError::Cancelled);
+        // Each action is reverted only once every action depending on it has been reverted
+        let mut dependents = vec![vec![]; dependencies.len()];
+        for (index, depends_on) in dependencies.iter().enumerate() {
+            for dependency in depends_on {
+                if let Some(dependents) = dependents.get_mut(*dependency) {
+                    dependents.push(index);
                 }
             }
+        }
 
-            tracing::info!("Revert: {}", action.tracing_synopsis());
-            if let Err(errs) = action.try_revert().await {
-                errors.push(errs);
+        let (walk, errors) = walk(
+            actions,
+            &dependents,
+            &mut cancel_channel,
+            false,
+            events,
+            None,
+            |mut action| {
+                tracing::info!("Revert: {}", action.tracing_synopsis());
+                async move {
+                    let res = action.try_revert().await;
+                    (action, res)
+                }
+            },
+        )
+        .await?;
+
+        if walk == Walk::Cancelled {
+            if let Err(err) = write_receipt(self.clone()).await {
+                tracing::error!("Error saving receipt: {:?}", err);
             }
+
+            #[cfg(feature = "diagnostics")]
+            if let Some(diagnostic_data) = &self.diagnostic_data {
+                diagnostic_data
+                    .clone()
+                    .send(
+                        crate::diagnostics::DiagnosticAction::Uninstall,
+                        crate::diagnostics::DiagnosticStatus::Cancelled,
+                    )
+                    .await?;
+            }
+            return Err(NixInstallerError::Cancelled);
         }
 
         if errors.is_empty() {
@@ -396,27 +594,173 @@ impl InstallPlan {
         }
     }
 
+    /// Check whether the effects of each completed action in the plan still hold on the system
+    ///
+    /// Nothing is changed on the system. Use [`Verification::holds`](crate::action::Verification::holds)
+    /// on each report to find out whether anything drifted.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn verify(&self) -> Result<Vec<VerificationReport>, NixInstallerError> {
+        self.check_compatible()?;
+
+        let mut reports = Vec::with_capacity(self.actions.len());
+        for action in self.actions.iter() {
+            reports.push(action.try_verify().await);
+        }
+        Ok(reports)
+    }
+
+    /// Ensure this plan can be carried out or reverted by this `nix-installer`
+    ///
+    /// A plan written by any version of `nix-installer` is compatible if its receipt schema version
+    /// matches, older receipts are [migrated](crate::migration) first. Plans used to be required to
+    /// match the version of `nix-installer` itself, that is no longer checked, so neither
+    /// [`NixInstallerError::IncompatibleVersion`] nor [`NixInstallerError::InvalidVersionRequirement`]
+    /// are returned.
     pub fn check_compatible(&self) -> Result<(), NixInstallerError> {
-        let self_version_string = self.version.to_string();
-        let req = VersionReq::parse(&self_version_string)
-            .map_err(|e| NixInstallerError::InvalidVersionRequirement(self_version_string, e))?;
-        let nix_installer_version = current_version()?;
-        if req.matches(&nix_installer_version) {
-            Ok(())
-        } else {
-            Err(NixInstallerError::IncompatibleVersion {
-                binary: nix_installer_version,
-                plan: self.version.clone(),
-            })
+        if self.schema_version != RECEIPT_SCHEMA_VERSION {
+            return Err(NixInstallerError::IncompatibleSchemaVersion {
+                binary: RECEIPT_SCHEMA_VERSION,
+                plan: self.schema_version,
+            });
+        }
+        if self.dependencies.len() != self.actions.len() {
+            return Err(NixInstallerError::DependencyCountMismatch {
+                actions: self.actions.len(),
+                dependencies: self.dependencies.len(),
+            });
         }
+        for (action, depends_on) in self.dependencies.iter().enumerate() {
+            // Only depending on earlier actions ensures the dependencies can never form a cycle
+            if let Some(dependency) = depends_on.iter().find(|dependency| **dependency >= action) {
+                return Err(NixInstallerError::InvalidDependency {
+                    action,
+                    dependency: *dependency,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How a [`walk`] over the actions of an [`InstallPlan`] ended
+#[derive(Debug, PartialEq, Eq)]
+enum Walk {
+    Finished,
+    Cancelled,
+}
+
+/// Run `step` on each of the `actions` once every action it `waits_on` has been stepped, stepping
+/// actions which do not wait on each other concurrently
+///
+/// If `stop_on_error` is set, no further actions are started once any action fails, though
+/// actions which were already started are waited for. An action which panics fails like any other,
+/// and is left [`Progress`](ActionState::Progress) since it may have been partly applied.
+///
+/// Each step, including the sub-actions it carries out, sends its events to `events` and records
+/// its state transitions into `journal`.
+async fn walk<F, Fut>(
+    actions: &mut [StatefulAction<Box<dyn Action>>],
+    waits_on: &[Vec<usize>],
+    cancel_channel: &mut Option<Receiver<()>>,
+    stop_on_error: bool,
+    events: Option<&EventSender>,
+    journal: Option<&Arc<Journal>>,
+    step: F,
+) -> Result<(Walk, Vec<ActionError>), NixInstallerError>
+where
+    F: Fn(StatefulAction<Box<dyn Action>>) -> Fut,
+    Fut: Future<Output = (StatefulAction<Box<dyn Action>>, Result<(), ActionError>)>
+        + Send
+        + 'static,
+{
+    let mut started = vec![false; actions.len()];
+    let mut finished = vec![false; actions.len()];
+    let mut errors = vec![];
+    let mut walk = Walk::Finished;
+    let mut set = JoinSet::new();
+    let mut join_error = None;
+
+    loop {
+        if walk == Walk::Finished && join_error.is_none() && (errors.is_empty() || !stop_on_error) {
+            for (index, action) in actions.iter().enumerate() {
+                let ready = waits_on
+                    .get(index)
+                    .map(|waits_on| waits_on.iter().all(|other| finished[*other]))
+                    .unwrap_or(true);
+                if started[index] || !ready {
+                    continue;
+                }
+                if let Some(ref mut cancel_channel) = cancel_channel {
+                    if cancel_channel.try_recv()
+                        != Err(tokio::sync::broadcast::error::TryRecvError::Empty)
+                    {
+                        walk = Walk::Cancelled;
+                        break;
+                    }
+                }
+
+                started[index] = true;
+                event::send(events, || InstallEvent::ActionStarted {
+                    index,
+                    action: ActionTag::from(action.inner_typetag_name()),
+                    synopsis: action.tracing_synopsis(),
+                });
+                // Each step is a task of its own, so if it panics it is still known which action it was
+                let step = tokio::spawn(
+                    event::scope(
+                        events.cloned(),
+                        checkpoint::scope(journal.cloned(), step(action.clone())),
+                    )
+                    .instrument(tracing::Span::current()),
+                );
+                let _abort_handle = set.spawn(async move { (index, step.await) });
+            }
+        }
+
+        let Some(result) = set.join_next().await else {
+            break;
+        };
+        let (index, action, res) = match result {
+            Ok((index, Ok((action, res)))) => (index, action, res),
+            Ok((index, Err(err))) => {
+                let mut action = actions[index].clone();
+                action.state = ActionState::Progress;
+                let action_tag = ActionTag::from(action.inner_typetag_name());
+                (index, action, Err(ActionError::new(action_tag, err)))
+            },
+            Err(err) => {
+                // Keep waiting on the other actions, so they are recorded before this is returned
+                join_error.get_or_insert(err);
+                continue;
+            },
+        };
+        event::send(events, || InstallEvent::ActionFinished {
+            index,
+            action: ActionTag::from(action.inner_typetag_name()),
+            synopsis: action.tracing_synopsis(),
+            state: action.state,
+            error: res.as_ref().err().map(|err| error_chain(err)),
+        });
+        actions[index] = action;
+        finished[index] = true;
+        if let Err(err) = res {
+            errors.push(err);
+        }
+    }
+
+    match join_error {
+        Some(err) => Err(err.into()),
+        None => Ok((walk, errors)),
     }
 }
 
 async fn write_receipt(plan: InstallPlan) -> Result<(), NixInstallerError> {
-    tokio::fs::create_dir_all("/nix")
+    let root = plan.planner.root();
+    let nix_dir = in_root(&root, "/nix");
+    tokio::fs::create_dir_all(&nix_dir)
         .await
-        .map_err(|e| NixInstallerError::RecordingReceipt(PathBuf::from("/nix"), e))?;
-    let install_receipt_path = PathBuf::from(RECEIPT_LOCATION);
+        .map_err(|e| NixInstallerError::RecordingReceipt(nix_dir, e))?;
+    let install_receipt_path = in_root(&root, RECEIPT_LOCATION);
     let self_json =
         serde_json::to_string_pretty(&plan).map_err(NixInstallerError::SerializingReceipt)?;
     tokio::fs::write(&install_receipt_path, format!("{self_json}\n"))
@@ -436,16 +780,26 @@ pub fn current_version() -> Result<Version, NixInstallerError> {
 mod test {
     use semver::Version;
 
-    use crate::{planner::BuiltinPlanner, InstallPlan, NixInstallerError};
+    use crate::{
+        action::{base::CreateDirectory, Action, ActionState, StatefulAction},
+        event::InstallEvent,
+        migration::RECEIPT_SCHEMA_VERSION,
+        planner::{ActionGraph, BuiltinPlanner},
+        InstallPlan, NixInstallerError,
+    };
+
+    use super::{walk, Walk};
 
     #[tokio::test]
-    async fn ensure_version_allows_compatible() -> Result<(), NixInstallerError> {
+    async fn ensure_schema_allows_compatible() -> Result<(), NixInstallerError> {
         let planner = BuiltinPlanner::default().await?;
-        let good_version = Version::parse(env!("CARGO_PKG_VERSION"))?;
         let value = serde_json::json!({
             "planner": planner.boxed(),
-            "version": good_version,
+            // Receipts from other `nix-installer` versions are fine as long as the schema matches
+            "version": Version::parse("9999999999999.9999999999.99999999")?,
+            "schema_version": RECEIPT_SCHEMA_VERSION,
             "actions": [],
+            "dependencies": [],
         });
         let maybe_plan: InstallPlan = serde_json::from_value(value)?;
         maybe_plan.check_compatible()?;
@@ -453,16 +807,224 @@ mod test {
     }
 
     #[tokio::test]
-    async fn ensure_version_denies_incompatible() -> Result<(), NixInstallerError> {
+    async fn ensure_schema_denies_incompatible() -> Result<(), NixInstallerError> {
         let planner = BuiltinPlanner::default().await?;
-        let bad_version = Version::parse("9999999999999.9999999999.99999999")?;
+        let good_version = Version::parse(env!("CARGO_PKG_VERSION"))?;
         let value = serde_json::json!({
             "planner": planner.boxed(),
-            "version": bad_version,
+            "version": good_version,
+            "schema_version": RECEIPT_SCHEMA_VERSION + 1,
             "actions": [],
+            "dependencies": [],
         });
         let maybe_plan: InstallPlan = serde_json::from_value(value)?;
         assert!(maybe_plan.check_compatible().is_err());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn walk_follows_dependencies() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let parent = temp_dir.path().join("parent");
+        let child = parent.join("child");
+        let other = temp_dir.path().join("other");
+
+        let mut graph = ActionGraph::default();
+        let create_parent = graph.push(
+            CreateDirectory::plan(&parent, None, None, None, false)
+                .await?
+                .boxed(),
+            [],
+        );
+        graph.push(
+            CreateDirectory::plan(&child, None, None, None, false)
+                .await?
+                .boxed(),
+            [create_parent],
+        );
+        graph.push(
+            CreateDirectory::plan(&other, None, None, None, false)
+                .await?
+                .boxed(),
+            [],
+        );
+        let ActionGraph {
+            mut actions,
+            dependencies,
+        } = graph;
+        assert_eq!(dependencies, vec![vec![], vec![0], vec![]]);
+
+        let (walked, errors) = walk(
+            &mut actions,
+            &dependencies,
+            &mut None,
+            true,
+            None,
+            None,
+            execute,
+        )
+        .await?;
+        assert_eq!(walked, Walk::Finished);
+        assert!(errors.is_empty());
+        assert!(actions.iter().all(|v| v.state == ActionState::Completed));
+        assert!(child.exists() && other.exists());
+
+        // The parent is only empty, and so removed, if the child is reverted first
+        let dependents = vec![vec![1], vec![], vec![]];
+        let (walked, errors) = walk(
+            &mut actions,
+            &dependents,
+            &mut None,
+            false,
+            None,
+            None,
+            revert,
+        )
+        .await?;
+        assert_eq!(walked, Walk::Finished);
+        assert!(errors.is_empty());
+        assert!(!parent.exists() && !other.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn walk_records_panics() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let panics = temp_dir.path().join("panics");
+        let other = temp_dir.path().join("other");
+
+        let mut actions = vec![
+            CreateDirectory::plan(&panics, None, None, None, false)
+                .await?
+                .boxed(),
+            CreateDirectory::plan(&other, None, None, None, false)
+                .await?
+                .boxed(),
+        ];
+        let panicking = actions[0].tracing_synopsis();
+        let step = move |mut action: StatefulAction<Box<dyn Action>>| {
+            let panics = action.tracing_synopsis() == panicking;
+            async move {
+                if panics {
+                    panic!("Partway through");
+                }
+                let res = action.try_execute().await;
+                (action, res)
+            }
+        };
+
+        let (walked, errors) = walk(
+            &mut actions,
+            &[vec![], vec![]],
+            &mut None,
+            false,
+            None,
+            None,
+            step,
+        )
+        .await?;
+        assert_eq!(walked, Walk::Finished);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(actions[0].state, ActionState::Progress);
+        assert_eq!(actions[1].state, ActionState::Completed);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn walk_emits_events() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+
+        // Walks carried out at once each only see their own events
+        let (evented, other) = tokio::join!(
+            walk_with_events(temp_dir.path().join("evented")),
+            walk_with_events(temp_dir.path().join("other")),
+        );
+        for (synopsis, events) in [evented?, other?] {
+            let seen = events
+                .into_iter()
+                .map(|event| match event {
+                    InstallEvent::ActionStarted {
+                        index: 0,
+                        synopsis: ref event_synopsis,
+                        ..
+                    } if *event_synopsis == synopsis => "started".to_string(),
+                    InstallEvent::ActionStateChanged {
+                        synopsis: ref event_synopsis,
+                        state,
+                        ..
+                    } if *event_synopsis == synopsis => format!("{state:?}"),
+                    InstallEvent::ActionFinished {
+                        index: 0,
+                        synopsis: ref event_synopsis,
+                        state,
+                        ref error,
+                        ..
+                    } if *event_synopsis == synopsis => {
+                        assert!(error.is_none());
+                        format!("finished {state:?}")
+                    },
+                    event => panic!("Unexpected event {event:?}"),
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                seen,
+                vec!["started", "Progress", "Completed", "finished Completed"]
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Walk a plan creating `path`, returning the synopsis of its action and the events it sent
+    async fn walk_with_events(
+        path: std::path::PathBuf,
+    ) -> eyre::Result<(String, Vec<InstallEvent>)> {
+        let mut actions = vec![CreateDirectory::plan(&path, None, None, None, false)
+            .await?
+            .boxed()];
+        let synopsis = actions[0].tracing_synopsis();
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (walked, errors) = walk(
+            &mut actions,
+            &[vec![]],
+            &mut None,
+            true,
+            Some(&sender),
+            None,
+            execute,
+        )
+        .await?;
+        assert_eq!(walked, Walk::Finished);
+        assert!(errors.is_empty());
+
+        drop(sender);
+        let mut events = vec![];
+        while let Some(event) = receiver.recv().await {
+            events.push(event);
+        }
+        Ok((synopsis, events))
+    }
+
+    async fn execute(
+        mut action: StatefulAction<Box<dyn Action>>,
+    ) -> (
+        StatefulAction<Box<dyn Action>>,
+        Result<(), crate::action::ActionError>,
+    ) {
+        let res = action.try_execute().await;
+        (action, res)
+    }
+
+    async fn revert(
+        mut action: StatefulAction<Box<dyn Action>>,
+    ) -> (
+        StatefulAction<Box<dyn Action>>,
+        Result<(), crate::action::ActionError>,
+    ) {
+        let res = action.try_revert().await;
+        (action, res)
+    }
 }
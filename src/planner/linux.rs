@@ -1,17 +1,20 @@
 use crate::{
     action::{
         base::{CreateDirectory, RemoveDirectory},
-        common::{ConfigureInitService, ConfigureNix, CreateUsersAndGroups, ProvisionNix},
+        common::{ConfigureInitService, ConfigureNix, ProvisionNix},
         linux::ProvisionSelinux,
         StatefulAction,
     },
     error::HasExpectedErrors,
-    planner::{Planner, PlannerError},
+    planner::{plan_build_users, ActionGraph, Planner, PlannerError},
     settings::CommonSettings,
-    settings::{InitSettings, InitSystem, InstallSettingsError},
+    settings::{in_root, is_alternate_root, InitSettings, InitSystem, InstallSettingsError},
     Action, BuiltinPlanner,
 };
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 use tokio::process::Command;
 use which::which;
 
@@ -38,63 +41,87 @@ impl Planner for Linux {
     }
 
     async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
-        let has_selinux = detect_selinux().await?;
+        Ok(self.plan_graph().await?.into_actions())
+    }
+
+    async fn plan_graph(&self) -> Result<ActionGraph, PlannerError> {
+        let root = &self.settings.root;
+        // The SELinux policy of the host says nothing about the policy of an alternate root
+        let has_selinux = if is_alternate_root(root) {
+            if detect_selinux().await? {
+                tracing::warn!(
+                    "Not installing the Nix SELinux policy into the alternate root `{}`",
+                    root.display()
+                );
+            }
+            false
+        } else {
+            detect_selinux().await?
+        };
 
-        let mut plan = vec![];
+        let mut plan = ActionGraph::default();
 
-        plan.push(
-            CreateDirectory::plan("/nix", None, None, 0o0755, true)
+        let create_nix_directory = plan.push(
+            CreateDirectory::plan(in_root(root, "/nix"), None, None, 0o0755, true)
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
+            [],
         );
 
-        plan.push(
+        let provision_nix = plan.push(
             ProvisionNix::plan(&self.settings.clone())
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
+            [create_nix_directory],
         );
-        plan.push(
-            CreateUsersAndGroups::plan(self.settings.clone())
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
-        );
-        plan.push(
+        // Build users are independent of the Nix store, so they are created while Nix is fetched
+        let create_users_and_groups = plan.push(plan_build_users(&self.settings).await?, []);
+        let configure_nix = plan.push(
             ConfigureNix::plan(ShellProfileLocations::default(), &self.settings)
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
+            [provision_nix, create_users_and_groups],
         );
 
+        let mut configure_init_service_dependencies = vec![configure_nix];
         if has_selinux {
+            configure_init_service_dependencies.push(
+                plan.push(
+                    ProvisionSelinux::plan("/usr/share/selinux/packages/nix.pp".into())
+                        .await
+                        .map_err(PlannerError::Action)?
+                        .boxed(),
+                    [configure_nix],
+                ),
+            );
+        }
+
+        configure_init_service_dependencies.push(
             plan.push(
-                ProvisionSelinux::plan("/usr/share/selinux/packages/nix.pp".into())
+                CreateDirectory::plan(in_root(root, "/etc/tmpfiles.d"), None, None, 0o0755, false)
                     .await
                     .map_err(PlannerError::Action)?
                     .boxed(),
-            );
-        }
-
-        plan.push(
-            CreateDirectory::plan("/etc/tmpfiles.d", None, None, 0o0755, false)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+                [],
+            ),
         );
 
-        plan.push(
-            ConfigureInitService::plan(self.init.init, self.init.start_daemon)
+        let configure_init_service = plan.push(
+            ConfigureInitService::plan(self.init.init, self.init.start_daemon, root)
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
+            configure_init_service_dependencies,
         );
         plan.push(
-            RemoveDirectory::plan(crate::settings::SCRATCH_DIR)
+            RemoveDirectory::plan(in_root(root, crate::settings::SCRATCH_DIR))
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
+            [configure_init_service],
         );
 
         Ok(plan)
@@ -110,6 +137,10 @@ impl Planner for Linux {
         Ok(map)
     }
 
+    fn root(&self) -> PathBuf {
+        self.settings.root.clone()
+    }
+
     async fn configured_settings(
         &self,
     ) -> Result<HashMap<String, serde_json::Value>, PlannerError> {
@@ -137,12 +168,16 @@ impl Planner for Linux {
                 .into_keys()
                 .collect::<Vec<_>>(),
             self.settings.ssl_cert_file.clone(),
+            self.settings.network.clone(),
         )?)
     }
     async fn pre_uninstall_check(&self) -> Result<(), PlannerError> {
         check_not_wsl1()?;
 
-        if self.init.init == InitSystem::Systemd && self.init.start_daemon {
+        if self.init.init == InitSystem::Systemd
+            && self.init.start_daemon
+            && !is_alternate_root(&self.settings.root)
+        {
             check_systemd_active()?;
         }
 
@@ -150,6 +185,14 @@ impl Planner for Linux {
     }
 
     async fn pre_install_check(&self) -> Result<(), PlannerError> {
+        if is_alternate_root(&self.settings.root) {
+            // The host is not being installed into, so none of its own state matters
+            if in_root(&self.settings.root, "/etc/NIXOS").exists() {
+                return Err(PlannerError::NixOs);
+            }
+            return Ok(());
+        }
+
         check_not_nixos()?;
 
         check_nix_not_already_installed().await?;
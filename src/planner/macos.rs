@@ -11,15 +11,15 @@ use crate::planner::HasExpectedErrors;
 use crate::{
     action::{
         base::RemoveDirectory,
-        common::{ConfigureInitService, ConfigureNix, CreateUsersAndGroups, ProvisionNix},
+        common::{ConfigureInitService, ConfigureNix, ProvisionNix},
         macos::{CreateNixHookService, CreateNixVolume, SetTmutilExclusions},
         StatefulAction,
     },
     execute_command,
     os::darwin::DiskUtilInfoOutput,
-    planner::{Planner, PlannerError},
+    planner::{plan_build_users, ActionGraph, Planner, PlannerError},
     settings::InstallSettingsError,
-    settings::{CommonSettings, InitSystem},
+    settings::{is_alternate_root, CommonSettings, InitSystem},
     Action, BuiltinPlanner,
 };
 
@@ -91,6 +91,13 @@ impl Planner for Macos {
     }
 
     async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        Ok(self.plan_graph().await?.into_actions())
+    }
+
+    async fn plan_graph(&self) -> Result<ActionGraph, PlannerError> {
+        if is_alternate_root(&self.settings.root) {
+            return Err(PlannerError::AlternateRootNotSupported(self.typetag_name()));
+        }
         let root_disk = match &self.root_disk {
             root_disk @ Some(_) => root_disk.clone(),
             None => {
@@ -127,9 +134,9 @@ impl Planner for Macos {
             },
         };
 
-        let mut plan = vec![];
+        let mut plan = ActionGraph::default();
 
-        plan.push(
+        let create_nix_volume = plan.push(
             CreateNixVolume::plan(
                 root_disk.unwrap(), /* We just ensured it was populated */
                 self.volume_label.clone(),
@@ -139,54 +146,58 @@ impl Planner for Macos {
             .await
             .map_err(PlannerError::Action)?
             .boxed(),
+            [],
         );
-        plan.push(
+        let provision_nix = plan.push(
             ProvisionNix::plan(&self.settings)
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
+            [create_nix_volume],
         );
-        // Auto-allocate uids is broken on Mac. Tools like `whoami` don't work.
-        // e.g. https://github.com/NixOS/nix/issues/8444
-        plan.push(
-            CreateUsersAndGroups::plan(self.settings.clone())
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
-        );
-        plan.push(
+        // Build users are independent of the Nix store, so they are created while Nix is fetched
+        let create_users_and_groups = plan.push(plan_build_users(&self.settings).await?, []);
+        let set_tmutil_exclusions = plan.push(
             SetTmutilExclusions::plan(vec![PathBuf::from("/nix/store"), PathBuf::from("/nix/var")])
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
+            [provision_nix],
         );
-        plan.push(
+        let configure_nix = plan.push(
             ConfigureNix::plan(ShellProfileLocations::default(), &self.settings)
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
+            [provision_nix, create_users_and_groups],
         );
 
+        let mut configure_init_service_dependencies = vec![configure_nix, set_tmutil_exclusions];
         if self.settings.modify_profile {
-            plan.push(
-                CreateNixHookService::plan()
-                    .await
-                    .map_err(PlannerError::Action)?
-                    .boxed(),
+            configure_init_service_dependencies.push(
+                plan.push(
+                    CreateNixHookService::plan()
+                        .await
+                        .map_err(PlannerError::Action)?
+                        .boxed(),
+                    [configure_nix],
+                ),
             );
         }
 
-        plan.push(
-            ConfigureInitService::plan(InitSystem::Launchd, true)
+        let configure_init_service = plan.push(
+            ConfigureInitService::plan(InitSystem::Launchd, true, "/")
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
+            configure_init_service_dependencies,
         );
         plan.push(
             RemoveDirectory::plan(crate::settings::SCRATCH_DIR)
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
+            [configure_init_service],
         );
 
         Ok(plan)
@@ -241,6 +252,7 @@ impl Planner for Macos {
                 .into_keys()
                 .collect::<Vec<_>>(),
             self.settings.ssl_cert_file.clone(),
+            self.settings.network.clone(),
         )?)
     }
 
@@ -80,6 +80,7 @@ impl Planner for MyPlanner {
                 .into_keys()
                 .collect::<Vec<_>>(),
             self.common.ssl_cert_file.clone(),
+            self.common.network.clone(),
         )?)
     }
 }
@@ -117,7 +118,7 @@ use std::{collections::HashMap, path::PathBuf, string::FromUtf8Error};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    action::{ActionError, StatefulAction},
+    action::{base::CreateGroup, common::CreateUsersAndGroups, ActionError, StatefulAction},
     error::HasExpectedErrors,
     settings::{CommonSettings, InstallSettingsError},
     Action, InstallPlan, NixInstallerError,
@@ -133,8 +134,19 @@ pub trait Planner: std::fmt::Debug + Send + Sync + dyn_clone::DynClone {
         Self: Sized;
     /// Plan out the [`Action`]s for an [`InstallPlan`]
     async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError>;
+    /// Plan out the [`Action`]s for an [`InstallPlan`], along with the dependencies between them
+    ///
+    /// Actions which do not depend on each other, directly or indirectly, are executed (and reverted)
+    /// concurrently. By default each action depends on the action planned before it.
+    async fn plan_graph(&self) -> Result<ActionGraph, PlannerError> {
+        Ok(ActionGraph::sequential(self.plan().await?))
+    }
     /// The settings being used by the planner
     fn settings(&self) -> Result<HashMap<String, serde_json::Value>, InstallSettingsError>;
+    /// The root filesystem the planned actions install into, the receipt is recorded inside it
+    fn root(&self) -> PathBuf {
+        PathBuf::from("/")
+    }
 
     async fn configured_settings(&self)
         -> Result<HashMap<String, serde_json::Value>, PlannerError>;
@@ -161,6 +173,121 @@ pub trait Planner: std::fmt::Debug + Send + Sync + dyn_clone::DynClone {
 
 dyn_clone::clone_trait_object!(Planner);
 
+/** The [`Action`]s planned by a [`Planner`], along with the dependencies between them
+
+Each action may only depend on actions pushed before it, so the order actions are pushed in is always a
+valid order to execute them in.
+
+```rust,no_run
+use nix_installer::{
+    action::base::CreateDirectory,
+    planner::{ActionGraph, PlannerError},
+};
+
+# async fn plan_graph() -> Result<ActionGraph, PlannerError> {
+let mut graph = ActionGraph::default();
+let parent = graph.push(
+    CreateDirectory::plan("/example", None, None, 0o0755, false).await?.boxed(),
+    [],
+);
+graph.push(
+    CreateDirectory::plan("/example/child", None, None, 0o0755, false).await?.boxed(),
+    [parent],
+);
+// Does not depend on either of the above, so it may be created concurrently
+graph.push(
+    CreateDirectory::plan("/other", None, None, 0o0755, false).await?.boxed(),
+    [],
+);
+# Ok(graph)
+# }
+```
+*/
+#[derive(Debug, Default, Clone)]
+pub struct ActionGraph {
+    pub(crate) actions: Vec<StatefulAction<Box<dyn Action>>>,
+    pub(crate) dependencies: Vec<Vec<usize>>,
+}
+
+impl ActionGraph {
+    /// A graph where each action depends on the action before it
+    pub fn sequential(actions: Vec<StatefulAction<Box<dyn Action>>>) -> Self {
+        let dependencies = sequential_dependencies(actions.len());
+        Self {
+            actions,
+            dependencies,
+        }
+    }
+
+    /// Add an action depending on the actions at the indexes in `depends_on`, returning its own index
+    pub fn push(
+        &mut self,
+        action: StatefulAction<Box<dyn Action>>,
+        depends_on: impl IntoIterator<Item = usize>,
+    ) -> usize {
+        let mut depends_on = depends_on.into_iter().collect::<Vec<_>>();
+        depends_on.sort_unstable();
+        depends_on.dedup();
+        self.actions.push(action);
+        self.dependencies.push(depends_on);
+        self.actions.len() - 1
+    }
+
+    pub fn actions(&self) -> &[StatefulAction<Box<dyn Action>>] {
+        &self.actions
+    }
+
+    pub fn dependencies(&self) -> &[Vec<usize>] {
+        &self.dependencies
+    }
+
+    pub fn into_actions(self) -> Vec<StatefulAction<Box<dyn Action>>> {
+        self.actions
+    }
+}
+
+/// Dependencies where each of `count` actions depends on the action before it
+pub(crate) fn sequential_dependencies(count: usize) -> Vec<Vec<usize>> {
+    (0..count)
+        .map(|index| index.checked_sub(1).into_iter().collect())
+        .collect()
+}
+
+/// The build users and their group, or only the build group when Nix allocates build UIDs itself
+///
+/// With [`auto_allocate_uids`](CommonSettings::auto_allocate_uids), the reserved range of UIDs is checked to not
+/// be used by any local user or group.
+pub(crate) async fn plan_build_users(
+    settings: &CommonSettings,
+) -> Result<StatefulAction<Box<dyn Action>>, PlannerError> {
+    if !settings.auto_allocate_uids {
+        return Ok(CreateUsersAndGroups::plan(settings.clone()).await?.boxed());
+    }
+
+    // Auto-allocate uids is broken on Mac. Tools like `whoami` don't work.
+    // e.g. https://github.com/NixOS/nix/issues/8444
+    if cfg!(target_os = "macos") {
+        return Err(PlannerError::AutoAllocateUidsUnsupported);
+    }
+    let start = settings.auto_allocate_uids_start;
+    let end = start.checked_add(settings.auto_allocate_uids_count).ok_or(
+        PlannerError::AutoAllocateUidsOverflow(start, settings.auto_allocate_uids_count),
+    )?;
+    let ids = start..end;
+    let used_by = crate::os::passwd::ids_in_range(&settings.root, &ids)
+        .map_err(|e| PlannerError::Action(CreateGroup::error(e)))?;
+    if !used_by.is_empty() {
+        return Err(PlannerError::AutoAllocateUidsInUse { ids, used_by });
+    }
+
+    Ok(CreateGroup::plan(
+        settings.nix_build_group_name.clone(),
+        settings.nix_build_group_id,
+        &settings.root,
+    )?
+    .boxed())
+}
+
 /// Planners built into this crate
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[cfg_attr(feature = "cli", derive(clap::Subcommand))]
@@ -430,6 +557,25 @@ pub enum PlannerError {
     NixExists,
     #[error("WSL1 is not supported, please upgrade to WSL2: https://learn.microsoft.com/en-us/windows/wsl/install#upgrade-version-from-wsl-1-to-wsl-2")]
     Wsl1,
+    /// The planner cannot install into a root filesystem other than `/`
+    #[error("The `{0}` planner does not support installing into an alternate root")]
+    AlternateRootNotSupported(&'static str),
+    /// Nix cannot allocate build UIDs itself on this platform
+    #[error(
+        "Letting Nix allocate build UIDs with `--auto-allocate-uids` is not supported on macOS"
+    )]
+    AutoAllocateUidsUnsupported,
+    /// The range of UIDs reserved for `auto-allocate-uids` does not fit in a UID
+    #[error(
+        "The {1} UIDs starting at {0} reserved for `--auto-allocate-uids` go past the largest UID"
+    )]
+    AutoAllocateUidsOverflow(u32, u32),
+    /// The range of UIDs reserved for `auto-allocate-uids` is already in use
+    #[error("The UIDs {}-{} reserved for `--auto-allocate-uids` are already used by {}, choose another range with `--auto-allocate-uids-start`", ids.start, ids.end - 1, used_by.join(", "))]
+    AutoAllocateUidsInUse {
+        ids: std::ops::Range<u32>,
+        used_by: Vec<String>,
+    },
     /// Failed to execute command
     #[error("Failed to execute command `{0}`")]
     Command(String, #[source] std::io::Error),
@@ -464,6 +610,10 @@ impl HasExpectedErrors for PlannerError {
             this @ PlannerError::NixOs => Some(Box::new(this)),
             this @ PlannerError::NixExists => Some(Box::new(this)),
             this @ PlannerError::Wsl1 => Some(Box::new(this)),
+            this @ PlannerError::AlternateRootNotSupported(_) => Some(Box::new(this)),
+            this @ PlannerError::AutoAllocateUidsUnsupported => Some(Box::new(this)),
+            this @ PlannerError::AutoAllocateUidsOverflow(..) => Some(Box::new(this)),
+            this @ PlannerError::AutoAllocateUidsInUse { .. } => Some(Box::new(this)),
             PlannerError::Command(_, _) => None,
             #[cfg(feature = "diagnostics")]
             PlannerError::Diagnostic(diagnostic_error) => Some(Box::new(diagnostic_error)),
@@ -478,3 +628,67 @@ impl crate::diagnostics::ErrorDiagnostic for PlannerError {
         static_str.to_string()
     }
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+
+    /// Settings letting Nix allocate build UIDs, inside a root with only a `root` user and group
+    async fn auto_allocate_settings(root: &std::path::Path) -> eyre::Result<CommonSettings> {
+        std::fs::create_dir_all(root.join("etc"))?;
+        std::fs::write(root.join("etc/passwd"), "root:x:0:0:root:/root:/bin/sh\n")?;
+        std::fs::write(root.join("etc/group"), "root:x:0:\n")?;
+        let mut settings = CommonSettings::default().await?;
+        settings.root = root.to_path_buf();
+        settings.auto_allocate_uids = true;
+        Ok(settings)
+    }
+
+    #[tokio::test]
+    async fn only_plans_the_build_group_when_auto_allocating_uids() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let settings = auto_allocate_settings(root.path()).await?;
+
+        let action = serde_json::to_value(plan_build_users(&settings).await?)?;
+        assert_eq!(
+            action.pointer("/action/action"),
+            Some(&"create_group".into())
+        );
+        assert_eq!(
+            action.pointer("/action/name"),
+            Some(&settings.nix_build_group_name.clone().into())
+        );
+
+        let settings = CommonSettings {
+            auto_allocate_uids: false,
+            ..settings
+        };
+        let action = serde_json::to_value(plan_build_users(&settings).await?)?;
+        assert_eq!(
+            action.pointer("/action/action"),
+            Some(&"create_users_and_groups".into())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuses_auto_allocated_uids_in_use() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let mut settings = auto_allocate_settings(root.path()).await?;
+        settings.auto_allocate_uids_start = 0;
+        settings.auto_allocate_uids_count = 10;
+
+        let err = plan_build_users(&settings).await.unwrap_err();
+        let PlannerError::AutoAllocateUidsInUse { used_by, .. } = err else {
+            panic!("Expected the range to be in use, got {err:?}");
+        };
+        assert_eq!(
+            used_by,
+            vec![
+                "user `root` (UID 0)".to_string(),
+                "group `root` (GID 0)".to_string()
+            ]
+        );
+        Ok(())
+    }
+}
@@ -1,14 +1,14 @@
 use crate::{
     action::{
         base::{CreateDirectory, CreateFile, RemoveDirectory},
-        common::{ConfigureInitService, ConfigureNix, CreateUsersAndGroups, ProvisionNix},
+        common::{ConfigureInitService, ConfigureNix, ProvisionNix},
         linux::{ProvisionSelinux, StartSystemdUnit, SystemctlDaemonReload},
         StatefulAction,
     },
     error::HasExpectedErrors,
-    planner::{Planner, PlannerError},
+    planner::{plan_build_users, Planner, PlannerError},
     settings::CommonSettings,
-    settings::{InitSystem, InstallSettingsError},
+    settings::{is_alternate_root, InitSystem, InstallSettingsError},
     Action, BuiltinPlanner,
 };
 use std::{collections::HashMap, path::PathBuf};
@@ -43,6 +43,9 @@ impl Planner for Ostree {
     }
 
     async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        if is_alternate_root(&self.settings.root) {
+            return Err(PlannerError::AlternateRootNotSupported(self.typetag_name()));
+        }
         let has_selinux = detect_selinux().await?;
         let mut plan = vec![
             // Primarily for uninstall
@@ -177,12 +180,7 @@ impl Planner for Ostree {
                 .map_err(PlannerError::Action)?
                 .boxed(),
         );
-        plan.push(
-            CreateUsersAndGroups::plan(self.settings.clone())
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
-        );
+        plan.push(plan_build_users(&self.settings).await?);
         plan.push(
             ConfigureNix::plan(shell_profile_locations, &self.settings)
                 .await
@@ -207,7 +205,7 @@ impl Planner for Ostree {
         );
 
         plan.push(
-            ConfigureInitService::plan(InitSystem::Systemd, true)
+            ConfigureInitService::plan(InitSystem::Systemd, true, "/")
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
@@ -277,6 +275,7 @@ impl Planner for Ostree {
                 .into_keys()
                 .collect::<Vec<_>>(),
             self.settings.ssl_cert_file.clone(),
+            self.settings.network.clone(),
         )?)
     }
     async fn pre_uninstall_check(&self) -> Result<(), PlannerError> {
@@ -103,15 +103,15 @@ use tokio::process::Command;
 use crate::{
     action::{
         base::{CreateDirectory, CreateFile, RemoveDirectory},
-        common::{ConfigureInitService, ConfigureNix, CreateUsersAndGroups, ProvisionNix},
+        common::{ConfigureInitService, ConfigureNix, ProvisionNix},
         linux::{
             EnsureSteamosNixDirectory, RevertCleanSteamosNixOffload, StartSystemdUnit,
             SystemctlDaemonReload,
         },
         Action, StatefulAction,
     },
-    planner::{Planner, PlannerError},
-    settings::{CommonSettings, InitSystem, InstallSettingsError},
+    planner::{plan_build_users, Planner, PlannerError},
+    settings::{is_alternate_root, CommonSettings, InitSystem, InstallSettingsError},
     BuiltinPlanner,
 };
 
@@ -146,6 +146,9 @@ impl Planner for SteamDeck {
     }
 
     async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        if is_alternate_root(&self.settings.root) {
+            return Err(PlannerError::AlternateRootNotSupported(self.typetag_name()));
+        }
         // Starting in roughly build ID `20230522.1000`, the Steam Deck has a `/home/.steamos/offload/nix` directory and `nix.mount` unit we can use instead of creating a mountpoint.
         let requires_nix_bind_mount = detect_requires_bind_mount().await?;
 
@@ -324,16 +327,13 @@ impl Planner for SteamDeck {
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
-            CreateUsersAndGroups::plan(self.settings.clone())
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            plan_build_users(&self.settings).await?,
             ConfigureNix::plan(shell_profile_locations, &self.settings)
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
             // Init is required for the steam-deck archetype to make the `/nix` mount
-            ConfigureInitService::plan(InitSystem::Systemd, true)
+            ConfigureInitService::plan(InitSystem::Systemd, true, "/")
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
@@ -396,6 +396,7 @@ impl Planner for SteamDeck {
                 .into_keys()
                 .collect::<Vec<_>>(),
             self.settings.ssl_cert_file.clone(),
+            self.settings.network.clone(),
         )?)
     }
 
@@ -0,0 +1,309 @@
+/*! Resolving a Nix version requirement against an index of Nix releases
+
+A release index is a JSON document mapping each Nix version, then each system, to the URL of its
+Nix package and (optionally) its SHA-256 digest:
+
+```json
+{
+  "versions": {
+    "2.18.1": {
+      "x86_64-linux": {
+        "url": "https://releases.nixos.org/nix/nix-2.18.1/nix-2.18.1-x86_64-linux.tar.xz",
+        "sha256": "..."
+      }
+    }
+  }
+}
+```
+
+Without an index, the [builtin](ReleaseIndex::builtin) one describing the default
+[`nix_package_url`](crate::settings::CommonSettings::nix_package_url)s is used.
+*/
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use semver::{Version, VersionReq};
+use url::Url;
+
+use crate::{
+    network::{NetworkError, NetworkPolicy},
+    settings::{
+        builtin_nix_package_sha256, Sha256Digest, UrlOrPath, NIX_AARCH64_DARWIN_URL,
+        NIX_AARCH64_LINUX_URL, NIX_I686_LINUX_URL, NIX_VERSION, NIX_X64_64_DARWIN_URL,
+        NIX_X64_64_LINUX_URL,
+    },
+};
+
+/// A Nix package in a [`ReleaseIndex`]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ReleaseArtifact {
+    pub url: Url,
+    #[serde(default)]
+    pub sha256: Option<Sha256Digest>,
+}
+
+/// The Nix packages of each system for each version of Nix
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ReleaseIndex {
+    pub versions: BTreeMap<Version, BTreeMap<String, ReleaseArtifact>>,
+}
+
+/// The release a version requirement resolved to
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct NixRelease {
+    pub version: Version,
+    pub system: String,
+    pub url: Url,
+    #[serde(default)]
+    pub sha256: Option<Sha256Digest>,
+}
+
+impl ReleaseIndex {
+    /// The index of the default [`nix_package_url`](crate::settings::CommonSettings::nix_package_url)s
+    pub fn builtin() -> Self {
+        let version = Version::parse(NIX_VERSION).expect("NIX_VERSION is a valid version");
+        let systems = [
+            ("x86_64-linux", NIX_X64_64_LINUX_URL),
+            ("i686-linux", NIX_I686_LINUX_URL),
+            ("aarch64-linux", NIX_AARCH64_LINUX_URL),
+            ("x86_64-darwin", NIX_X64_64_DARWIN_URL),
+            ("aarch64-darwin", NIX_AARCH64_DARWIN_URL),
+        ]
+        .into_iter()
+        .map(|(system, url)| {
+            let url = Url::parse(url).expect("Default Nix package URLs are valid");
+            let sha256 = builtin_nix_package_sha256(&UrlOrPath::Url(url.clone()));
+            (system.to_string(), ReleaseArtifact { url, sha256 })
+        })
+        .collect();
+        Self {
+            versions: [(version, systems)].into_iter().collect(),
+        }
+    }
+
+    /// Read the index at `source`, fetching it with `network` if it is a URL
+    #[tracing::instrument(level = "debug", skip_all, fields(source = %source))]
+    pub async fn read(
+        source: &UrlOrPath,
+        network: &NetworkPolicy,
+        proxy: Option<&Url>,
+        ssl_cert_file: Option<&std::path::Path>,
+    ) -> Result<Self, ReleaseIndexError> {
+        let path = match source {
+            UrlOrPath::Url(url) => match url.scheme() {
+                "https" | "http" => {
+                    let client = network.client(proxy, ssl_cert_file).await?;
+                    let buf = network.fetch(&client, url).await?;
+                    return Ok(serde_json::from_slice(&buf)?);
+                },
+                "file" => PathBuf::from(url.path()),
+                _ => return Err(ReleaseIndexError::UnsupportedSource(source.to_string())),
+            },
+            UrlOrPath::Path(path) => path.clone(),
+            UrlOrPath::Embedded => {
+                return Err(ReleaseIndexError::UnsupportedSource(source.to_string()))
+            },
+        };
+        let buf = tokio::fs::read(&path)
+            .await
+            .map_err(|e| ReleaseIndexError::Read(path, e))?;
+        Ok(serde_json::from_slice(&buf)?)
+    }
+
+    /// The newest release matching `requirement` which has a Nix package for `system`
+    pub fn resolve(
+        &self,
+        requirement: &VersionReq,
+        system: &str,
+    ) -> Result<NixRelease, ReleaseIndexError> {
+        let mut matching = self
+            .versions
+            .iter()
+            .rev()
+            .filter(|(version, _)| requirement.matches(version))
+            .peekable();
+        if matching.peek().is_none() {
+            return Err(ReleaseIndexError::NoMatchingVersion {
+                requirement: requirement.clone(),
+                available: self.versions.keys().cloned().collect(),
+            });
+        }
+        matching
+            .find_map(|(version, systems)| {
+                systems.get(system).map(|artifact| NixRelease {
+                    version: version.clone(),
+                    system: system.to_string(),
+                    url: artifact.url.clone(),
+                    sha256: artifact.sha256.clone(),
+                })
+            })
+            .ok_or_else(|| ReleaseIndexError::NoMatchingSystem {
+                requirement: requirement.clone(),
+                system: system.to_string(),
+            })
+    }
+}
+
+/// The Nix system of the host, such as `x86_64-linux`
+pub fn host_system() -> Option<&'static str> {
+    use target_lexicon::{Architecture, OperatingSystem};
+    match (Architecture::host(), OperatingSystem::host()) {
+        (Architecture::X86_64, OperatingSystem::Linux) => Some("x86_64-linux"),
+        (Architecture::X86_32(_), OperatingSystem::Linux) => Some("i686-linux"),
+        (Architecture::Aarch64(_), OperatingSystem::Linux) => Some("aarch64-linux"),
+        (Architecture::X86_64, OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin) => {
+            Some("x86_64-darwin")
+        },
+        (Architecture::Aarch64(_), OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin) => {
+            Some("aarch64-darwin")
+        },
+        _ => None,
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum ReleaseIndexError {
+    #[error("A release index cannot be read from `{0}`")]
+    UnsupportedSource(String),
+    #[error("Reading release index `{0}`")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("Parsing release index")]
+    Parse(
+        #[from]
+        #[source]
+        serde_json::Error,
+    ),
+    #[error(transparent)]
+    Network(#[from] NetworkError),
+    #[error("No Nix release matches `{requirement}`, the release index has {}", available.iter().map(|v| format!("`{v}`")).collect::<Vec<_>>().join(", "))]
+    NoMatchingVersion {
+        requirement: VersionReq,
+        available: Vec<Version>,
+    },
+    #[error("No Nix release matching `{requirement}` has a Nix package for `{system}`")]
+    NoMatchingSystem {
+        requirement: VersionReq,
+        system: String,
+    },
+    #[error("There are no Nix releases for this system")]
+    UnsupportedSystem,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn index() -> eyre::Result<ReleaseIndex> {
+        Ok(serde_json::from_str(
+            r#"{
+                "versions": {
+                    "2.17.1": {
+                        "x86_64-linux": { "url": "https://example.com/nix-2.17.1-x86_64-linux.tar.xz" }
+                    },
+                    "2.18.0": {
+                        "x86_64-linux": { "url": "https://example.com/nix-2.18.0-x86_64-linux.tar.xz" },
+                        "aarch64-darwin": { "url": "https://example.com/nix-2.18.0-aarch64-darwin.tar.xz" }
+                    },
+                    "2.18.1": {
+                        "x86_64-linux": {
+                            "url": "https://example.com/nix-2.18.1-x86_64-linux.tar.xz",
+                            "sha256": "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+                        }
+                    },
+                    "2.19.0-rc1": {
+                        "x86_64-linux": { "url": "https://example.com/nix-2.19.0-rc1-x86_64-linux.tar.xz" }
+                    }
+                }
+            }"#,
+        )?)
+    }
+
+    #[test]
+    fn resolves_the_newest_match() -> eyre::Result<()> {
+        let index = index()?;
+
+        let release = index.resolve(&"~2.18".parse()?, "x86_64-linux")?;
+        assert_eq!(release.version, Version::new(2, 18, 1));
+        assert_eq!(release.sha256, Some(Sha256Digest::of(b"")));
+
+        // Pre-releases are only chosen when asked for
+        assert_eq!(
+            index.resolve(&"*".parse()?, "x86_64-linux")?.version,
+            Version::new(2, 18, 1)
+        );
+        assert_eq!(
+            index
+                .resolve(&"=2.19.0-rc1".parse()?, "x86_64-linux")?
+                .url
+                .as_str(),
+            "https://example.com/nix-2.19.0-rc1-x86_64-linux.tar.xz"
+        );
+
+        // The newest release with a package for the system
+        assert_eq!(
+            index.resolve(&"~2.18".parse()?, "aarch64-darwin")?.version,
+            Version::new(2, 18, 0)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn explains_missing_releases() -> eyre::Result<()> {
+        let index = index()?;
+
+        let err = index.resolve(&"^3".parse()?, "x86_64-linux").unwrap_err();
+        assert!(matches!(err, ReleaseIndexError::NoMatchingVersion { .. }));
+        assert!(err.to_string().contains("`2.18.1`"), "{err}");
+
+        let err = index
+            .resolve(&"~2.17".parse()?, "aarch64-darwin")
+            .unwrap_err();
+        assert!(matches!(err, ReleaseIndexError::NoMatchingSystem { .. }));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pinned_versions_only_use_matching_sources() -> eyre::Result<()> {
+        let index = index()?;
+        let temp_dir = tempfile::tempdir()?;
+        let mirrors = temp_dir.path().join("mirrors");
+        tokio::fs::write(&mirrors, "https://mirror.example.com/nix.tar.xz\n").await?;
+        let mut settings = crate::settings::CommonSettings::default().await?;
+        settings.nix_package_mirrors = Some(mirrors);
+        let mirror = UrlOrPath::Url("https://mirror.example.com/nix.tar.xz".parse()?);
+
+        // Without a digest a mirror could hold any version of Nix
+        let release = index.resolve(&"=2.18.0".parse()?, "x86_64-linux")?;
+        assert_eq!(
+            settings.nix_package_sources(Some(&release)).await?,
+            vec![UrlOrPath::Url(release.url.clone())]
+        );
+        assert_eq!(settings.nix_package_digest(Some(&release)).await?, None);
+
+        // With one, whichever source is used must be that release
+        let release = index.resolve(&"~2.18".parse()?, "x86_64-linux")?;
+        assert_eq!(
+            settings.nix_package_sources(Some(&release)).await?,
+            vec![mirror, UrlOrPath::Url(release.url.clone())]
+        );
+        assert_eq!(
+            settings.nix_package_digest(Some(&release)).await?,
+            Some(Sha256Digest::of(b""))
+        );
+
+        settings.nix_package_sha256 = Some(Sha256Digest::of(b"nix"));
+        assert!(settings.nix_package_digest(Some(&release)).await.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn builtin_describes_the_defaults() -> eyre::Result<()> {
+        let release = ReleaseIndex::builtin().resolve(&"~2.18".parse()?, "x86_64-linux")?;
+        assert_eq!(release.url.as_str(), NIX_X64_64_LINUX_URL);
+        Ok(())
+    }
+}
@@ -3,6 +3,8 @@ use std::{process::Output, time::SystemTime};
 use tokio::process::Command;
 use which::which;
 
+use crate::{action::error_chain, event::InstallEvent};
+
 #[non_exhaustive]
 #[derive(thiserror::Error, Debug, strum::IntoStaticStr)]
 pub enum SelfTestError {
@@ -47,7 +49,8 @@ impl crate::diagnostics::ErrorDiagnostic for SelfTestError {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Shell {
     Sh,
     Bash,
@@ -155,7 +158,12 @@ pub async fn self_test() -> Result<(), Vec<SelfTestError>> {
     let mut failures = vec![];
 
     for shell in shells {
-        match shell.self_test().await {
+        let res = shell.self_test().await;
+        crate::event::emit(|| InstallEvent::SelfTest {
+            shell,
+            error: res.as_ref().err().map(|err| error_chain(err)),
+        });
+        match res {
             Ok(()) => (),
             Err(err) => failures.push(err),
         }
@@ -1,16 +1,39 @@
 /*! Configurable knobs and their related errors
 */
-use std::{collections::HashMap, fmt::Display, path::PathBuf, str::FromStr};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 #[cfg(feature = "cli")]
 use clap::{
     error::{ContextKind, ContextValue},
     ArgAction,
 };
+use semver::{Version, VersionReq};
 use url::Url;
 
+use crate::{
+    action::base::{
+        create_or_merge_nix_config::parse_merge_rule, MergePolicy, MergeStrategy, RegistryPin,
+    },
+    bundle::BundleError,
+    cache::TarballCache,
+    network::{NetworkError, NetworkPolicy},
+    nix_settings::NixConfValidation,
+    release::{NixRelease, ReleaseIndex, ReleaseIndexError},
+};
+
 pub const SCRATCH_DIR: &str = "/nix/temp-install-dir";
 
+/// Default [`cache_max_size`](CommonSettings::cache_max_size), enough for a few versions of every platform's Nix package
+pub const DEFAULT_CACHE_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// The version of Nix of the default [`nix_package_url`](CommonSettings::nix_package_url)s
+pub const NIX_VERSION: &str = "2.18.1";
+
 /// Default [`nix_package_url`](CommonSettings::nix_package_url) for Linux x86_64
 pub const NIX_X64_64_LINUX_URL: &str =
     "https://releases.nixos.org/nix/nix-2.18.1/nix-2.18.1-x86_64-linux.tar.xz";
@@ -27,6 +50,86 @@ pub const NIX_X64_64_DARWIN_URL: &str =
 pub const NIX_AARCH64_DARWIN_URL: &str =
     "https://releases.nixos.org/nix/nix-2.18.1/nix-2.18.1-aarch64-darwin.tar.xz";
 
+/// Every default [`nix_package_url`](CommonSettings::nix_package_url)
+pub const NIX_PACKAGE_URLS: &[&str] = &[
+    NIX_X64_64_LINUX_URL,
+    NIX_I686_LINUX_URL,
+    NIX_AARCH64_LINUX_URL,
+    NIX_X64_64_DARWIN_URL,
+    NIX_AARCH64_DARWIN_URL,
+];
+
+/// The SHA-256 digests of the default [`nix_package_url`](CommonSettings::nix_package_url)s, as published
+/// next to each tarball (`$URL.sha256`)
+///
+/// These must be updated alongside the URLs above. A default URL without an entry is checked against
+/// the digest published next to it, see [`default_nix_package_sha256`].
+const NIX_PACKAGE_SHA256: &[(&str, &str)] = &[
+    // Copy each entry from the published `nix-2.18.1-$SYSTEM.tar.xz.sha256` file of the release,
+    // never from a download of the tarball itself
+];
+
+/// The builtin digest of `url`, if it is one of the default [`nix_package_url`](CommonSettings::nix_package_url)s
+pub(crate) fn builtin_nix_package_sha256(url: &UrlOrPath) -> Option<Sha256Digest> {
+    let UrlOrPath::Url(url) = url else {
+        return None;
+    };
+    NIX_PACKAGE_SHA256
+        .iter()
+        .find(|(builtin_url, _)| *builtin_url == url.as_str())
+        .and_then(|(_, digest)| digest.parse().ok())
+}
+
+/// The digest of `url`, if it is one of the default [`nix_package_url`](CommonSettings::nix_package_url)s
+///
+/// This is its builtin digest, or else the digest published next to it (`$URL.sha256`).
+pub(crate) async fn default_nix_package_sha256(
+    url: &UrlOrPath,
+    network: &NetworkPolicy,
+    proxy: Option<&Url>,
+    ssl_cert_file: Option<&Path>,
+) -> Result<Option<Sha256Digest>, InstallSettingsError> {
+    if let Some(sha256) = builtin_nix_package_sha256(url) {
+        return Ok(Some(sha256));
+    }
+    match url {
+        UrlOrPath::Url(published) if is_default_nix_package_url(url) => {
+            published_sha256(published, network, proxy, ssl_cert_file)
+                .await
+                .map(Some)
+        },
+        _ => Ok(None),
+    }
+}
+
+fn is_default_nix_package_url(url: &UrlOrPath) -> bool {
+    matches!(url, UrlOrPath::Url(url) if NIX_PACKAGE_URLS.contains(&url.as_str()))
+}
+
+/// Fetch the SHA-256 digest published next to `url`, at `$URL.sha256`
+pub(crate) async fn published_sha256(
+    url: &Url,
+    network: &NetworkPolicy,
+    proxy: Option<&Url>,
+    ssl_cert_file: Option<&Path>,
+) -> Result<Sha256Digest, InstallSettingsError> {
+    let sha256_url = Url::parse(&format!("{url}.sha256"))?;
+    tracing::debug!("Fetching the published digest `{sha256_url}`");
+    let buf = async {
+        let client = network.client(proxy, ssl_cert_file).await?;
+        network.fetch(&client, &sha256_url).await
+    }
+    .await
+    .map_err(|e| InstallSettingsError::FetchingSha256(sha256_url.clone(), Box::new(e)))?;
+    // The file may name the tarball after the digest, like the output of `sha256sum`
+    String::from_utf8_lossy(&buf)
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .parse()
+        .map_err(|e| InstallSettingsError::PublishedSha256(sha256_url, e))
+}
+
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
 pub enum InitSystem {
@@ -59,6 +162,101 @@ impl std::fmt::Display for InitSystem {
     }
 }
 
+/// How `/etc/nix/nix.conf` is written
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum NixConfMode {
+    /// Merge the settings into any existing `nix.conf`, following `nix_conf_merge`
+    #[default]
+    Merge,
+    /// Own `nix.conf` outright, ending it with `!include nix.custom.conf` for settings of the administrator's
+    /// own, which `nix-installer` never touches
+    Own,
+}
+
+impl std::fmt::Display for NixConfMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NixConfMode::Merge => write!(f, "merge"),
+            NixConfMode::Own => write!(f, "own"),
+        }
+    }
+}
+
+/// The default `start-id` of Nix, the first UID it allocates with `auto-allocate-uids`
+pub const DEFAULT_AUTO_ALLOCATE_UIDS_START: u32 = 872_415_232;
+/// The default `id-count` of Nix, the number of UIDs it allocates from with `auto-allocate-uids`
+pub const DEFAULT_AUTO_ALLOCATE_UIDS_COUNT: u32 = 8_388_608;
+
+fn default_auto_allocate_uids_start() -> u32 {
+    DEFAULT_AUTO_ALLOCATE_UIDS_START
+}
+
+fn default_auto_allocate_uids_count() -> u32 {
+    DEFAULT_AUTO_ALLOCATE_UIDS_COUNT
+}
+
+/// The experimental features enabled by default
+pub const DEFAULT_EXPERIMENTAL_FEATURES: &[&str] = &["nix-command", "flakes", "repl-flake"];
+
+fn default_experimental_features() -> Vec<String> {
+    DEFAULT_EXPERIMENTAL_FEATURES
+        .iter()
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// An opinionated setting `nix-installer` adds to `/etc/nix/nix.conf`, unless it is skipped
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum NixConfDefault {
+    /// `auto-optimise-store = true`, except on macOS
+    AutoOptimiseStore,
+    /// `bash-prompt-prefix = (nix:$name)\040`
+    BashPromptPrefix,
+    /// `max-jobs = auto`
+    MaxJobs,
+    /// `extra-nix-path = nixpkgs=flake:nixpkgs`
+    ExtraNixPath,
+}
+
+impl NixConfDefault {
+    pub const ALL: &'static [Self] = &[
+        Self::AutoOptimiseStore,
+        Self::BashPromptPrefix,
+        Self::MaxJobs,
+        Self::ExtraNixPath,
+    ];
+
+    /// The name of the setting
+    pub fn name(&self) -> &'static str {
+        match self {
+            NixConfDefault::AutoOptimiseStore => "auto-optimise-store",
+            NixConfDefault::BashPromptPrefix => "bash-prompt-prefix",
+            NixConfDefault::MaxJobs => "max-jobs",
+            NixConfDefault::ExtraNixPath => "extra-nix-path",
+        }
+    }
+
+    /// The value it is set to
+    pub fn value(&self) -> &'static str {
+        match self {
+            NixConfDefault::AutoOptimiseStore => "true",
+            NixConfDefault::BashPromptPrefix => "(nix:$name)\\040",
+            NixConfDefault::MaxJobs => "auto",
+            NixConfDefault::ExtraNixPath => "nixpkgs=flake:nixpkgs",
+        }
+    }
+}
+
+impl std::fmt::Display for NixConfDefault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 /** Common settings used by all [`BuiltinPlanner`](crate::planner::BuiltinPlanner)s
 
 Settings which only apply to certain [`Planner`](crate::planner::Planner)s should be located in the planner.
@@ -150,10 +348,59 @@ pub struct CommonSettings {
     )]
     pub nix_build_user_id_base: u32,
 
+    /// Let Nix allocate a UID for each build from a reserved range, instead of creating build users
+    ///
+    /// Only the build group is created, and `auto-allocate-uids` is set (and enabled as an experimental
+    /// feature) in `/etc/nix/nix.conf`. Not supported on macOS.
+    ///
+    /// The reserved range must not be used by any user or group. Every local user and group (in
+    /// `/etc/passwd` and `/etc/group`) is checked, but users and groups from other sources (such as LDAP)
+    /// cannot be listed, so only the first and last UIDs of the range are looked up in them.
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            global = true,
+            env = "NIX_INSTALLER_AUTO_ALLOCATE_UIDS"
+        )
+    )]
+    #[serde(default)]
+    pub auto_allocate_uids: bool,
+
+    /// The first UID of the range reserved for `auto_allocate_uids`, set as `start-id`
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            default_value_t = DEFAULT_AUTO_ALLOCATE_UIDS_START,
+            env = "NIX_INSTALLER_AUTO_ALLOCATE_UIDS_START",
+            global = true
+        )
+    )]
+    #[serde(default = "default_auto_allocate_uids_start")]
+    pub auto_allocate_uids_start: u32,
+
+    /// The number of UIDs reserved for `auto_allocate_uids`, set as `id-count`
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            default_value_t = DEFAULT_AUTO_ALLOCATE_UIDS_COUNT,
+            env = "NIX_INSTALLER_AUTO_ALLOCATE_UIDS_COUNT",
+            global = true
+        )
+    )]
+    #[serde(default = "default_auto_allocate_uids_count")]
+    pub auto_allocate_uids_count: u32,
+
     /// The Nix package URL
+    ///
+    /// When given several times, each is tried in order until one succeeds.
     #[cfg_attr(
         feature = "cli",
-        clap(long, env = "NIX_INSTALLER_NIX_PACKAGE_URL", global = true, value_parser = clap::value_parser!(UrlOrPath))
+        clap(long, env = "NIX_INSTALLER_NIX_PACKAGE_URL", global = true, action = ArgAction::Append, value_parser = clap::value_parser!(UrlOrPath))
     )]
     #[cfg_attr(
         all(target_os = "macos", target_arch = "x86_64", feature = "cli"),
@@ -185,7 +432,51 @@ pub struct CommonSettings {
             default_value = NIX_AARCH64_LINUX_URL,
         )
     )]
-    pub nix_package_url: UrlOrPath,
+    pub nix_package_url: Vec<UrlOrPath>,
+
+    /// A version requirement (such as `~2.18`, or `=2.19.0-rc1`) for the Nix package, which is resolved against
+    /// `nix_release_index` instead of using `nix_package_url`
+    ///
+    /// Mirrors and an embedded Nix package are only used when the index knows the digest of the resolved release.
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            env = "NIX_INSTALLER_NIX_VERSION",
+            conflicts_with = "nix_package_url",
+            global = true
+        )
+    )]
+    #[serde(default)]
+    pub nix_version: Option<VersionReq>,
+
+    /// A JSON index of Nix releases to resolve `nix_version` against, by default only the versions of the default
+    /// `nix_package_url`s are known
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_NIX_RELEASE_INDEX", global = true, value_parser = clap::value_parser!(UrlOrPath))
+    )]
+    #[serde(default)]
+    pub nix_release_index: Option<UrlOrPath>,
+
+    /// A file listing mirrors of the Nix package, one URL or path per line, which are tried before `nix_package_url`
+    ///
+    /// Empty lines and lines starting with `#` are ignored.
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_NIX_PACKAGE_MIRRORS", global = true)
+    )]
+    pub nix_package_mirrors: Option<PathBuf>,
+
+    /// The SHA-256 digest the Nix package must match, otherwise the unpacked Nix package is discarded
+    ///
+    /// When this is unset, a default Nix package URL is checked against the digest published next to
+    /// it. Any other Nix package is not verified.
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_NIX_PACKAGE_SHA256", global = true)
+    )]
+    pub nix_package_sha256: Option<Sha256Digest>,
 
     /// The proxy to use (if any), valid proxy bases are `https://$URL`, `http://$URL` and `socks5://$URL`
     #[cfg_attr(feature = "cli", clap(long, env = "NIX_INSTALLER_PROXY"))]
@@ -195,10 +486,221 @@ pub struct CommonSettings {
     #[cfg_attr(feature = "cli", clap(long, env = "NIX_INSTALLER_SSL_CERT_FILE"))]
     pub ssl_cert_file: Option<PathBuf>,
 
+    /// How fetches of the Nix package and `extra_conf` URLs are timed out and retried
+    #[cfg_attr(feature = "cli", clap(flatten))]
+    #[serde(default)]
+    pub network: NetworkPolicy,
+
+    /// A directory to cache verified Nix package tarballs in, so later installs can skip fetching them
+    ///
+    /// Tarballs are keyed by their URL and SHA-256 digest, and are kept through uninstalls. Only tarballs with
+    /// a known SHA-256 digest are cached.
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_CACHE_DIR", global = true)
+    )]
+    #[serde(default)]
+    pub cache_dir: Option<PathBuf>,
+
+    /// The most bytes `cache_dir` may hold, the least recently used tarballs are evicted beyond this
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            default_value_t = DEFAULT_CACHE_MAX_SIZE,
+            env = "NIX_INSTALLER_CACHE_MAX_SIZE",
+            global = true
+        )
+    )]
+    #[serde(default = "default_cache_max_size")]
+    pub cache_max_size: u64,
+
     /// Extra configuration lines for `/etc/nix.conf`
+    ///
+    /// The settings are checked against those the Nix being installed knows, following `nix_conf_validation`.
     #[cfg_attr(feature = "cli", clap(long, action = ArgAction::Append, num_args = 0.., env = "NIX_INSTALLER_EXTRA_CONF", global = true))]
     pub extra_conf: Vec<UrlOrPathOrString>,
 
+    /// Binary caches to substitute store paths from, in addition to `https://cache.nixos.org`
+    ///
+    /// Set as `extra-substituters`, the keys they sign with must be given with `trusted_public_keys`.
+    #[cfg_attr(
+        feature = "cli",
+        clap(long = "substituter", action = ArgAction::Append, env = "NIX_INSTALLER_SUBSTITUTERS", value_delimiter = ',', global = true)
+    )]
+    #[serde(default)]
+    pub substituters: Vec<Substituter>,
+
+    /// Public keys to trust signatures of store paths from, as `name:base64`
+    ///
+    /// Set as `extra-trusted-public-keys`.
+    #[cfg_attr(
+        feature = "cli",
+        clap(long = "trusted-public-key", action = ArgAction::Append, env = "NIX_INSTALLER_TRUSTED_PUBLIC_KEYS", value_delimiter = ',', global = true)
+    )]
+    #[serde(default)]
+    pub trusted_public_keys: Vec<TrustedPublicKey>,
+
+    /// A netrc file with the credentials Nix uses for substituters, placed at `/etc/nix/netrc` where only `root`
+    /// can read it, and set as `netrc-file`
+    ///
+    /// Only the path is recorded in the receipt, not the credentials.
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_NIX_NETRC_FILE", global = true)
+    )]
+    #[serde(default)]
+    pub nix_netrc_file: Option<PathBuf>,
+
+    /// Secret Nix settings (such as `access-tokens`), placed at `/etc/nix/secrets.conf` where only `root` can
+    /// read it, and included from `/etc/nix/nix.conf`
+    ///
+    /// Only the path is recorded in the receipt, not the settings.
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_NIX_SECRET_CONF", global = true)
+    )]
+    #[serde(default)]
+    pub nix_secret_conf: Option<PathBuf>,
+
+    /// Machines to distribute builds to, as `URI SYSTEMS [MAX-JOBS [FEATURES]]`, written to `/etc/nix/machines`
+    ///
+    /// `SYSTEMS` and `FEATURES` are comma separated, such as
+    /// `ssh-ng://nix@builder.example.com x86_64-linux,aarch64-linux 8 kvm,big-parallel`.
+    #[cfg_attr(
+        feature = "cli",
+        clap(long = "remote-builder", action = ArgAction::Append, env = "NIX_INSTALLER_REMOTE_BUILDERS", value_delimiter = ';', global = true)
+    )]
+    #[serde(default)]
+    pub remote_builders: Vec<RemoteBuilder>,
+
+    /// The SSH private key `root` connects to the remote builders with, placed at `/etc/nix/builder_key` where
+    /// only `root` can read it
+    ///
+    /// Only the path is recorded in the receipt, not the key.
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            env = "NIX_INSTALLER_REMOTE_BUILDER_SSH_KEY",
+            requires = "remote_builders",
+            global = true
+        )
+    )]
+    #[serde(default)]
+    pub remote_builder_ssh_key: Option<PathBuf>,
+
+    /// The host keys of the remote builders, as `known_hosts` entries (`HOSTS KEY-TYPE BASE64-KEY`) added to
+    /// `/etc/ssh/ssh_known_hosts`
+    #[cfg_attr(
+        feature = "cli",
+        clap(long = "remote-builder-known-host", action = ArgAction::Append, env = "NIX_INSTALLER_REMOTE_BUILDER_KNOWN_HOSTS", value_delimiter = ';', requires = "remote_builders", global = true)
+    )]
+    #[serde(default)]
+    pub remote_builder_known_hosts: Vec<SshKnownHost>,
+
+    /// Entries to pin in the system flake registry `/etc/nix/registry.json`, as `ID=FLAKE-REF`, such as
+    /// `nixpkgs=github:NixOS/nixpkgs/057f9aecfb71c4437d2b27d3323df7f93c010b7e`
+    ///
+    /// Other entries of an existing registry are kept, an entry for the same ID is only replaced with `--force`.
+    #[cfg_attr(
+        feature = "cli",
+        clap(long = "flake-registry-pin", action = ArgAction::Append, env = "NIX_INSTALLER_FLAKE_REGISTRY_PINS", value_delimiter = ',', global = true)
+    )]
+    #[serde(default)]
+    pub flake_registry_pins: Vec<RegistryPin>,
+
+    /// The experimental features to enable, give none (`--experimental-features ""`) to only use stable features
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            num_args = 0..,
+            value_delimiter = ',',
+            default_values_t = default_experimental_features(),
+            env = "NIX_INSTALLER_EXPERIMENTAL_FEATURES",
+            global = true
+        )
+    )]
+    #[serde(default = "default_experimental_features")]
+    pub experimental_features: Vec<String>,
+
+    /// Opinionated settings to leave out of `/etc/nix/nix.conf`, by default all of them are set
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            value_parser,
+            long = "skip-nix-conf-default",
+            action = ArgAction::Append,
+            value_delimiter = ',',
+            env = "NIX_INSTALLER_SKIP_NIX_CONF_DEFAULTS",
+            global = true
+        )
+    )]
+    #[serde(default)]
+    pub skip_nix_conf_defaults: Vec<NixConfDefault>,
+
+    /// How `/etc/nix/nix.conf` is written
+    ///
+    /// With `own`, an existing `nix.conf` is only replaced with `--force`, its settings can be kept by moving them
+    /// to `/etc/nix/nix.custom.conf` first.
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            value_parser,
+            long,
+            default_value_t = NixConfMode::Merge,
+            env = "NIX_INSTALLER_NIX_CONF_MODE",
+            global = true
+        )
+    )]
+    #[serde(default)]
+    pub nix_conf_mode: NixConfMode,
+
+    /// What to do about unknown settings, or values of the wrong type, in `extra_conf`
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            value_parser,
+            long,
+            default_value_t = NixConfValidation::Warn,
+            env = "NIX_INSTALLER_NIX_CONF_VALIDATION",
+            global = true
+        )
+    )]
+    #[serde(default)]
+    pub nix_conf_validation: NixConfValidation,
+
+    /// How a setting already in `/etc/nix/nix.conf` is merged, as `name=strategy` where the strategy is one of
+    /// `union`, `prefer-existing`, `prefer-ours` or `fail`
+    ///
+    /// By default, list settings (such as `substituters`, or any `extra-` setting) are merged with `union`,
+    /// and the installation fails if any other setting already has a different value.
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, action = ArgAction::Append, env = "NIX_INSTALLER_NIX_CONF_MERGE", value_delimiter = ',', value_parser = parse_merge_rule, global = true)
+    )]
+    #[serde(default)]
+    pub nix_conf_merge: Vec<(String, MergeStrategy)>,
+
+    /// Store closures to import once Nix is installed, each a `nix-store --export` file or a binary cache directory
+    ///
+    /// The closures are trusted as they are, their signatures are not checked.
+    #[cfg_attr(
+        feature = "cli",
+        clap(long = "extra-closure", action = ArgAction::Append, env = "NIX_INSTALLER_EXTRA_CLOSURES", value_delimiter = ',', global = true)
+    )]
+    #[serde(default)]
+    pub extra_closures: Vec<PathBuf>,
+
+    /// Store paths from `extra_closures` to install into the default profile
+    #[cfg_attr(
+        feature = "cli",
+        clap(long = "extra-closure-install", action = ArgAction::Append, env = "NIX_INSTALLER_EXTRA_CLOSURE_INSTALL", value_delimiter = ',', global = true)
+    )]
+    #[serde(default)]
+    pub extra_closure_install: Vec<PathBuf>,
+
     /// If `nix-installer` should forcibly recreate files it finds existing
     #[cfg_attr(
         feature = "cli",
@@ -212,6 +714,17 @@ pub struct CommonSettings {
     )]
     pub force: bool,
 
+    /// The root filesystem to install into, for example an image being built which is mounted at another location
+    ///
+    /// When set to anything other than `/`, users and groups are created in the root's user database, init
+    /// services are enabled without being started, and the receipt is recorded inside the root.
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, default_value = "/", env = "NIX_INSTALLER_ROOT", global = true)
+    )]
+    #[serde(default = "default_root")]
+    pub root: PathBuf,
+
     #[cfg(feature = "diagnostics")]
     /// Relate the install diagnostic to a specific value
     #[cfg_attr(
@@ -316,11 +829,37 @@ impl CommonSettings {
             nix_build_user_id_base,
             nix_build_user_count,
             nix_build_user_prefix: nix_build_user_prefix.to_string(),
-            nix_package_url: url.parse()?,
+            auto_allocate_uids: false,
+            auto_allocate_uids_start: DEFAULT_AUTO_ALLOCATE_UIDS_START,
+            auto_allocate_uids_count: DEFAULT_AUTO_ALLOCATE_UIDS_COUNT,
+            nix_package_url: vec![url.parse()?],
+            nix_version: None,
+            nix_release_index: None,
+            nix_package_mirrors: None,
+            nix_package_sha256: None,
             proxy: Default::default(),
             extra_conf: Default::default(),
+            substituters: Default::default(),
+            trusted_public_keys: Default::default(),
+            nix_netrc_file: None,
+            nix_secret_conf: None,
+            remote_builders: Default::default(),
+            remote_builder_ssh_key: None,
+            remote_builder_known_hosts: Default::default(),
+            flake_registry_pins: Default::default(),
+            experimental_features: default_experimental_features(),
+            skip_nix_conf_defaults: Default::default(),
+            nix_conf_mode: Default::default(),
+            nix_conf_validation: Default::default(),
+            nix_conf_merge: Default::default(),
+            extra_closures: Default::default(),
+            extra_closure_install: Default::default(),
             force: false,
+            root: default_root(),
             ssl_cert_file: Default::default(),
+            network: Default::default(),
+            cache_dir: None,
+            cache_max_size: DEFAULT_CACHE_MAX_SIZE,
             #[cfg(feature = "diagnostics")]
             diagnostic_attribution: None,
             #[cfg(feature = "diagnostics")]
@@ -337,11 +876,37 @@ impl CommonSettings {
             nix_build_user_prefix,
             nix_build_user_id_base,
             nix_build_user_count,
+            auto_allocate_uids,
+            auto_allocate_uids_start,
+            auto_allocate_uids_count,
             nix_package_url,
+            nix_version,
+            nix_release_index,
+            nix_package_mirrors,
+            nix_package_sha256,
             proxy,
             extra_conf,
+            substituters,
+            trusted_public_keys,
+            nix_netrc_file,
+            nix_secret_conf,
+            remote_builders,
+            remote_builder_ssh_key,
+            remote_builder_known_hosts,
+            flake_registry_pins,
+            experimental_features,
+            skip_nix_conf_defaults,
+            nix_conf_mode,
+            nix_conf_validation,
+            nix_conf_merge,
+            extra_closures,
+            extra_closure_install,
             force,
+            root,
             ssl_cert_file,
+            network,
+            cache_dir,
+            cache_max_size,
             #[cfg(feature = "diagnostics")]
                 diagnostic_attribution: _,
             #[cfg(feature = "diagnostics")]
@@ -373,14 +938,100 @@ impl CommonSettings {
             "nix_build_user_count".into(),
             serde_json::to_value(nix_build_user_count)?,
         );
+        map.insert(
+            "auto_allocate_uids".into(),
+            serde_json::to_value(auto_allocate_uids)?,
+        );
+        map.insert(
+            "auto_allocate_uids_start".into(),
+            serde_json::to_value(auto_allocate_uids_start)?,
+        );
+        map.insert(
+            "auto_allocate_uids_count".into(),
+            serde_json::to_value(auto_allocate_uids_count)?,
+        );
         map.insert(
             "nix_package_url".into(),
             serde_json::to_value(nix_package_url)?,
         );
+        map.insert("nix_version".into(), serde_json::to_value(nix_version)?);
+        map.insert(
+            "nix_release_index".into(),
+            serde_json::to_value(nix_release_index)?,
+        );
+        map.insert(
+            "nix_package_mirrors".into(),
+            serde_json::to_value(nix_package_mirrors)?,
+        );
+        map.insert(
+            "nix_package_sha256".into(),
+            serde_json::to_value(nix_package_sha256)?,
+        );
         map.insert("proxy".into(), serde_json::to_value(proxy)?);
         map.insert("ssl_cert_file".into(), serde_json::to_value(ssl_cert_file)?);
+        map.insert("network".into(), serde_json::to_value(network)?);
+        map.insert("cache_dir".into(), serde_json::to_value(cache_dir)?);
+        map.insert(
+            "cache_max_size".into(),
+            serde_json::to_value(cache_max_size)?,
+        );
         map.insert("extra_conf".into(), serde_json::to_value(extra_conf)?);
+        map.insert("substituters".into(), serde_json::to_value(substituters)?);
+        map.insert(
+            "trusted_public_keys".into(),
+            serde_json::to_value(trusted_public_keys)?,
+        );
+        map.insert(
+            "nix_netrc_file".into(),
+            serde_json::to_value(nix_netrc_file)?,
+        );
+        map.insert(
+            "nix_secret_conf".into(),
+            serde_json::to_value(nix_secret_conf)?,
+        );
+        map.insert(
+            "remote_builders".into(),
+            serde_json::to_value(remote_builders)?,
+        );
+        map.insert(
+            "remote_builder_ssh_key".into(),
+            serde_json::to_value(remote_builder_ssh_key)?,
+        );
+        map.insert(
+            "remote_builder_known_hosts".into(),
+            serde_json::to_value(remote_builder_known_hosts)?,
+        );
+        map.insert(
+            "flake_registry_pins".into(),
+            serde_json::to_value(flake_registry_pins)?,
+        );
+        map.insert(
+            "experimental_features".into(),
+            serde_json::to_value(experimental_features)?,
+        );
+        map.insert(
+            "skip_nix_conf_defaults".into(),
+            serde_json::to_value(skip_nix_conf_defaults)?,
+        );
+        map.insert("nix_conf_mode".into(), serde_json::to_value(nix_conf_mode)?);
+        map.insert(
+            "nix_conf_validation".into(),
+            serde_json::to_value(nix_conf_validation)?,
+        );
+        map.insert(
+            "nix_conf_merge".into(),
+            serde_json::to_value(nix_conf_merge)?,
+        );
+        map.insert(
+            "extra_closures".into(),
+            serde_json::to_value(extra_closures)?,
+        );
+        map.insert(
+            "extra_closure_install".into(),
+            serde_json::to_value(extra_closure_install)?,
+        );
         map.insert("force".into(), serde_json::to_value(force)?);
+        map.insert("root".into(), serde_json::to_value(root)?);
 
         #[cfg(feature = "diagnostics")]
         map.insert(
@@ -390,6 +1041,218 @@ impl CommonSettings {
 
         Ok(map)
     }
+
+    /// The [`MergePolicy`] for `/etc/nix/nix.conf` chosen with `nix_conf_merge`
+    pub fn nix_conf_merge_policy(&self) -> MergePolicy {
+        self.nix_conf_merge.iter().cloned().collect()
+    }
+
+    /// The Nix release `nix_version` resolves to for this host, if it is set
+    pub(crate) async fn nix_release(&self) -> Result<Option<NixRelease>, InstallSettingsError> {
+        let Some(nix_version) = &self.nix_version else {
+            return Ok(None);
+        };
+        let index = match &self.nix_release_index {
+            Some(source) => {
+                ReleaseIndex::read(
+                    source,
+                    &self.network,
+                    self.proxy.as_ref(),
+                    self.ssl_cert_file.as_deref(),
+                )
+                .await?
+            },
+            None => ReleaseIndex::builtin(),
+        };
+        let system = crate::release::host_system().ok_or(ReleaseIndexError::UnsupportedSystem)?;
+        let release = index.resolve(nix_version, system)?;
+        tracing::debug!(
+            "Resolved Nix `{nix_version}` to `{}` at `{}`",
+            release.version,
+            release.url
+        );
+        Ok(Some(release))
+    }
+
+    /// The version of Nix being installed, if it is known
+    ///
+    /// It is known when `nix_version` is set, or when only the default `nix_package_url`s can be used.
+    pub(crate) async fn nix_package_version(
+        &self,
+    ) -> Result<Option<Version>, InstallSettingsError> {
+        if let Some(release) = self.nix_release().await? {
+            return Ok(Some(release.version));
+        }
+        let builtin = ReleaseIndex::builtin();
+        let all_builtin = self
+            .nix_package_sources(None)
+            .await?
+            .iter()
+            .all(|source| match source {
+                UrlOrPath::Url(url) => builtin
+                    .versions
+                    .values()
+                    .flat_map(|systems| systems.values())
+                    .any(|artifact| artifact.url == *url),
+                UrlOrPath::Path(_) | UrlOrPath::Embedded => false,
+            });
+        Ok(all_builtin
+            .then(|| builtin.versions.keys().next_back().cloned())
+            .flatten())
+    }
+
+    /// The sources the Nix package can be fetched from, in the order they should be tried
+    ///
+    /// When running from a bundle, the embedded Nix package is tried first, then any mirrors.
+    ///
+    /// When `release` (from [`CommonSettings::nix_release`]) is given, its URL is used instead of
+    /// `nix_package_url`. The embedded Nix package and mirrors may hold any version of Nix, so they
+    /// are only used when the digest of `release` is known to tell them apart.
+    pub(crate) async fn nix_package_sources(
+        &self,
+        release: Option<&NixRelease>,
+    ) -> Result<Vec<UrlOrPath>, InstallSettingsError> {
+        let mut sources = vec![];
+        if let Some(release) = release {
+            if release.sha256.is_none() {
+                tracing::debug!(
+                    "Nix {} has no known digest, only fetching it from `{}`",
+                    release.version,
+                    release.url
+                );
+                return Ok(vec![UrlOrPath::Url(release.url.clone())]);
+            }
+        }
+        if crate::bundle::current().await?.is_some() {
+            sources.push(UrlOrPath::Embedded);
+        }
+        if let Some(mirrors) = &self.nix_package_mirrors {
+            let buf = tokio::fs::read_to_string(mirrors)
+                .await
+                .map_err(|e| UrlOrPathError::Io(mirrors.clone(), e))?;
+            for line in buf.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                sources.push(line.parse()?);
+            }
+        }
+        let nix_package_url = match release {
+            Some(release) => vec![UrlOrPath::Url(release.url.clone())],
+            None => self.nix_package_url.clone(),
+        };
+        for source in &nix_package_url {
+            if !sources.contains(source) {
+                sources.push(source.clone());
+            }
+        }
+        Ok(sources)
+    }
+
+    /// The SHA-256 digest the Nix package must match, if any
+    ///
+    /// When `release` is given with a known digest, that digest is used, so whichever source the
+    /// Nix package comes from it is that release.
+    pub(crate) async fn nix_package_digest(
+        &self,
+        release: Option<&NixRelease>,
+    ) -> Result<Option<Sha256Digest>, InstallSettingsError> {
+        match (release, &self.nix_package_sha256) {
+            (
+                Some(
+                    release @ NixRelease {
+                        sha256: Some(known),
+                        ..
+                    },
+                ),
+                Some(given),
+            ) if known != given => {
+                return Err(InstallSettingsError::ConflictingNixPackageSha256 {
+                    version: release.version.clone(),
+                    known: known.clone(),
+                    given: given.clone(),
+                })
+            },
+            (
+                Some(NixRelease {
+                    sha256: Some(known),
+                    ..
+                }),
+                _,
+            ) => return Ok(Some(known.clone())),
+            (_, Some(given)) => return Ok(Some(given.clone())),
+            (_, None) => (),
+        }
+
+        let nix_package_url = match release {
+            Some(release) => vec![UrlOrPath::Url(release.url.clone())],
+            None => self.nix_package_url.clone(),
+        };
+        let Some(default_url) = nix_package_url
+            .iter()
+            .find(|url| is_default_nix_package_url(url))
+        else {
+            return Ok(None);
+        };
+        if let Some(sha256) = builtin_nix_package_sha256(default_url) {
+            return Ok(Some(sha256));
+        }
+        // A bundle embeds a default Nix package only after checking it against its published digest
+        if let Some((_, manifest)) = crate::bundle::current().await? {
+            if manifest.nix_package_source == default_url.to_string() {
+                return Ok(Some(manifest.nix_package.sha256));
+            }
+        }
+        default_nix_package_sha256(
+            default_url,
+            &self.network,
+            self.proxy.as_ref(),
+            self.ssl_cert_file.as_deref(),
+        )
+        .await
+    }
+
+    /// The `extra_conf`, after any `nix.conf` fragments embedded in the running bundle
+    pub(crate) async fn extra_conf_with_embedded(
+        &self,
+    ) -> Result<Vec<UrlOrPathOrString>, InstallSettingsError> {
+        let mut extra_conf = match crate::bundle::current().await? {
+            Some((path, manifest)) => crate::bundle::read_extra_conf(&path, &manifest)
+                .await?
+                .into_iter()
+                .map(UrlOrPathOrString::String)
+                .collect(),
+            None => vec![],
+        };
+        extra_conf.extend(self.extra_conf.iter().cloned());
+        Ok(extra_conf)
+    }
+
+    /// The cache of Nix package tarballs, if `cache_dir` is set
+    pub(crate) fn tarball_cache(&self) -> Option<TarballCache> {
+        self.cache_dir
+            .as_ref()
+            .map(|dir| TarballCache::new(dir, self.cache_max_size))
+    }
+}
+
+pub(crate) fn default_cache_max_size() -> u64 {
+    DEFAULT_CACHE_MAX_SIZE
+}
+
+pub(crate) fn default_root() -> PathBuf {
+    PathBuf::from("/")
+}
+
+/// If `root` is an alternate root filesystem, rather than `/`
+pub(crate) fn is_alternate_root(root: &Path) -> bool {
+    root != Path::new("/")
+}
+
+/// The location of the absolute `path` inside the root filesystem at `root`
+pub(crate) fn in_root(root: &Path, path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    root.join(path.strip_prefix("/").unwrap_or(path))
 }
 
 #[cfg(target_os = "linux")]
@@ -540,6 +1403,22 @@ pub enum InstallSettingsError {
     InitNotSupported,
     #[error(transparent)]
     UrlOrPath(#[from] UrlOrPathError),
+    #[error(transparent)]
+    Bundle(#[from] BundleError),
+    #[error(transparent)]
+    ReleaseIndex(#[from] ReleaseIndexError),
+    #[error(
+        "Nix {version} has the SHA-256 digest `{known}`, but `--nix-package-sha256` is `{given}`"
+    )]
+    ConflictingNixPackageSha256 {
+        version: Version,
+        known: Sha256Digest,
+        given: Sha256Digest,
+    },
+    #[error("Fetching the published digest `{0}`")]
+    FetchingSha256(Url, #[source] Box<NetworkError>),
+    #[error("The published digest `{0}` is invalid")]
+    PublishedSha256(Url, #[source] Sha256DigestError),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -558,6 +1437,13 @@ pub enum UrlOrPathError {
 pub enum UrlOrPath {
     Url(Url),
     Path(PathBuf),
+    /// The Nix package embedded in the running executable, written as `embedded:`, see [`crate::bundle`]
+    Embedded,
+}
+
+impl UrlOrPath {
+    /// How [`UrlOrPath::Embedded`] is written
+    pub const EMBEDDED: &'static str = "embedded:";
 }
 
 impl Display for UrlOrPath {
@@ -565,6 +1451,7 @@ impl Display for UrlOrPath {
         match self {
             UrlOrPath::Url(url) => f.write_fmt(format_args!("{url}")),
             UrlOrPath::Path(path) => f.write_fmt(format_args!("{}", path.display())),
+            UrlOrPath::Embedded => f.write_str(Self::EMBEDDED),
         }
     }
 }
@@ -573,6 +1460,9 @@ impl FromStr for UrlOrPath {
     type Err = UrlOrPathError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == Self::EMBEDDED {
+            return Ok(UrlOrPath::Embedded);
+        }
         match Url::parse(s) {
             Ok(url) => Ok(UrlOrPath::Url(url)),
             Err(url::ParseError::RelativeUrlWithoutBase) => {
@@ -624,6 +1514,366 @@ impl clap::builder::TypedValueParser for UrlOrPath {
     }
 }
 
+/// A SHA-256 digest, written as 64 hexadecimal characters
+#[derive(Debug, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Sha256Digest([u8; 32]);
+
+impl Sha256Digest {
+    /// The digest of `buf`
+    pub fn of(buf: &[u8]) -> Self {
+        use sha2::Digest;
+        Self(sha2::Sha256::digest(buf).into())
+    }
+
+    /// The digest of everything written to `hasher`
+    pub(crate) fn from_hasher(hasher: sha2::Sha256) -> Self {
+        use sha2::Digest;
+        Self(hasher.finalize().into())
+    }
+}
+
+impl Display for Sha256Digest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Sha256Digest {
+    type Err = Sha256DigestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 64 || !s.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(Sha256DigestError(s.to_string()));
+        }
+        let mut digest = [0; 32];
+        for (index, byte) in digest.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[index * 2..index * 2 + 2], 16)
+                .map_err(|_| Sha256DigestError(s.to_string()))?;
+        }
+        Ok(Self(digest))
+    }
+}
+
+impl TryFrom<String> for Sha256Digest {
+    type Error = Sha256DigestError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Sha256Digest> for String {
+    fn from(value: Sha256Digest) -> Self {
+        value.to_string()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("`{0}` is not a SHA-256 digest, expected 64 hexadecimal characters")]
+pub struct Sha256DigestError(String);
+
+/// The Nix store URL schemes a binary cache can be reached through
+const SUBSTITUTER_SCHEMES: &[&str] = &["http", "https", "s3", "file", "ssh", "ssh-ng"];
+
+/// A binary cache for Nix to substitute store paths from, written as its store URL
+#[derive(Debug, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Substituter(Url);
+
+impl Substituter {
+    pub fn url(&self) -> &Url {
+        &self.0
+    }
+}
+
+impl Display for Substituter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // `Url` adds a trailing slash to URLs without a path, which Nix would keep
+        match self.0.scheme() {
+            "file" => f.write_str(self.0.as_str()),
+            _ => f.write_str(self.0.as_str().trim_end_matches('/')),
+        }
+    }
+}
+
+impl FromStr for Substituter {
+    type Err = BinaryCacheError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).map_err(|e| BinaryCacheError::Url(s.to_string(), e))?;
+        if !SUBSTITUTER_SCHEMES.contains(&url.scheme()) {
+            return Err(BinaryCacheError::UnsupportedScheme(s.to_string()));
+        }
+        Ok(Self(url))
+    }
+}
+
+impl TryFrom<String> for Substituter {
+    type Error = BinaryCacheError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Substituter> for String {
+    fn from(value: Substituter) -> Self {
+        value.to_string()
+    }
+}
+
+/// The public key a binary cache signs store paths with, written as `name:base64`
+#[derive(Debug, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TrustedPublicKey {
+    name: String,
+    key: [u8; 32],
+}
+
+impl TrustedPublicKey {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Display for TrustedPublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use base64::Engine;
+        write!(
+            f,
+            "{}:{}",
+            self.name,
+            base64::engine::general_purpose::STANDARD.encode(self.key)
+        )
+    }
+}
+
+impl FromStr for TrustedPublicKey {
+    type Err = BinaryCacheError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use base64::Engine;
+        let (name, key) = s
+            .split_once(':')
+            .filter(|(name, key)| !name.is_empty() && !key.is_empty())
+            .ok_or_else(|| BinaryCacheError::KeyFormat(s.to_string()))?;
+        let key = base64::engine::general_purpose::STANDARD
+            .decode(key)
+            .map_err(|_| BinaryCacheError::KeyBase64(s.to_string()))?;
+        // Nix public keys are ed25519 public keys
+        let key = key
+            .try_into()
+            .map_err(|key: Vec<u8>| BinaryCacheError::KeyLength(s.to_string(), key.len()))?;
+        Ok(Self {
+            name: name.to_string(),
+            key,
+        })
+    }
+}
+
+impl TryFrom<String> for TrustedPublicKey {
+    type Error = BinaryCacheError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<TrustedPublicKey> for String {
+    fn from(value: TrustedPublicKey) -> Self {
+        value.to_string()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BinaryCacheError {
+    #[error("Error parsing substituter URL `{0}`")]
+    Url(String, #[source] url::ParseError),
+    #[error("Substituter `{0}` is not a binary cache URL, expected one of the schemes {}", SUBSTITUTER_SCHEMES.iter().map(|scheme| format!("`{scheme}`")).collect::<Vec<_>>().join(", "))]
+    UnsupportedScheme(String),
+    #[error("`{0}` is not a trusted public key, expected `name:base64`, such as `cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=`")]
+    KeyFormat(String),
+    #[error("The key of trusted public key `{0}` is not valid base64")]
+    KeyBase64(String),
+    #[error(
+        "The key of trusted public key `{0}` is {1} bytes, but ed25519 public keys are 32 bytes"
+    )]
+    KeyLength(String, usize),
+}
+
+/// A machine Nix distributes builds to, written as `URI SYSTEMS [MAX-JOBS [FEATURES]]`
+///
+/// `SYSTEMS` and `FEATURES` are comma separated, such as
+/// `ssh-ng://nix@builder.example.com x86_64-linux,aarch64-linux 8 kvm,big-parallel`.
+#[derive(Debug, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RemoteBuilder {
+    uri: Url,
+    systems: Vec<String>,
+    max_jobs: u32,
+    features: Vec<String>,
+}
+
+impl RemoteBuilder {
+    pub fn uri(&self) -> &Url {
+        &self.uri
+    }
+
+    /// The line of `/etc/nix/machines` for this builder, authenticating with `ssh_key` (if any)
+    pub fn machines_entry(&self, ssh_key: Option<&Path>) -> String {
+        let list = |items: &[String]| match items.is_empty() {
+            true => "-".to_string(),
+            false => items.join(","),
+        };
+        format!(
+            "{uri} {systems} {ssh_key} {max_jobs} 1 {features} - -",
+            uri = self.uri.as_str().trim_end_matches('/'),
+            systems = list(&self.systems),
+            ssh_key = ssh_key
+                .map(|ssh_key| ssh_key.display().to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            max_jobs = self.max_jobs,
+            features = list(&self.features),
+        )
+    }
+}
+
+impl Display for RemoteBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.uri.as_str().trim_end_matches('/'),
+            self.systems.join(","),
+            self.max_jobs
+        )?;
+        if !self.features.is_empty() {
+            write!(f, " {}", self.features.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for RemoteBuilder {
+    type Err = RemoteBuilderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let list = |items: &str| {
+            items
+                .split(',')
+                .filter(|item| !item.is_empty())
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        };
+        let mut fields = s.split_whitespace();
+        let (Some(uri), Some(systems)) = (fields.next(), fields.next()) else {
+            return Err(RemoteBuilderError::Format(s.to_string()));
+        };
+        let uri = Url::parse(uri).map_err(|e| RemoteBuilderError::Url(uri.to_string(), e))?;
+        if !matches!(uri.scheme(), "ssh" | "ssh-ng") {
+            return Err(RemoteBuilderError::UnsupportedScheme(uri.to_string()));
+        }
+        let max_jobs = match fields.next() {
+            Some(max_jobs) => max_jobs
+                .parse()
+                .map_err(|_| RemoteBuilderError::MaxJobs(max_jobs.to_string()))?,
+            None => 1,
+        };
+        let features = fields.next().map(list).unwrap_or_default();
+        if fields.next().is_some() {
+            return Err(RemoteBuilderError::Format(s.to_string()));
+        }
+        let systems = list(systems);
+        if systems.is_empty() {
+            return Err(RemoteBuilderError::Format(s.to_string()));
+        }
+        Ok(Self {
+            uri,
+            systems,
+            max_jobs,
+            features,
+        })
+    }
+}
+
+impl TryFrom<String> for RemoteBuilder {
+    type Error = RemoteBuilderError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<RemoteBuilder> for String {
+    fn from(value: RemoteBuilder) -> Self {
+        value.to_string()
+    }
+}
+
+/// A `known_hosts` entry for a remote builder, written as `HOSTS KEY-TYPE BASE64-KEY`
+#[derive(Debug, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SshKnownHost(String);
+
+impl Display for SshKnownHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for SshKnownHost {
+    type Err = RemoteBuilderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use base64::Engine;
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+        let [_hosts, key_type, key, ..] = fields[..] else {
+            return Err(RemoteBuilderError::KnownHostFormat(s.to_string()));
+        };
+        if !(key_type.starts_with("ssh-") || key_type.starts_with("ecdsa-")) {
+            return Err(RemoteBuilderError::KnownHostFormat(s.to_string()));
+        }
+        base64::engine::general_purpose::STANDARD
+            .decode(key)
+            .map_err(|_| RemoteBuilderError::KnownHostKey(s.to_string()))?;
+        Ok(Self(fields.join(" ")))
+    }
+}
+
+impl TryFrom<String> for SshKnownHost {
+    type Error = RemoteBuilderError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<SshKnownHost> for String {
+    fn from(value: SshKnownHost) -> Self {
+        value.0
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RemoteBuilderError {
+    #[error("`{0}` is not a remote builder, expected `URI SYSTEMS [MAX-JOBS [FEATURES]]`, such as `ssh-ng://nix@builder.example.com x86_64-linux,aarch64-linux 8 kvm,big-parallel`")]
+    Format(String),
+    #[error("Error parsing remote builder URI `{0}`")]
+    Url(String, #[source] url::ParseError),
+    #[error("Remote builder `{0}` is not reached over SSH, expected the scheme `ssh` or `ssh-ng`")]
+    UnsupportedScheme(String),
+    #[error("The maximum number of jobs `{0}` is not a number")]
+    MaxJobs(String),
+    #[error("`{0}` is not a `known_hosts` entry, expected `HOSTS KEY-TYPE BASE64-KEY`, such as `builder.example.com ssh-ed25519 AAAA...`")]
+    KnownHostFormat(String),
+    #[error("The key of `known_hosts` entry `{0}` is not valid base64")]
+    KnownHostKey(String),
+}
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize, Clone)]
 pub enum UrlOrPathOrString {
     Url(Url),
@@ -697,7 +1947,12 @@ impl crate::diagnostics::ErrorDiagnostic for InstallSettingsError {
 
 #[cfg(test)]
 mod tests {
-    use super::{FromStr, PathBuf, Url, UrlOrPath, UrlOrPathOrString};
+    use super::{
+        default_nix_package_sha256, published_sha256, BinaryCacheError, FromStr,
+        InstallSettingsError, NetworkPolicy, Path, PathBuf, ReleaseIndex, RemoteBuilder,
+        RemoteBuilderError, Sha256Digest, SshKnownHost, Substituter, TrustedPublicKey, Url,
+        UrlOrPath, UrlOrPathOrString, NIX_PACKAGE_SHA256, NIX_PACKAGE_URLS,
+    };
 
     #[test]
     fn url_or_path_or_string_parses() -> Result<(), Box<dyn std::error::Error>> {
@@ -736,6 +1991,199 @@ mod tests {
             UrlOrPath::from_str(file!())?,
             UrlOrPath::Path(PathBuf::from_str(file!())?),
         );
+        assert_eq!(UrlOrPath::from_str("embedded:")?, UrlOrPath::Embedded);
+        assert_eq!(UrlOrPath::Embedded.to_string(), "embedded:");
+        Ok(())
+    }
+
+    #[test]
+    fn sha256_digest_parses() -> Result<(), Box<dyn std::error::Error>> {
+        let empty = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(Sha256Digest::from_str(empty)?, Sha256Digest::of(b""));
+        assert_eq!(
+            Sha256Digest::from_str(&empty.to_uppercase())?.to_string(),
+            empty
+        );
+        assert!(Sha256Digest::from_str(&empty[1..]).is_err());
+        assert!(Sha256Digest::from_str(&format!("+{}", &empty[1..])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn binary_cache_settings_parse() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(
+            Substituter::from_str("https://cache.example.com")?.to_string(),
+            "https://cache.example.com"
+        );
+        assert_eq!(
+            Substituter::from_str("s3://nix-cache?region=eu-west-1")?.to_string(),
+            "s3://nix-cache?region=eu-west-1"
+        );
+        assert!(matches!(
+            Substituter::from_str("ftp://cache.example.com"),
+            Err(BinaryCacheError::UnsupportedScheme(_))
+        ));
+
+        let key = "cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=";
+        let parsed = TrustedPublicKey::from_str(key)?;
+        assert_eq!(parsed.name(), "cache.nixos.org-1");
+        assert_eq!(parsed.to_string(), key);
+        assert!(matches!(
+            TrustedPublicKey::from_str("6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY="),
+            Err(BinaryCacheError::KeyFormat(_))
+        ));
+        assert!(matches!(
+            TrustedPublicKey::from_str(
+                "cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShj!"
+            ),
+            Err(BinaryCacheError::KeyBase64(_))
+        ));
+        // A key with its last character cut off
+        assert!(matches!(
+            TrustedPublicKey::from_str(
+                "cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDSh"
+            ),
+            Err(BinaryCacheError::KeyBase64(_) | BinaryCacheError::KeyLength(..))
+        ));
+        assert!(matches!(
+            TrustedPublicKey::from_str("cache.nixos.org-1:AAAA"),
+            Err(BinaryCacheError::KeyLength(_, 3))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn remote_builder_settings_parse() -> Result<(), Box<dyn std::error::Error>> {
+        let builder = RemoteBuilder::from_str(
+            "ssh-ng://nix@builder.example.com x86_64-linux,aarch64-linux 8 kvm,big-parallel",
+        )?;
+        assert_eq!(
+            builder.machines_entry(Some(Path::new("/etc/nix/builder_key"))),
+            "ssh-ng://nix@builder.example.com x86_64-linux,aarch64-linux /etc/nix/builder_key 8 1 kvm,big-parallel - -"
+        );
+        assert_eq!(
+            RemoteBuilder::from_str(&builder.to_string())?,
+            builder,
+            "Should round trip"
+        );
+        assert_eq!(
+            RemoteBuilder::from_str("ssh://builder.example.com aarch64-darwin")?
+                .machines_entry(None),
+            "ssh://builder.example.com aarch64-darwin - 1 1 - - -"
+        );
+        assert!(matches!(
+            RemoteBuilder::from_str("ssh://builder.example.com"),
+            Err(RemoteBuilderError::Format(_))
+        ));
+        assert!(matches!(
+            RemoteBuilder::from_str("https://builder.example.com x86_64-linux"),
+            Err(RemoteBuilderError::UnsupportedScheme(_))
+        ));
+        assert!(matches!(
+            RemoteBuilder::from_str("ssh://builder.example.com x86_64-linux many"),
+            Err(RemoteBuilderError::MaxJobs(_))
+        ));
+
+        assert_eq!(
+            SshKnownHost::from_str("builder.example.com  ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl")?.to_string(),
+            "builder.example.com ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl"
+        );
+        assert!(matches!(
+            SshKnownHost::from_str("builder.example.com ssh-ed25519"),
+            Err(RemoteBuilderError::KnownHostFormat(_))
+        ));
+        assert!(matches!(
+            SshKnownHost::from_str("builder.example.com ssh-ed25519 not!base64"),
+            Err(RemoteBuilderError::KnownHostKey(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn every_builtin_digest_is_for_a_default_url() {
+        for (url, digest) in NIX_PACKAGE_SHA256 {
+            assert!(
+                NIX_PACKAGE_URLS.contains(url),
+                "`{url}` is not a default URL"
+            );
+            assert!(
+                digest.parse::<Sha256Digest>().is_ok(),
+                "The digest of `{url}` is invalid"
+            );
+        }
+        for url in NIX_PACKAGE_URLS {
+            assert!(
+                ReleaseIndex::builtin()
+                    .versions
+                    .values()
+                    .flat_map(|systems| systems.values())
+                    .any(|artifact| artifact.url.as_str() == *url),
+                "`{url}` is not in the builtin release index"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn only_default_urls_have_a_default_digest() -> eyre::Result<()> {
+        let network = NetworkPolicy::default();
+        let other = UrlOrPath::Url("https://mirror.example.com/nix.tar.xz".parse()?);
+        assert_eq!(
+            default_nix_package_sha256(&other, &network, None, None).await?,
+            None
+        );
+        let path = UrlOrPath::Path(PathBuf::from(file!()));
+        assert_eq!(
+            default_nix_package_sha256(&path, &network, None, None).await?,
+            None
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetches_published_digests() -> eyre::Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let digest = Sha256Digest::of(b"nix");
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/nix.tar.xz", listener.local_addr()?))?;
+        let bodies = vec![
+            format!("{digest}  nix.tar.xz\n"),
+            format!("{digest}\n"),
+            "not a digest\n".to_string(),
+        ];
+        let server = tokio::spawn(async move {
+            let mut paths = vec![];
+            for body in bodies {
+                let (mut socket, _) = listener.accept().await?;
+                let mut buf = vec![0; 4096];
+                let read = socket.read(&mut buf).await?;
+                let request = String::from_utf8_lossy(&buf[..read]).to_string();
+                paths.push(request.split_whitespace().nth(1).map(ToString::to_string));
+                socket
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{body}",
+                            body.len()
+                        )
+                        .as_bytes(),
+                    )
+                    .await?;
+                socket.shutdown().await?;
+            }
+            Ok::<_, eyre::Report>(paths)
+        });
+
+        let network = NetworkPolicy::default();
+        assert_eq!(published_sha256(&url, &network, None, None).await?, digest);
+        assert_eq!(published_sha256(&url, &network, None, None).await?, digest);
+        assert!(matches!(
+            published_sha256(&url, &network, None, None).await,
+            Err(InstallSettingsError::PublishedSha256(..))
+        ));
+        assert_eq!(
+            server.await??,
+            vec![Some("/nix.tar.xz.sha256".to_string()); 3]
+        );
         Ok(())
     }
 }
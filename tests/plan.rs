@@ -1,35 +1,139 @@
-use nix_installer::InstallPlan;
+use nix_installer::{
+    migration::{self, RECEIPT_SCHEMA_VERSION},
+    InstallPlan,
+};
+use serde_json::Value;
 
 #[cfg(target_os = "linux")]
 const LINUX: &str = include_str!("./fixtures/linux/linux.json");
 #[cfg(target_os = "linux")]
+const LINUX_V3: &str = include_str!("./fixtures/linux/linux-v3.json");
+#[cfg(target_os = "linux")]
 const STEAM_DECK: &str = include_str!("./fixtures/linux/steam-deck.json");
 #[cfg(target_os = "macos")]
 const MACOS: &str = include_str!("./fixtures/macos/macos.json");
 
-// Ensure existing plans still parse
-// If this breaks and you need to update the fixture, disable these tests, bump `nix_installer` to a new version, and update the plans.
+/// Migrate a receipt written by an older `nix-installer` and ensure the result parses without
+/// losing anything along the way
+///
+/// The fixtures are never updated, when the receipt layout changes add a migration to
+/// `nix_installer::migration` instead.
+fn migrate_fixture(fixture: &str) -> eyre::Result<Value> {
+    let receipt: Value = serde_json::from_str(fixture)?;
+    assert!(migration::schema_version(&receipt)? < RECEIPT_SCHEMA_VERSION);
+
+    let migrated = migration::migrate(receipt)?;
+    assert_eq!(
+        migration::schema_version(&migrated)?,
+        RECEIPT_SCHEMA_VERSION
+    );
+    // Migrating a current receipt changes nothing
+    assert_eq!(migration::migrate(migrated.clone())?, migrated);
+
+    let plan: InstallPlan = serde_json::from_value(migrated.clone())?;
+    plan.check_compatible()?;
+    assert_retained(&migrated, &serde_json::to_value(&plan)?, "");
+
+    Ok(migrated)
+}
+
+/// Ensure every value in `migrated` survives a round trip through [`InstallPlan`]
+fn assert_retained(migrated: &Value, reserialized: &Value, pointer: &str) {
+    match (migrated, reserialized) {
+        (Value::Object(migrated), Value::Object(reserialized)) => {
+            for (key, value) in migrated {
+                let pointer = format!("{pointer}/{key}");
+                match reserialized.get(key) {
+                    Some(reserialized) => assert_retained(value, reserialized, &pointer),
+                    None => panic!("`{pointer}` was lost when parsing the migrated receipt"),
+                }
+            }
+        },
+        (Value::Array(migrated), Value::Array(reserialized)) => {
+            assert_eq!(
+                migrated.len(),
+                reserialized.len(),
+                "`{pointer}` changed length"
+            );
+            for (index, (value, reserialized)) in migrated.iter().zip(reserialized).enumerate() {
+                assert_retained(value, reserialized, &format!("{pointer}/{index}"));
+            }
+        },
+        (migrated, reserialized) => {
+            assert_eq!(migrated, reserialized, "`{pointer}` changed")
+        },
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn plan_migrate_linux() -> eyre::Result<()> {
+    let migrated = migrate_fixture(LINUX)?;
+    // The build group moved out of `provision_nix`
+    assert!(migrated.pointer("/actions/1/action/create_group").is_none());
+    assert_eq!(
+        migrated.pointer("/actions/2/action/action"),
+        Some(&Value::from("create_users_and_groups"))
+    );
+    assert_eq!(
+        migrated.pointer("/actions/2/action/create_group/action/name"),
+        Some(&Value::from("nixbld"))
+    );
+    // Older plans were executed in order
+    assert_eq!(
+        migrated.pointer("/dependencies/3"),
+        Some(&serde_json::json!([2]))
+    );
+    Ok(())
+}
+
 #[cfg(target_os = "linux")]
 #[test]
-fn plan_compat_linux() -> eyre::Result<()> {
-    let _: InstallPlan = serde_json::from_str(LINUX)?;
+fn plan_migrate_linux_v3() -> eyre::Result<()> {
+    let migrated = migrate_fixture(LINUX_V3)?;
+    // What was added since is left to its default
+    let plan: InstallPlan = serde_json::from_value(migrated)?;
+    let reserialized = serde_json::to_value(&plan)?;
+    assert_eq!(
+        reserialized.pointer("/actions/3/action/import_closures"),
+        Some(&Value::Null)
+    );
+    assert_eq!(
+        reserialized.pointer("/actions/3/action/configure_remote_builders"),
+        Some(&Value::Null)
+    );
+    assert_eq!(
+        reserialized.pointer("/actions/3/action/create_or_merge_flake_registry"),
+        Some(&Value::Null)
+    );
+    assert_eq!(
+        reserialized.pointer("/actions/1/action/release"),
+        Some(&Value::Null)
+    );
+    assert!(reserialized
+        .pointer("/actions/3/action/place_nix_configuration/action/create_or_merge_nix_config/action/merge_policy")
+        .is_some());
     Ok(())
 }
 
-// Ensure existing plans still parse
-// If this breaks and you need to update the fixture, disable these tests, bump `nix_installer` to a new version, and update the plans.
 #[cfg(target_os = "linux")]
 #[test]
-fn plan_compat_steam_deck() -> eyre::Result<()> {
-    let _: InstallPlan = serde_json::from_str(STEAM_DECK)?;
+fn plan_migrate_steam_deck() -> eyre::Result<()> {
+    let migrated = migrate_fixture(STEAM_DECK)?;
+    assert_eq!(
+        migrated.pointer("/actions/6/action/action"),
+        Some(&Value::from("create_users_and_groups"))
+    );
     Ok(())
 }
 
-// Ensure existing plans still parse
-// If this breaks and you need to update the fixture, disable these tests, bump `nix_installer` to a new version, and update the plans.
 #[cfg(target_os = "macos")]
 #[test]
-fn plan_compat_macos() -> eyre::Result<()> {
-    let _: InstallPlan = serde_json::from_str(MACOS)?;
+fn plan_migrate_macos() -> eyre::Result<()> {
+    let migrated = migrate_fixture(MACOS)?;
+    assert_eq!(
+        migrated.pointer("/actions/0/action/action"),
+        Some(&Value::from("create_nix_volume"))
+    );
     Ok(())
 }